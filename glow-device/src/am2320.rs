@@ -1,72 +1,308 @@
 //! Environment sensor
 //!
+//! Reads an AM2320 over I2C unless `SENSOR_TYPE=ds18b20` picks a 1-Wire DS18B20.
+//!
 //! TODO: investigate turning thread part into request / response service
-use std::{sync::mpsc::sync_channel, thread};
+use std::{env, sync::mpsc::sync_channel, thread, time::Instant};
 
 use am2320::Am2320;
+use async_trait::async_trait;
+use embedded_hal::blocking::i2c;
 use log::{debug, error, info};
 use rppal::{hal::Delay, i2c::I2c};
-use tokio::time::{delay_for, Duration};
+use tokio::{
+    sync::broadcast::RecvError,
+    time::{delay_for, Duration},
+};
 
 use glow_events::{
-    v2::{Command, Event, Message},
+    v2::{Command, Event, Message, Payload},
     Measurement,
 };
 
-use crate::events::Sender;
+use crate::ds18b20::Ds18b20Sensor;
+use crate::events::{Handler, Receiver, Sender};
 use core::time;
 
 const SENSOR_ERROR_LIMIT: u8 = 3;
 const SENSOR_ERROR_BACKOFF_LIMIT: u64 = 3;
 const SENSOR_SLEEP: u64 = 30;
 const SENSOR_MAX_SKIP: u8 = 10;
+/// Smallest change in °C or %RH that is sent to the bus, override with `SENSOR_EMIT_THRESHOLD`
+///
+/// This only decides what gets charted, the LED handler skips updates that do not change a
+/// pixel. The sensor reads in steps of 0.1 so the default sends every change.
+const DEFAULT_EMIT_THRESHOLD: f64 = 0.05;
+/// Which readings are worth sending to the bus
+#[derive(Debug, Clone, Copy)]
+struct EmitPolicy {
+    /// Smallest change in °C or %RH that is sent
+    threshold: f64,
+    /// Changes within this time of the last reading sent are skipped, override with
+    /// `SENSOR_MIN_INTERVAL_SECONDS`
+    ///
+    /// This caps how often readings are uploaded while the room warms up.
+    min_interval: Duration,
+}
+
+impl Default for EmitPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_EMIT_THRESHOLD,
+            min_interval: Duration::from_secs(0),
+        }
+    }
+}
+
+/// How long a reading can stay exactly the same before it is reported as a failure, override
+/// with `SENSOR_STALE_MINUTES`, 0 turns it off
+///
+/// A failing AM2320 can keep returning the same cached value, which `should_send` would
+/// otherwise quietly skip as unchanged.
+const DEFAULT_STALE_AFTER: Duration = Duration::from_secs(2 * 60 * 60);
+/// Where the AM2320 answers unless `AM2320_ADDR` says otherwise
+const DEFAULT_I2C_ADDRESS: u8 = 0x5c;
+
+/// The kinds of sensor that can be attached, picked with `SENSOR_TYPE`
+#[derive(Debug, PartialEq)]
+enum SensorType {
+    Am2320,
+    Ds18b20,
+}
+
+/// Parse `SENSOR_TYPE`, an AM2320 is assumed if it is not set
+fn parse_sensor_type(value: Option<&str>) -> Result<SensorType, String> {
+    match value {
+        None | Some("am2320") => Ok(SensorType::Am2320),
+        Some("ds18b20") => Ok(SensorType::Ds18b20),
+        Some(other) => Err(format!(
+            "SENSOR_TYPE must be am2320 or ds18b20, not {:?}",
+            other
+        )),
+    }
+}
 
 type ResponseSender = tokio::sync::oneshot::Sender<Option<Measurement>>;
 type RequestReceiver = std::sync::mpsc::Receiver<ResponseSender>;
 
-pub async fn handler(tx: Sender) {
-    let (req_sender, req_receiver) = sync_channel(0);
+/// An AM2320 temperature and humidity sensor
+///
+/// Unlabelled sensors emit `Event::Measurement`. Labelled sensors emit
+/// `Event::LabelledMeasurement` so that readings from different rooms can be told apart.
+#[derive(Default)]
+pub struct EnvironmentSensor {
+    label: Option<String>,
+}
 
-    let mut previous_data: Option<Measurement> = None;
-    let mut num_skipped: u8 = 0;
+impl EnvironmentSensor {
+    pub fn labelled(label: &str) -> Self {
+        Self {
+            label: Some(label.to_string()),
+        }
+    }
+}
 
-    thread::spawn(move || {
-        run_worker(req_receiver);
-    });
+#[async_trait]
+impl Handler for EnvironmentSensor {
+    async fn run(&self, tx: Sender) {
+        let (req_sender, req_receiver) = sync_channel(0);
+        let (temperature_offset, humidity_offset) = calibration_from_env();
+        let sensor_type = parse_sensor_type(env::var("SENSOR_TYPE").ok().as_deref())
+            .unwrap_or_else(|err| panic!("Invalid sensor setup: {}", err));
+        let i2c_config = parse_i2c_config(
+            env::var("AM2320_BUS").ok().as_deref(),
+            env::var("AM2320_ADDR").ok().as_deref(),
+        )
+        .unwrap_or_else(|err| panic!("Invalid AM2320 I2C setup: {}", err));
+        let ds18b20_path = env::var("DS18B20_PATH").ok();
+        let emit_policy = EmitPolicy {
+            threshold: env::var("SENSOR_EMIT_THRESHOLD").map_or(DEFAULT_EMIT_THRESHOLD, |value| {
+                value
+                    .parse()
+                    .expect("SENSOR_EMIT_THRESHOLD must be a number")
+            }),
+            min_interval: env::var("SENSOR_MIN_INTERVAL_SECONDS").map_or(
+                Duration::from_secs(0),
+                |seconds| {
+                    Duration::from_secs(
+                        seconds
+                            .parse()
+                            .expect("SENSOR_MIN_INTERVAL_SECONDS must be a number"),
+                    )
+                },
+            ),
+        };
+        let stale_after =
+            env::var("SENSOR_STALE_MINUTES").map_or(Some(DEFAULT_STALE_AFTER), |minutes| {
+                match minutes
+                    .parse::<u64>()
+                    .expect("SENSOR_STALE_MINUTES must be a number")
+                {
+                    0 => None,
+                    minutes => Some(Duration::from_secs(minutes * 60)),
+                }
+            });
 
-    loop {
-        let (resp_sender, resp_receiver) = tokio::sync::oneshot::channel();
-        req_sender
-            .try_send(resp_sender)
-            .expect("Could not request sensor reading");
-        let measurement = resp_receiver.await.unwrap();
-
-        if let Some(message) = handle_measurement(measurement, &mut previous_data, &mut num_skipped)
-        {
-            tx.send(message)
-                .expect("Failed to write sensor data to channel");
+        let mut previous_data: Option<Measurement> = None;
+        let mut num_skipped: u8 = 0;
+        let mut last_sent: Option<Instant> = None;
+        let mut last_change: Option<LastChange> = None;
+        let mut rx = tx.subscribe();
+        let mut self_test = false;
+
+        thread::spawn(move || match sensor_type {
+            SensorType::Am2320 => run_worker(req_receiver, open_am2320(&i2c_config)),
+            SensorType::Ds18b20 => run_worker(
+                req_receiver,
+                Ds18b20Sensor::new(ds18b20_path.as_deref())
+                    .unwrap_or_else(|err| panic!("could not find the DS18B20: {}", err)),
+            ),
+        });
+
+        loop {
+            let (resp_sender, resp_receiver) = tokio::sync::oneshot::channel();
+            req_sender
+                .try_send(resp_sender)
+                .expect("Could not request sensor reading");
+            let measurement = resp_receiver
+                .await
+                .unwrap()
+                .map(|m| apply_calibration(m, temperature_offset, humidity_offset));
+
+            if self_test {
+                tx.send(Message::new_event(Event::SelfTestResult {
+                    component: "sensor".to_string(),
+                    passed: measurement.is_some(),
+                }))
+                .expect("Failed to write self test result to channel");
+                // always send the reading taken for a self test
+                previous_data = None;
+                last_sent = None;
+            }
+
+            if let (Some(measurement), Some(stale_after)) = (measurement, stale_after) {
+                if check_staleness(&mut last_change, measurement, Instant::now(), stale_after) {
+                    error!(
+                        "Sensor reading has not changed for {:?}: {:?}",
+                        stale_after, measurement
+                    );
+                    tx.send(Message::new_event(Event::MeasurementFailure))
+                        .expect("Failed to write sensor failure to channel");
+                }
+            }
+
+            if let Some(message) = handle_measurement(
+                measurement,
+                self.label.as_deref(),
+                emit_policy,
+                &mut previous_data,
+                &mut num_skipped,
+                &mut last_sent,
+                Instant::now(),
+            ) {
+                tx.send(message)
+                    .expect("Failed to write sensor data to channel");
+            }
+
+            self_test = wait_for_reading(&mut rx, sensor_sleep(num_skipped)).await;
         }
+    }
+}
 
-        let sleep = SENSOR_SLEEP + (SENSOR_SLEEP as f64 * 0.5 * num_skipped as f64) as u64;
-        delay_for(Duration::from_secs(sleep)).await;
+/// How long to wait for the next reading, backing off while the readings are unchanged
+fn sensor_sleep(num_skipped: u8) -> Duration {
+    Duration::from_secs(SENSOR_SLEEP + (SENSOR_SLEEP as f64 * 0.5 * num_skipped as f64) as u64)
+}
+
+/// Wait until the next reading is due, returns `true` early if a self test is requested
+async fn wait_for_reading(rx: &mut Receiver, sleep: Duration) -> bool {
+    let delay = delay_for(sleep);
+    tokio::pin!(delay);
+    loop {
+        tokio::select! {
+            _ = &mut delay => return false,
+            message = rx.recv() => match message {
+                Ok(message) if *message.payload() == Payload::Command(Command::SelfTest) => {
+                    return true;
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => {
+                    (&mut delay).await;
+                    return false;
+                }
+            },
+        }
     }
 }
 
+pub async fn handler(tx: Sender) {
+    EnvironmentSensor::default().run(tx).await
+}
+
+/// Read the calibration offsets from the environment
+///
+/// `TEMP_OFFSET` and `HUMIDITY_OFFSET` are added to every reading and default to zero.
+fn calibration_from_env() -> (f64, f64) {
+    let offset = |name: &str| {
+        env::var(name).map_or(0.0, |value| {
+            value
+                .parse::<f64>()
+                .unwrap_or_else(|_| panic!("{} must be a number", name))
+        })
+    };
+    (offset("TEMP_OFFSET"), offset("HUMIDITY_OFFSET"))
+}
+
+/// Adjust a measurement by fixed temperature and humidity offsets
+///
+/// Humidity is clamped to 0-100% after the offset is applied.
+fn apply_calibration(m: Measurement, t_off: f64, h_off: f64) -> Measurement {
+    let temperature = m.temperature + t_off;
+    let humidity = (m.humidity + h_off).clamp(0.0, 100.0);
+    Measurement::new(
+        (temperature * 100.0).round() / 100.0,
+        (humidity * 100.0).round() / 100.0,
+    )
+}
+
 fn handle_measurement(
     measurement: Option<Measurement>,
+    label: Option<&str>,
+    policy: EmitPolicy,
     previous_data: &mut Option<Measurement>,
     num_skipped: &mut u8,
+    last_sent: &mut Option<Instant>,
+    now: Instant,
 ) -> Option<Message> {
     if let Some(measurement) = measurement {
-        if should_send(&measurement, previous_data, *num_skipped) {
+        let since_last_sent = last_sent.map(|sent| now.duration_since(sent));
+        if should_send(
+            &measurement,
+            previous_data,
+            policy,
+            *num_skipped,
+            since_last_sent,
+        ) {
             *num_skipped = 0;
+            *last_sent = Some(now);
             debug!(
                 "Sending changed data: {:?} {:?}",
                 measurement, previous_data
             );
             *previous_data = Some(measurement);
 
-            Some(Message::new_event(Event::Measurement(measurement)))
+            Some(Message::new_event(match label {
+                Some(label) => Event::LabelledMeasurement(label.to_string(), measurement),
+                None => Event::Measurement(measurement),
+            }))
+        } else if is_changed(&measurement, previous_data, policy) {
+            // held back by the send rate, the readings are still changing so do not back off
+            *num_skipped = 0;
+            debug!(
+                "Holding back changed data: {:?} {:?}",
+                measurement, previous_data
+            );
+            None
         } else {
             *num_skipped += 1;
             debug!(
@@ -80,9 +316,117 @@ fn handle_measurement(
     }
 }
 
-fn run_worker(requests: RequestReceiver) {
-    let mut sensor = Am2320::new(I2c::new().expect("could not initialise I2C"), Delay::new());
+/// When the sensor reading last changed at all
+#[derive(Debug)]
+struct LastChange {
+    measurement: Measurement,
+    at: Instant,
+    reported: bool,
+}
+
+/// Record a reading, returns `true` the first time it has stayed the same for `stale_after`
+///
+/// Any change, however small, resets the clock so that another stale period is reported
+/// again.
+fn check_staleness(
+    last_change: &mut Option<LastChange>,
+    measurement: Measurement,
+    now: Instant,
+    stale_after: Duration,
+) -> bool {
+    match last_change {
+        Some(last) if last.measurement == measurement => {
+            if !last.reported && now.duration_since(last.at) >= stale_after {
+                last.reported = true;
+                true
+            } else {
+                false
+            }
+        }
+        _ => {
+            *last_change = Some(LastChange {
+                measurement,
+                at: now,
+                reported: false,
+            });
+            false
+        }
+    }
+}
+
+/// The I2C bus and address of the sensor
+#[derive(Debug, PartialEq)]
+struct I2cConfig {
+    /// `None` lets rppal pick the usual bus for the Pi model
+    bus: Option<u8>,
+    address: u8,
+}
+
+/// Parse `AM2320_BUS` and `AM2320_ADDR`, addresses can be decimal or hex like `0x5c`
+fn parse_i2c_config(bus: Option<&str>, address: Option<&str>) -> Result<I2cConfig, String> {
+    let bus = bus
+        .map(|bus| {
+            bus.parse()
+                .map_err(|_| format!("'{}' is not an I2C bus number", bus))
+        })
+        .transpose()?;
+    let address = match address {
+        None => DEFAULT_I2C_ADDRESS,
+        Some(address) => {
+            let parsed = match address.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => address.parse(),
+            };
+            match parsed {
+                // only 7 bit addresses are supported
+                Ok(parsed) if parsed <= 0x7f => parsed,
+                _ => return Err(format!("'{}' is not a 7 bit I2C address", address)),
+            }
+        }
+    };
+    Ok(I2cConfig { bus, address })
+}
+
+/// An I2C bus that talks to a configured address
+///
+/// The am2320 driver always uses the sensor's default address.
+struct AddressedI2c {
+    i2c: I2c,
+    address: u8,
+}
+
+impl i2c::Write for AddressedI2c {
+    type Error = rppal::i2c::Error;
+
+    fn write(&mut self, _address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        i2c::Write::write(&mut self.i2c, self.address, bytes)
+    }
+}
+
+impl i2c::Read for AddressedI2c {
+    type Error = rppal::i2c::Error;
+
+    fn read(&mut self, _address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        i2c::Read::read(&mut self.i2c, self.address, buffer)
+    }
+}
+
+fn open_am2320(config: &I2cConfig) -> Am2320<AddressedI2c, Delay> {
+    let i2c = match config.bus {
+        Some(bus) => I2c::with_bus(bus),
+        None => I2c::new(),
+    }
+    .expect("could not initialise I2C");
+    Am2320::new(
+        AddressedI2c {
+            i2c,
+            address: config.address,
+        },
+        Delay::new(),
+    )
+}
 
+fn run_worker(requests: RequestReceiver, mut sensor: impl Sensor) {
     // receive a request
     for sender in requests.iter() {
         sender
@@ -92,47 +436,68 @@ fn run_worker(requests: RequestReceiver) {
     }
 }
 
-type SensorResult = Result<am2320::Measurement, am2320::Error>;
+pub(crate) type SensorResult = Result<Measurement, String>;
 
-trait Sensor {
+/// Something that reads the temperature and humidity
+pub(crate) trait Sensor {
     fn read(&mut self) -> SensorResult;
 }
 
-impl Sensor for Am2320<I2c, Delay> {
+impl Sensor for Am2320<AddressedI2c, Delay> {
     fn read(&mut self) -> SensorResult {
         self.read()
+            .map(Measurement::from)
+            .map_err(|err| format!("{:?}", err))
     }
 }
 
+/// Whether a reading should be sent, `since_last_sent` is `None` before the first one
+///
+/// Changed readings are sent unless one was sent less than `min_interval` ago. Unchanged
+/// readings are still sent once too many have been skipped.
 fn should_send(
     measurement: &Measurement,
     previous_data: &Option<Measurement>,
+    policy: EmitPolicy,
     num_skipped: u8,
+    since_last_sent: Option<Duration>,
+) -> bool {
+    let is_due = since_last_sent.is_none_or(|since| since >= policy.min_interval);
+    (is_changed(measurement, previous_data, policy) && is_due) || num_skipped > SENSOR_MAX_SKIP
+}
+
+fn is_changed(
+    measurement: &Measurement,
+    previous_data: &Option<Measurement>,
+    policy: EmitPolicy,
 ) -> bool {
-    let is_changed = if let Some(previous_data) = previous_data {
-        !previous_data.temperature_roughly_equal(measurement)
+    if let Some(previous_data) = previous_data {
+        (previous_data.temperature - measurement.temperature).abs() >= policy.threshold
+            || (previous_data.humidity - measurement.humidity).abs() >= policy.threshold
     } else {
         true
-    };
-    is_changed || num_skipped > SENSOR_MAX_SKIP
+    }
 }
 
 fn read_measurement<S: Sensor>(sensor: &mut S, sensor_sleep: u64) -> Option<Measurement> {
     let mut error_count: u8 = 0;
     let mut backoff_count: u64 = 0;
     loop {
-        match sensor.read() {
+        let reading = sensor.read().and_then(|m| {
+            Measurement::new_checked(m.temperature, m.humidity).map_err(|err| err.to_string())
+        });
+        match reading {
             Ok(m) => {
                 if error_count > 0 {
                     info!(
-                        "AM2320 read success after {} failures: {:?} ",
+                        "Sensor read success after {} failures: {:?} ",
                         error_count, m
                     );
                 }
-                return Some(m.into());
+                return Some(m);
             }
             Err(err) => {
-                error!("AM232O read error: {:?}", err);
+                error!("Sensor read error: {}", err);
                 error_count += 1;
                 if error_count > SENSOR_ERROR_LIMIT {
                     let sleep = sensor_sleep * (backoff_count + 1);
@@ -154,20 +519,197 @@ fn read_measurement<S: Sensor>(sensor: &mut S, sensor_sleep: u64) -> Option<Meas
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tokio::{sync::broadcast::channel, time::timeout};
+
+    #[tokio::test]
+    async fn self_test_cuts_the_wait_short() {
+        // arrange
+        let (tx, mut rx) = channel(5);
+        tx.send(Message::new_event(Event::SingleTap)).unwrap();
+        tx.send(Message::new_command(Command::SelfTest)).unwrap();
+
+        // act
+        let self_test = timeout(
+            Duration::from_secs(1),
+            wait_for_reading(&mut rx, Duration::from_secs(60)),
+        )
+        .await
+        .expect("did not wake for the self test");
+
+        // assert
+        assert!(self_test);
+    }
+
+    #[tokio::test]
+    async fn wait_for_reading_times_out_without_a_self_test() {
+        // arrange
+        let (tx, mut rx) = channel(5);
+        tx.send(Message::new_event(Event::SingleTap)).unwrap();
+
+        // act
+        let self_test = wait_for_reading(&mut rx, Duration::from_millis(10)).await;
+
+        // assert
+        assert!(!self_test);
+    }
+
+    mod staleness {
+        use super::*;
+
+        const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+        #[test]
+        fn a_reading_that_keeps_changing_is_not_stale() {
+            // arrange
+            let start = Instant::now();
+            let mut last_change = None;
+
+            // act
+            let stale = (0..10)
+                .map(|i| {
+                    check_staleness(
+                        &mut last_change,
+                        Measurement::new(20.0 + f64::from(i) / 10.0, 40.0),
+                        start + STALE_AFTER * i,
+                        STALE_AFTER,
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            // assert
+            assert!(stale.iter().all(|stale| !stale));
+        }
+
+        #[test]
+        fn an_unchanged_reading_is_reported_once_when_stale() {
+            // arrange
+            let start = Instant::now();
+            let mut last_change = None;
+            check_staleness(&mut last_change, MEASUREMENT, start, STALE_AFTER);
+
+            // act
+            let before = check_staleness(
+                &mut last_change,
+                MEASUREMENT,
+                start + STALE_AFTER - Duration::from_secs(1),
+                STALE_AFTER,
+            );
+            let at = check_staleness(
+                &mut last_change,
+                MEASUREMENT,
+                start + STALE_AFTER,
+                STALE_AFTER,
+            );
+            let after = check_staleness(
+                &mut last_change,
+                MEASUREMENT,
+                start + STALE_AFTER * 2,
+                STALE_AFTER,
+            );
+
+            // assert
+            assert_eq!((before, at, after), (false, true, false));
+        }
+
+        #[test]
+        fn a_change_restarts_the_clock() {
+            // arrange
+            let start = Instant::now();
+            let mut last_change = None;
+            check_staleness(&mut last_change, MEASUREMENT, start, STALE_AFTER);
+            check_staleness(
+                &mut last_change,
+                MEASUREMENT,
+                start + STALE_AFTER,
+                STALE_AFTER,
+            );
+
+            // act
+            let changed = Measurement::new(1.2, 2.2);
+            let at_change = check_staleness(
+                &mut last_change,
+                changed,
+                start + STALE_AFTER * 2,
+                STALE_AFTER,
+            );
+            let soon_after = check_staleness(
+                &mut last_change,
+                changed,
+                start + STALE_AFTER * 2 + Duration::from_secs(1),
+                STALE_AFTER,
+            );
+            let stale_again = check_staleness(
+                &mut last_change,
+                changed,
+                start + STALE_AFTER * 3,
+                STALE_AFTER,
+            );
+
+            // assert
+            assert_eq!((at_change, soon_after, stale_again), (false, false, true));
+        }
+    }
+
+    #[test]
+    fn sensor_type_defaults_to_am2320() {
+        assert_eq!(parse_sensor_type(None), Ok(SensorType::Am2320));
+        assert_eq!(parse_sensor_type(Some("am2320")), Ok(SensorType::Am2320));
+    }
+
+    #[test]
+    fn sensor_type_ds18b20() {
+        assert_eq!(parse_sensor_type(Some("ds18b20")), Ok(SensorType::Ds18b20));
+        assert!(parse_sensor_type(Some("dht22")).is_err());
+    }
+
+    #[test]
+    fn i2c_config_defaults() {
+        assert_eq!(
+            parse_i2c_config(None, None).unwrap(),
+            I2cConfig {
+                bus: None,
+                address: 0x5c
+            }
+        );
+    }
+
+    #[test]
+    fn i2c_config_from_env_values() {
+        assert_eq!(
+            parse_i2c_config(Some("3"), Some("0x5d")).unwrap(),
+            I2cConfig {
+                bus: Some(3),
+                address: 0x5d
+            }
+        );
+        assert_eq!(parse_i2c_config(None, Some("92")).unwrap().address, 0x5c);
+    }
+
+    #[test]
+    fn i2c_config_invalid_env_values() {
+        assert!(parse_i2c_config(Some("one"), None).is_err());
+        assert!(parse_i2c_config(Some("-1"), None).is_err());
+        assert!(parse_i2c_config(None, Some("0x80")).is_err());
+        assert!(parse_i2c_config(None, Some("0xzz")).is_err());
+        assert!(parse_i2c_config(None, Some("sensor")).is_err());
+    }
 
     struct MockSensor {
-        values: Vec<SensorResult>,
+        values: Vec<Result<am2320::Measurement, am2320::Error>>,
     }
 
     impl MockSensor {
-        fn new(values: Vec<SensorResult>) -> Self {
+        fn new(values: Vec<Result<am2320::Measurement, am2320::Error>>) -> Self {
             Self { values }
         }
     }
 
     impl Sensor for MockSensor {
         fn read(&mut self) -> SensorResult {
-            self.values.remove(0)
+            self.values
+                .remove(0)
+                .map(Measurement::from)
+                .map_err(|err| format!("{:?}", err))
         }
     }
 
@@ -208,6 +750,24 @@ mod tests {
         assert_eq!(read_measurement, Measurement::from(AM2320_MEASUREMENT));
     }
 
+    #[test]
+    fn read_a_measurement_after_an_implausible_one() {
+        let mut sensor = MockSensor::new(vec![
+            Ok(am2320::Measurement {
+                temperature: -500.0,
+                humidity: 2.2,
+            }),
+            Ok(am2320::Measurement {
+                temperature: 1.1,
+                humidity: 300.0,
+            }),
+            Ok(AM2320_MEASUREMENT),
+        ]);
+        let read_measurement = read_measurement(&mut sensor, 0).unwrap();
+
+        assert_eq!(read_measurement, Measurement::from(AM2320_MEASUREMENT));
+    }
+
     #[test]
     fn read_a_measurement_until_backoff_exceeded() {
         let mut sensor = MockSensor::new(vec![
@@ -234,6 +794,32 @@ mod tests {
         assert!(read_measurement.is_none());
     }
 
+    #[test]
+    fn apply_positive_calibration() {
+        let calibrated = apply_calibration(Measurement::new(20.5, 40.0), 1.5, 2.25);
+
+        assert_eq!(calibrated, Measurement::new(22.0, 42.25));
+    }
+
+    #[test]
+    fn apply_negative_calibration() {
+        let calibrated = apply_calibration(Measurement::new(21.37, 40.0), -1.5, -5.0);
+
+        assert_eq!(calibrated, Measurement::new(19.87, 35.0));
+    }
+
+    #[test]
+    fn apply_calibration_clamps_humidity() {
+        assert_eq!(
+            apply_calibration(Measurement::new(20.0, 98.0), 0.0, 5.0),
+            Measurement::new(20.0, 100.0)
+        );
+        assert_eq!(
+            apply_calibration(Measurement::new(20.0, 2.0), 0.0, -5.0),
+            Measurement::new(20.0, 0.0)
+        );
+    }
+
     #[test]
     fn handle_measurement_stop() {
         // arrange
@@ -241,7 +827,16 @@ mod tests {
         let mut num_skipped = 0;
 
         // act
-        let message = handle_measurement(None, &mut previous_data, &mut num_skipped).unwrap();
+        let message = handle_measurement(
+            None,
+            None,
+            EmitPolicy::default(),
+            &mut previous_data,
+            &mut num_skipped,
+            &mut None,
+            Instant::now(),
+        )
+        .unwrap();
 
         // assert
         assert_eq!(message.into_command(), Some(Command::Stop));
@@ -254,13 +849,49 @@ mod tests {
         let mut num_skipped = 0;
 
         // act
-        let message =
-            handle_measurement(Some(MEASUREMENT), &mut previous_data, &mut num_skipped).unwrap();
+        let message = handle_measurement(
+            Some(MEASUREMENT),
+            None,
+            EmitPolicy::default(),
+            &mut previous_data,
+            &mut num_skipped,
+            &mut None,
+            Instant::now(),
+        )
+        .unwrap();
 
         // assert
         assert_eq!(message.into_event(), Some(Event::Measurement(MEASUREMENT)));
     }
 
+    #[test]
+    fn handle_measurement_labelled() {
+        // arrange
+        let mut previous_data = None;
+        let mut num_skipped = 0;
+
+        // act
+        let message = handle_measurement(
+            Some(MEASUREMENT),
+            Some("bathroom"),
+            EmitPolicy::default(),
+            &mut previous_data,
+            &mut num_skipped,
+            &mut None,
+            Instant::now(),
+        )
+        .unwrap();
+
+        // assert
+        assert_eq!(
+            message.into_event(),
+            Some(Event::LabelledMeasurement(
+                "bathroom".to_string(),
+                MEASUREMENT
+            ))
+        );
+    }
+
     #[test]
     fn handle_measurement_skip() {
         // arrange
@@ -268,9 +899,164 @@ mod tests {
         let mut num_skipped = 0;
 
         // act
-        let message = handle_measurement(Some(MEASUREMENT), &mut previous_data, &mut num_skipped);
+        let message = handle_measurement(
+            Some(MEASUREMENT),
+            None,
+            EmitPolicy::default(),
+            &mut previous_data,
+            &mut num_skipped,
+            &mut None,
+            Instant::now(),
+        );
 
         // assert
         assert!(message.is_none());
     }
+
+    fn threshold(threshold: f64) -> EmitPolicy {
+        EmitPolicy {
+            threshold,
+            ..EmitPolicy::default()
+        }
+    }
+
+    #[test]
+    fn should_send_small_changes_over_the_threshold() {
+        let previous = Some(Measurement::new(20.0, 50.0));
+
+        assert!(should_send(
+            &Measurement::new(20.1, 50.0),
+            &previous,
+            EmitPolicy::default(),
+            0,
+            None
+        ));
+        assert!(should_send(
+            &Measurement::new(20.0, 50.1),
+            &previous,
+            EmitPolicy::default(),
+            0,
+            None
+        ));
+        assert!(!should_send(
+            &Measurement::new(20.0, 50.0),
+            &previous,
+            EmitPolicy::default(),
+            0,
+            None
+        ));
+    }
+
+    #[test]
+    fn should_send_respects_a_larger_threshold() {
+        let previous = Some(Measurement::new(20.0, 50.0));
+
+        assert!(!should_send(
+            &Measurement::new(20.4, 50.4),
+            &previous,
+            threshold(0.5),
+            0,
+            None
+        ));
+        assert!(should_send(
+            &Measurement::new(20.5, 50.0),
+            &previous,
+            threshold(0.5),
+            0,
+            None
+        ));
+        // unchanged readings are still sent now and then
+        assert!(should_send(
+            &Measurement::new(20.0, 50.0),
+            &previous,
+            threshold(0.5),
+            SENSOR_MAX_SKIP + 1,
+            None
+        ));
+    }
+
+    #[test]
+    fn should_send_every_reading_with_a_zero_threshold() {
+        let previous = Some(Measurement::new(20.0, 50.0));
+
+        assert!(should_send(
+            &Measurement::new(20.0, 50.0),
+            &previous,
+            threshold(0.0),
+            0,
+            None
+        ));
+    }
+
+    #[test]
+    fn handle_measurement_caps_the_send_rate() {
+        // arrange
+        let policy = EmitPolicy {
+            min_interval: Duration::from_secs(300),
+            ..EmitPolicy::default()
+        };
+        let start = Instant::now();
+        let mut now = start;
+        let mut previous_data = None;
+        let mut num_skipped = 0;
+        let mut last_sent = None;
+        let mut sent = vec![];
+
+        // act
+        // a warming room, each reading warmer than the last, read as often as the loop does
+        for i in 0..12u32 {
+            if handle_measurement(
+                Some(Measurement::new(15.0 + f64::from(i) / 10.0, 50.0)),
+                None,
+                policy,
+                &mut previous_data,
+                &mut num_skipped,
+                &mut last_sent,
+                now,
+            )
+            .is_some()
+            {
+                sent.push(now.duration_since(start).as_secs());
+            }
+            now += sensor_sleep(num_skipped);
+        }
+
+        // assert
+        assert_eq!(sent, vec![0, 300]);
+        assert_eq!(num_skipped, 0);
+    }
+
+    #[test]
+    fn should_send_changes_only_after_the_min_interval() {
+        let policy = EmitPolicy {
+            min_interval: Duration::from_secs(300),
+            ..EmitPolicy::default()
+        };
+        let previous = Some(Measurement::new(20.0, 50.0));
+        let changed = Measurement::new(21.0, 50.0);
+
+        assert!(should_send(&changed, &None, policy, 0, None));
+        assert!(!should_send(
+            &changed,
+            &previous,
+            policy,
+            0,
+            Some(Duration::from_secs(299))
+        ));
+        assert!(should_send(
+            &changed,
+            &previous,
+            policy,
+            0,
+            Some(Duration::from_secs(300))
+        ));
+        // too many skipped readings are sent whatever the interval
+        assert!(should_send(
+            &changed,
+            &previous,
+            policy,
+            SENSOR_MAX_SKIP + 1,
+            Some(Duration::from_secs(1))
+        ));
+    }
 }
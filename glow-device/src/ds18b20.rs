@@ -0,0 +1,204 @@
+//! DS18B20 1-Wire temperature sensor
+//!
+//! The kernel's `w1_therm` driver exposes each sensor as a `w1_slave` file under
+//! `/sys/bus/w1/devices/28-*/`. Reading the file makes the sensor take a conversion and
+//! returns two lines, the first ending in `YES` when the checksum matched and the second
+//! ending in `t=` and the temperature in thousandths of a degree:
+//!
+//! ```text
+//! 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+//! 72 01 4b 46 7f ff 0e 10 57 t=23125
+//! ```
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use glow_events::Measurement;
+
+use crate::am2320::{Sensor, SensorResult};
+
+/// Where the kernel lists 1-Wire devices
+const W1_DEVICES: &str = "/sys/bus/w1/devices";
+/// DS18B20 device directories start with its family code
+const DS18B20_FAMILY: &str = "28-";
+/// The DS18B20 has no humidity sensor, its readings report this instead
+pub const DS18B20_HUMIDITY: f64 = 0.0;
+/// The temperature register holds 85°C after power on until the first conversion
+const POWER_ON_RESET_MILLIDEGREES: i32 = 85_000;
+
+/// A DS18B20 read through the `w1_therm` sysfs interface
+pub struct Ds18b20Sensor {
+    path: PathBuf,
+}
+
+impl Ds18b20Sensor {
+    /// Use the `w1_slave` file at `DS18B20_PATH`, or the first DS18B20 the kernel found
+    pub fn new(path: Option<&str>) -> Result<Self, String> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => find_sensor(Path::new(W1_DEVICES))?,
+        };
+        Ok(Self { path })
+    }
+}
+
+impl Sensor for Ds18b20Sensor {
+    fn read(&mut self) -> SensorResult {
+        let reading = fs::read_to_string(&self.path)
+            .map_err(|err| format!("cannot read {}: {}", self.path.display(), err))?;
+        Ok(Measurement::new(
+            parse_w1_slave(&reading)?,
+            DS18B20_HUMIDITY,
+        ))
+    }
+}
+
+/// Find the `w1_slave` file of the first DS18B20 under `devices`
+fn find_sensor(devices: &Path) -> Result<PathBuf, String> {
+    let mut sensors = fs::read_dir(devices)
+        .map_err(|err| format!("cannot list {}: {}", devices.display(), err))?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(DS18B20_FAMILY))
+        })
+        .collect::<Vec<_>>();
+    sensors.sort();
+    sensors
+        .into_iter()
+        .next()
+        .map(|sensor| sensor.join("w1_slave"))
+        .ok_or_else(|| format!("no DS18B20 found in {}", devices.display()))
+}
+
+/// Parse the temperature in °C from the contents of a `w1_slave` file
+pub fn parse_w1_slave(reading: &str) -> Result<f64, String> {
+    let mut lines = reading.lines();
+    match lines.next() {
+        Some(line) if line.trim_end().ends_with("YES") => {}
+        _ => return Err(format!("DS18B20 checksum failed: {:?}", reading)),
+    }
+    let millidegrees = lines
+        .next()
+        .and_then(|line| line.rsplit_once("t="))
+        .and_then(|(_, value)| value.trim().parse::<i32>().ok())
+        .ok_or_else(|| format!("DS18B20 reading has no temperature: {:?}", reading))?;
+    if millidegrees == POWER_ON_RESET_MILLIDEGREES {
+        return Err("DS18B20 has not taken a reading since power on".to_string());
+    }
+    Ok(f64::from(millidegrees) / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// An empty directory for one test
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("glow-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).unwrap();
+        path
+    }
+
+    const READING: &str = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n\
+                           72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+
+    #[test]
+    fn parse_a_reading() {
+        assert_eq!(parse_w1_slave(READING), Ok(23.125));
+    }
+
+    #[test]
+    fn parse_a_negative_reading() {
+        // arrange
+        let reading = "5e ff 4b 46 7f ff 02 10 d8 : crc=d8 YES\n\
+                       5e ff 4b 46 7f ff 02 10 d8 t=-10125\n";
+
+        // act
+        let temperature = parse_w1_slave(reading);
+
+        // assert
+        assert_eq!(temperature, Ok(-10.125));
+    }
+
+    #[test]
+    fn parse_rejects_a_failed_checksum() {
+        // arrange
+        let reading = "72 01 4b 46 7f ff 0e 10 57 : crc=a3 NO\n\
+                       72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+
+        // act
+        let temperature = parse_w1_slave(reading);
+
+        // assert
+        assert!(temperature.is_err());
+    }
+
+    #[test]
+    fn parse_rejects_the_power_on_value() {
+        // arrange
+        let reading = "50 05 4b 46 7f ff 0c 10 1c : crc=1c YES\n\
+                       50 05 4b 46 7f ff 0c 10 1c t=85000\n";
+
+        // act
+        let temperature = parse_w1_slave(reading);
+
+        // assert
+        assert!(temperature.is_err());
+    }
+
+    #[test]
+    fn parse_rejects_incomplete_readings() {
+        assert!(parse_w1_slave("").is_err());
+        assert!(parse_w1_slave("72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n").is_err());
+        assert!(parse_w1_slave("72 01 : crc=57 YES\n72 01 t=warm\n").is_err());
+    }
+
+    #[test]
+    fn read_the_sensor_file() {
+        // arrange
+        let path = temp_dir("ds18b20-read").join("w1_slave");
+        fs::write(&path, READING).unwrap();
+        let mut sensor = Ds18b20Sensor::new(path.to_str()).unwrap();
+
+        // act
+        let measurement = sensor.read();
+
+        // assert
+        assert_eq!(measurement, Ok(Measurement::new(23.125, DS18B20_HUMIDITY)));
+    }
+
+    #[test]
+    fn find_the_first_sensor() {
+        // arrange
+        let dir = temp_dir("ds18b20-find");
+        for name in &["w1_bus_master1", "28-0316a2794aff", "28-01193a1d5c2a"] {
+            fs::create_dir(dir.join(name)).unwrap();
+        }
+
+        // act
+        let path = find_sensor(&dir).unwrap();
+
+        // assert
+        assert_eq!(path, dir.join("28-01193a1d5c2a").join("w1_slave"));
+    }
+
+    #[test]
+    fn find_fails_without_a_sensor() {
+        // arrange
+        let dir = temp_dir("ds18b20-missing");
+        fs::create_dir(dir.join("w1_bus_master1")).unwrap();
+
+        // act
+        let path = find_sensor(&dir);
+
+        // assert
+        assert!(path.is_err());
+    }
+}
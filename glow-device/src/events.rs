@@ -1,13 +1,27 @@
-use std::{future::Future, time::Duration};
+use std::{env, future::Future, time::Duration};
 
 use async_trait::async_trait;
-use futures::future::{join, select_all};
+use futures::{
+    future::{join, pending, select_all, FutureExt},
+    stream::{FuturesUnordered, StreamExt},
+};
 use glow_events::v2::{Command, Event, Message, Payload};
-use tokio::sync::broadcast::channel;
+use log::{error, info};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::broadcast::channel,
+};
 
 pub type Sender = tokio::sync::broadcast::Sender<Message>;
 pub type Receiver = tokio::sync::broadcast::Receiver<Message>;
 
+/// Let `glow-web` know that a handler has acted on `command`
+pub fn send_ack(tx: &Sender, command: &Command) {
+    if tx.send(Message::new_event(Event::ack(command))).is_err() {
+        error!("failed to acknowledge {}", command.command_type());
+    }
+}
+
 #[async_trait]
 pub trait Handler: Send + Sync {
     async fn run(&self, tx: Sender);
@@ -24,9 +38,55 @@ where
     }
 }
 
-#[derive(Default)]
+/// Which of the hardware handlers to run
+///
+/// Each is on unless turned off in the environment, e.g. `ENABLE_LEDS=false` on a Pi without a
+/// Blinkt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EnabledHandlers {
+    pub tplink: bool,
+    pub leds: bool,
+    pub sensor: bool,
+    pub vibration: bool,
+}
+
+impl EnabledHandlers {
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| env::var(name).ok())
+            .unwrap_or_else(|err| panic!("Invalid handler settings: {}", err))
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let enabled = |name: &str| match var(name).as_deref().map(str::trim) {
+            None | Some("true") | Some("1") => Ok(true),
+            Some("false") | Some("0") => Ok(false),
+            Some(value) => Err(format!("{} must be true or false, not {:?}", name, value)),
+        };
+        Ok(Self {
+            tplink: enabled("ENABLE_TPLINK")?,
+            leds: enabled("ENABLE_LEDS")?,
+            sensor: enabled("ENABLE_SENSOR")?,
+            vibration: enabled("ENABLE_VIBRATION")?,
+        })
+    }
+}
+
+/// How long handlers get to finish once the runner is stopping
+const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_millis(50);
+
 pub struct Runner {
-    handlers: Vec<Box<dyn Handler>>,
+    /// Each handler and whether it returns once it has cleaned up after a stop command
+    handlers: Vec<(Box<dyn Handler>, bool)>,
+    shutdown_grace: Duration,
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Self {
+            handlers: vec![],
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+        }
+    }
 }
 
 async fn stopper(tx: Sender) {
@@ -34,25 +94,76 @@ async fn stopper(tx: Sender) {
 
     while let Ok(message) = rx.recv().await {
         if let Payload::Command(Command::Stop) = message.payload() {
-            // small delay to allow other handlers to receive the event
-            tokio::time::delay_for(Duration::from_millis(50)).await;
             return;
         }
     }
 }
 
+/// Broadcast a stop command once `shutdown` completes so handlers can clean up
+async fn stop_on<F: Future<Output = ()>>(tx: Sender, shutdown: F) {
+    shutdown.await;
+    if tx.send(Message::new_command(Command::Stop)).is_err() {
+        error!("failed to send stop command");
+    }
+    // the stopper ends the runner once the stop command has been handled
+    pending::<()>().await;
+}
+
+/// Wait for SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let (mut interrupt, mut terminate) = match (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) {
+        (Ok(interrupt), Ok(terminate)) => (interrupt, terminate),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("failed to listen for shutdown signals: {}", err);
+            return pending().await;
+        }
+    };
+    tokio::select! {
+        _ = interrupt.recv() => info!("received SIGINT, stopping"),
+        _ = terminate.recv() => info!("received SIGTERM, stopping"),
+    }
+}
+
+async fn signal_stopper(tx: Sender) {
+    stop_on(tx, shutdown_signal()).await;
+}
+
 impl Runner {
     pub fn add<T: Handler + 'static>(&mut self, handler: T) {
-        self.handlers.push(Box::new(handler));
+        self.handlers.push((Box::new(handler), false));
+    }
+
+    /// Add a handler that returns once it has cleaned up after a stop command, for example
+    /// by sending its last events, which the runner waits for
+    pub fn add_cleanup<T: Handler + 'static>(&mut self, handler: T) {
+        self.handlers.push((Box::new(handler), true));
+    }
+
+    /// Wait up to `grace` for the handlers added with `add_cleanup` to return after a stop
+    pub fn with_shutdown_grace(mut self, grace: Duration) -> Self {
+        self.shutdown_grace = grace;
+        self
     }
 
     pub async fn run(self) {
         let (sender, _) = channel(20);
 
-        let mut handlers = self.handlers;
-        handlers.insert(0, Box::new(stopper));
+        let Runner {
+            mut handlers,
+            shutdown_grace,
+        } = self;
+        handlers.insert(0, (Box::new(stopper), false));
+        handlers.insert(1, (Box::new(signal_stopper), false));
+        let cleanup_handlers = handlers.iter().filter(|(_, cleans_up)| *cleans_up).count();
 
-        let handler_futures = handlers.iter().map(|handler| handler.run(sender.clone()));
+        // each handler finishes with whether it cleans up, so the runner knows what to wait for
+        let handler_futures = handlers.iter().map(|(handler, cleans_up)| {
+            let cleans_up = *cleans_up;
+            handler.run(sender.clone()).map(move |_| cleans_up).boxed()
+        });
 
         let sender = sender.clone();
         let start_handler = async move {
@@ -63,7 +174,22 @@ impl Runner {
 
         // start_handler comes second so that handlers can receive
         // the start event.
-        join(select_all(handler_futures), start_handler).await;
+        let ((cleaned_up, _, remaining), _) =
+            join(select_all(handler_futures), start_handler).await;
+
+        // handlers that never finish, such as sensors, are dropped without being waited for
+        let mut cleaning_up = cleanup_handlers - usize::from(cleaned_up);
+        let mut remaining = remaining.into_iter().collect::<FuturesUnordered<_>>();
+        let _ = tokio::time::timeout(shutdown_grace, async {
+            while cleaning_up > 0 {
+                match remaining.next().await {
+                    Some(true) => cleaning_up -= 1,
+                    Some(false) => {}
+                    None => break,
+                }
+            }
+        })
+        .await;
     }
 }
 
@@ -83,7 +209,15 @@ mod tests {
             let mut rx = tx.subscribe();
 
             while let Ok(message) = rx.recv().await {
+                let stop = *message.payload() == Payload::Command(Command::Stop);
                 self.sender.send(message).unwrap();
+                if stop {
+                    // pass on whatever was already sent before returning
+                    while let Ok(message) = rx.try_recv() {
+                        self.sender.send(message).unwrap();
+                    }
+                    return;
+                }
             }
         }
     }
@@ -109,7 +243,7 @@ mod tests {
         let mut runner = Runner::default();
         let (tx, mut rx) = channel(3);
 
-        runner.add(TestHandler { sender: tx });
+        runner.add_cleanup(TestHandler { sender: tx });
         runner.add(party_runner);
 
         tokio::spawn(async move {
@@ -133,10 +267,11 @@ mod tests {
 
     #[tokio::test]
     async fn test_stop_runner_with_event() {
-        let mut runner = Runner::default();
+        // stop well before the stopper sends its last message
+        let mut runner = Runner::default().with_shutdown_grace(Duration::from_millis(10));
         let (tx, mut rx) = channel(3);
 
-        runner.add(TestHandler { sender: tx });
+        runner.add_cleanup(TestHandler { sender: tx });
         runner.add(stopper);
 
         tokio::spawn(async move {
@@ -157,4 +292,115 @@ mod tests {
         );
         assert!(rx.recv().await.is_err(),);
     }
+
+    #[tokio::test]
+    async fn test_stop_runner_on_shutdown() {
+        // arrange
+        let mut runner = Runner::default();
+        let (tx, mut rx) = channel(3);
+        runner.add_cleanup(TestHandler { sender: tx });
+        runner.add(|tx| stop_on(tx, tokio::time::delay_for(Duration::from_millis(50))));
+
+        // act
+        tokio::spawn(async move {
+            runner.run().await;
+        });
+
+        // assert
+        assert_eq!(
+            rx.recv().await.unwrap().payload(),
+            &Payload::Event(Event::Started)
+        );
+        assert_eq!(
+            rx.recv().await.unwrap().payload(),
+            &Payload::Command(Command::Stop)
+        );
+        assert!(rx.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stop_only_waits_for_handlers_cleaning_up() {
+        // arrange
+        let mut runner = Runner::default().with_shutdown_grace(Duration::from_secs(5));
+        runner.add_cleanup(|tx: Sender| async move {
+            super::stopper(tx).await;
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        });
+        // a sensor that never finishes
+        runner.add(|_tx: Sender| pending::<()>());
+        runner.add(|tx| stop_on(tx, tokio::time::delay_for(Duration::from_millis(20))));
+
+        // act
+        let stopped = tokio::time::timeout(Duration::from_secs(1), runner.run()).await;
+
+        // assert
+        assert!(stopped.is_ok(), "the runner waited for the whole grace");
+    }
+
+    mod enabled_handlers {
+        use super::*;
+
+        fn vars(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+            let vars = vars
+                .iter()
+                .map(|(name, value)| ((*name).to_string(), (*value).to_string()))
+                .collect::<Vec<_>>();
+            move |name| {
+                vars.iter()
+                    .find(|(var, _)| var == name)
+                    .map(|(_, value)| value.clone())
+            }
+        }
+
+        #[test]
+        fn every_handler_is_on_by_default() {
+            // act
+            let enabled = EnabledHandlers::from_vars(vars(&[])).unwrap();
+
+            // assert
+            assert_eq!(
+                enabled,
+                EnabledHandlers {
+                    tplink: true,
+                    leds: true,
+                    sensor: true,
+                    vibration: true,
+                }
+            );
+        }
+
+        #[test]
+        fn handlers_can_be_turned_off() {
+            // act
+            let enabled = EnabledHandlers::from_vars(vars(&[
+                ("ENABLE_LEDS", "false"),
+                ("ENABLE_VIBRATION", "0"),
+                ("ENABLE_TPLINK", "true"),
+            ]))
+            .unwrap();
+
+            // assert
+            assert_eq!(
+                enabled,
+                EnabledHandlers {
+                    tplink: true,
+                    leds: false,
+                    sensor: true,
+                    vibration: false,
+                }
+            );
+        }
+
+        #[test]
+        fn other_values_are_rejected() {
+            // act
+            let result = EnabledHandlers::from_vars(vars(&[("ENABLE_SENSOR", "nope")]));
+
+            // assert
+            assert_eq!(
+                result,
+                Err("ENABLE_SENSOR must be true or false, not \"nope\"".to_string())
+            );
+        }
+    }
 }
@@ -1,44 +1,277 @@
-use std::{cmp::Ordering, convert::TryInto, f32, fmt, sync::mpsc::sync_channel, thread};
-
+use std::{
+    cmp::Ordering,
+    collections::VecDeque,
+    convert::TryInto,
+    env, f32, fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{mpsc::sync_channel, Arc, Mutex},
+    thread,
+};
+
+use async_trait::async_trait;
 use blinkt::Blinkt;
-use glow_events::v2::Message;
-use log::{debug, error};
-use tokio::time::{delay_for, Duration};
+use chrono::{Local, NaiveTime};
+use glow_events::{colour, v2::Message, Measurement};
+use log::{debug, error, warn};
+use tokio::{
+    sync::broadcast::RecvError,
+    time::{delay_for, Duration},
+};
 
-use crate::events::Sender;
+use crate::events::{send_ack, Sender};
 
 const NUM_PIXELS: usize = 8;
 
+/// Where the last brightness is kept between restarts, override with `BRIGHTNESS_FILE`
+const DEFAULT_BRIGHTNESS_FILE: &str = "/var/lib/glow/brightness";
+
+/// How long a fade between colours takes, override with `FADE_DURATION_MS`
+const DEFAULT_FADE_DURATION: Duration = Duration::from_millis(500);
+const FADE_STEPS: usize = 10;
+
+/// How long each frame of the rainbow is shown for
+const RAINBOW_FRAME_DELAY: Duration = Duration::from_millis(100);
+/// How far the rainbow hues move round the colour wheel each frame
+const RAINBOW_HUE_STEP: f32 = 5.0;
+
+/// How long each frame of the party is shown for unless `RunPartyWith` says otherwise
+const DEFAULT_PARTY_SPEED: Duration = Duration::from_millis(50);
+/// How many times the party sweeps through its colours unless `RunPartyWith` says otherwise
+const DEFAULT_PARTY_CYCLES: u32 = 1;
+/// Limits on `RunPartyWith` so that a party cannot take over the LEDs for long
+const MAX_PARTY_SPEED: Duration = Duration::from_secs(1);
+const MAX_PARTY_CYCLES: u32 = 20;
+
+/// How long each frame of the self test pattern is shown for
+const SELF_TEST_FRAME_DELAY: Duration = Duration::from_millis(300);
+
+/// How often to check whether the night mode window has started or ended
+const NIGHT_MODE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
 pub const COLOUR_BLUE: Colour = Colour(10, 10, 100);
 pub const COLOUR_ORANGE: Colour = Colour(120, 20, 0);
 pub const COLOUR_SALMON: Colour = Colour(160, 10, 1);
 pub const COLOUR_CORAL: Colour = Colour(255, 1, 1);
 pub const COLOUR_RED: Colour = Colour(255, 0, 100);
 
+// humidity colours, from dry to damp
+pub const COLOUR_LIME: Colour = Colour(60, 120, 0);
+pub const COLOUR_GREEN: Colour = Colour(0, 120, 10);
+pub const COLOUR_TEAL: Colour = Colour(0, 100, 60);
+pub const COLOUR_CYAN: Colour = Colour(0, 60, 120);
+pub const COLOUR_NAVY: Colour = Colour(0, 0, 160);
+
+/// How the LED handler behaves, read from the environment
+pub struct LedConfig {
+    pub metric: LedMetric,
+    pub brightness_file: PathBuf,
+    pub fade_duration: Duration,
+    pub brightness_levels: BrightnessCycle,
+    pub night_mode: Option<NightMode>,
+    /// The colours the party sweeps through, never empty
+    pub party_colours: Vec<Colour>,
+}
+
+impl LedConfig {
+    pub fn from_env() -> Self {
+        Self {
+            metric: LedMetric::from_env(),
+            brightness_file: env::var("BRIGHTNESS_FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| PathBuf::from(DEFAULT_BRIGHTNESS_FILE)),
+            fade_duration: env::var("FADE_DURATION_MS")
+                .map(|duration| {
+                    Duration::from_millis(
+                        duration.parse().expect("FADE_DURATION_MS must be a number"),
+                    )
+                })
+                .unwrap_or(DEFAULT_FADE_DURATION),
+            brightness_levels: env::var("BRIGHTNESS_LEVELS")
+                .map(|levels| {
+                    BrightnessCycle::parse(&levels)
+                        .unwrap_or_else(|err| panic!("Invalid BRIGHTNESS_LEVELS: {}", err))
+                })
+                .unwrap_or_default(),
+            night_mode: env::var("NIGHT_MODE").ok().map(|window| {
+                let brightness = env::var("NIGHT_BRIGHTNESS")
+                    .map(|brightness| {
+                        brightness
+                            .parse()
+                            .expect("NIGHT_BRIGHTNESS must be a number")
+                    })
+                    .unwrap_or(0.0);
+                NightMode::parse(&window, brightness)
+                    .unwrap_or_else(|err| panic!("Invalid NIGHT_MODE: {}", err))
+            }),
+            party_colours: env::var("PARTY_COLOURS").map_or_else(
+                |_| default_party_colours(),
+                |colours| {
+                    parse_party_colours(&colours)
+                        .unwrap_or_else(|err| panic!("Invalid PARTY_COLOURS: {}", err))
+                },
+            ),
+        }
+    }
+}
+
+/// Drive the Blinkt, or just log the colours when `LED_BACKEND=mock` on hosts without one
 pub async fn handler(tx: Sender) {
-    let colour_range = ColourRange::new(
-        14.0,
-        4.0,
-        &[
-            COLOUR_BLUE,
-            COLOUR_ORANGE,
-            COLOUR_SALMON,
-            COLOUR_CORAL,
-            COLOUR_RED,
-        ],
-    )
-    .unwrap();
+    let config = LedConfig::from_env();
+    let night_mode = config.night_mode.clone();
+    if let Some(night_mode) = night_mode.clone() {
+        tokio::spawn(watch_night_mode(tx.clone(), night_mode));
+    }
+    let leds = open_leds(
+        env::var("LED_BACKEND").ok().as_deref(),
+        BlinktBackgroundLEDs::try_new,
+    );
+    run_leds(tx, NightModeLeds::new(leds, night_mode), config).await;
+}
+
+/// Pick the LEDs to drive
+///
+/// A missing Blinkt should not take the rest of the device down with it, so if it cannot be
+/// opened nothing is shown at all.
+fn open_leds<B: LedBackend + 'static>(
+    backend: Option<&str>,
+    open_blinkt: impl FnOnce() -> Result<B, LedError>,
+) -> Box<dyn LedBackend> {
+    if backend == Some("mock") {
+        return Box::new(MockLeds::default());
+    }
+    match open_blinkt() {
+        Ok(leds) => Box::new(leds),
+        Err(err) => {
+            warn!("Not showing any colours: {}", err);
+            Box::new(AbsentLeds)
+        }
+    }
+}
+
+/// Show the colours again whenever the night mode window starts or ends
+///
+/// Nothing else may be shown for a while so without this the brightness would only change
+/// with the next measurement.
+async fn watch_night_mode(tx: Sender, night_mode: NightMode) {
+    let mut was_night = night_mode.contains(Local::now().time());
+    loop {
+        delay_for(NIGHT_MODE_CHECK_INTERVAL).await;
+        let is_night = night_mode.contains(Local::now().time());
+        if is_night != was_night {
+            debug!("night mode {}", if is_night { "started" } else { "ended" });
+            if tx
+                .send(Message::new_command(glow_events::v2::Command::UpdateLEDs))
+                .is_err()
+            {
+                break;
+            }
+            was_night = is_night;
+        }
+    }
+}
+
+pub async fn run_leds(tx: Sender, mut leds: impl LedBackend, config: LedConfig) {
+    let LedConfig {
+        metric,
+        brightness_file,
+        fade_duration,
+        brightness_levels,
+        party_colours,
+        ..
+    } = config;
+    let colour_range = metric.colour_range();
     let mut colours = colour_range.all(Colour::black());
-    let mut brightness = Brightness::default().value();
-    let mut leds = BlinktBackgroundLEDs::new();
+    let mut brightness = read_brightness(&brightness_file, brightness_levels.default_level());
     let mut rx = tx.subscribe();
+    // the rainbow frame to show next while the rainbow is running
+    let mut rainbow: Option<usize> = None;
+    // frames still to show while fading to new colours
+    let mut fade: VecDeque<Vec<Colour>> = VecDeque::new();
+    // the colours currently showing, which a fade starts from
+    let mut shown = colours.clone();
+    // a colour set by hand, shown instead of the measurement colours until resumed
+    let mut solid: Option<Colour> = None;
+    // party frames still to show, how long each is shown for and the command to acknowledge
+    let mut party: VecDeque<Vec<Colour>> = VecDeque::new();
+    let mut party_speed = DEFAULT_PARTY_SPEED;
+    let mut party_command: Option<glow_events::v2::Command> = None;
 
     use glow_events::v2::{Command::*, Event::*, Payload::*};
-    while let Ok(message) = rx.recv().await {
+    loop {
+        let message = if let Some(frame) = party.front().cloned() {
+            // show the next party frame unless a message arrives first
+            tokio::select! {
+                message = rx.recv() => message,
+                _ = delay_for(party_speed) => {
+                    leds.show(&frame, brightness_levels.brightest())
+                        .await
+                        .unwrap_or_else(|err| {
+                            error!("party error: {}", err);
+                        });
+                    party.pop_front();
+                    shown = frame;
+                    if party.is_empty() {
+                        // go back to whatever the party interrupted
+                        tx.send(Message::new_command(UpdateLEDs)).unwrap();
+                        if let Some(command) = party_command.take() {
+                            send_ack(&tx, &command);
+                        }
+                    }
+                    continue;
+                }
+            }
+        } else if let Some(frame) = rainbow {
+            // show the next rainbow frame unless a message arrives first
+            tokio::select! {
+                message = rx.recv() => message,
+                _ = delay_for(RAINBOW_FRAME_DELAY) => {
+                    let frame_colours = rainbow_frame(frame);
+                    if let Err(err) = leds.show(&frame_colours, brightness).await {
+                        error!("rainbow error: {}", err);
+                    }
+                    shown = frame_colours.to_vec();
+                    rainbow = Some(frame.wrapping_add(1));
+                    continue;
+                }
+            }
+        } else if let Some(frame) = fade.front().cloned() {
+            // show the next fade frame unless a message arrives first
+            tokio::select! {
+                message = rx.recv() => message,
+                _ = delay_for(fade_duration / FADE_STEPS as u32) => {
+                    if let Err(err) = leds.show(&frame, brightness).await {
+                        error!("fade error: {}", err);
+                    }
+                    fade.pop_front();
+                    shown = frame;
+                    continue;
+                }
+            }
+        } else {
+            rx.recv().await
+        };
+        let message = match message {
+            Ok(message) => message,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("LED handler fell behind and missed {} messages", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
         match message.payload() {
+            Event(Measurement(measurement)) if !metric.value(measurement).is_finite() => {
+                // keep showing the last good colours rather than the lowest bucket
+                error!("Not updating LEDs for a bad measurement: {:?}", measurement);
+            }
             Event(Measurement(measurement)) => {
-                let new_colours = colour_range.get_pixels(measurement.temperature as f32);
-                if new_colours.iter().zip(&colours).any(|(&a, &b)| a != b) {
+                let new_colours = colour_range.get_pixels(metric.value(measurement));
+                if solid.is_some() {
+                    // keep up with the measurements so that resuming shows the latest
+                    colours = new_colours;
+                    debug!("Not updating LEDs while showing a solid colour");
+                } else if new_colours.iter().zip(&colours).any(|(&a, &b)| a != b) {
                     colours = new_colours;
                     tx.send(Message::new_command(UpdateLEDs))
                         .expect("Failed to write TPLink device list to channel");
@@ -46,85 +279,230 @@ pub async fn handler(tx: Sender) {
                     debug!("Not updating unchanged LEDs");
                 }
             }
+            Event(Started) => {
+                // let the server know what the strip is showing without waiting for a
+                // measurement
+                let current = solid.map_or_else(|| colours.clone(), |c| colour_range.all(c));
+                tx.send(Message::new_event(LEDColours(
+                    current.iter().map(|c| (c.0, c.1, c.2)).collect(),
+                )))
+                .unwrap();
+                tx.send(Message::new_event(LEDBrightness(brightness)))
+                    .unwrap();
+            }
             Event(SingleTap) => {
-                brightness = Brightness::next_from(brightness).value();
+                brightness = brightness_levels.next_from(brightness);
+                save_brightness(&brightness_file, brightness);
                 tx.send(Message::new_command(RunParty)).unwrap();
                 tx.send(Message::new_command(UpdateLEDs)).unwrap();
             }
-            Command(RunParty) => {
+            Event(TripleTap) => {
+                brightness = brightness_levels.default_level();
+                save_brightness(&brightness_file, brightness);
+                tx.send(Message::new_command(UpdateLEDs)).unwrap();
+            }
+            Command(command @ RunParty) | Command(command @ RunPartyWith { .. }) => {
                 // Have a party!
                 //
                 // Play a short flashing sequence on the LEDs
-                // TODO: move this to a function?
-                let colours = [Colour::red(), Colour::green(), Colour::blue()];
-                let mut current_colours = [Colour::black(); NUM_PIXELS as usize];
-
-                for colour in colours.iter() {
-                    for i in 0..NUM_PIXELS {
-                        current_colours[i as usize] = *colour;
-                        leds.show(&current_colours, Brightness::Bright.value())
-                            .await
-                            .unwrap_or_else(|err| {
-                                error!("party error: {}", err);
-                            });
-                        delay_for(Duration::from_millis(50)).await;
-                    }
+                let (speed, cycles) = match command {
+                    RunPartyWith { speed_ms, cycles } => (
+                        Duration::from_millis(*speed_ms).min(MAX_PARTY_SPEED),
+                        (*cycles).min(MAX_PARTY_CYCLES),
+                    ),
+                    _ => (DEFAULT_PARTY_SPEED, DEFAULT_PARTY_CYCLES),
+                };
+                // a new party replaces one that is still running
+                if let Some(previous) = party_command.take() {
+                    send_ack(&tx, &previous);
+                }
+                fade.clear();
+                party = party_frames(&party_colours, cycles).into();
+                party_speed = speed;
+                if party.is_empty() {
+                    send_ack(&tx, command);
+                } else {
+                    party_command = Some(command.clone());
                 }
             }
+            Command(command @ StartRainbow) => {
+                fade.clear();
+                rainbow.get_or_insert(0);
+                send_ack(&tx, command);
+            }
+            Command(command @ StopRainbow) if rainbow.is_some() => {
+                // restore the colours from before the rainbow
+                rainbow = None;
+                tx.send(Message::new_command(UpdateLEDs)).unwrap();
+                send_ack(&tx, command);
+            }
+            Command(UpdateLEDs) if rainbow.is_some() => {
+                debug!("Not updating LEDs while the rainbow is running");
+            }
+            Command(UpdateLEDs) if !party.is_empty() => {
+                debug!("Not updating LEDs until the party ends");
+            }
             Command(UpdateLEDs) => {
-                if let Err(err) = leds.show(&colours, brightness).await {
+                let target = solid.map_or_else(|| colours.clone(), |c| colour_range.all(c));
+                if shown != target && fade_duration > Duration::from_secs(0) {
+                    // fade from whatever is showing, replacing any fade already running
+                    fade = fade_frames(&shown, &target, FADE_STEPS).into();
+                } else if let Err(err) = leds.show(&target, brightness).await {
                     error!("show error: {}", err);
+                    continue;
                 } else {
-                    tx.send(Message::new_event(LEDColours(
-                        colours.iter().map(|c| (c.0, c.1, c.2)).collect(),
-                    )))
-                    .unwrap();
+                    fade.clear();
+                    shown = target.clone();
                 }
+                tx.send(Message::new_event(LEDColours(
+                    target.iter().map(|c| (c.0, c.1, c.2)).collect(),
+                )))
+                .unwrap();
+            }
+            Command(command @ SetSolidColour(red, green, blue)) => {
+                rainbow = None;
+                solid = Some(Colour(*red, *green, *blue));
+                tx.send(Message::new_command(UpdateLEDs)).unwrap();
+                send_ack(&tx, command);
+            }
+            Command(command @ ResumeAuto) if solid.is_some() => {
+                solid = None;
+                tx.send(Message::new_command(UpdateLEDs)).unwrap();
+                send_ack(&tx, command);
             }
-            Command(SetBrightness(new_brightness)) => {
+            Command(command @ SetBrightness(new_brightness)) => {
                 brightness = *new_brightness;
+                save_brightness(&brightness_file, brightness);
                 tx.send(Message::new_command(UpdateLEDs)).unwrap();
                 tx.send(Message::new_event(LEDBrightness(*new_brightness)))
                     .unwrap();
+                send_ack(&tx, command);
+            }
+            Command(SelfTest) => {
+                rainbow = None;
+                fade.clear();
+                let mut passed = true;
+                for frame in self_test_frames() {
+                    if let Err(err) = leds.show(&frame, brightness_levels.brightest()).await {
+                        error!("self test error: {}", err);
+                        passed = false;
+                    }
+                    delay_for(SELF_TEST_FRAME_DELAY).await;
+                    shown = frame;
+                }
+                tx.send(Message::new_event(SelfTestResult {
+                    component: "leds".to_string(),
+                    passed,
+                }))
+                .unwrap();
+                tx.send(Message::new_command(UpdateLEDs)).unwrap();
+            }
+            Command(Stop) => {
+                // don't leave the strip lit once the device has stopped
+                if let Err(err) = leds
+                    .show(&colour_range.all(Colour::black()), brightness)
+                    .await
+                {
+                    error!("failed to clear LEDs: {}", err);
+                }
+                break;
             }
             _ => {}
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub enum Brightness {
-    Dim,
-    Bright,
-    Off,
+/// Read the last saved brightness, falling back to `default` if it is missing or invalid
+fn read_brightness(path: &Path, default: f32) -> f32 {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| contents.trim().parse::<f32>().ok())
+        .filter(|brightness| (0.0..=1.0).contains(brightness))
+        .unwrap_or(default)
+}
+
+fn write_brightness(path: &Path, brightness: f32) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, brightness.to_string())
+}
+
+fn save_brightness(path: &Path, brightness: f32) {
+    if let Err(err) = write_brightness(path, brightness) {
+        error!("failed to save brightness to {}: {}", path.display(), err);
+    }
+}
+
+/// The brightness levels a tap steps through, override with `BRIGHTNESS_LEVELS`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrightnessCycle {
+    levels: Vec<f32>,
 }
 
-impl Default for Brightness {
+impl Default for BrightnessCycle {
     fn default() -> Self {
-        Self::Dim
+        Self {
+            levels: vec![0.0, 0.01, 0.5],
+        }
     }
 }
 
-impl Brightness {
+impl BrightnessCycle {
+    /// Create a cycle from levels between 0.0 and 1.0, in any order
+    pub fn new(mut levels: Vec<f32>) -> Result<Self, String> {
+        if levels.is_empty() {
+            return Err("must have at least one brightness level".to_string());
+        }
+        if let Some(level) = levels.iter().find(|level| !(0.0..=1.0).contains(*level)) {
+            return Err(format!(
+                "brightness level {} is not between 0.0 and 1.0",
+                level
+            ));
+        }
+        levels.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        levels.dedup();
+        Ok(Self { levels })
+    }
+
+    /// Parse a comma separated list of levels such as `0,0.01,0.1,0.5`
+    pub fn parse(levels: &str) -> Result<Self, String> {
+        Self::new(
+            levels
+                .split(',')
+                .map(|level| {
+                    level
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("invalid brightness level {:?}", level))
+                })
+                .collect::<Result<_, _>>()?,
+        )
+    }
+
     /// Find the next brightness level from a given brightness
     ///
-    /// Should go Off -> Dim -> Bright
-    pub(crate) fn next_from(brightness: f32) -> Self {
-        if brightness < Brightness::Dim.value() {
-            Brightness::Dim
-        } else if brightness < Brightness::Bright.value() {
-            Brightness::Bright
-        } else {
-            Brightness::Off
-        }
+    /// Walks up through the levels and wraps round to the lowest after the highest.
+    pub(crate) fn next_from(&self, brightness: f32) -> f32 {
+        self.levels
+            .iter()
+            .copied()
+            .find(|&level| level > brightness)
+            .unwrap_or(self.levels[0])
     }
 
-    pub(crate) fn value(&self) -> f32 {
-        match self {
-            Brightness::Dim => 0.01,
-            Brightness::Bright => 0.5,
-            Brightness::Off => 0.0,
-        }
+    /// The dimmest level that is lit, used when nothing else has been chosen
+    pub(crate) fn default_level(&self) -> f32 {
+        self.levels
+            .iter()
+            .copied()
+            .find(|&level| level > 0.0)
+            .unwrap_or(self.levels[0])
+    }
+
+    /// The brightest level, used for the party and self test
+    pub(crate) fn brightest(&self) -> f32 {
+        self.levels[self.levels.len() - 1]
     }
 }
 
@@ -148,6 +526,23 @@ impl Colour {
         Colour(10, 10, 226)
     }
 
+    /// Create a fully saturated colour from a hue in degrees
+    pub fn from_hue(hue: f32) -> Colour {
+        let hue = hue.rem_euclid(360.0) / 60.0;
+        let x = 1.0 - (hue % 2.0 - 1.0).abs();
+        let (r, g, b) = match hue as u8 {
+            0 => (1.0, x, 0.0),
+            1 => (x, 1.0, 0.0),
+            2 => (0.0, 1.0, x),
+            3 => (0.0, x, 1.0),
+            4 => (x, 0.0, 1.0),
+            _ => (1.0, 0.0, x),
+        };
+        let to_u8 = |value: f32| (value * 255.0).round() as u8;
+
+        Colour(to_u8(r), to_u8(g), to_u8(b))
+    }
+
     pub fn name(self) -> &'static str {
         match self {
             COLOUR_BLUE => "blue",
@@ -155,9 +550,58 @@ impl Colour {
             COLOUR_SALMON => "salmon",
             COLOUR_CORAL => "coral",
             COLOUR_RED => "red",
+            COLOUR_LIME => "lime",
+            COLOUR_GREEN => "green",
+            COLOUR_TEAL => "teal",
+            COLOUR_CYAN => "cyan",
+            COLOUR_NAVY => "navy",
+            Colour(0, 0, 0) => "black",
             _ => "unnamed",
         }
     }
+
+    /// The colour `name` gives this name to
+    ///
+    /// `red`, `green` and `blue` are the softer colours of the temperature and humidity
+    /// ranges rather than `Colour::red()` and friends.
+    pub fn from_name(name: &str) -> Option<Colour> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "blue" => Some(COLOUR_BLUE),
+            "orange" => Some(COLOUR_ORANGE),
+            "salmon" => Some(COLOUR_SALMON),
+            "coral" => Some(COLOUR_CORAL),
+            "red" => Some(COLOUR_RED),
+            "lime" => Some(COLOUR_LIME),
+            "green" => Some(COLOUR_GREEN),
+            "teal" => Some(COLOUR_TEAL),
+            "cyan" => Some(COLOUR_CYAN),
+            "navy" => Some(COLOUR_NAVY),
+            "black" => Some(Colour::black()),
+            _ => None,
+        }
+    }
+
+    /// Format the colour as `#RRGGBB`
+    pub fn to_hex(&self) -> String {
+        colour::rgb_to_hex((self.0, self.1, self.2))
+    }
+
+    /// Parse a `#RRGGBB` or short `#RGB` colour, with or without the `#`
+    pub fn from_hex(hex: &str) -> Result<Colour, LedError> {
+        colour::rgb_from_hex(hex.trim())
+            .map(|(red, green, blue)| Colour(red, green, blue))
+            .map_err(|_| LedError::BadColour(hex.to_string()))
+    }
+}
+
+impl FromStr for Colour {
+    type Err = LedError;
+
+    /// Parse a colour name or a `#RRGGBB` or `#RGB` hex colour
+    fn from_str(colour: &str) -> Result<Self, Self::Err> {
+        let colour = colour.trim();
+        Colour::from_name(colour).map_or_else(|| Colour::from_hex(colour), Ok)
+    }
 }
 
 impl fmt::Debug for Colour {
@@ -173,6 +617,47 @@ impl fmt::Debug for Colour {
     }
 }
 
+/// Frames to fade from one set of colours to another
+///
+/// Each channel is interpolated linearly, the last frame is always `to`.
+fn fade_frames(from: &[Colour], to: &[Colour], steps: usize) -> Vec<Vec<Colour>> {
+    let steps = steps.max(1);
+    let channel = |from: u8, to: u8, step: usize| {
+        let from = f32::from(from);
+        let to = f32::from(to);
+        (from + (to - from) * step as f32 / steps as f32).round() as u8
+    };
+
+    (1..=steps)
+        .map(|step| {
+            from.iter()
+                .zip(to)
+                .map(|(from, to)| {
+                    Colour(
+                        channel(from.0, to.0, step),
+                        channel(from.1, to.1, step),
+                        channel(from.2, to.2, step),
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Colours for one frame of the rainbow
+///
+/// The pixels are spread evenly round the colour wheel and move round it as the frame increases.
+fn rainbow_frame(frame: usize) -> [Colour; NUM_PIXELS] {
+    let mut colours = [Colour::black(); NUM_PIXELS];
+    let offset = (frame as f32 * RAINBOW_HUE_STEP) % 360.0;
+
+    for (pixel, colour) in colours.iter_mut().enumerate() {
+        *colour = Colour::from_hue(offset + pixel as f32 * 360.0 / NUM_PIXELS as f32);
+    }
+
+    colours
+}
+
 /// A colour and a value
 ///
 /// Used to build a ColourRange. The value is the upper bound for this bucket.
@@ -226,9 +711,170 @@ impl PartialEq for ColourBucket {
 
 impl Eq for ColourBucket {}
 
-/// A linear range of colours
+/// Red, green then blue, the party colours unless `PARTY_COLOURS` says otherwise
+fn default_party_colours() -> Vec<Colour> {
+    vec![Colour::red(), Colour::green(), Colour::blue()]
+}
+
+/// Parse party colours from a comma separated list of `red:green:blue` values, `#RRGGBB`
+/// hex colours or colour names
+///
+/// For example `255:0:0,#00ff00,navy`.
+fn parse_party_colours(colours: &str) -> Result<Vec<Colour>, LedError> {
+    let colours = colours
+        .split(',')
+        .map(str::trim)
+        .filter(|colour| !colour.is_empty())
+        .map(|colour| {
+            if !colour.contains(':') {
+                return colour.parse();
+            }
+            let channels = colour
+                .split(':')
+                .map(|channel| channel.trim().parse::<u8>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| LedError::BadColour(colour.to_string()))?;
+            match channels.as_slice() {
+                [red, green, blue] => Ok(Colour(*red, *green, *blue)),
+                _ => Err(LedError::BadColour(colour.to_string())),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if colours.is_empty() {
+        Err(LedError::EmptyRange)
+    } else {
+        Ok(colours)
+    }
+}
+
+/// The party pattern, each colour in turn sweeping along the strip one pixel at a time
+///
+/// Each cycle sweeps over the colours left by the one before, starting from black.
+fn party_frames(colours: &[Colour], cycles: u32) -> Vec<Vec<Colour>> {
+    let mut current = vec![Colour::black(); NUM_PIXELS];
+    let mut frames = Vec::new();
+    for _ in 0..cycles {
+        for colour in colours {
+            for pixel in 0..NUM_PIXELS {
+                current[pixel] = *colour;
+                frames.push(current.clone());
+            }
+        }
+    }
+    frames
+}
+
+/// The self test pattern, every LED red, green then blue and a single LED walking the strip
+///
+/// A dead channel or pixel stands out against the solid colours.
+fn self_test_frames() -> Vec<Vec<Colour>> {
+    let solid = vec![Colour::red(), Colour::green(), Colour::blue()]
+        .into_iter()
+        .map(|colour| vec![colour; NUM_PIXELS]);
+    let walk = (0..NUM_PIXELS).map(|lit| {
+        (0..NUM_PIXELS)
+            .map(|pixel| {
+                if pixel == lit {
+                    Colour(255, 255, 255)
+                } else {
+                    Colour::black()
+                }
+            })
+            .collect()
+    });
+    solid.chain(walk).collect()
+}
+
+/// Which part of a measurement the LEDs show, set with `LED_METRIC`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LedMetric {
+    Temperature,
+    /// Useful as a ventilation indicator in a bathroom
+    Humidity,
+}
+
+impl LedMetric {
+    fn from_env() -> Self {
+        match env::var("LED_METRIC").as_deref() {
+            Ok("humidity") => LedMetric::Humidity,
+            Ok("temperature") | Err(_) => LedMetric::Temperature,
+            Ok(other) => panic!("LED_METRIC must be temperature or humidity, not {}", other),
+        }
+    }
+
+    pub fn colour_range(self) -> ColourRange {
+        match self {
+            LedMetric::Temperature => ColourRange::new(
+                14.0,
+                4.0,
+                &[
+                    COLOUR_BLUE,
+                    COLOUR_ORANGE,
+                    COLOUR_SALMON,
+                    COLOUR_CORAL,
+                    COLOUR_RED,
+                ],
+            )
+            .unwrap(),
+            LedMetric::Humidity => ColourRange::humidity_default(),
+        }
+    }
+
+    pub fn value(self, measurement: &Measurement) -> f32 {
+        match self {
+            LedMetric::Temperature => measurement.temperature as f32,
+            LedMetric::Humidity => measurement.humidity as f32,
+        }
+    }
+}
+
+/// Why colours could not be set up or shown
+#[derive(Debug, Clone, PartialEq)]
+pub enum LedError {
+    /// A colour range was configured with no colours
+    EmptyRange,
+    /// Colour range bucket values were not strictly increasing
+    UnorderedBuckets(f32, f32),
+    /// Asked to show a different number of colours than there are LEDs
+    BadPixelCount(usize),
+    /// A colour could not be parsed
+    BadColour(String),
+    /// The LEDs could not be opened
+    Unavailable(String),
+    /// The LEDs could not be written to
+    WriteFailed(String),
+}
+
+impl fmt::Display for LedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedError::EmptyRange => write!(f, "must have at least one colour"),
+            LedError::UnorderedBuckets(first, second) => write!(
+                f,
+                "bucket values must be strictly increasing, {} is followed by {}",
+                first, second
+            ),
+            LedError::BadPixelCount(count) => {
+                write!(f, "expected {} colours but got {}", NUM_PIXELS, count)
+            }
+            LedError::BadColour(colour) => {
+                write!(
+                    f,
+                    "invalid colour {:?}, expected red:green:blue, #RRGGBB, #RGB or a colour name",
+                    colour
+                )
+            }
+            LedError::Unavailable(reason) => write!(f, "failed to open LEDs: {}", reason),
+            LedError::WriteFailed(reason) => write!(f, "failed to write LEDs: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for LedError {}
+
+/// A range of colours
 ///
-/// Given a lower bound, a step and a set of colours we can map any value to our LED array.
+/// Given the value each colour starts at we can map any value to our LED array.
 pub struct ColourRange {
     buckets: Vec<ColourBucket>,
     num_pixels: u8,
@@ -239,29 +885,62 @@ impl ColourRange {
     ///
     /// Given a lower bound, a step and a set of colours we can map any float value to our LED
     /// array.
-    pub fn new(lower: f32, step: f32, colours: &[Colour]) -> Result<ColourRange, String> {
-        if colours.is_empty() {
-            Err("must have at least one colour".to_string())
-        } else {
-            let buckets = colours
+    pub fn new(lower: f32, step: f32, colours: &[Colour]) -> Result<ColourRange, LedError> {
+        Self::from_buckets(
+            colours
                 .iter()
                 .enumerate()
-                .map(|(i, &colour)| {
-                    ColourBucket::new(colour.name(), lower + (i as f32) * step, colour)
-                })
-                .collect();
+                .map(|(i, &colour)| (lower + (i as f32) * step, colour))
+                .collect(),
+        )
+    }
 
-            Ok(ColourRange {
-                buckets,
-                num_pixels: NUM_PIXELS as u8,
-            })
+    /// Green for dry air through to blue for damp, over 0% to 100% relative humidity
+    pub fn humidity_default() -> ColourRange {
+        ColourRange::new(
+            0.0,
+            25.0,
+            &[
+                COLOUR_LIME,
+                COLOUR_GREEN,
+                COLOUR_TEAL,
+                COLOUR_CYAN,
+                COLOUR_NAVY,
+            ],
+        )
+        .unwrap()
+    }
+
+    /// Create a ColourRange from explicit thresholds such as 10, 15, 18 and 25°C
+    ///
+    /// The values must be strictly increasing.
+    pub fn from_buckets(buckets: Vec<(f32, Colour)>) -> Result<ColourRange, LedError> {
+        if buckets.is_empty() {
+            return Err(LedError::EmptyRange);
+        }
+        if let Some(pair) = buckets
+            .windows(2)
+            .find(|pair| pair[0].0.partial_cmp(&pair[1].0) != Some(Ordering::Less))
+        {
+            return Err(LedError::UnorderedBuckets(pair[0].0, pair[1].0));
         }
+
+        Ok(ColourRange {
+            buckets: buckets
+                .into_iter()
+                .map(|(value, colour)| ColourBucket::new(colour.name(), value, colour))
+                .collect(),
+            num_pixels: NUM_PIXELS as u8,
+        })
     }
 
     /// Get the colours that should be used for each LED.
+    ///
+    /// Values beyond the buckets are clamped to the end ones. NaN and infinite values, which
+    /// only come from bad readings, show the lowest bucket.
     pub fn get_pixels(&self, value: f32) -> Vec<Colour> {
         let first = self.buckets.first().unwrap();
-        if value <= first.value {
+        if !value.is_finite() || value <= first.value {
             return vec![first.colour; self.num_pixels as usize];
         }
 
@@ -270,21 +949,25 @@ impl ColourRange {
             return vec![last.colour; self.num_pixels as usize];
         }
 
-        for i in 0..self.buckets.len() - 1 {
-            let (bottom, top) = (&self.buckets[i], &self.buckets[i + 1]);
-            if bottom.value <= value && value <= top.value {
-                let bottom_to_value = value - bottom.value;
-                let bottom_to_top = top.value - bottom.value;
-                let num_pixels =
-                    (f32::from(self.num_pixels) * (bottom_to_value / bottom_to_top)).round() as u8;
-
-                let mut pixels = vec![bottom.colour; (self.num_pixels - num_pixels) as usize];
-                let top_pixels = vec![top.colour; num_pixels as usize];
-                pixels.extend(top_pixels);
-                return pixels;
-            }
-        }
-        unreachable!();
+        self.buckets
+            .windows(2)
+            .find(|pair| pair[0].value <= value && value <= pair[1].value)
+            .map_or_else(
+                || vec![last.colour; self.num_pixels as usize],
+                |pair| {
+                    let (bottom, top) = (&pair[0], &pair[1]);
+                    let bottom_to_value = value - bottom.value;
+                    let bottom_to_top = top.value - bottom.value;
+                    let num_pixels = (f32::from(self.num_pixels)
+                        * (bottom_to_value / bottom_to_top))
+                        .round() as u8;
+
+                    let mut pixels = vec![bottom.colour; (self.num_pixels - num_pixels) as usize];
+                    let top_pixels = vec![top.colour; num_pixels as usize];
+                    pixels.extend(top_pixels);
+                    pixels
+                },
+            )
     }
 
     /// Return colours for all LEDs set to the same colour.
@@ -293,7 +976,148 @@ impl ColourRange {
     }
 }
 
-type ResponseSender = tokio::sync::oneshot::Sender<Result<(), String>>;
+/// Somewhere to show colours
+#[async_trait]
+pub trait LedBackend: Send {
+    async fn show(&mut self, colours: &[Colour], brightness: f32) -> Result<(), LedError>;
+}
+
+#[async_trait]
+impl LedBackend for Box<dyn LedBackend> {
+    async fn show(&mut self, colours: &[Colour], brightness: f32) -> Result<(), LedError> {
+        (**self).show(colours, brightness).await
+    }
+}
+
+/// A quiet window, such as 22:00-07:00, when the LEDs are held at a low brightness
+#[derive(Debug, Clone, PartialEq)]
+pub struct NightMode {
+    start: NaiveTime,
+    end: NaiveTime,
+    brightness: f32,
+}
+
+impl NightMode {
+    /// Parse a window like `22:00-07:00`, which may wrap past midnight
+    pub fn parse(window: &str, brightness: f32) -> Result<Self, String> {
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|_| format!("invalid time {:?}, expected HH:MM", time))
+        };
+        let (start, end) = window
+            .split_once('-')
+            .ok_or_else(|| format!("invalid window {:?}, expected HH:MM-HH:MM", window))?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            return Err("the window must not start and end at the same time".to_string());
+        }
+        if !(0.0..=1.0).contains(&brightness) {
+            return Err(format!(
+                "brightness {} is not between 0.0 and 1.0",
+                brightness
+            ));
+        }
+        Ok(Self {
+            start,
+            end,
+            brightness,
+        })
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        in_window(time, self.start, self.end)
+    }
+
+    /// The brightness to show at `time`, never brighter than the night brightness in the window
+    pub fn brightness_at(&self, time: NaiveTime, brightness: f32) -> f32 {
+        if self.contains(time) {
+            brightness.min(self.brightness)
+        } else {
+            brightness
+        }
+    }
+}
+
+/// Whether `time` is in the window from `start` up to `end`, wrapping past midnight if
+/// `end` is before `start`
+fn in_window(time: NaiveTime, start: NaiveTime, end: NaiveTime) -> bool {
+    if start <= end {
+        start <= time && time < end
+    } else {
+        time >= start || time < end
+    }
+}
+
+/// Dims another backend while night mode is on, by the device clock
+pub struct NightModeLeds<B: LedBackend> {
+    leds: B,
+    night_mode: Option<NightMode>,
+}
+
+impl<B: LedBackend> NightModeLeds<B> {
+    pub fn new(leds: B, night_mode: Option<NightMode>) -> Self {
+        Self { leds, night_mode }
+    }
+}
+
+#[async_trait]
+impl<B: LedBackend> LedBackend for NightModeLeds<B> {
+    async fn show(&mut self, colours: &[Colour], brightness: f32) -> Result<(), LedError> {
+        let brightness = match &self.night_mode {
+            Some(night_mode) => night_mode.brightness_at(Local::now().time(), brightness),
+            None => brightness,
+        };
+        self.leds.show(colours, brightness).await
+    }
+}
+
+/// Keeps every frame it is asked to show rather than lighting anything
+///
+/// Clones share their frames so a test can keep one while the handler owns another.
+#[derive(Clone, Default)]
+pub struct MockLeds {
+    frames: Arc<Mutex<Vec<Frame>>>,
+}
+
+/// Colours shown together with their brightness
+pub type Frame = (Vec<Colour>, f32);
+
+impl MockLeds {
+    /// Every frame shown so far, oldest first
+    pub fn frames(&self) -> Vec<Frame> {
+        self.frames.lock().unwrap().clone()
+    }
+
+    /// The frame showing now
+    pub fn last(&self) -> Option<Frame> {
+        self.frames.lock().unwrap().last().cloned()
+    }
+}
+
+#[async_trait]
+impl LedBackend for MockLeds {
+    async fn show(&mut self, colours: &[Colour], brightness: f32) -> Result<(), LedError> {
+        debug!("showing {:?} at brightness {}", colours, brightness);
+        self.frames
+            .lock()
+            .unwrap()
+            .push((colours.to_vec(), brightness));
+        Ok(())
+    }
+}
+
+/// Stands in for LEDs that could not be opened, showing nothing
+pub struct AbsentLeds;
+
+#[async_trait]
+impl LedBackend for AbsentLeds {
+    async fn show(&mut self, colours: &[Colour], brightness: f32) -> Result<(), LedError> {
+        debug!("no LEDs to show {:?} at brightness {}", colours, brightness);
+        Ok(())
+    }
+}
+
+type ResponseSender = tokio::sync::oneshot::Sender<Result<(), LedError>>;
 type Request = (LEDCommand, ResponseSender);
 type RequestSender = std::sync::mpsc::SyncSender<Request>;
 type RequestReceiver = std::sync::mpsc::Receiver<Request>;
@@ -307,30 +1131,53 @@ struct BlinktBackgroundLEDs {
 }
 
 impl BlinktBackgroundLEDs {
-    pub fn new() -> Self {
+    /// Open the Blinkt on a worker thread, failing if it is not there
+    pub fn try_new() -> Result<Self, LedError> {
         // TODO: check if this should be 0
         let (req_sender, req_receiver) = sync_channel(0);
+        let (opened_sender, opened_receiver) = sync_channel(1);
 
-        thread::spawn(move || {
-            run_worker(req_receiver);
+        thread::spawn(move || match BlinktLEDs::new() {
+            Ok(leds) => {
+                let _ = opened_sender.send(Ok(()));
+                run_worker(leds, req_receiver);
+            }
+            Err(err) => {
+                let _ = opened_sender.send(Err(err));
+            }
         });
 
-        BlinktBackgroundLEDs { sender: req_sender }
+        opened_receiver
+            .recv()
+            .map_err(|_| LedError::Unavailable("the LED worker stopped".to_string()))??;
+        Ok(BlinktBackgroundLEDs { sender: req_sender })
     }
+}
 
-    async fn show(&mut self, colours: &[Colour], brightness: f32) -> Result<(), String> {
+#[async_trait]
+impl LedBackend for BlinktBackgroundLEDs {
+    async fn show(&mut self, colours: &[Colour], brightness: f32) -> Result<(), LedError> {
         let (resp_sender, resp_receiver) = tokio::sync::oneshot::channel();
-        let colours: [Colour; 8] = colours.try_into().expect("Invalid colour slice size");
+        let colours = pixel_array(colours)?;
         self.sender
             .try_send((LEDCommand::Show(colours, brightness), resp_sender))
-            .expect("Could not request LED update");
-        resp_receiver.await.unwrap()
+            .map_err(|err| {
+                LedError::WriteFailed(format!("could not request LED update: {}", err))
+            })?;
+        resp_receiver
+            .await
+            .map_err(|_| LedError::WriteFailed("the LED worker stopped".to_string()))?
     }
 }
 
-fn run_worker(requests: RequestReceiver) {
-    let mut leds = BlinktLEDs::new();
+/// Check there is exactly one colour for each LED
+fn pixel_array(colours: &[Colour]) -> Result<[Colour; NUM_PIXELS], LedError> {
+    colours
+        .try_into()
+        .map_err(|_| LedError::BadPixelCount(colours.len()))
+}
 
+fn run_worker(mut leds: BlinktLEDs, requests: RequestReceiver) {
     for (command, sender) in requests.iter() {
         match command {
             LEDCommand::Show(colours, brightness) => {
@@ -346,11 +1193,11 @@ pub struct BlinktLEDs {
 }
 
 impl BlinktLEDs {
-    pub fn new() -> Self {
-        Self {
-            blinkt: Blinkt::new().unwrap(),
+    pub fn new() -> Result<Self, LedError> {
+        Ok(Self {
+            blinkt: Blinkt::new().map_err(|err| LedError::Unavailable(format!("{}", err)))?,
             current: None,
-        }
+        })
     }
 
     #[allow(clippy::if_same_then_else)]
@@ -372,10 +1219,9 @@ impl BlinktLEDs {
         result
     }
 
-    fn show(&mut self, colours: &[Colour], brightness: f32) -> Result<(), String> {
+    fn show(&mut self, colours: &[Colour], brightness: f32) -> Result<(), LedError> {
+        let colours_array = pixel_array(colours)?;
         if self.should_update(colours, brightness) {
-            let mut colours_array: [Colour; NUM_PIXELS] = Default::default();
-            colours_array.copy_from_slice(colours);
             let brightnesses = get_blinkt_brightness(&colours_array, brightness);
             let details = colours.iter().enumerate().zip(brightnesses.iter());
 
@@ -385,7 +1231,7 @@ impl BlinktLEDs {
             }
 
             if let Err(err) = self.blinkt.show() {
-                return Err(format!("Failed to write LEDs: {:?}", err));
+                return Err(LedError::WriteFailed(format!("{:?}", err)));
             }
         }
 
@@ -463,12 +1309,6 @@ pub(self) fn get_blinkt_brightness(
     }
 }
 
-impl Default for BlinktLEDs {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -482,7 +1322,51 @@ mod tests {
             let colour_range = ColourRange::new(0.0, 0.0, &[]);
 
             // assert
-            assert!(colour_range.is_err());
+            assert_eq!(colour_range.err(), Some(LedError::EmptyRange));
+        }
+
+        #[test]
+        fn cannot_create_colour_range_with_non_increasing_buckets() {
+            assert_eq!(
+                ColourRange::from_buckets(vec![(10.0, COLOUR_BLUE), (10.0, COLOUR_RED)]).err(),
+                Some(LedError::UnorderedBuckets(10.0, 10.0))
+            );
+            assert_eq!(
+                ColourRange::from_buckets(vec![(15.0, COLOUR_BLUE), (10.0, COLOUR_RED)]).err(),
+                Some(LedError::UnorderedBuckets(15.0, 10.0))
+            );
+            assert!(matches!(
+                ColourRange::from_buckets(vec![(f32::NAN, COLOUR_BLUE), (10.0, COLOUR_RED)]),
+                Err(LedError::UnorderedBuckets(..))
+            ));
+        }
+
+        #[test]
+        fn get_pixels_with_uneven_buckets() {
+            // arrange
+            let colour_range = ColourRange::from_buckets(vec![
+                (10.0, COLOUR_BLUE),
+                (15.0, COLOUR_ORANGE),
+                (18.0, COLOUR_SALMON),
+                (25.0, COLOUR_RED),
+            ])
+            .unwrap();
+
+            // assert
+            assert_eq!(colour_range.get_pixels(9.0), vec![COLOUR_BLUE; 8]);
+            assert_eq!(
+                colour_range.get_pixels(12.5),
+                [vec![COLOUR_BLUE; 4], vec![COLOUR_ORANGE; 4]].concat()
+            );
+            assert_eq!(
+                colour_range.get_pixels(16.5),
+                [vec![COLOUR_ORANGE; 4], vec![COLOUR_SALMON; 4]].concat()
+            );
+            assert_eq!(
+                colour_range.get_pixels(21.5),
+                [vec![COLOUR_SALMON; 4], vec![COLOUR_RED; 4]].concat()
+            );
+            assert_eq!(colour_range.get_pixels(30.0), vec![COLOUR_RED; 8]);
         }
 
         fn get_colour_range() -> ColourRange {
@@ -529,6 +1413,21 @@ mod tests {
             assert!(colour_range.get_pixels(31.0) == vec![COLOUR_RED; 8]);
         }
 
+        #[test]
+        fn get_pixels_clamps_bad_values() {
+            // arrange
+            let colour_range = get_colour_range();
+
+            // assert
+            assert_eq!(colour_range.get_pixels(f32::NAN), vec![COLOUR_BLUE; 8]);
+            assert_eq!(colour_range.get_pixels(f32::INFINITY), vec![COLOUR_BLUE; 8]);
+            assert_eq!(
+                colour_range.get_pixels(f32::NEG_INFINITY),
+                vec![COLOUR_BLUE; 8]
+            );
+            assert_eq!(colour_range.get_pixels(1.0e30), vec![COLOUR_RED; 8]);
+        }
+
         #[test]
         fn get_pixels_with_multiple_colour_ranges_split_pixels() {
             // arrange
@@ -722,11 +1621,858 @@ mod tests {
 
     #[test]
     fn brightness_next_from() {
-        assert_eq!(Brightness::next_from(0.0), Brightness::Dim);
-        assert_eq!(Brightness::next_from(0.009), Brightness::Dim);
-        assert_eq!(Brightness::next_from(0.01), Brightness::Bright);
-        assert_eq!(Brightness::next_from(0.49), Brightness::Bright);
-        assert_eq!(Brightness::next_from(0.5), Brightness::Off);
-        assert_eq!(Brightness::next_from(0.9), Brightness::Off);
+        let cycle = BrightnessCycle::default();
+        assert_eq!(cycle.next_from(0.0), 0.01);
+        assert_eq!(cycle.next_from(0.009), 0.01);
+        assert_eq!(cycle.next_from(0.01), 0.5);
+        assert_eq!(cycle.next_from(0.49), 0.5);
+        assert_eq!(cycle.next_from(0.5), 0.0);
+        assert_eq!(cycle.next_from(0.9), 0.0);
+    }
+
+    #[test]
+    fn brightness_cycles_through_custom_levels() {
+        // arrange
+        let cycle = BrightnessCycle::parse("0.5, 0, 0.05, 1").unwrap();
+        let mut brightness = cycle.default_level();
+
+        // act
+        let mut seen = vec![brightness];
+        for _ in 0..4 {
+            brightness = cycle.next_from(brightness);
+            seen.push(brightness);
+        }
+
+        // assert
+        assert_eq!(seen, vec![0.05, 0.5, 1.0, 0.0, 0.05]);
+        assert_eq!(cycle.brightest(), 1.0);
+    }
+
+    mod colour_names {
+        use super::*;
+
+        #[test]
+        fn names_round_trip() {
+            for colour in &[
+                COLOUR_BLUE,
+                COLOUR_ORANGE,
+                COLOUR_SALMON,
+                COLOUR_CORAL,
+                COLOUR_RED,
+                COLOUR_LIME,
+                COLOUR_GREEN,
+                COLOUR_TEAL,
+                COLOUR_CYAN,
+                COLOUR_NAVY,
+                Colour::black(),
+            ] {
+                assert_eq!(Colour::from_name(colour.name()), Some(*colour));
+            }
+        }
+
+        #[test]
+        fn from_name_ignores_case_and_spaces() {
+            assert_eq!(Colour::from_name(" Coral "), Some(COLOUR_CORAL));
+        }
+
+        #[test]
+        fn from_name_unknown() {
+            assert_eq!(Colour::from_name("unnamed"), None);
+            assert_eq!(Colour::from_name("magenta"), None);
+            assert_eq!(Colour::from_name(""), None);
+        }
+
+        #[test]
+        fn parse_names_and_hex() {
+            assert_eq!("coral".parse(), Ok(COLOUR_CORAL));
+            assert_eq!("#ff8000".parse(), Ok(Colour(255, 128, 0)));
+            assert_eq!("#0A0a64".parse(), Ok(Colour(10, 10, 100)));
+        }
+
+        #[test]
+        fn parse_short_and_bare_hex() {
+            assert_eq!("#f80".parse(), Ok(Colour(255, 136, 0)));
+            assert_eq!("ff8000".parse(), Ok(Colour(255, 128, 0)));
+        }
+
+        #[test]
+        fn parse_rejects_unknown_colours() {
+            for colour in &[
+                "magenta", "#ff80", "#ff80000", "#gg0000", "#+f0000", "#", "",
+            ] {
+                assert_eq!(
+                    colour.parse::<Colour>(),
+                    Err(LedError::BadColour(colour.to_string()))
+                );
+            }
+        }
+    }
+
+    mod night_mode {
+        use super::*;
+
+        fn time(time: &str) -> NaiveTime {
+            NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+        }
+
+        #[test]
+        fn in_window_without_wrapping() {
+            let (start, end) = (time("13:00"), time("15:30"));
+
+            assert!(!in_window(time("12:59"), start, end));
+            assert!(in_window(time("13:00"), start, end));
+            assert!(in_window(time("15:29"), start, end));
+            assert!(!in_window(time("15:30"), start, end));
+            assert!(!in_window(time("02:00"), start, end));
+        }
+
+        #[test]
+        fn in_window_wrapping_midnight() {
+            let (start, end) = (time("22:00"), time("07:00"));
+
+            assert!(!in_window(time("21:59"), start, end));
+            assert!(in_window(time("22:00"), start, end));
+            assert!(in_window(time("00:00"), start, end));
+            assert!(in_window(time("06:59"), start, end));
+            assert!(!in_window(time("07:00"), start, end));
+            assert!(!in_window(time("12:00"), start, end));
+        }
+
+        #[test]
+        fn brightness_is_capped_in_the_window() {
+            // arrange
+            let night_mode = NightMode::parse("22:00-07:00", 0.01).unwrap();
+
+            // assert
+            assert_eq!(night_mode.brightness_at(time("02:00"), 0.5), 0.01);
+            assert_eq!(night_mode.brightness_at(time("02:00"), 0.0), 0.0);
+            assert_eq!(night_mode.brightness_at(time("12:00"), 0.5), 0.5);
+        }
+
+        #[test]
+        fn parse_rejects_invalid_windows() {
+            assert!(NightMode::parse("22:00", 0.0).is_err());
+            assert!(NightMode::parse("22:00-25:00", 0.0).is_err());
+            assert!(NightMode::parse("22:00-22:00", 0.0).is_err());
+            assert!(NightMode::parse("22:00-07:00", 2.0).is_err());
+        }
+    }
+
+    #[test]
+    fn brightness_cycle_rejects_invalid_levels() {
+        assert!(BrightnessCycle::parse("").is_err());
+        assert!(BrightnessCycle::parse("0,1.5").is_err());
+        assert!(BrightnessCycle::parse("0,-0.1").is_err());
+        assert!(BrightnessCycle::parse("0,bright").is_err());
+        assert!(BrightnessCycle::new(vec![]).is_err());
+        assert!(BrightnessCycle::new(vec![f32::NAN]).is_err());
+    }
+
+    mod led_metric {
+        use super::*;
+
+        #[test]
+        fn humidity_maps_across_the_whole_percentage_range() {
+            // arrange
+            let metric = LedMetric::Humidity;
+            let colour_range = metric.colour_range();
+
+            // act
+            let dry = colour_range.get_pixels(metric.value(&Measurement::new(30.0, 0.0)));
+            let steamy = colour_range.get_pixels(metric.value(&Measurement::new(15.0, 100.0)));
+            let between = colour_range.get_pixels(metric.value(&Measurement::new(20.0, 62.5)));
+
+            // assert
+            assert_eq!(dry, vec![COLOUR_LIME; 8]);
+            assert_eq!(steamy, vec![COLOUR_NAVY; 8]);
+            assert_eq!(
+                between,
+                [vec![COLOUR_TEAL; 4], vec![COLOUR_CYAN; 4]].concat()
+            );
+        }
+
+        #[test]
+        fn humidity_default_colours() {
+            // arrange
+            let colour_range = ColourRange::humidity_default();
+
+            // act
+            let colours = [0.0, 50.0, 100.0]
+                .iter()
+                .map(|&humidity| colour_range.get_pixels(humidity))
+                .collect::<Vec<_>>();
+
+            // assert
+            assert_eq!(
+                colours,
+                vec![
+                    vec![COLOUR_LIME; NUM_PIXELS],
+                    vec![COLOUR_TEAL; NUM_PIXELS],
+                    vec![COLOUR_NAVY; NUM_PIXELS],
+                ]
+            );
+        }
+
+        #[test]
+        fn temperature_ignores_humidity() {
+            // arrange
+            let metric = LedMetric::Temperature;
+
+            // act
+            let value = metric.value(&Measurement::new(21.5, 80.0));
+
+            // assert
+            assert!((value - 21.5).abs() < f32::EPSILON);
+        }
+    }
+
+    mod mock_leds {
+        use super::*;
+        use glow_events::v2::{Command, Event, Payload};
+        use tokio::sync::broadcast::channel;
+
+        fn config(name: &str) -> LedConfig {
+            LedConfig {
+                metric: LedMetric::Temperature,
+                brightness_file: env::temp_dir().join(format!(
+                    "glow-{}-{}",
+                    std::process::id(),
+                    name
+                )),
+                fade_duration: Duration::from_millis(0),
+                brightness_levels: BrightnessCycle::default(),
+                night_mode: None,
+                party_colours: default_party_colours(),
+            }
+        }
+
+        /// Wait for the handler to report the colours it is showing
+        async fn next_colours(rx: &mut crate::events::Receiver) -> Vec<(u8, u8, u8)> {
+            loop {
+                if let Payload::Event(Event::LEDColours(colours)) =
+                    rx.recv().await.unwrap().payload()
+                {
+                    return colours.clone();
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn started_reports_the_current_state() {
+            // arrange
+            let (tx, mut rx) = channel(20);
+            let leds = MockLeds::default();
+            let driver = async {
+                tx.send(Message::new_event(Event::Started)).unwrap();
+                let mut reported = Vec::new();
+                while reported.len() < 2 {
+                    match rx.recv().await.unwrap().payload() {
+                        Payload::Event(event @ Event::LEDColours(_))
+                        | Payload::Event(event @ Event::LEDBrightness(_)) => {
+                            reported.push(event.clone())
+                        }
+                        _ => {}
+                    }
+                }
+                tx.send(Message::new_command(Command::Stop)).unwrap();
+                reported
+            };
+
+            // act
+            let (_, reported) = futures::future::join(
+                run_leds(tx.clone(), leds.clone(), config("started")),
+                driver,
+            )
+            .await;
+
+            // assert
+            assert_eq!(
+                reported,
+                vec![
+                    Event::LEDColours(vec![(0, 0, 0); NUM_PIXELS]),
+                    Event::LEDBrightness(BrightnessCycle::default().default_level()),
+                ]
+            );
+        }
+
+        #[tokio::test]
+        async fn set_brightness_is_acknowledged() {
+            // arrange
+            let (tx, mut rx) = channel(20);
+            let leds = MockLeds::default();
+            let driver = async {
+                tx.send(Message::new_command(Command::SetBrightness(0.5)))
+                    .unwrap();
+                let mut brightness_set = false;
+                let ack = loop {
+                    match rx.recv().await.unwrap().payload() {
+                        Payload::Event(Event::LEDBrightness(_)) => brightness_set = true,
+                        Payload::Event(ack @ Event::CommandAck { .. }) => break ack.clone(),
+                        _ => {}
+                    }
+                };
+                tx.send(Message::new_command(Command::Stop)).unwrap();
+                (brightness_set, ack)
+            };
+
+            // act
+            let (_, (brightness_set, ack)) =
+                futures::future::join(run_leds(tx.clone(), leds.clone(), config("ack")), driver)
+                    .await;
+
+            // assert
+            assert!(brightness_set, "the ack came before the brightness was set");
+            assert_eq!(
+                ack,
+                Event::CommandAck {
+                    command_type: "command.set-brightness".to_string()
+                }
+            );
+        }
+
+        #[tokio::test]
+        async fn solid_colour_holds_through_measurements() {
+            // arrange
+            let (tx, mut rx) = channel(20);
+            let leds = MockLeds::default();
+            let driver = async {
+                tx.send(Message::new_command(Command::SetSolidColour(1, 2, 3)))
+                    .unwrap();
+                next_colours(&mut rx).await;
+                tx.send(Message::new_event(Event::Measurement(Measurement::new(
+                    30.0, 50.0,
+                ))))
+                .unwrap();
+                tx.send(Message::new_command(Command::UpdateLEDs)).unwrap();
+                let colours = next_colours(&mut rx).await;
+                tx.send(Message::new_command(Command::Stop)).unwrap();
+                colours
+            };
+
+            // act
+            let (_, colours) = futures::future::join(
+                run_leds(tx.clone(), leds.clone(), config("solid-colour")),
+                driver,
+            )
+            .await;
+
+            // assert
+            assert_eq!(colours, vec![(1, 2, 3); NUM_PIXELS]);
+            let frames = leds.frames();
+            assert_eq!(frames.len(), 3);
+            assert!(frames[..2]
+                .iter()
+                .all(|(colours, _)| *colours == vec![Colour(1, 2, 3); NUM_PIXELS]));
+        }
+
+        #[tokio::test]
+        async fn resume_auto_shows_the_latest_measurement() {
+            // arrange
+            let (tx, mut rx) = channel(20);
+            let leds = MockLeds::default();
+            let expected = LedMetric::Temperature.colour_range().get_pixels(30.0);
+            let driver = async {
+                tx.send(Message::new_command(Command::SetSolidColour(1, 2, 3)))
+                    .unwrap();
+                next_colours(&mut rx).await;
+                tx.send(Message::new_event(Event::Measurement(Measurement::new(
+                    30.0, 50.0,
+                ))))
+                .unwrap();
+                tx.send(Message::new_command(Command::ResumeAuto)).unwrap();
+                let colours = next_colours(&mut rx).await;
+                tx.send(Message::new_command(Command::Stop)).unwrap();
+                colours
+            };
+
+            // act
+            let (_, colours) = futures::future::join(
+                run_leds(tx.clone(), leds.clone(), config("resume-auto")),
+                driver,
+            )
+            .await;
+
+            // assert
+            assert_eq!(
+                colours,
+                expected.iter().map(|c| (c.0, c.1, c.2)).collect::<Vec<_>>()
+            );
+            assert_eq!(leds.frames()[1].0, expected);
+        }
+
+        #[tokio::test]
+        async fn commands_are_handled_while_a_party_runs() {
+            // arrange
+            let (tx, mut rx) = channel(20);
+            let leds = MockLeds::default();
+            let driver = async {
+                tx.send(Message::new_command(Command::RunPartyWith {
+                    speed_ms: 1,
+                    cycles: 2,
+                }))
+                .unwrap();
+                tx.send(Message::new_command(Command::SetBrightness(0.5)))
+                    .unwrap();
+                let mut acks = Vec::new();
+                while acks.len() < 2 {
+                    if let Payload::Event(Event::CommandAck { command_type }) =
+                        rx.recv().await.unwrap().payload()
+                    {
+                        acks.push(command_type.clone());
+                    }
+                }
+                tx.send(Message::new_command(Command::Stop)).unwrap();
+                acks
+            };
+
+            // act
+            let (_, acks) = futures::future::join(
+                run_leds(tx.clone(), leds.clone(), config("party-commands")),
+                driver,
+            )
+            .await;
+
+            // assert
+            assert_eq!(
+                acks,
+                vec![
+                    "command.set-brightness".to_string(),
+                    "command.run-party-with".to_string(),
+                ]
+            );
+            let party_frames = party_frames(&default_party_colours(), 2).len();
+            assert!(leds.frames().len() > party_frames);
+        }
+
+        #[tokio::test]
+        async fn falling_behind_does_not_stop_the_handler() {
+            // arrange
+            let (tx, _rx) = channel(2);
+            let leds = MockLeds::default();
+            let driver = async {
+                // more messages than the channel holds before the handler can read any
+                for _ in 0..5 {
+                    tx.send(Message::new_command(Command::ResumeAuto)).unwrap();
+                }
+                tx.send(Message::new_command(Command::Stop)).unwrap();
+            };
+
+            // act
+            tokio::time::timeout(
+                Duration::from_secs(1),
+                futures::future::join(run_leds(tx.clone(), leds.clone(), config("lagged")), driver),
+            )
+            .await
+            .unwrap();
+
+            // assert
+            assert_eq!(leds.frames().len(), 1);
+            assert_eq!(leds.last().unwrap().0, vec![Colour::black(); NUM_PIXELS]);
+        }
+
+        #[tokio::test]
+        async fn measurement_shows_colours_then_stop_clears_them() {
+            // arrange
+            let (tx, mut rx) = channel(20);
+            let leds = MockLeds::default();
+            let config = config("mock-leds");
+            let expected = LedMetric::Temperature.colour_range().get_pixels(20.0);
+            let driver = async {
+                tx.send(Message::new_event(Event::Measurement(Measurement::new(
+                    20.0, 50.0,
+                ))))
+                .unwrap();
+                while let Ok(message) = rx.recv().await {
+                    if let Payload::Event(Event::LEDColours(_)) = message.payload() {
+                        break;
+                    }
+                }
+                tx.send(Message::new_command(Command::Stop)).unwrap();
+            };
+
+            // act
+            futures::future::join(run_leds(tx.clone(), leds.clone(), config), driver).await;
+
+            // assert
+            let frames = leds.frames();
+            assert_eq!(frames.len(), 2);
+            assert_eq!(
+                frames[0],
+                (expected, BrightnessCycle::default().default_level())
+            );
+            assert_eq!(leds.last().unwrap().0, vec![Colour::black(); NUM_PIXELS]);
+        }
+    }
+
+    mod blinkt_errors {
+        use super::*;
+
+        #[tokio::test]
+        async fn showing_the_wrong_number_of_colours_is_a_bad_pixel_count() {
+            // arrange
+            let (sender, _receiver) = sync_channel(1);
+            let mut leds = BlinktBackgroundLEDs { sender };
+
+            // act
+            let result = leds.show(&[COLOUR_BLUE; 3], 0.5).await;
+
+            // assert
+            assert_eq!(result, Err(LedError::BadPixelCount(3)));
+        }
+
+        #[tokio::test]
+        async fn showing_without_a_worker_is_a_write_failure() {
+            // arrange
+            let (sender, receiver) = sync_channel(1);
+            drop(receiver);
+            let mut leds = BlinktBackgroundLEDs { sender };
+
+            // act
+            let result = leds.show(&[COLOUR_BLUE; NUM_PIXELS], 0.5).await;
+
+            // assert
+            assert!(matches!(result, Err(LedError::WriteFailed(_))));
+        }
+
+        #[tokio::test]
+        async fn a_worker_that_drops_the_request_is_a_write_failure() {
+            // arrange
+            let (sender, receiver) = sync_channel(1);
+            let worker = thread::spawn(move || drop(receiver.recv()));
+            let mut leds = BlinktBackgroundLEDs { sender };
+
+            // act
+            let result = leds.show(&[COLOUR_BLUE; NUM_PIXELS], 0.5).await;
+
+            // assert
+            worker.join().unwrap();
+            assert_eq!(
+                result,
+                Err(LedError::WriteFailed("the LED worker stopped".to_string()))
+            );
+        }
+    }
+
+    mod open_leds {
+        use super::*;
+
+        #[tokio::test]
+        async fn falls_back_to_no_leds_without_a_blinkt() {
+            // act
+            let mut leds = open_leds(None, || -> Result<MockLeds, LedError> {
+                Err(LedError::Unavailable("no SPI device".to_string()))
+            });
+
+            // assert
+            assert_eq!(leds.show(&[COLOUR_BLUE; NUM_PIXELS], 0.5).await, Ok(()));
+        }
+
+        #[tokio::test]
+        async fn uses_the_blinkt_when_it_opens() {
+            // arrange
+            let blinkt = MockLeds::default();
+            let opened = blinkt.clone();
+
+            // act
+            let mut leds = open_leds(None, move || Ok(opened));
+            leds.show(&[COLOUR_BLUE; NUM_PIXELS], 0.5).await.unwrap();
+
+            // assert
+            assert_eq!(blinkt.last(), Some((vec![COLOUR_BLUE; NUM_PIXELS], 0.5)));
+        }
+
+        #[test]
+        fn mock_never_opens_the_blinkt() {
+            open_leds(Some("mock"), || -> Result<MockLeds, LedError> {
+                panic!("opened the Blinkt")
+            });
+        }
+    }
+
+    mod party {
+        use super::*;
+
+        #[test]
+        fn party_frames_sweep_each_colour_along_the_strip() {
+            // act
+            let frames = party_frames(&default_party_colours(), 1);
+
+            // assert
+            assert_eq!(frames.len(), 3 * NUM_PIXELS);
+            let mut first = vec![Colour::black(); NUM_PIXELS];
+            first[0] = Colour::red();
+            assert_eq!(frames[0], first);
+            assert_eq!(frames[NUM_PIXELS - 1], vec![Colour::red(); NUM_PIXELS]);
+            let mut green_starts = vec![Colour::red(); NUM_PIXELS];
+            green_starts[0] = Colour::green();
+            assert_eq!(frames[NUM_PIXELS], green_starts);
+            assert_eq!(
+                frames[2 * NUM_PIXELS - 1],
+                vec![Colour::green(); NUM_PIXELS]
+            );
+            assert_eq!(frames[3 * NUM_PIXELS - 1], vec![Colour::blue(); NUM_PIXELS]);
+        }
+
+        #[test]
+        fn party_frames_repeat_for_each_cycle() {
+            // act
+            let frames = party_frames(&default_party_colours(), 3);
+
+            // assert
+            assert_eq!(frames.len(), 3 * 3 * NUM_PIXELS);
+            let mut second_cycle_starts = vec![Colour::blue(); NUM_PIXELS];
+            second_cycle_starts[0] = Colour::red();
+            assert_eq!(frames[3 * NUM_PIXELS], second_cycle_starts);
+            // later cycles all start from the blue the one before left
+            assert_eq!(
+                frames[3 * NUM_PIXELS..6 * NUM_PIXELS],
+                frames[6 * NUM_PIXELS..]
+            );
+        }
+
+        #[test]
+        fn no_cycles_is_no_party() {
+            assert!(party_frames(&default_party_colours(), 0).is_empty());
+        }
+
+        #[test]
+        fn party_frames_with_two_colours() {
+            // arrange
+            let white = Colour(255, 255, 255);
+
+            // act
+            let frames = party_frames(&[white, Colour::black()], 1);
+
+            // assert
+            assert_eq!(frames.len(), 2 * NUM_PIXELS);
+            for (pixel, frame) in frames[..NUM_PIXELS].iter().enumerate() {
+                let mut expected = vec![Colour::black(); NUM_PIXELS];
+                expected[..=pixel].fill(white);
+                assert_eq!(frame, &expected);
+            }
+            for (pixel, frame) in frames[NUM_PIXELS..].iter().enumerate() {
+                let mut expected = vec![white; NUM_PIXELS];
+                expected[..=pixel].fill(Colour::black());
+                assert_eq!(frame, &expected);
+            }
+        }
+
+        #[test]
+        fn parse_party_colours_from_a_list() {
+            assert_eq!(
+                parse_party_colours("255:0:0, 0:0:255").unwrap(),
+                vec![Colour(255, 0, 0), Colour(0, 0, 255)]
+            );
+        }
+
+        #[test]
+        fn parse_party_colours_from_names_and_hex() {
+            assert_eq!(
+                parse_party_colours("coral, #00ff00,navy").unwrap(),
+                vec![COLOUR_CORAL, Colour(0, 255, 0), COLOUR_NAVY]
+            );
+        }
+
+        #[test]
+        fn parse_party_colours_rejects_bad_lists() {
+            assert_eq!(parse_party_colours(""), Err(LedError::EmptyRange));
+            assert_eq!(parse_party_colours(" , "), Err(LedError::EmptyRange));
+            assert_eq!(
+                parse_party_colours("255:0"),
+                Err(LedError::BadColour("255:0".to_string()))
+            );
+            assert_eq!(
+                parse_party_colours("255:0:256"),
+                Err(LedError::BadColour("255:0:256".to_string()))
+            );
+        }
+    }
+
+    mod self_test {
+        use super::*;
+
+        #[test]
+        fn self_test_frames_show_each_channel_then_each_pixel() {
+            // act
+            let frames = self_test_frames();
+
+            // assert
+            assert_eq!(frames.len(), 3 + NUM_PIXELS);
+            assert_eq!(frames[0], vec![Colour::red(); NUM_PIXELS]);
+            assert_eq!(frames[1], vec![Colour::green(); NUM_PIXELS]);
+            assert_eq!(frames[2], vec![Colour::blue(); NUM_PIXELS]);
+            for (lit, frame) in frames[3..].iter().enumerate() {
+                for (pixel, colour) in frame.iter().enumerate() {
+                    let expected = if pixel == lit {
+                        Colour(255, 255, 255)
+                    } else {
+                        Colour::black()
+                    };
+                    assert!(*colour == expected, "frame {} pixel {}", lit, pixel);
+                }
+            }
+        }
+    }
+
+    mod fade {
+        use super::*;
+
+        #[test]
+        fn fade_ends_on_target() {
+            // arrange
+            let from = vec![Colour::black(); NUM_PIXELS];
+            let to = vec![COLOUR_ORANGE; NUM_PIXELS];
+
+            // act
+            let frames = fade_frames(&from, &to, 10);
+
+            // assert
+            assert_eq!(frames.len(), 10);
+            assert_eq!(frames.last().unwrap(), &to);
+        }
+
+        #[test]
+        fn fade_interpolates_each_channel() {
+            // arrange
+            let from = vec![Colour(0, 200, 10), Colour(100, 100, 100)];
+            let to = vec![Colour(200, 0, 10), Colour(0, 0, 0)];
+
+            // act
+            let frames = fade_frames(&from, &to, 4);
+
+            // assert
+            assert_eq!(
+                frames,
+                vec![
+                    vec![Colour(50, 150, 10), Colour(75, 75, 75)],
+                    vec![Colour(100, 100, 10), Colour(50, 50, 50)],
+                    vec![Colour(150, 50, 10), Colour(25, 25, 25)],
+                    vec![Colour(200, 0, 10), Colour(0, 0, 0)],
+                ]
+            );
+        }
+
+        #[test]
+        fn fade_with_no_steps_jumps_to_target() {
+            // arrange
+            let from = vec![Colour::black(); NUM_PIXELS];
+            let to = vec![COLOUR_RED; NUM_PIXELS];
+
+            // act
+            let frames = fade_frames(&from, &to, 0);
+
+            // assert
+            assert_eq!(frames, vec![to]);
+        }
+    }
+
+    mod rainbow {
+        use super::*;
+
+        #[test]
+        fn colour_from_hue() {
+            assert_eq!(Colour::from_hue(0.0), Colour(255, 0, 0));
+            assert_eq!(Colour::from_hue(60.0), Colour(255, 255, 0));
+            assert_eq!(Colour::from_hue(120.0), Colour(0, 255, 0));
+            assert_eq!(Colour::from_hue(180.0), Colour(0, 255, 255));
+            assert_eq!(Colour::from_hue(240.0), Colour(0, 0, 255));
+            assert_eq!(Colour::from_hue(300.0), Colour(255, 0, 255));
+            assert_eq!(Colour::from_hue(360.0), Colour(255, 0, 0));
+            assert_eq!(Colour::from_hue(-120.0), Colour(0, 0, 255));
+        }
+
+        #[test]
+        fn first_frame_spreads_hues_across_pixels() {
+            // act
+            let frame = rainbow_frame(0);
+
+            // assert
+            assert_eq!(frame[0], Colour(255, 0, 0));
+            assert_eq!(frame[2], Colour::from_hue(90.0));
+            assert_eq!(frame[4], Colour(0, 255, 255));
+            assert_eq!(frame[6], Colour::from_hue(270.0));
+        }
+
+        #[test]
+        fn frames_step_round_the_wheel() {
+            // act
+            let frame = rainbow_frame(1);
+
+            // assert
+            assert_eq!(frame[0], Colour::from_hue(RAINBOW_HUE_STEP));
+            assert_ne!(frame, rainbow_frame(0));
+        }
+
+        #[test]
+        fn frames_cycle() {
+            // arrange
+            let frames_per_cycle = (360.0 / RAINBOW_HUE_STEP) as usize;
+
+            // assert
+            assert_eq!(rainbow_frame(3), rainbow_frame(3 + frames_per_cycle));
+        }
+    }
+
+    mod brightness_file {
+        use super::*;
+
+        fn temp_path(name: &str) -> PathBuf {
+            let path = env::temp_dir().join(format!("glow-{}-{}", std::process::id(), name));
+            let _ = fs::remove_file(&path);
+            path
+        }
+
+        #[test]
+        fn round_trip() {
+            // arrange
+            let path = temp_path("brightness-round-trip");
+
+            // act
+            write_brightness(&path, 0.5).unwrap();
+
+            // assert
+            assert_eq!(read_brightness(&path, 0.01), 0.5);
+            fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn missing_file_is_default() {
+            // arrange
+            let path = temp_path("brightness-missing");
+
+            // act
+            let brightness = read_brightness(&path, 0.01);
+
+            // assert
+            assert_eq!(brightness, 0.01);
+        }
+
+        #[test]
+        fn corrupt_file_is_default() {
+            // arrange
+            let path = temp_path("brightness-corrupt");
+            fs::write(&path, "not a number").unwrap();
+
+            // act
+            let brightness = read_brightness(&path, 0.01);
+
+            // assert
+            assert_eq!(brightness, 0.01);
+            fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn out_of_range_is_default() {
+            // arrange
+            let path = temp_path("brightness-out-of-range");
+            fs::write(&path, "12.0").unwrap();
+
+            // act
+            let brightness = read_brightness(&path, 0.01);
+
+            // assert
+            assert_eq!(brightness, 0.01);
+            fs::remove_file(&path).unwrap();
+        }
     }
 }
@@ -1,4 +1,5 @@
 pub mod am2320;
+pub mod ds18b20;
 pub mod events;
 pub mod leds;
 pub mod tplink;
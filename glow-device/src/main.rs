@@ -1,28 +1,86 @@
-use std::env;
+use std::{env, time::Duration};
 
 use log::info;
 
-use glow_device::events::Runner;
+use glow_device::{
+    events::{EnabledHandlers, Runner},
+    web::{ClientSettings, CommandMode, WireFormat},
+};
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    glow_events::logging::init();
 
-    let mut runner = Runner::default();
-    runner.add(glow_device::tplink::handler);
-    runner.add(glow_device::leds::handler);
-    runner.add(glow_device::am2320::handler);
-    runner.add(glow_device::vibration::handler);
+    // long enough for the web handler to send its last events
+    let mut runner = Runner::default().with_shutdown_grace(Duration::from_secs(5));
+    let enabled = EnabledHandlers::from_env();
+    info!("Running handlers {:?}", enabled);
+    if enabled.tplink {
+        runner.add(glow_device::tplink::handler);
+    }
+    if enabled.leds {
+        runner.add_cleanup(glow_device::leds::handler);
+    }
+    if enabled.sensor {
+        match env::var("SENSOR_LABEL") {
+            Ok(label) => {
+                info!("Labelling sensor readings {:?}", label);
+                runner.add(glow_device::am2320::EnvironmentSensor::labelled(&label));
+            }
+            Err(_) => runner.add(glow_device::am2320::handler),
+        }
+    }
+    if enabled.vibration {
+        runner.add(glow_device::vibration::handler);
+    }
 
     if let (Ok(web_event_url), Ok(web_event_token)) =
         (env::var("WEB_EVENT_URL"), env::var("WEB_EVENT_TOKEN"))
     {
-        info!("Adding web event handler");
-        runner.add(glow_device::web::WebHandler::new(
-            web_event_url,
-            web_event_token,
-        ));
+        let mode = match env::var("WEB_COMMAND_MODE").as_deref() {
+            Ok("websocket") => CommandMode::WebSocket,
+            _ => CommandMode::Poll,
+        };
+        let format = match env::var("WEB_WIRE_FORMAT").as_deref() {
+            Ok("cbor") => WireFormat::Cbor,
+            _ => WireFormat::Json,
+        };
+        info!(
+            "Adding web event handler in {:?} mode with {:?}",
+            mode, format
+        );
+        runner.add_cleanup(
+            glow_device::web::WebHandler::with_mode(web_event_url, web_event_token, mode)
+                .with_format(format)
+                .with_device_id(env::var("DEVICE_ID").ok())
+                .with_client_settings(ClientSettings {
+                    connect_timeout: env_seconds(
+                        "WEB_CONNECT_TIMEOUT_SECONDS",
+                        glow_device::web::DEFAULT_CONNECT_TIMEOUT,
+                    ),
+                    request_timeout: env_seconds(
+                        "WEB_REQUEST_TIMEOUT_SECONDS",
+                        glow_device::web::DEFAULT_REQUEST_TIMEOUT,
+                    ),
+                    user_agent_suffix: env::var("WEB_USER_AGENT_SUFFIX").ok(),
+                })
+                .with_max_batch(
+                    env::var("WEB_MAX_BATCH").map_or(glow_device::web::DEFAULT_MAX_BATCH, |max| {
+                        max.parse().expect("WEB_MAX_BATCH must be a number")
+                    }),
+                ),
+        );
     }
 
     runner.run().await;
 }
+
+fn env_seconds(name: &str, default: Duration) -> Duration {
+    env::var(name).map_or(default, |seconds| {
+        Duration::from_secs(
+            seconds
+                .parse()
+                .unwrap_or_else(|_| panic!("{} must be a number of seconds", name)),
+        )
+    })
+}
@@ -1,18 +1,40 @@
-use std::{net::SocketAddr, time};
+use std::{
+    env,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time,
+};
 
-use log::{debug, error};
-use tokio::stream::StreamExt;
-use tplinker::{capabilities::Switch, datatypes::DeviceData, devices::Device, discovery::discover};
+use async_trait::async_trait;
+use log::{debug, error, info};
+use serde_json::Value;
+use tokio::{stream::StreamExt, sync::oneshot};
+use tplinker::{
+    capabilities::{Emeter, Switch},
+    datatypes::DeviceData,
+    devices::Device,
+    discovery::discover,
+};
 
 use glow_events::{
-    v2::{Event, Message},
+    v2::{Command, Event, Message, Payload, MAX_HEATER_SECONDS},
     TPLinkDevice,
 };
 
-use crate::events::Sender;
+use crate::events::{send_ack, Sender};
+
+/// Alias of the plug the heater is connected to, override with `HEATER_ALIAS`
+const DEFAULT_HEATER_ALIAS: &str = "Heater";
 
+/// How long the heater runs for when no duration is requested
 const HEATER_ON_TIME: time::Duration = time::Duration::from_secs(90);
 
+/// How often to read the power draw of plugs with energy monitoring while the heater is on
+const POWER_READING_INTERVAL: time::Duration = time::Duration::from_secs(15);
+
+/// How often to report whether the heater is on, override with `HEATER_POLL_SECONDS`
+const DEFAULT_HEATER_POLL_SECONDS: u64 = 300;
+
 struct TPLinkDeviceWrap(TPLinkDevice);
 
 impl From<DeviceData> for TPLinkDeviceWrap {
@@ -23,17 +45,97 @@ impl From<DeviceData> for TPLinkDeviceWrap {
     }
 }
 
+/// Tracks the running heater cycle so a second run command cannot overlap it and a stop
+/// command can end it
+#[derive(Clone, Default)]
+struct HeaterGuard(Arc<Mutex<CycleSlot>>);
+
+#[derive(Default)]
+struct CycleSlot {
+    next_id: u64,
+    /// The running cycle and how to cancel it
+    running: Option<(u64, oneshot::Sender<()>)>,
+}
+
+impl HeaterGuard {
+    /// Claim the heater for a cycle, `None` if a cycle is already running
+    fn try_start(&self) -> Option<HeaterCycle> {
+        let mut slot = self.0.lock().unwrap();
+        if slot.running.is_some() {
+            return None;
+        }
+        let id = slot.next_id;
+        slot.next_id += 1;
+        let (cancel, cancelled) = oneshot::channel();
+        slot.running = Some((id, cancel));
+        Some(HeaterCycle {
+            slot: self.0.clone(),
+            id,
+            cancelled,
+        })
+    }
+
+    /// Cancel the running cycle and free the heater, `false` if no cycle was running
+    fn stop(&self) -> bool {
+        match self.0.lock().unwrap().running.take() {
+            Some((_, cancel)) => {
+                let _ = cancel.send(());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A running heater cycle, the heater is free again once this is dropped or stopped
+struct HeaterCycle {
+    slot: Arc<Mutex<CycleSlot>>,
+    id: u64,
+    /// Resolves when a stop command cancels the cycle
+    cancelled: oneshot::Receiver<()>,
+}
+
+impl Drop for HeaterCycle {
+    fn drop(&mut self) {
+        let mut slot = self.slot.lock().unwrap();
+        // a stopped cycle must not free the heater from a cycle started after it
+        if matches!(slot.running, Some((id, _)) if id == self.id) {
+            slot.running = None;
+        }
+    }
+}
+
 pub async fn handler(tx: Sender) {
     let rx = tx.subscribe();
+    let heater_alias =
+        env::var("HEATER_ALIAS").unwrap_or_else(|_| DEFAULT_HEATER_ALIAS.to_string());
+    let poll_interval = env::var("HEATER_POLL_SECONDS")
+        .map(|seconds| {
+            seconds
+                .parse()
+                .expect("HEATER_POLL_SECONDS must be a number")
+        })
+        .unwrap_or(DEFAULT_HEATER_POLL_SECONDS);
+    spawn_heater_poll(
+        tx.clone(),
+        NetworkDiscovery,
+        heater_alias.clone(),
+        time::Duration::from_secs(poll_interval),
+    );
 
     tokio::pin! {
         let commands = rx.into_stream()
             .filter(Result::is_ok)
             .map(Result::unwrap)
-            .filter_map(Message::into_command);
+            .filter_map(|message| match message.payload() {
+                // a double tap runs the heater
+                Payload::Event(Event::DoubleTap) => Some(Command::RunHeater),
+                _ => message.into_command(),
+            });
     }
 
     debug!("Listening for TPLink commands");
+    let guard = HeaterGuard::default();
 
     while let Some(command) = commands.next().await {
         use glow_events::v2::Command::*;
@@ -56,25 +158,128 @@ pub async fn handler(tx: Sender) {
                     Err(err) => error!("Failed to list TPLink devices {}", err),
                 }
             }
-            command @ RunHeater | command @ StopHeater => {
-                debug!("Running or Stopping heater");
-                if let Some((addr, data)) = async_find_by_alias(&"Heater").await {
-                    let device = Device::from_data(addr, &data);
-
-                    if let Device::HS100(_) = device {
-                        match command {
-                            RunHeater => async_run_heater(device, &tx).await,
-                            StopHeater => async_stop_header(device, &tx).await,
-                            _ => unreachable!(),
+            command @ RunHeater | command @ RunHeaterFor(_) => {
+                let mut cycle = match guard.try_start() {
+                    Some(cycle) => cycle,
+                    None => {
+                        info!("Ignoring {:?}, the heater is already running", command);
+                        continue;
+                    }
+                };
+                let duration = heater_duration(&command);
+                debug!("Running heater");
+                // run the cycle in its own task so stop commands are not held up behind it
+                let tx = tx.clone();
+                let heater_alias = heater_alias.clone();
+                tokio::spawn(async move {
+                    let run = async {
+                        if let Some(device) = async_find_heater(&heater_alias).await {
+                            async_run_heater(device, &tx, duration, &command).await;
                         }
+                    };
+                    tokio::select! {
+                        _ = run => {}
+                        // the stop command switches the heater off and reports it
+                        _ = &mut cycle.cancelled => debug!("Heater cycle cancelled"),
                     }
+                    drop(cycle);
+                });
+            }
+            command @ StopHeater => {
+                debug!("Stopping heater");
+                // end the running cycle now so the heater can be run again straight away
+                guard.stop();
+                if let Some(device) = async_find_heater(&heater_alias).await {
+                    async_stop_header(device, &tx).await;
+                    send_ack(&tx, &command);
                 }
             }
+            QueryHeaterState => {
+                debug!("Querying heater state");
+                send_heater_state(&tx, &NetworkDiscovery, &heater_alias).await;
+            }
             _ => {}
         }
     }
 }
 
+/// How long a run command runs the heater for, never more than `MAX_HEATER_SECONDS`
+fn heater_duration(command: &Command) -> time::Duration {
+    match command {
+        Command::RunHeaterFor(seconds) if *seconds > MAX_HEATER_SECONDS => {
+            info!(
+                "Running the heater for {}s rather than the {}s asked for",
+                MAX_HEATER_SECONDS, seconds
+            );
+            time::Duration::from_secs(MAX_HEATER_SECONDS)
+        }
+        Command::RunHeaterFor(seconds) => time::Duration::from_secs(*seconds),
+        _ => HEATER_ON_TIME,
+    }
+}
+
+/// Somewhere to find out whether the heater is on
+#[async_trait]
+pub trait HeaterDiscovery: Send + Sync + 'static {
+    async fn heater_state(&self, alias: &str) -> Option<bool>;
+}
+
+/// Finds the heater plug on the local network
+pub struct NetworkDiscovery;
+
+#[async_trait]
+impl HeaterDiscovery for NetworkDiscovery {
+    async fn heater_state(&self, alias: &str) -> Option<bool> {
+        let (_, data) = async_find_by_alias(alias).await?;
+        heater_state(&data)
+    }
+}
+
+async fn send_heater_state(tx: &Sender, discovery: &impl HeaterDiscovery, alias: &str) {
+    match discovery.heater_state(alias).await {
+        Some(on) => {
+            tx.send(Message::new_event(Event::HeaterState(on)))
+                .unwrap_or_else(|_err| {
+                    error!("Failed to write heater state event");
+                    0
+                });
+        }
+        None => error!("Failed to find the state of heater {}", alias),
+    }
+}
+
+/// Report the heater state every `interval` so the server stays in sync if commands are missed
+///
+/// The poll runs in its own task so a slow discovery never holds up commands. A zero
+/// interval turns polling off.
+fn spawn_heater_poll(
+    tx: Sender,
+    discovery: impl HeaterDiscovery,
+    alias: String,
+    interval: time::Duration,
+) -> Option<tokio::task::JoinHandle<()>> {
+    if interval == time::Duration::from_secs(0) {
+        return None;
+    }
+    Some(tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            if tx.receiver_count() == 0 {
+                break;
+            }
+            send_heater_state(&tx, &discovery, &alias).await;
+        }
+    }))
+}
+
+/// Whether a plug's relay is on, from the system info it sent during discovery
+fn heater_state(data: &DeviceData) -> Option<bool> {
+    data.sysinfo()
+        .relay_state
+        .map(|relay_state| relay_state > 0)
+}
+
 async fn async_discover() -> tplinker::error::Result<Vec<(SocketAddr, DeviceData)>> {
     let (tx, mut rx) = tokio::sync::oneshot::channel();
 
@@ -88,68 +293,394 @@ async fn async_discover() -> tplinker::error::Result<Vec<(SocketAddr, DeviceData
 }
 
 async fn async_find_by_alias(alias: &str) -> Option<(SocketAddr, DeviceData)> {
-    if let Ok(result) = async_discover().await {
-        for (addr, device) in result {
-            if device.clone().sysinfo().alias == alias {
-                return Some((addr, device));
+    let devices = async_discover().await.ok()?;
+    let aliases = devices
+        .iter()
+        .map(|(addr, device)| (*addr, device.sysinfo().alias.clone()))
+        .collect::<Vec<_>>();
+    let addr = find_device_by_alias(&aliases, alias)?;
+
+    devices
+        .into_iter()
+        .find(|(device_addr, _)| *device_addr == addr)
+}
+
+/// Find the heater plug, as long as it is a plug that can be switched
+async fn async_find_heater(alias: &str) -> Option<Device> {
+    let (addr, data) = async_find_by_alias(alias).await?;
+    match Device::from_data(addr, &data) {
+        device @ Device::HS100(_) | device @ Device::HS110(_) => Some(device),
+        _ => None,
+    }
+}
+
+fn find_device_by_alias(devices: &[(SocketAddr, String)], alias: &str) -> Option<SocketAddr> {
+    devices
+        .iter()
+        .find(|(_, device_alias)| device_alias == alias)
+        .map(|(addr, _)| *addr)
+}
+
+/// Run the heater for `duration`, acknowledging `command` as soon as it is on
+async fn async_run_heater(
+    device: Device,
+    sender: &Sender,
+    duration: time::Duration,
+    command: &Command,
+) {
+    async_switch(device.clone(), true).await;
+
+    sender
+        .send(Message::new_event(Event::HeaterStarted))
+        .unwrap_or_else(|_err| {
+            error!("Failed to write heater on event");
+            0
+        });
+    send_ack(sender, command);
+
+    if let Device::HS110(_) = device {
+        let deadline = time::Instant::now() + duration;
+        // a slow reading can run past the deadline, so stop as soon as no time is left
+        while time_left(deadline, time::Instant::now()).is_some() {
+            if let Some(watts) = async_power_reading(device.clone()).await {
+                sender
+                    .send(Message::new_event(Event::PowerReading { watts }))
+                    .unwrap_or_else(|_err| {
+                        error!("Failed to write power reading event");
+                        0
+                    });
+            }
+            if let Some(remaining) = time_left(deadline, time::Instant::now()) {
+                tokio::time::delay_for(POWER_READING_INTERVAL.min(remaining)).await;
             }
         }
+    } else {
+        tokio::time::delay_for(duration).await;
     }
-    None
+
+    async_switch(device, false).await;
+
+    sender
+        .send(Message::new_event(Event::HeaterStopped))
+        .unwrap_or_else(|_err| {
+            error!("Failed to write heater off event");
+            0
+        });
 }
 
-async fn async_run_heater(device: Device, sender: &Sender) {
-    if let Device::HS100(inner) = device {
-        let inner1 = inner.clone();
-        tokio::task::spawn_blocking(move || {
-            inner1
-                .switch_on()
-                .unwrap_or_else(|_err| error!("Failed to switch heater on"));
-        })
-        .await
-        .unwrap_or_else(|_| error!("Failed to spawn tplink switch heater on"));
-
-        sender
-            .send(Message::new_event(Event::HeaterStarted))
-            .unwrap_or_else(|_err| {
-                error!("Failed to write heater on event");
-                0
-            });
+/// How long is left until `deadline`, `None` once it has passed
+fn time_left(deadline: time::Instant, now: time::Instant) -> Option<time::Duration> {
+    deadline
+        .checked_duration_since(now)
+        .filter(|remaining| *remaining > time::Duration::from_secs(0))
+}
+
+async fn async_stop_header(device: Device, sender: &Sender) {
+    async_switch(device, false).await;
 
-        tokio::time::delay_for(HEATER_ON_TIME).await;
+    sender
+        .send(Message::new_event(Event::HeaterStopped))
+        .unwrap_or_else(|_err| {
+            error!("Failed to write heater off event");
+            0
+        });
+}
 
-        tokio::task::spawn_blocking(move || {
-            inner
-                .switch_off()
-                .unwrap_or_else(|_err| error!("Failed to switch heater off"));
-        })
-        .await
-        .unwrap_or_else(|_| error!("Failed to spawn tplink switch heater off"));
-
-        sender
-            .send(Message::new_event(Event::HeaterStopped))
-            .unwrap_or_else(|_err| {
-                error!("Failed to write heater off event");
-                0
-            });
+async fn async_switch(device: Device, on: bool) {
+    tokio::task::spawn_blocking(move || {
+        let result = match (&device, on) {
+            (Device::HS100(inner), true) => inner.switch_on(),
+            (Device::HS100(inner), false) => inner.switch_off(),
+            (Device::HS110(inner), true) => inner.switch_on(),
+            (Device::HS110(inner), false) => inner.switch_off(),
+            _ => Ok(()),
+        };
+        result.unwrap_or_else(|_err| {
+            error!("Failed to switch heater {}", if on { "on" } else { "off" })
+        });
+    })
+    .await
+    .unwrap_or_else(|_| error!("Failed to spawn tplink switch heater"));
+}
+
+async fn async_power_reading(device: Device) -> Option<f32> {
+    tokio::task::spawn_blocking(move || match device {
+        Device::HS110(inner) => match inner.get_emeter_realtime() {
+            Ok(response) => parse_power_reading(&response),
+            Err(err) => {
+                error!("Failed to read heater power: {}", err);
+                None
+            }
+        },
+        _ => None,
+    })
+    .await
+    .unwrap_or_else(|_| {
+        error!("Failed to spawn tplink power reading");
+        None
+    })
+}
+
+/// Get the power draw in watts from an emeter realtime response
+///
+/// Older hardware reports `power` in watts while newer hardware reports `power_mw` in milliwatts.
+#[allow(clippy::cast_possible_truncation)]
+fn parse_power_reading(response: &Value) -> Option<f32> {
+    let realtime = &response["emeter"]["get_realtime"];
+
+    if let Some(watts) = realtime["power"].as_f64() {
+        Some(watts as f32)
+    } else {
+        realtime["power_mw"]
+            .as_f64()
+            .map(|milliwatts| (milliwatts / 1000.0) as f32)
     }
 }
 
-async fn async_stop_header(device: Device, sender: &Sender) {
-    if let Device::HS100(inner) = device {
-        tokio::task::spawn_blocking(move || {
-            inner
-                .switch_off()
-                .unwrap_or_else(|_err| error!("Failed to switch heater off"));
-        })
-        .await
-        .unwrap_or_else(|_| error!("Failed to spawn tplink switch heater off"));
-
-        sender
-            .send(Message::new_event(Event::HeaterStopped))
-            .unwrap_or_else(|_err| {
-                error!("Failed to write heater off event");
-                0
-            });
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn devices() -> Vec<(SocketAddr, String)> {
+        vec![
+            ("192.168.1.10:9999".parse().unwrap(), "Lamp".to_string()),
+            ("192.168.1.11:9999".parse().unwrap(), "Radiator".to_string()),
+        ]
+    }
+
+    #[test]
+    fn find_device_by_alias_matches() {
+        // act
+        let addr = find_device_by_alias(&devices(), "Radiator");
+
+        // assert
+        assert_eq!(addr, Some("192.168.1.11:9999".parse().unwrap()));
+    }
+
+    #[test]
+    fn find_device_by_alias_missing() {
+        // act
+        let addr = find_device_by_alias(&devices(), "Heater");
+
+        // assert
+        assert_eq!(addr, None);
+    }
+
+    #[test]
+    fn parse_power_reading_in_watts() {
+        // arrange
+        let response = serde_json::json!({"emeter": {"get_realtime": {
+            "current": 6.2, "voltage": 240.1, "power": 1496.3, "total": 12.4, "err_code": 0
+        }}});
+
+        // act
+        let watts = parse_power_reading(&response);
+
+        // assert
+        assert_eq!(watts, Some(1496.3));
+    }
+
+    #[test]
+    fn parse_power_reading_in_milliwatts() {
+        // arrange
+        let response = serde_json::json!({"emeter": {"get_realtime": {
+            "current_ma": 6200, "voltage_mv": 240100, "power_mw": 1496300, "total_wh": 12400,
+            "err_code": 0
+        }}});
+
+        // act
+        let watts = parse_power_reading(&response);
+
+        // assert
+        assert_eq!(watts, Some(1496.3));
+    }
+
+    #[test]
+    fn parse_power_reading_error() {
+        // arrange
+        let response =
+            serde_json::json!({"emeter": {"err_code": -1, "err_msg": "module not support"}});
+
+        // act
+        let watts = parse_power_reading(&response);
+
+        // assert
+        assert_eq!(watts, None);
+    }
+
+    /// Discovery data for a plug with the given relay state
+    fn plug_data(relay_state: u8) -> DeviceData {
+        serde_json::from_value(serde_json::json!({"system": {"get_sysinfo": {
+            "sw_ver": "1.5.8", "hw_ver": "2.1", "type": "IOT.SMARTPLUGSWITCH",
+            "model": "HS100(UK)", "mac": "00:00:00:00:00:00", "deviceId": "0", "hwId": "0",
+            "oemId": "0", "alias": "Heater", "err_code": 0, "rssi": -53,
+            "relay_state": relay_state
+        }}}))
+        .unwrap()
+    }
+
+    #[test]
+    fn heater_guard_rejects_a_second_cycle() {
+        // arrange
+        let guard = HeaterGuard::default();
+        let first = guard.try_start();
+
+        // act
+        let second = guard.clone().try_start();
+
+        // assert
+        assert!(first.is_some());
+        assert!(second.is_none());
+    }
+
+    #[test]
+    fn heater_guard_is_free_once_the_cycle_ends() {
+        // arrange
+        let guard = HeaterGuard::default();
+        drop(guard.try_start());
+
+        // act
+        let next = guard.try_start();
+
+        // assert
+        assert!(next.is_some());
+    }
+
+    #[test]
+    fn heater_run_is_accepted_again_right_after_a_stop() {
+        // arrange
+        let guard = HeaterGuard::default();
+        let mut first = guard.try_start().unwrap();
+
+        // act
+        let stopped = guard.stop();
+        let second = guard.try_start();
+
+        // assert
+        assert!(stopped);
+        assert_eq!(first.cancelled.try_recv(), Ok(()));
+        assert!(second.is_some());
+    }
+
+    #[test]
+    fn a_stopped_cycle_does_not_free_the_next_one() {
+        // arrange
+        let guard = HeaterGuard::default();
+        let first = guard.try_start().unwrap();
+        guard.stop();
+        let _second = guard.try_start().unwrap();
+
+        // act
+        drop(first);
+
+        // assert
+        assert!(guard.try_start().is_none());
+    }
+
+    #[test]
+    fn stop_without_a_cycle() {
+        assert!(!HeaterGuard::default().stop());
+    }
+
+    #[test]
+    fn heater_duration_for_run_commands() {
+        assert_eq!(heater_duration(&Command::RunHeater), HEATER_ON_TIME);
+        assert_eq!(
+            heater_duration(&Command::RunHeaterFor(300)),
+            time::Duration::from_secs(300)
+        );
+    }
+
+    #[test]
+    fn heater_duration_is_clamped() {
+        assert_eq!(
+            heater_duration(&Command::RunHeaterFor(u64::MAX)),
+            time::Duration::from_secs(MAX_HEATER_SECONDS)
+        );
+        assert_eq!(
+            heater_duration(&Command::RunHeaterFor(86_400)),
+            time::Duration::from_secs(MAX_HEATER_SECONDS)
+        );
+    }
+
+    #[test]
+    fn time_left_before_the_deadline() {
+        // arrange
+        let now = time::Instant::now();
+
+        // act
+        let remaining = time_left(now + time::Duration::from_secs(5), now);
+
+        // assert
+        assert_eq!(remaining, Some(time::Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn no_time_left_at_or_past_the_deadline() {
+        // arrange
+        let deadline = time::Instant::now();
+
+        // assert
+        assert_eq!(time_left(deadline, deadline), None);
+        assert_eq!(
+            time_left(deadline, deadline + time::Duration::from_secs(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn heater_state_from_relay_state() {
+        assert_eq!(heater_state(&plug_data(1)), Some(true));
+        assert_eq!(heater_state(&plug_data(0)), Some(false));
+    }
+
+    struct MockDiscovery(Option<bool>);
+
+    #[async_trait]
+    impl HeaterDiscovery for MockDiscovery {
+        async fn heater_state(&self, _alias: &str) -> Option<bool> {
+            self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn heater_poll_reports_the_state_each_interval() {
+        // arrange
+        let (tx, mut rx) = tokio::sync::broadcast::channel(10);
+
+        // act
+        let poll = spawn_heater_poll(
+            tx,
+            MockDiscovery(Some(true)),
+            "Heater".to_string(),
+            time::Duration::from_millis(10),
+        );
+
+        // assert
+        assert!(poll.is_some());
+        for _ in 0..2 {
+            let message = tokio::time::timeout(time::Duration::from_secs(1), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(*message.payload(), Payload::Event(Event::HeaterState(true)));
+        }
+    }
+
+    #[tokio::test]
+    async fn heater_poll_is_off_with_zero_interval() {
+        // arrange
+        let (tx, _rx) = tokio::sync::broadcast::channel(10);
+
+        // act
+        let poll = spawn_heater_poll(
+            tx,
+            MockDiscovery(Some(true)),
+            "Heater".to_string(),
+            time::Duration::from_secs(0),
+        );
+
+        // assert
+        assert!(poll.is_none());
     }
 }
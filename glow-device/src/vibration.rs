@@ -1,30 +1,102 @@
-use std::{thread, time};
+use std::{env, thread, time};
 
 use log::{error, info};
 use rppal::gpio::{Gpio, Trigger};
+use tokio::time::timeout;
 
 use crate::events::Sender;
 use glow_events::v2::{Event, Message};
 
 const INTERRUPT_PIN: u8 = 17;
-const INTERRUPT_BOUNCE: u128 = 300;
+
+/// How long after a counted interrupt any more are ignored as the sensor settles
+///
+/// Override with `VIBRATION_BOUNCE_MS`.
+const DEFAULT_INTERRUPT_BOUNCE: time::Duration = time::Duration::from_millis(300);
+
+/// How long to wait after a tap for another before deciding on the gesture
+///
+/// Override with `TAP_WINDOW_MS`.
+const DEFAULT_TAP_WINDOW: time::Duration = time::Duration::from_millis(500);
 
 pub async fn handler(tx: Sender) {
+    let window = env::var("TAP_WINDOW_MS")
+        .map(|window| {
+            time::Duration::from_millis(window.parse().expect("TAP_WINDOW_MS must be a number"))
+        })
+        .unwrap_or(DEFAULT_TAP_WINDOW);
+    let bounce = env::var("VIBRATION_BOUNCE_MS")
+        .map(|bounce| {
+            time::Duration::from_millis(
+                bounce
+                    .parse()
+                    .expect("VIBRATION_BOUNCE_MS must be a number"),
+            )
+        })
+        .unwrap_or(DEFAULT_INTERRUPT_BOUNCE);
     let (interrupt_sender, mut interrupt_receiver) = tokio::sync::mpsc::channel(5);
 
     thread::spawn(move || {
-        run_worker(interrupt_sender);
+        run_worker(interrupt_sender, bounce);
     });
 
-    while interrupt_receiver.recv().await.is_some() {
-        tx.send(Message::new_event(Event::SingleTap))
-            .expect("Failed to write tap event");
+    while let Some(tap) = interrupt_receiver.recv().await {
+        let mut taps = vec![tap];
+        while let Ok(Some(tap)) = timeout(window, interrupt_receiver.recv()).await {
+            taps.push(tap);
+        }
+
+        for event in classify_taps(&taps, window) {
+            tx.send(Message::new_event(event))
+                .expect("Failed to write tap event");
+        }
+    }
+}
+
+/// Turn a sequence of tap times into gestures
+///
+/// Taps are grouped into bursts where each tap follows the previous one within `window`,
+/// a burst of one tap is a single tap, two is a double tap and three or more is a triple tap.
+fn classify_taps(taps: &[time::Instant], window: time::Duration) -> Vec<Event> {
+    let mut events = vec![];
+    let mut burst = 0;
+    let mut previous: Option<time::Instant> = None;
+
+    for &tap in taps {
+        if let Some(previous) = previous {
+            if tap.duration_since(previous) > window {
+                events.push(gesture(burst));
+                burst = 0;
+            }
+        }
+        burst += 1;
+        previous = Some(tap);
+    }
+    if burst > 0 {
+        events.push(gesture(burst));
+    }
+
+    events
+}
+
+/// Whether an interrupt counts as a tap rather than the sensor still bouncing from the last
+///
+/// It counts once more than `bounce` has passed since the last counted interrupt.
+fn is_new_tap(last_tap: time::Instant, now: time::Instant, bounce: time::Duration) -> bool {
+    now.saturating_duration_since(last_tap) > bounce
+}
+
+fn gesture(taps: usize) -> Event {
+    match taps {
+        1 => Event::SingleTap,
+        2 => Event::DoubleTap,
+        _ => Event::TripleTap,
     }
 }
 
-type InterruptSender = tokio::sync::mpsc::Sender<()>;
+type InterruptSender = tokio::sync::mpsc::Sender<time::Instant>;
 
-fn run_worker(mut interrupts: InterruptSender) {
+fn run_worker(mut interrupts: InterruptSender, bounce: time::Duration) {
     let gpio = Gpio::new().unwrap();
     let mut pin = gpio.get(INTERRUPT_PIN).unwrap().into_input_pullup();
     pin.set_interrupt(Trigger::FallingEdge).unwrap();
@@ -33,9 +105,10 @@ fn run_worker(mut interrupts: InterruptSender) {
     loop {
         match pin.poll_interrupt(true, None) {
             Ok(Some(_)) => {
-                if last_event.elapsed().as_millis() > INTERRUPT_BOUNCE {
-                    last_event = time::Instant::now();
-                    if let Err(err) = interrupts.try_send(()) {
+                let now = time::Instant::now();
+                if is_new_tap(last_event, now, bounce) {
+                    last_event = now;
+                    if let Err(err) = interrupts.try_send(last_event) {
                         error!("Failed to write tap event to channel: {:?}", err);
                     }
                 }
@@ -49,3 +122,86 @@ fn run_worker(mut interrupts: InterruptSender) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn taps_at(offsets: &[u64]) -> Vec<time::Instant> {
+        let start = time::Instant::now();
+        offsets
+            .iter()
+            .map(|offset| start + time::Duration::from_millis(*offset))
+            .collect()
+    }
+
+    #[test]
+    fn classify_taps_table() {
+        let cases: &[(&[u64], &[Event])] = &[
+            (&[], &[]),
+            (&[0], &[Event::SingleTap]),
+            (&[0, 400], &[Event::DoubleTap]),
+            (&[0, 400, 800], &[Event::TripleTap]),
+            (&[0, 400, 800, 1200], &[Event::TripleTap]),
+            (&[0, 600], &[Event::SingleTap, Event::SingleTap]),
+            (&[0, 400, 1000], &[Event::DoubleTap, Event::SingleTap]),
+            (&[0, 600, 1000, 1400], &[Event::SingleTap, Event::TripleTap]),
+            (&[0, 500], &[Event::DoubleTap]),
+            (&[0, 501], &[Event::SingleTap, Event::SingleTap]),
+        ];
+
+        for (offsets, expected) in cases {
+            // act
+            let events = classify_taps(&taps_at(offsets), DEFAULT_TAP_WINDOW);
+
+            // assert
+            assert_eq!(&events, expected, "taps at {:?}", offsets);
+        }
+    }
+
+    #[test]
+    fn is_new_tap_at_the_bounce_boundary() {
+        // arrange
+        let last_tap = time::Instant::now();
+        let bounce = time::Duration::from_millis(300);
+        let after = |millis| last_tap + time::Duration::from_millis(millis);
+
+        // assert
+        assert!(!is_new_tap(last_tap, last_tap, bounce));
+        assert!(!is_new_tap(last_tap, after(299), bounce));
+        assert!(!is_new_tap(last_tap, after(300), bounce));
+        assert!(is_new_tap(last_tap, after(301), bounce));
+    }
+
+    #[test]
+    fn is_new_tap_with_a_longer_bounce() {
+        // arrange
+        let last_tap = time::Instant::now();
+        let bounce = time::Duration::from_millis(800);
+
+        // assert
+        assert!(!is_new_tap(
+            last_tap,
+            last_tap + time::Duration::from_millis(500),
+            bounce
+        ));
+        assert!(is_new_tap(
+            last_tap,
+            last_tap + time::Duration::from_millis(801),
+            bounce
+        ));
+    }
+
+    #[test]
+    fn is_new_tap_ignores_clocks_going_backwards() {
+        // arrange
+        let last_tap = time::Instant::now() + time::Duration::from_secs(1);
+
+        // assert
+        assert!(!is_new_tap(
+            last_tap,
+            time::Instant::now(),
+            DEFAULT_INTERRUPT_BOUNCE
+        ));
+    }
+}
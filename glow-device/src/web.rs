@@ -1,46 +1,260 @@
-use reqwest::Client;
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use crate::events::{Handler, Receiver, Sender};
+use async_trait::async_trait;
+use flate2::{write::GzEncoder, Compression};
+use futures::{future::select, StreamExt};
 use log::{error, info};
+use reqwest::{header, Client, ClientBuilder, Response, StatusCode, Url};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::broadcast::{RecvError, TryRecvError},
+    time::delay_for,
+};
+use tokio_rustls::{rustls::ClientConfig, webpki::DNSNameRef, TlsConnector};
+use tokio_tungstenite::{
+    client_async,
+    tungstenite::{self, http::Request},
+    WebSocketStream,
+};
 
-use async_trait::async_trait;
-use glow_events::v2::Message;
-use std::time::Duration;
-use tokio::time::delay_for;
+use crate::events::{Handler, Receiver, Sender};
+use glow_events::v2::{Command, Event, Message, Payload, CBOR_CONTENT_TYPE, DEVICE_HEADER};
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
+const SOCKET_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+pub const DEFAULT_MAX_BATCH: usize = 100;
+
+/// How long to wait between polls with events to send, with nothing to send and with
+/// commands arriving over the socket instead
+const BUSY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const SOCKET_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// The longest wait between polls while `glow-web` cannot be reached
+const MAX_POLL_BACKOFF: Duration = Duration::from_secs(60);
+/// Unsent events kept for when `glow-web` comes back, the oldest are dropped beyond this
+const MAX_BUFFERED_EVENTS: usize = 10_000;
+
+const SEND_ATTEMPTS: u32 = 5;
+/// Delay before the first retry, doubling for each one after
+const SEND_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the last send before stopping can take, well inside the runner's shutdown grace
+const STOP_SEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Batches bigger than this are gzipped, smaller ones are not worth the CPU on a Pi
+const GZIP_THRESHOLD_BYTES: usize = 8 * 1024;
+
+/// What to do after `glow-web` responds to a batch of events
+#[derive(Debug, PartialEq)]
+enum SendOutcome {
+    Sent,
+    /// The server is struggling or rate limiting, it may accept the events later
+    Retry,
+    /// The request itself is wrong, such as a bad token, so retrying cannot help
+    Abort,
+}
+
+fn send_outcome(status: StatusCode) -> SendOutcome {
+    if status.is_success() {
+        SendOutcome::Sent
+    } else if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+        SendOutcome::Abort
+    } else {
+        SendOutcome::Retry
+    }
+}
+
+/// What became of a batch of events
+#[derive(Debug, PartialEq)]
+enum Delivery {
+    /// `glow-web` took the events, with the commands it had queued
+    Sent(Vec<Message>),
+    /// `glow-web` could not be reached or could not take the events yet, keep them for later
+    Retry,
+    /// `glow-web` refused the events, sending them again would be refused too
+    Rejected,
+}
+
+/// How commands are received from `glow-web`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandMode {
+    /// Commands are returned in the response to posting events
+    Poll,
+    /// Commands are pushed over a websocket as soon as they are queued
+    ///
+    /// Events are still posted and polling carries on, less often, so that commands
+    /// are still received if the socket drops.
+    WebSocket,
+}
+
+/// How messages are encoded when posted to `glow-web`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WireFormat {
+    Json,
+    /// CBOR is much smaller than JSON which matters over a metered connection
+    Cbor,
+}
+
+/// How the HTTP client used to post events behaves
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientSettings {
+    /// Give up if a connection cannot be made in this time
+    pub connect_timeout: Duration,
+    /// Give up on a whole request, including reading the response, after this time
+    pub request_timeout: Duration,
+    /// Appended to the user-agent, e.g. to tell apart Pis sharing one `glow-web`
+    pub user_agent_suffix: Option<String>,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            user_agent_suffix: None,
+        }
+    }
+}
+
+impl ClientSettings {
+    fn user_agent(&self) -> String {
+        match &self.user_agent_suffix {
+            Some(suffix) => format!("{} {}", APP_USER_AGENT, suffix),
+            None => APP_USER_AGENT.to_string(),
+        }
+    }
+}
+
+fn client_builder(settings: &ClientSettings) -> ClientBuilder {
+    Client::builder()
+        .user_agent(settings.user_agent())
+        .connect_timeout(settings.connect_timeout)
+        .timeout(settings.request_timeout)
+}
+
 pub struct WebHandler {
     url: String,
     token: String,
+    mode: CommandMode,
+    format: WireFormat,
+    device_id: Option<String>,
+    max_batch: usize,
+    client_settings: ClientSettings,
+    socket_connected: AtomicBool,
 }
 
 impl WebHandler {
     pub fn new(url: String, token: String) -> Self {
-        Self { url, token }
-    }
-
-    async fn send_messages(&self, client: &Client, messages: &[Message]) -> Option<Vec<Message>> {
-        let mut tries = 5;
-        while tries > 0 {
-            tries -= 1;
-            let resp = client
-                .post(&self.url)
-                .bearer_auth(&self.token)
-                .json(&serde_json::to_value(&messages).unwrap())
-                .send();
-            match resp.await {
-                Ok(resp) => {
-                    if let Ok(data) = resp.json().await {
-                        if let Ok(commands) = serde_json::from_value::<Vec<Message>>(data) {
-                            return Some(commands);
-                        } else {
-                            error!("received badly formatted json");
+        Self::with_mode(url, token, CommandMode::Poll)
+    }
+
+    pub fn with_mode(url: String, token: String, mode: CommandMode) -> Self {
+        Self {
+            url,
+            token,
+            mode,
+            format: WireFormat::Json,
+            device_id: None,
+            max_batch: DEFAULT_MAX_BATCH,
+            client_settings: ClientSettings::default(),
+            socket_connected: AtomicBool::new(false),
+        }
+    }
+
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Send at most `max_batch` events in each request
+    pub fn with_max_batch(mut self, max_batch: usize) -> Self {
+        self.max_batch = max_batch;
+        self
+    }
+
+    pub fn with_client_settings(mut self, client_settings: ClientSettings) -> Self {
+        self.client_settings = client_settings;
+        self
+    }
+
+    /// Identify this device to a server shared with other devices
+    pub fn with_device_id(mut self, device_id: Option<String>) -> Self {
+        self.device_id = device_id;
+        self
+    }
+
+    async fn send_messages(
+        &self,
+        client: &Client,
+        messages: &[Message],
+        stopping: bool,
+    ) -> Delivery {
+        // a stopping device cannot wait out retries or the usual request timeout
+        let attempts = if stopping { 1 } else { SEND_ATTEMPTS };
+        let mut backoff = SEND_RETRY_DELAY;
+        for attempt in 1..=attempts {
+            if attempt > 1 {
+                delay_for(backoff).await;
+                backoff *= 2;
+            }
+            let mut request = client.post(&self.url).bearer_auth(&self.token);
+            if stopping {
+                request = request.timeout(STOP_SEND_TIMEOUT);
+            }
+            if let Some(device_id) = &self.device_id {
+                request = request.header(DEVICE_HEADER, device_id);
+            }
+            let (request, body) = match self.format {
+                WireFormat::Json => (
+                    request.header(header::CONTENT_TYPE, "application/json"),
+                    serde_json::to_vec(messages).unwrap(),
+                ),
+                WireFormat::Cbor => (
+                    request
+                        .header(header::CONTENT_TYPE, CBOR_CONTENT_TYPE)
+                        .header(header::ACCEPT, CBOR_CONTENT_TYPE),
+                    Message::batch_to_cbor(messages).unwrap(),
+                ),
+            };
+            let request = match gzip_if_large(body, GZIP_THRESHOLD_BYTES) {
+                (body, true) => request.header(header::CONTENT_ENCODING, "gzip").body(body),
+                (body, false) => request.body(body),
+            };
+            match request.send().await {
+                Ok(resp) => match send_outcome(resp.status()) {
+                    SendOutcome::Sent => {
+                        if let Some(commands) = read_commands(resp).await {
+                            return Delivery::Sent(commands);
                         }
-                    } else {
-                        error!("received invalid json");
                     }
-                }
+                    SendOutcome::Retry => {
+                        error!(
+                            "Failed to send {} events: server responded {}",
+                            messages.len(),
+                            resp.status()
+                        );
+                    }
+                    SendOutcome::Abort => {
+                        error!(
+                            "Not retrying {} events: server rejected them with {}, check WEB_EVENT_URL and WEB_EVENT_TOKEN",
+                            messages.len(),
+                            resp.status()
+                        );
+                        return Delivery::Rejected;
+                    }
+                },
                 Err(err) => {
                     error!("Failed to send {} events: {}", messages.len(), err);
                 }
@@ -48,47 +262,785 @@ impl WebHandler {
         }
         error!("Failed all attempts at sending events");
 
-        None
+        Delivery::Retry
+    }
+
+    async fn poll(&self, tx: Sender) {
+        let client = HttpEventClient {
+            handler: self,
+            client: client_builder(&self.client_settings).build().unwrap(),
+        };
+        poll_events(&client, tx, &self.socket_connected, self.max_batch).await;
+    }
+
+    /// Keep a command socket open, reconnecting whenever it drops
+    async fn listen(&self, tx: Sender) {
+        let url = match socket_url(&self.url) {
+            Ok(url) => url,
+            Err(err) => {
+                error!(
+                    "Cannot open command socket, falling back to polling: {}",
+                    err
+                );
+                return;
+            }
+        };
+
+        loop {
+            if let Err(err) = self.connect_and_listen(&url, &tx).await {
+                error!("Command socket failed: {}", err);
+            }
+            self.socket_connected.store(false, Ordering::SeqCst);
+            delay_for(SOCKET_RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn connect_and_listen(&self, url: &Url, tx: &Sender) -> Result<(), String> {
+        let host = url.host_str().ok_or("socket url has no host")?;
+        let port = url
+            .port_or_known_default()
+            .ok_or("socket url has no port")?;
+        let mut request = Request::builder()
+            .uri(url.as_str())
+            .header("Authorization", format!("Bearer {}", self.token))
+            .header("User-Agent", self.client_settings.user_agent());
+        if let Some(device_id) = &self.device_id {
+            request = request.header(DEVICE_HEADER, device_id.as_str());
+        }
+        let request = request
+            .body(())
+            .map_err(|err| format!("invalid socket request: {}", err))?;
+
+        let stream = TcpStream::connect((host, port))
+            .await
+            .map_err(|err| format!("failed to connect to {}: {}", url, err))?;
+
+        if url.scheme() == "wss" {
+            let mut config = ClientConfig::new();
+            config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+            let domain = DNSNameRef::try_from_ascii_str(host)
+                .map_err(|err| format!("invalid host {}: {}", host, err))?;
+            let stream = TlsConnector::from(Arc::new(config))
+                .connect(domain, stream)
+                .await
+                .map_err(|err| format!("TLS handshake failed: {}", err))?;
+            self.forward_commands(handshake(request, stream).await?, tx)
+                .await
+        } else {
+            self.forward_commands(handshake(request, stream).await?, tx)
+                .await
+        }
+    }
+
+    async fn forward_commands<S>(
+        &self,
+        mut socket: WebSocketStream<S>,
+        tx: &Sender,
+    ) -> Result<(), String>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        info!("Command socket connected");
+        self.socket_connected.store(true, Ordering::SeqCst);
+
+        while let Some(message) = socket.next().await {
+            match message.map_err(|err| format!("failed to read from socket: {}", err))? {
+                tungstenite::Message::Text(text) => match serde_json::from_str::<Message>(&text) {
+                    Ok(command) => {
+                        info!("received command from socket");
+                        if let Err(err) = tx.send(command) {
+                            error!("failed to send remote command to bus {:?}", err);
+                        }
+                    }
+                    Err(err) => error!("received badly formatted command: {}", err),
+                },
+                tungstenite::Message::Close(_) => break,
+                _ => {}
+            }
+        }
+
+        info!("Command socket closed");
+        Ok(())
     }
 }
 
 #[async_trait]
 impl Handler for WebHandler {
     async fn run(&self, tx: Sender) {
-        let client = Client::builder()
-            .user_agent(APP_USER_AGENT)
-            .build()
-            .unwrap();
-        let mut rx = tx.subscribe();
-        loop {
-            // try_recv to get all pending events
-            let messages = get_messages_from_queue(&mut rx);
-            let mut no_messages = messages.is_empty();
+        match self.mode {
+            CommandMode::Poll => self.poll(tx).await,
+            CommandMode::WebSocket => {
+                // polling only stops once the last events have been sent
+                let poll = self.poll(tx.clone());
+                let listen = self.listen(tx);
+                futures::pin_mut!(poll, listen);
+                select(poll, listen).await;
+            }
+        }
+    }
+}
 
-            let commands = self.send_messages(&client, &messages).await;
+/// Gzip a request body bigger than `threshold`, returning the body and whether it was gzipped
+fn gzip_if_large(body: Vec<u8>, threshold: usize) -> (Vec<u8>, bool) {
+    if body.len() <= threshold {
+        return (body, false);
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    match encoder.write_all(&body).and_then(|_| encoder.finish()) {
+        Ok(compressed) => (compressed, true),
+        Err(err) => {
+            error!("Failed to gzip events, sending them uncompressed: {}", err);
+            (body, false)
+        }
+    }
+}
+
+/// Posts a batch of events to `glow-web` and returns any queued commands
+#[async_trait]
+trait EventClient: Sync {
+    /// Send `messages`, with a single quick attempt if the device is `stopping`
+    async fn send_messages(&self, messages: &[Message], stopping: bool) -> Delivery;
+}
+
+struct HttpEventClient<'a> {
+    handler: &'a WebHandler,
+    client: Client,
+}
+
+#[async_trait]
+impl EventClient for HttpEventClient<'_> {
+    async fn send_messages(&self, messages: &[Message], stopping: bool) -> Delivery {
+        self.handler
+            .send_messages(&self.client, messages, stopping)
+            .await
+    }
+}
 
-            if let Some(commands) = commands {
-                no_messages = no_messages && commands.is_empty();
-                if !commands.is_empty() {
-                    info!("received {} commands from remote", commands.len());
+/// Send events from the bus to `glow-web` and put the commands that come back on the bus
+///
+/// Events are buffered between polls and kept while `glow-web` cannot be reached, polling
+/// less often the longer it is away. A stop command or the bus closing cuts the wait short and
+/// the buffer is sent one last time before returning so that no events are lost on shutdown.
+/// That last send is a single attempt with a short timeout so it fits in the shutdown grace.
+async fn poll_events(
+    client: &impl EventClient,
+    tx: Sender,
+    socket_connected: &AtomicBool,
+    max_batch: usize,
+) {
+    let mut rx = tx.subscribe();
+    let mut messages = vec![];
+    // rounds in a row where some events could not be sent
+    let mut failures: u32 = 0;
+    loop {
+        // try_recv to get all pending events
+        let open = get_messages_from_queue(&mut rx, &mut messages);
+        let stopping = !open || messages.iter().any(is_stop);
+        let self_test = messages
+            .iter()
+            .any(|message| *message.payload() == Payload::Command(Command::SelfTest));
+        let mut no_messages = messages.is_empty();
+
+        let Round {
+            commands,
+            unsent,
+            retry,
+        } = send_batches(client, &messages, max_batch, stopping).await;
+        failures = if retry { failures.saturating_add(1) } else { 0 };
+        messages = unsent;
+        if messages.len() > MAX_BUFFERED_EVENTS {
+            let dropped = messages.len() - MAX_BUFFERED_EVENTS;
+            error!("dropping {} unsent events", dropped);
+            messages.drain(..dropped);
+        }
+
+        if self_test {
+            let result = Message::new_event(Event::SelfTestResult {
+                component: "web".to_string(),
+                passed: commands.is_some(),
+            });
+            if let Err(err) = tx.send(result) {
+                error!("failed to send self test result to bus {:?}", err);
+            }
+        }
+
+        if stopping {
+            info!("sent remaining events before stopping");
+            return;
+        }
+
+        if let Some(commands) = commands {
+            no_messages = no_messages && commands.is_empty();
+            if !commands.is_empty() {
+                info!("received {} commands from remote", commands.len());
+            }
+            for command in commands {
+                if let Err(err) = tx.send(command) {
+                    error!("failed to send remote error to bus {:?}", err);
                 }
-                for command in commands {
-                    if let Err(err) = tx.send(command) {
-                        error!("failed to send remote error to bus {:?}", err);
+            }
+        }
+
+        let sleep = poll_interval(
+            !no_messages,
+            socket_connected.load(Ordering::SeqCst),
+            failures,
+        );
+        let delay = delay_for(sleep);
+        tokio::pin!(delay);
+        loop {
+            tokio::select! {
+                _ = &mut delay => break,
+                message = rx.recv() => match message {
+                    Ok(message) => {
+                        let stop = is_stop(&message);
+                        messages.push(message);
+                        if stop {
+                            break;
+                        }
                     }
-                }
+                    Err(RecvError::Lagged(missed)) => error!("missed {} events", missed),
+                    // send what is buffered before returning
+                    Err(RecvError::Closed) => break,
+                },
             }
+        }
+    }
+}
+
+/// What came of sending the buffered events
+struct Round {
+    /// Commands from `glow-web`, `None` if no batch got through
+    commands: Option<Vec<Message>>,
+    /// Events to keep and send again next round
+    unsent: Vec<Message>,
+    /// Whether any batch is worth retrying, which backs off polling
+    retry: bool,
+}
 
-            let sleep = if no_messages { 5 } else { 1 };
-            delay_for(Duration::from_secs(sleep)).await;
+/// Send `messages` in batches of at most `max_batch`
+///
+/// Batches `glow-web` rejects are dropped rather than kept, they would only be rejected again.
+async fn send_batches(
+    client: &impl EventClient,
+    messages: &[Message],
+    max_batch: usize,
+    stopping: bool,
+) -> Round {
+    let mut round = Round {
+        commands: None,
+        unsent: vec![],
+        retry: false,
+    };
+    for batch in batches(messages, max_batch) {
+        match client.send_messages(batch, stopping).await {
+            Delivery::Sent(batch_commands) => {
+                round
+                    .commands
+                    .get_or_insert_with(Vec::new)
+                    .extend(batch_commands);
+            }
+            Delivery::Retry => {
+                round.unsent.extend_from_slice(batch);
+                round.retry = true;
+            }
+            Delivery::Rejected => error!("dropping {} rejected events", batch.len()),
         }
     }
+    round
 }
 
-fn get_messages_from_queue(rx: &mut Receiver) -> Vec<Message> {
-    let mut messages = vec![];
-    while let Ok(message) = rx.try_recv() {
-        messages.push(message);
+/// How long to wait before the next poll
+///
+/// After `failures` rounds in a row that could not reach `glow-web` the wait doubles from
+/// the idle interval up to `MAX_POLL_BACKOFF`.
+fn poll_interval(busy: bool, socket_connected: bool, failures: u32) -> Duration {
+    if failures > 0 {
+        2u32.checked_pow(failures)
+            .and_then(|factor| IDLE_POLL_INTERVAL.checked_mul(factor))
+            .map_or(MAX_POLL_BACKOFF, |backoff| backoff.min(MAX_POLL_BACKOFF))
+    } else if busy {
+        BUSY_POLL_INTERVAL
+    } else if socket_connected {
+        SOCKET_POLL_INTERVAL
+    } else {
+        IDLE_POLL_INTERVAL
+    }
+}
+
+/// Split messages into requests of at most `max` so a backlog is not one huge request
+///
+/// There is always at least one batch, an empty one still collects queued commands.
+fn batches(messages: &[Message], max: usize) -> Vec<&[Message]> {
+    if messages.is_empty() {
+        vec![messages]
+    } else {
+        messages.chunks(max.max(1)).collect()
+    }
+}
+
+fn is_stop(message: &Message) -> bool {
+    matches!(message.payload(), Payload::Command(Command::Stop))
+}
+
+/// Read the commands from a response in whichever format the server chose
+async fn read_commands(resp: Response) -> Option<Vec<Message>> {
+    let is_cbor = matches!(
+        resp.headers().get(header::CONTENT_TYPE),
+        Some(content_type) if content_type == CBOR_CONTENT_TYPE
+    );
+
+    if is_cbor {
+        match resp.bytes().await {
+            Ok(data) => match Message::batch_from_cbor(&data) {
+                Ok(commands) => return Some(commands),
+                Err(err) => error!("received badly formatted cbor: {}", err),
+            },
+            Err(err) => error!("failed to read response: {}", err),
+        }
+    } else if let Ok(data) = resp.json().await {
+        if let Ok(commands) = serde_json::from_value::<Vec<Message>>(data) {
+            return Some(commands);
+        } else {
+            error!("received badly formatted json");
+        }
+    } else {
+        error!("received invalid json");
+    }
+
+    None
+}
+
+async fn handshake<S>(request: Request<()>, stream: S) -> Result<WebSocketStream<S>, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    client_async(request, stream)
+        .await
+        .map(|(socket, _)| socket)
+        .map_err(|err| format!("socket handshake failed: {}", err))
+}
+
+/// Derive the command socket url from the events url
+///
+/// `https://example.org/api/events` becomes `wss://example.org/api/ws`.
+fn socket_url(events_url: &str) -> Result<Url, String> {
+    let mut url = Url::parse(events_url).map_err(|err| err.to_string())?;
+    let scheme = match url.scheme() {
+        "https" => "wss",
+        "http" => "ws",
+        scheme => return Err(format!("unsupported scheme {}", scheme)),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| format!("could not set scheme to {}", scheme))?;
+    url.path_segments_mut()
+        .map_err(|_| "events url cannot be a base".to_string())?
+        .pop()
+        .push("ws");
+    Ok(url)
+}
+
+/// Move every message waiting on the bus into `messages`
+///
+/// If the receiver fell behind the oldest messages are gone; that is logged and the newer
+/// ones still taken. Returns `false` once the bus has closed and nothing more will arrive.
+fn get_messages_from_queue(rx: &mut Receiver, messages: &mut Vec<Message>) -> bool {
+    loop {
+        match rx.try_recv() {
+            Ok(message) => messages.push(message),
+            Err(TryRecvError::Lagged(missed)) => error!("missed {} events", missed),
+            Err(TryRecvError::Empty) => return true,
+            Err(TryRecvError::Closed) => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::{sync::broadcast::channel, time::timeout};
+
+    use super::*;
+
+    #[test]
+    fn user_agent_has_the_configured_suffix() {
+        // arrange
+        let settings = ClientSettings {
+            user_agent_suffix: Some("kitchen".to_string()),
+            ..ClientSettings::default()
+        };
+
+        // act
+        let user_agent = settings.user_agent();
+
+        // assert
+        assert_eq!(user_agent, format!("{} kitchen", APP_USER_AGENT));
+        assert_eq!(ClientSettings::default().user_agent(), APP_USER_AGENT);
+    }
+
+    #[test]
+    fn client_builder_applies_the_settings() {
+        // arrange
+        let settings = ClientSettings {
+            connect_timeout: Duration::from_secs(2),
+            request_timeout: Duration::from_secs(7),
+            user_agent_suffix: Some("kitchen".to_string()),
+        };
+
+        // act
+        let builder = client_builder(&settings);
+
+        // assert
+        // reqwest has no getters, its debug output is the only way to see the settings
+        let debug = format!("{:?}", builder);
+        assert!(debug.contains("connect_timeout: 2s"), "{}", debug);
+        assert!(debug.contains("timeout: 7s"), "{}", debug);
+        assert!(debug.contains(&settings.user_agent()), "{}", debug);
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        // arrange
+        let messages = (0..500)
+            .map(|_| {
+                Message::new_event(Event::Measurement(glow_events::Measurement::new(
+                    21.5, 40.0,
+                )))
+            })
+            .collect::<Vec<_>>();
+        let body = serde_json::to_vec(&messages).unwrap();
+
+        // act
+        let (compressed, gzipped) = gzip_if_large(body.clone(), GZIP_THRESHOLD_BYTES);
+
+        // assert
+        assert!(gzipped);
+        assert!(compressed.len() < body.len());
+        let decoded: Vec<Message> =
+            serde_json::from_reader(flate2::read::GzDecoder::new(&compressed[..])).unwrap();
+        assert_eq!(decoded, messages);
+    }
+
+    #[test]
+    fn gzip_leaves_small_bodies() {
+        // arrange
+        let body = serde_json::to_vec(&[Message::new_event(Event::SingleTap)]).unwrap();
+
+        // act
+        let (sent, gzipped) = gzip_if_large(body.clone(), GZIP_THRESHOLD_BYTES);
+
+        // assert
+        assert!(!gzipped);
+        assert_eq!(sent, body);
+    }
+
+    #[test]
+    fn send_outcome_by_status() {
+        let cases = [
+            (StatusCode::OK, SendOutcome::Sent),
+            (StatusCode::NO_CONTENT, SendOutcome::Sent),
+            (StatusCode::BAD_REQUEST, SendOutcome::Abort),
+            (StatusCode::UNAUTHORIZED, SendOutcome::Abort),
+            (StatusCode::FORBIDDEN, SendOutcome::Abort),
+            (StatusCode::NOT_FOUND, SendOutcome::Abort),
+            (StatusCode::TOO_MANY_REQUESTS, SendOutcome::Retry),
+            (StatusCode::INTERNAL_SERVER_ERROR, SendOutcome::Retry),
+            (StatusCode::BAD_GATEWAY, SendOutcome::Retry),
+            (StatusCode::SERVICE_UNAVAILABLE, SendOutcome::Retry),
+        ];
+        for (status, outcome) in &cases {
+            assert_eq!(send_outcome(*status), *outcome, "{}", status);
+        }
+    }
+
+    #[test]
+    fn poll_interval_without_failures() {
+        assert_eq!(poll_interval(true, false, 0), BUSY_POLL_INTERVAL);
+        assert_eq!(poll_interval(true, true, 0), BUSY_POLL_INTERVAL);
+        assert_eq!(poll_interval(false, true, 0), SOCKET_POLL_INTERVAL);
+        assert_eq!(poll_interval(false, false, 0), IDLE_POLL_INTERVAL);
+    }
+
+    #[test]
+    fn poll_interval_backs_off_up_to_the_cap() {
+        // act
+        let intervals = (1..=6)
+            .map(|failures| poll_interval(true, false, failures).as_secs())
+            .collect::<Vec<_>>();
+
+        // assert
+        assert_eq!(intervals, vec![10, 20, 40, 60, 60, 60]);
+        assert_eq!(poll_interval(false, true, u32::MAX), MAX_POLL_BACKOFF);
+    }
+
+    #[derive(Default)]
+    struct RecordingClient {
+        batches: Mutex<Vec<Vec<Message>>>,
+        /// Whether each batch was sent while stopping
+        stopping: Mutex<Vec<bool>>,
+    }
+
+    #[async_trait]
+    impl EventClient for RecordingClient {
+        async fn send_messages(&self, messages: &[Message], stopping: bool) -> Delivery {
+            self.batches.lock().unwrap().push(messages.to_vec());
+            self.stopping.lock().unwrap().push(stopping);
+            Delivery::Sent(vec![])
+        }
+    }
+
+    /// Rejects batches with a single tap and cannot reach the server for a double tap
+    struct FlakyClient;
+
+    #[async_trait]
+    impl EventClient for FlakyClient {
+        async fn send_messages(&self, messages: &[Message], _stopping: bool) -> Delivery {
+            let has = |event: Event| {
+                messages
+                    .iter()
+                    .any(|message| *message.payload() == Payload::Event(event.clone()))
+            };
+            if has(Event::SingleTap) {
+                Delivery::Rejected
+            } else if has(Event::DoubleTap) {
+                Delivery::Retry
+            } else {
+                Delivery::Sent(vec![])
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn send_batches_drops_rejected_events() {
+        // arrange
+        let messages = vec![
+            Message::new_event(Event::SingleTap),
+            Message::new_event(Event::TripleTap),
+        ];
+
+        // act
+        let round = send_batches(&FlakyClient, &messages, 1, false).await;
+
+        // assert
+        assert!(round.unsent.is_empty());
+        assert!(!round.retry);
+        assert_eq!(round.commands, Some(vec![]));
+    }
+
+    #[tokio::test]
+    async fn send_batches_keeps_events_to_retry() {
+        // arrange
+        let messages = vec![
+            Message::new_event(Event::SingleTap),
+            Message::new_event(Event::DoubleTap),
+        ];
+
+        // act
+        let round = send_batches(&FlakyClient, &messages, 1, false).await;
+
+        // assert
+        let unsent: Vec<&Payload> = round.unsent.iter().map(Message::payload).collect();
+        assert_eq!(unsent, vec![&Payload::Event(Event::DoubleTap)]);
+        assert!(round.retry);
+        assert_eq!(round.commands, None);
+    }
+
+    #[tokio::test]
+    async fn poll_events_flushes_buffered_events_on_stop() {
+        // arrange
+        let client = RecordingClient::default();
+        let (tx, _) = channel(20);
+        let connected = AtomicBool::new(false);
+        let events = async {
+            delay_for(Duration::from_millis(20)).await;
+            tx.send(Message::new_event(Event::SingleTap)).unwrap();
+            tx.send(Message::new_event(Event::DoubleTap)).unwrap();
+            tx.send(Message::new_command(Command::Stop)).unwrap();
+        };
+
+        // act
+        timeout(
+            Duration::from_secs(1),
+            futures::future::join(
+                poll_events(&client, tx.clone(), &connected, DEFAULT_MAX_BATCH),
+                events,
+            ),
+        )
+        .await
+        .expect("poll_events did not stop");
+
+        // assert
+        let batches = client.batches.lock().unwrap();
+        let last: Vec<&Payload> = batches
+            .last()
+            .unwrap()
+            .iter()
+            .map(Message::payload)
+            .collect();
+        assert_eq!(
+            last,
+            vec![
+                &Payload::Event(Event::SingleTap),
+                &Payload::Event(Event::DoubleTap),
+                &Payload::Command(Command::Stop),
+            ]
+        );
+        let stopping = client.stopping.lock().unwrap();
+        assert_eq!(stopping.last(), Some(&true));
+        assert!(!stopping[0], "sent as if stopping before the stop");
+    }
+
+    #[test]
+    fn socket_url_from_https_events_url() {
+        assert_eq!(
+            socket_url("https://example.org/api/events")
+                .unwrap()
+                .as_str(),
+            "wss://example.org/api/ws"
+        );
+    }
+
+    #[test]
+    fn socket_url_from_http_events_url() {
+        assert_eq!(
+            socket_url("http://127.0.0.1:8088/api/events")
+                .unwrap()
+                .as_str(),
+            "ws://127.0.0.1:8088/api/ws"
+        );
+    }
+
+    #[test]
+    fn socket_url_from_invalid_events_url() {
+        assert!(socket_url("ftp://example.org/api/events").is_err());
+        assert!(socket_url("not a url").is_err());
+    }
+
+    #[test]
+    fn get_messages_from_queue_skips_past_a_lag() {
+        // arrange
+        let (tx, mut rx) = channel(2);
+        for i in 0..5 {
+            tx.send(Message::new_event(Event::LEDBrightness(i as f32)))
+                .unwrap();
+        }
+        let mut messages = vec![];
+
+        // act
+        let open = get_messages_from_queue(&mut rx, &mut messages);
+
+        // assert
+        assert!(open);
+        let payloads: Vec<&Payload> = messages.iter().map(Message::payload).collect();
+        assert_eq!(
+            payloads,
+            vec![
+                &Payload::Event(Event::LEDBrightness(3.0)),
+                &Payload::Event(Event::LEDBrightness(4.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_messages_from_queue_reports_a_closed_bus() {
+        // arrange
+        let (tx, mut rx) = channel(2);
+        tx.send(Message::new_event(Event::SingleTap)).unwrap();
+        drop(tx);
+        let mut messages = vec![];
+
+        // act
+        let open = get_messages_from_queue(&mut rx, &mut messages);
+
+        // assert
+        assert!(!open);
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn poll_events_keeps_running_after_a_lag() {
+        // arrange
+        let client = RecordingClient::default();
+        let (tx, _) = channel(4);
+        let connected = AtomicBool::new(false);
+        let events = async {
+            delay_for(Duration::from_millis(20)).await;
+            // more than the channel holds before the handler gets a look in
+            for i in 0..10 {
+                tx.send(Message::new_event(Event::LEDBrightness(i as f32)))
+                    .unwrap();
+            }
+            delay_for(Duration::from_millis(20)).await;
+            tx.send(Message::new_event(Event::SingleTap)).unwrap();
+            tx.send(Message::new_command(Command::Stop)).unwrap();
+        };
+
+        // act
+        timeout(
+            Duration::from_secs(1),
+            futures::future::join(
+                poll_events(&client, tx.clone(), &connected, DEFAULT_MAX_BATCH),
+                events,
+            ),
+        )
+        .await
+        .expect("poll_events did not stop");
+
+        // assert
+        let batches = client.batches.lock().unwrap();
+        let sent: Vec<&Payload> = batches.iter().flatten().map(Message::payload).collect();
+        assert_eq!(
+            sent,
+            vec![
+                &Payload::Event(Event::LEDBrightness(6.0)),
+                &Payload::Event(Event::LEDBrightness(7.0)),
+                &Payload::Event(Event::LEDBrightness(8.0)),
+                &Payload::Event(Event::LEDBrightness(9.0)),
+                &Payload::Event(Event::SingleTap),
+                &Payload::Command(Command::Stop),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_events_splits_a_backlog_into_batches() {
+        // arrange
+        let client = RecordingClient::default();
+        let (tx, _) = channel(300);
+        let connected = AtomicBool::new(false);
+        let events = async {
+            delay_for(Duration::from_millis(20)).await;
+            for i in 0..250 {
+                tx.send(Message::new_event(Event::LEDBrightness(i as f32)))
+                    .unwrap();
+            }
+            tx.send(Message::new_command(Command::Stop)).unwrap();
+        };
+
+        // act
+        timeout(
+            Duration::from_secs(1),
+            futures::future::join(poll_events(&client, tx.clone(), &connected, 100), events),
+        )
+        .await
+        .expect("poll_events did not stop");
+
+        // assert
+        let batches = client.batches.lock().unwrap();
+        let sizes: Vec<usize> = batches.iter().skip(1).map(Vec::len).collect();
+        assert_eq!(sizes, vec![100, 100, 51]);
+        let sent: Vec<Payload> = batches
+            .iter()
+            .flatten()
+            .map(|message| message.payload().clone())
+            .collect();
+        let expected: Vec<Payload> = (0..250)
+            .map(|i| Payload::Event(Event::LEDBrightness(i as f32)))
+            .chain(std::iter::once(Payload::Command(Command::Stop)))
+            .collect();
+        assert_eq!(sent, expected);
     }
-    messages
 }
@@ -0,0 +1,117 @@
+//! Sending alerts to a webhook
+//!
+//! Alerts are edge triggered; a monitor tracks whether it was alarming on its last check and
+//! only sends an alert when that changes, so an outage raises one alert and one recovery.
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Result};
+use hyper::{Body, Client, Method, Request};
+use log::{error, info};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transition {
+    Raised,
+    Cleared,
+}
+
+/// Work out whether a change in alarm state needs an alert sending
+pub fn transition(was_alarming: bool, alarming: bool) -> Option<Transition> {
+    match (was_alarming, alarming) {
+        (false, true) => Some(Transition::Raised),
+        (true, false) => Some(Transition::Cleared),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Alert {
+    #[serde(rename = "alert")]
+    pub name: &'static str,
+    pub status: Transition,
+    pub message: String,
+    pub stamp: DateTime<Utc>,
+}
+
+impl Alert {
+    pub fn new(name: &'static str, status: Transition, message: String) -> Self {
+        Self {
+            name,
+            status,
+            message,
+            stamp: Utc::now(),
+        }
+    }
+}
+
+/// Where alerts are posted, alerts are only logged if no url is configured
+#[derive(Clone, Default)]
+pub struct Webhook {
+    url: Option<String>,
+}
+
+impl Webhook {
+    pub fn new(url: Option<String>) -> Self {
+        Self { url }
+    }
+
+    pub async fn send(self, alert: Alert) {
+        info!("{:?} alert {}: {}", alert.status, alert.name, alert.message);
+        if let Some(url) = &self.url {
+            if let Err(err) = post_json(url, None, &alert).await {
+                error!("failed to send {} alert: {}", alert.name, err);
+            }
+        }
+    }
+}
+
+/// Post a JSON body, authenticating with `bearer` if given
+pub(crate) async fn post_json(
+    url: &str,
+    bearer: Option<&str>,
+    body: &impl Serialize,
+) -> Result<()> {
+    let client: Client<_, Body> = Client::builder().build(hyper_rustls::HttpsConnector::new());
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("content-type", "application/json");
+    if let Some(token) = bearer {
+        request = request.header("authorization", format!("Bearer {}", token));
+    }
+    let request = request.body(Body::from(serde_json::to_vec(body)?))?;
+    let response = client.request(request).await?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(eyre!("webhook responded with {}", response.status()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transition_is_edge_triggered() {
+        assert_eq!(transition(false, false), None);
+        assert_eq!(transition(false, true), Some(Transition::Raised));
+        assert_eq!(transition(true, true), None);
+        assert_eq!(transition(true, false), Some(Transition::Cleared));
+    }
+
+    #[test]
+    fn serialize_an_alert() {
+        // arrange
+        let alert = Alert::new("device-silent", Transition::Raised, "gone".to_string());
+
+        // act
+        let alert = serde_json::to_value(&alert).unwrap();
+
+        // assert
+        assert_eq!(alert["alert"], "device-silent");
+        assert_eq!(alert["status"], "raised");
+        assert_eq!(alert["message"], "gone");
+    }
+}
@@ -0,0 +1,109 @@
+//! Errors from the JSON API
+//!
+//! API routes answer errors with `{ "error": "message" }` so clients never have to parse the
+//! plain text error pages the browser routes get.
+use std::fmt;
+
+use actix_web::{http::StatusCode, web, HttpResponse, ResponseError};
+use serde_json::json;
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    pub fn bad_request(message: impl fmt::Display) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn forbidden(message: impl fmt::Display) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn internal(message: impl fmt::Display) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(json!({ "error": self.message }))
+    }
+}
+
+impl From<eyre::Report> for ApiError {
+    fn from(err: eyre::Report) -> Self {
+        Self::internal(err)
+    }
+}
+
+/// Report malformed query strings as API errors
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err, _| ApiError::bad_request(err).into())
+}
+
+/// Report malformed path segments as API errors
+pub fn path_config() -> web::PathConfig {
+    web::PathConfig::default().error_handler(|err, _| ApiError::bad_request(err).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Limit {
+        limit: u32,
+    }
+
+    #[actix_rt::test]
+    async fn bad_queries_are_json_errors() {
+        // arrange
+        let mut app = test::init_service(App::new().service(
+            web::resource("/limited").app_data(query_config()).route(
+                web::get().to(|query: web::Query<Limit>| {
+                    HttpResponse::Ok().body(query.limit.to_string())
+                }),
+            ),
+        ))
+        .await;
+
+        // act
+        let req = test::TestRequest::get()
+            .uri("/limited?limit=lots")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(
+            body,
+            json!({ "error": "Query deserialize error: invalid digit found in string" })
+        );
+    }
+}
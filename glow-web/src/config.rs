@@ -0,0 +1,108 @@
+//! Settings from a JSON file as an alternative to environment variables
+//!
+//! `GLOW_CONFIG` can point at a JSON object whose keys are the environment variable names,
+//! for example `{"DB_PATH": "glow.db", "BIND_ADDRESS": "0.0.0.0:8088"}`. Values are strings,
+//! just as they would be in the environment, and anything set in the environment wins.
+use std::path::Path;
+
+use eyre::{Result, WrapErr};
+use serde::Deserialize;
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE", deny_unknown_fields)]
+pub struct Config {
+    pub db_path: Option<String>,
+    pub app_token: Option<String>,
+    pub app_password: Option<String>,
+    pub cookie_secret: Option<String>,
+    pub bbc_weather_location: Option<String>,
+    pub weather_interval_seconds: Option<String>,
+    pub bind_address: Option<String>,
+    pub device_silence_minutes: Option<String>,
+    pub command_ttl_minutes: Option<String>,
+    pub alert_webhook_url: Option<String>,
+    pub events_webhook_url: Option<String>,
+    pub events_webhook_token: Option<String>,
+    pub temperature_alert_min: Option<String>,
+    pub temperature_alert_max: Option<String>,
+    pub cookie_secure: Option<String>,
+    pub session_max_age_seconds: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub cors_allowed_origins: Option<String>,
+    pub events_body_limit_bytes: Option<String>,
+    pub allow_destructive: Option<String>,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("cannot read {}", path.display()))?;
+        Self::from_json(&json).wrap_err_with(|| format!("invalid config in {}", path.display()))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Replace settings with any that `var` finds, usually from the environment
+    pub fn overlay(self, var: impl Fn(&str) -> Option<String>) -> Self {
+        Self {
+            db_path: var("DB_PATH").or(self.db_path),
+            app_token: var("APP_TOKEN").or(self.app_token),
+            app_password: var("APP_PASSWORD").or(self.app_password),
+            cookie_secret: var("COOKIE_SECRET").or(self.cookie_secret),
+            bbc_weather_location: var("BBC_WEATHER_LOCATION").or(self.bbc_weather_location),
+            weather_interval_seconds: var("WEATHER_INTERVAL_SECONDS")
+                .or(self.weather_interval_seconds),
+            bind_address: var("BIND_ADDRESS").or(self.bind_address),
+            device_silence_minutes: var("DEVICE_SILENCE_MINUTES").or(self.device_silence_minutes),
+            command_ttl_minutes: var("COMMAND_TTL_MINUTES").or(self.command_ttl_minutes),
+            alert_webhook_url: var("ALERT_WEBHOOK_URL").or(self.alert_webhook_url),
+            events_webhook_url: var("EVENTS_WEBHOOK_URL").or(self.events_webhook_url),
+            events_webhook_token: var("EVENTS_WEBHOOK_TOKEN").or(self.events_webhook_token),
+            temperature_alert_min: var("TEMPERATURE_ALERT_MIN").or(self.temperature_alert_min),
+            temperature_alert_max: var("TEMPERATURE_ALERT_MAX").or(self.temperature_alert_max),
+            cookie_secure: var("COOKIE_SECURE").or(self.cookie_secure),
+            session_max_age_seconds: var("SESSION_MAX_AGE_SECONDS")
+                .or(self.session_max_age_seconds),
+            tls_cert: var("TLS_CERT").or(self.tls_cert),
+            tls_key: var("TLS_KEY").or(self.tls_key),
+            cors_allowed_origins: var("CORS_ALLOWED_ORIGINS").or(self.cors_allowed_origins),
+            events_body_limit_bytes: var("EVENTS_BODY_LIMIT_BYTES")
+                .or(self.events_body_limit_bytes),
+            allow_destructive: var("ALLOW_DESTRUCTIVE").or(self.allow_destructive),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_overrides_file() {
+        // arrange
+        let file =
+            Config::from_json(r#"{"DB_PATH": "file.db", "APP_TOKEN": "file-token"}"#).unwrap();
+
+        // act
+        let config = file.overlay(|name| match name {
+            "DB_PATH" => Some("env.db".to_string()),
+            "BIND_ADDRESS" => Some("0.0.0.0:80".to_string()),
+            _ => None,
+        });
+
+        // assert
+        assert_eq!(config.db_path.as_deref(), Some("env.db"));
+        assert_eq!(config.app_token.as_deref(), Some("file-token"));
+        assert_eq!(config.bind_address.as_deref(), Some("0.0.0.0:80"));
+        assert_eq!(config.app_password, None);
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        assert!(Config::from_json(r#"{"DB_PAHT": "glow.db"}"#).is_err());
+        assert!(Config::from_json(r#"{"DEVICE_SILENCE_MINUTES": 3}"#).is_err());
+    }
+}
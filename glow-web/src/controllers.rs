@@ -1,39 +1,151 @@
-use chrono::{Duration, Utc};
+use std::convert::TryFrom;
+
+use chrono::{DateTime, Duration, Utc};
 use eyre::{Result, WrapErr};
 use itertools::Itertools;
 
-use glow_events::v2::{Command, Event, Message, Payload};
+use glow_events::colour::rgb_from_hex;
+use glow_events::v1;
+use glow_events::v2::{Command, Event, Message, Payload, MAX_HEATER_SECONDS};
+use glow_events::TPLinkDevice;
 
+use crate::data::{self, StoredMessage};
+use crate::formatting::format_time_since;
+use crate::metrics::Metrics;
+use crate::monitor::{self, AlarmSnooze};
 use crate::session::Session;
-use crate::store::Store;
-use crate::view::data::{ClimateObservation, EventSummary};
+use crate::store::{Store, StorePool, DEFAULT_DEVICE};
+use crate::view::data::{
+    AlarmSnoozed, ClimateMeasurement, ClimateObservation, DailyClimateSummary, DeviceStatus,
+    EventSummary, ForecastSummary, LastSeen, LedState, SensorObservation, TemperatureBucket,
+};
 use crate::view::View;
+use crate::weather::{WeatherMonitor, WeatherService};
+
+/// The dashboard shows this many recent events unless asked for more or fewer
+pub(crate) const DEFAULT_INDEX_EVENTS: u32 = 20;
+/// The most events the dashboard will show, however many are asked for
+pub(crate) const MAX_INDEX_EVENTS: u32 = 200;
+
+/// What the dashboard shows about the heater
+fn insert_heater(store: &impl Store, view: &mut impl View) -> Result<()> {
+    view.insert("max_heater_seconds", &MAX_HEATER_SECONDS);
+
+    if let Some((_, watts)) = store
+        .get_power_readings_since(Duration::minutes(5))?
+        .first()
+    {
+        view.insert("heater_power", &format!("{:.0}", watts));
+    }
+
+    // the state the plug last reported, rather than what the started and stopped events imply
+    if let Some(message) = store
+        .get_latest_events_of_type("tplink.heater-state", 1)?
+        .first()
+    {
+        if let Payload::Event(Event::HeaterState(on)) = message.payload() {
+            view.insert("heater_on", on);
+            view.insert(
+                "heater_state_age",
+                &format_time_since(Utc::now(), message.stamp()),
+            );
+        }
+    }
+
+    Ok(())
+}
 
 pub(crate) fn index(
     store: &impl Store,
     view: &mut impl View,
     session: &mut impl Session,
+    events: Option<u32>,
 ) -> Result<String> {
     view.insert("flash", &session.pop::<Option<String>>("flash")?);
 
+    let device = selected_device(session)?;
+    view.insert("devices", &store.get_devices().unwrap_or_default());
+    view.insert("device", &device);
+
     if let Some(observation) = store.get_latest_measurement() {
         view.insert("observation", &ClimateObservation::from(observation));
     }
 
+    if let Some(average) = store.get_today_average()? {
+        view.insert(
+            "today_average",
+            &ClimateMeasurement::from(data::ClimateMeasurement::from(average)),
+        );
+    }
+
+    view.insert(
+        "sensors",
+        &store
+            .get_latest_labelled_measurements()
+            .unwrap_or_default()
+            .into_iter()
+            .map(SensorObservation::try_from)
+            .collect::<Result<Vec<SensorObservation>>>()?,
+    );
+
+    insert_heater(store, view)?;
+
+    view.insert(
+        "forecast",
+        &store
+            .get_forecasts_since(Duration::hours(24))?
+            .iter()
+            .take(3)
+            .zip(0..)
+            .map(|(forecast, day)| ForecastSummary::new(day, forecast))
+            .collect::<Vec<ForecastSummary>>(),
+    );
+
+    view.insert(
+        "daily_climate",
+        &store
+            .get_daily_climate_since(6)?
+            .into_iter()
+            .map(DailyClimateSummary::from)
+            .collect::<Vec<DailyClimateSummary>>(),
+    );
+
+    let mut last_seen = store
+        .get_last_seen(&device)
+        .unwrap_or_default()
+        .into_iter()
+        .collect::<Vec<_>>();
+    last_seen.sort_by(|(_, a), (_, b)| b.cmp(a));
+    let now = Utc::now();
+    view.insert(
+        "last_seen",
+        &last_seen
+            .into_iter()
+            .map(|(event_type, stamp)| LastSeen {
+                event_type,
+                age: format_time_since(now, stamp),
+            })
+            .collect::<Vec<LastSeen>>(),
+    );
+
     view.insert(
         "events",
         &store
-            .get_latest_events(20)
+            .get_latest_device_events(
+                &device,
+                events.unwrap_or(DEFAULT_INDEX_EVENTS).min(MAX_INDEX_EVENTS),
+            )
             .unwrap_or_default()
             .iter()
             .map(EventSummary::from)
             .collect::<Vec<EventSummary>>(),
     );
 
+    let window = Duration::hours(CLIMATE_HISTORY_HOURS);
     view.insert(
         "climate_history",
         &store
-            .get_climate_history_since(Duration::hours(24))?
+            .get_climate_history_since(window, history_bucket(window))?
             .into_iter()
             .map(ClimateObservation::from)
             .group_by(|m| m.date.clone())
@@ -45,27 +157,87 @@ pub(crate) fn index(
     Ok(view.render("index.html")?)
 }
 
+/// How far back the climate history table on the dashboard goes
+const CLIMATE_HISTORY_HOURS: i64 = 24;
+
+/// Pick a bucket width that keeps the climate history to around a day's worth of hourly rows
+fn history_bucket(window: Duration) -> Duration {
+    std::cmp::max(Duration::hours(1), window / 24)
+}
+
 pub(crate) fn set_brightness(
     store: &impl Store,
     session: &mut impl Session,
     brightness: f32,
 ) -> Result<()> {
-    store.queue_command(Command::SetBrightness(brightness))?;
+    store.queue_command(
+        &selected_device(session)?,
+        Command::SetBrightness(brightness),
+    )?;
     session.set("flash", "set brightness event was queued")?;
 
     Ok(())
 }
 
+pub(crate) fn set_solid_colour(
+    store: &impl Store,
+    session: &mut impl Session,
+    colour: &str,
+) -> Result<()> {
+    match rgb_from_hex(colour) {
+        Ok((red, green, blue)) => {
+            store.queue_command(
+                &selected_device(session)?,
+                Command::SetSolidColour(red, green, blue),
+            )?;
+            session.set("flash", "set solid colour event was queued")?;
+        }
+        Err(err) => session.set("flash", format!("cannot set solid colour: {}", err))?,
+    }
+
+    Ok(())
+}
+
+pub(crate) fn resume_auto(store: &impl Store, session: &mut impl Session) -> Result<()> {
+    store.queue_command(&selected_device(session)?, Command::ResumeAuto)?;
+    session.set("flash", "resume auto event was queued")?;
+
+    Ok(())
+}
+
 pub(crate) fn list_devices(store: &impl Store, session: &mut impl Session) -> Result<()> {
-    store.queue_command(Command::ListDevices)?;
+    store.queue_command(&selected_device(session)?, Command::ListDevices)?;
     session.set("flash", "list devices request sent")?;
 
     Ok(())
 }
 
-pub(crate) fn run_heater(store: &impl Store, session: &mut impl Session) -> Result<()> {
+pub(crate) fn self_test(store: &impl Store, session: &mut impl Session) -> Result<()> {
+    store.queue_command(&selected_device(session)?, Command::SelfTest)?;
+    session.set("flash", "self test request sent")?;
+
+    Ok(())
+}
+
+pub(crate) fn run_heater(
+    store: &impl Store,
+    session: &mut impl Session,
+    duration: Option<u64>,
+) -> Result<()> {
+    if let Some(seconds) = duration.filter(|seconds| *seconds > MAX_HEATER_SECONDS) {
+        session.set(
+            "flash",
+            format!(
+                "cannot run the heater for {} seconds, the most is {}",
+                seconds, MAX_HEATER_SECONDS
+            ),
+        )?;
+        return Ok(());
+    }
+
+    let device = selected_device(session)?;
     let latest_event = store
-        .get_latest_event_like(&r#"{"TPLink":"RunHeater"}"#)
+        .get_latest_device_event_like(&device, &r#"{"TPLink":"RunHeater"}"#)
         .wrap_err("failed to get latest heater event")?;
 
     let can_run_heater = latest_event.map_or(true, |latest_event| {
@@ -77,7 +249,10 @@ pub(crate) fn run_heater(store: &impl Store, session: &mut impl Session) -> Resu
 
     if can_run_heater {
         store
-            .queue_command(Command::RunHeater)
+            .queue_command(
+                &device,
+                duration.map_or(Command::RunHeater, Command::RunHeaterFor),
+            )
             .wrap_err("failed to queue run heater event")?;
         session.set("flash", "run heater event queued")?;
     } else {
@@ -89,7 +264,7 @@ pub(crate) fn run_heater(store: &impl Store, session: &mut impl Session) -> Resu
 
 pub(crate) fn stop_heater(store: &impl Store, session: &mut impl Session) -> Result<()> {
     store
-        .queue_command(Command::StopHeater)
+        .queue_command(&selected_device(session)?, Command::StopHeater)
         .wrap_err("failed to queue stop heater event")?;
     session.set("flash", "stop heater event queued")?;
 
@@ -98,13 +273,47 @@ pub(crate) fn stop_heater(store: &impl Store, session: &mut impl Session) -> Res
 
 pub(crate) fn stop_device(store: &impl Store, session: &mut impl Session) -> Result<()> {
     store
-        .queue_command(Command::Stop)
+        .queue_command(&selected_device(session)?, Command::Stop)
         .wrap_err("failed to stop device")?;
     session.set("flash", "stop event queued")?;
 
     Ok(())
 }
 
+pub(crate) fn clear_commands(store: &impl Store, session: &mut impl Session) -> Result<()> {
+    let cleared = store
+        .clear_pending_commands(&selected_device(session)?)
+        .wrap_err("failed to clear queued commands")?;
+    session.set("flash", format!("cleared {} queued commands", cleared))?;
+
+    Ok(())
+}
+
+pub(crate) async fn refresh_weather<P, W>(
+    monitor: WeatherMonitor<P, W>,
+    session: &mut impl Session,
+) -> Result<()>
+where
+    P: StorePool + 'static,
+    W: WeatherService + 'static,
+{
+    match monitor.update().await {
+        Ok(()) => session.set("flash", "weather refreshed"),
+        Err(err) => session.set("flash", format!("failed to refresh weather: {}", err)),
+    }
+}
+
+/// The device the dashboard is showing and sending commands to
+fn selected_device(session: &impl Session) -> Result<String> {
+    Ok(session
+        .get::<String>("device")?
+        .unwrap_or_else(|| DEFAULT_DEVICE.to_string()))
+}
+
+pub(crate) fn select_device(session: &impl Session, device: &str) -> Result<()> {
+    session.set("device", device)
+}
+
 pub(crate) fn sign_in(
     session: &impl Session,
     password: &str,
@@ -122,27 +331,232 @@ pub(crate) fn sign_out(session: &impl Session) -> Result<()> {
     session.set("authenticated", false)
 }
 
-pub(crate) fn store_events(store: &impl Store, events: &[Message]) -> Result<Vec<Message>> {
-    for event in events {
-        store.add_event(event).unwrap();
-        if let Payload::Event(Event::Measurement(measurement)) = event.payload() {
-            store.add_measurement(event.stamp(), measurement).unwrap();
+/// An event as sent by a device, before it has been checked
+#[derive(Debug)]
+pub(crate) enum IncomingEvent {
+    V2(Message),
+    V1,
+    Invalid(String),
+}
+
+impl IncomingEvent {
+    pub(crate) fn from_json(value: serde_json::Value) -> Self {
+        Self::check(serde_json::from_value(value.clone()), || {
+            serde_json::from_value::<v1::Event>(value).is_ok()
+        })
+    }
+
+    pub(crate) fn from_cbor(value: serde_cbor::Value) -> Self {
+        Self::check(serde_cbor::value::from_value(value.clone()), || {
+            serde_cbor::value::from_value::<v1::Event>(value).is_ok()
+        })
+    }
+
+    fn check<E: std::fmt::Display>(
+        event: std::result::Result<Message, E>,
+        is_v1: impl FnOnce() -> bool,
+    ) -> Self {
+        match event {
+            Ok(event) => Self::V2(event),
+            Err(_) if is_v1() => Self::V1,
+            Err(err) => Self::Invalid(err.to_string()),
+        }
+    }
+}
+
+/// Check a batch of events from a device, describing every event that cannot be stored
+pub(crate) fn validate_events(
+    incoming: Vec<IncomingEvent>,
+) -> std::result::Result<Vec<Message>, String> {
+    let mut events = Vec::with_capacity(incoming.len());
+    let mut problems = vec![];
+    for (index, event) in incoming.into_iter().enumerate() {
+        match event {
+            IncomingEvent::V2(event) => events.push(event),
+            IncomingEvent::V1 => problems.push(format!(
+                "event {} is a v1 event, upgrade the device to send v2 events",
+                index
+            )),
+            IncomingEvent::Invalid(err) => {
+                problems.push(format!("event {} is not a valid event: {}", index, err));
+            }
         }
     }
-    store.dequeue_commands()
+    if problems.is_empty() {
+        Ok(events)
+    } else {
+        Err(problems.join("\n"))
+    }
+}
+
+pub(crate) fn store_events(
+    store: &impl Store,
+    metrics: &Metrics,
+    device: &str,
+    events: &[Message],
+) -> Result<Vec<Message>> {
+    // a retried post repeats events that were already counted
+    for event in store.add_events_batch(device, events)? {
+        metrics.events_stored.inc();
+        match event.payload() {
+            Payload::Event(Event::Measurement(measurement)) => {
+                metrics.measurements_stored.inc();
+                metrics.temperature.set(measurement.temperature);
+                metrics.humidity.set(measurement.humidity);
+            }
+            Payload::Event(Event::LabelledMeasurement(_, _)) => {
+                metrics.measurements_stored.inc();
+            }
+            Payload::Event(Event::PowerReading { watts }) => {
+                metrics.power.set(f64::from(*watts));
+            }
+            _ => {}
+        }
+    }
+    store.dequeue_commands(device)
+}
+
+pub(crate) fn status(store: &impl Store, silence: Duration) -> DeviceStatus {
+    let age = store
+        .get_latest_event()
+        .map(|event| Utc::now().signed_duration_since(event.stamp()));
+    // no events at all is as bad as old ones
+    let alarming = match age {
+        Some(age) => monitor::is_stale(age, silence),
+        None => true,
+    };
+
+    DeviceStatus {
+        status: if alarming { "alarming" } else { "ok" }.to_string(),
+        last_event_age_seconds: age.map(|age| age.num_seconds()),
+        alarming,
+        uptime_seconds: store.get_uptime().map(|uptime| uptime.num_seconds()),
+    }
+}
+
+/// The colours and brightness last reported by the selected device's LED strip, `None` before
+/// any colours
+pub(crate) fn leds(store: &impl Store, session: &impl Session) -> Result<Option<LedState>> {
+    let device = selected_device(session)?;
+    let latest = |like: &str| -> Result<Option<Event>> {
+        Ok(store
+            .get_latest_device_event_like(&device, like)?
+            .and_then(|message| match message.payload() {
+                Payload::Event(event) => Some(event.clone()),
+                Payload::Command(_) => None,
+            }))
+    };
+
+    if let Some(Event::LEDColours(colours)) = latest(r#"%"LEDColours"%"#)? {
+        let brightness = match latest(r#"%"LEDBrightness"%"#)? {
+            Some(Event::LEDBrightness(brightness)) => Some(brightness),
+            _ => None,
+        };
+        Ok(Some(LedState {
+            colours,
+            brightness,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+/// The TP-Link devices found by the selected device's latest discovery, empty before any has
+/// run
+pub(crate) fn devices(store: &impl Store, session: &impl Session) -> Result<Vec<TPLinkDevice>> {
+    let device = selected_device(session)?;
+    Ok(
+        match store.get_latest_device_event_like(&device, r#"%"Devices"%"#)? {
+            Some(message) => match message.payload() {
+                Payload::Event(Event::Devices(devices)) => devices.clone(),
+                _ => vec![],
+            },
+            None => vec![],
+        },
+    )
+}
+
+pub(crate) fn list_events(store: &impl Store, event_type: Option<&str>) -> Result<Vec<Message>> {
+    match event_type {
+        Some(event_type) => store.get_latest_events_of_type(event_type, 20),
+        None => store.get_latest_events(20),
+    }
 }
 
-pub(crate) fn list_events(store: &impl Store) -> Result<Vec<Message>> {
-    store.get_latest_events(20)
+/// The most events returned for a time range, so a wide range cannot exhaust memory
+const EVENTS_RANGE_LIMIT: u32 = 1000;
+
+/// The most events returned after an id, the client asks again for any more
+const EVENTS_AFTER_LIMIT: u32 = 100;
+
+pub(crate) fn list_events_after(store: &impl Store, id: i64) -> Result<Vec<StoredMessage>> {
+    store.get_events_after(id, EVENTS_AFTER_LIMIT)
+}
+
+pub(crate) fn list_events_between(
+    store: &impl Store,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<Message>> {
+    store.get_events_between(from, to, EVENTS_RANGE_LIMIT)
+}
+
+/// The device-silent alarm is snoozed for an hour unless asked otherwise
+pub(crate) const DEFAULT_SNOOZE_MINUTES: i64 = 60;
+/// The longest the device-silent alarm can be snoozed for, a week
+pub(crate) const MAX_SNOOZE_MINUTES: i64 = 7 * 24 * 60;
+
+/// Snooze the device-silent alarm for `minutes` from now, 0 ends a snooze early
+pub(crate) fn snooze_alarm(snooze: &AlarmSnooze, minutes: i64) -> AlarmSnoozed {
+    let until = Utc::now() + Duration::minutes(minutes);
+    snooze.snooze_until(until);
+    AlarmSnoozed { until }
+}
+
+/// The histogram covers a day unless asked otherwise
+pub(crate) const DEFAULT_HISTOGRAM_HOURS: i64 = 24;
+/// Histogram buckets are a degree wide unless asked otherwise
+pub(crate) const DEFAULT_HISTOGRAM_WIDTH: f64 = 1.0;
+
+/// Count the indoor temperatures over the last `hours` into buckets `width` °C wide
+pub(crate) fn temperature_histogram(
+    store: &impl Store,
+    hours: i64,
+    width: f64,
+) -> Result<Vec<TemperatureBucket>> {
+    Ok(store
+        .get_temperature_histogram(Duration::hours(hours), width)?
+        .into_iter()
+        .map(|(from, count)| TemperatureBucket {
+            from,
+            to: from + width,
+            count,
+        })
+        .collect())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::index;
+    use super::{
+        devices, history_bucket, index, leds, run_heater, select_device, set_solid_colour, status,
+        store_events, validate_events, IncomingEvent, MAX_INDEX_EVENTS,
+    };
+
+    use chrono::Utc;
+    use glow_events::v1;
+    use glow_events::v2::{Command, Event, Message, Payload, MAX_HEATER_SECONDS};
+    use glow_events::Measurement;
 
-    use crate::session::test::TestSession;
-    use crate::store::test::{now, TestDb};
-    use crate::{view::data::ClimateObservation, view::test::TestView};
+    use crate::metrics::Metrics;
+    use crate::session::{test::TestSession, Session};
+    use crate::store::{
+        test::{now, TestDb},
+        Store, DEFAULT_DEVICE,
+    };
+    use crate::{
+        view::data::{ClimateMeasurement, ClimateObservation, EventSummary, ForecastSummary},
+        view::test::TestView,
+    };
     use chrono::Duration;
 
     #[test]
@@ -160,7 +574,7 @@ mod tests {
         let mut view = TestView::default();
 
         // act
-        index(&store, &mut view, &mut session).unwrap();
+        index(&store, &mut view, &mut session, None).unwrap();
 
         // assert
         let climate_history: Vec<(String, Vec<ClimateObservation>)> =
@@ -172,4 +586,490 @@ mod tests {
             .iter()
             .all(|(_, observations)| observations.iter().all(|o| &o.time[2..] == ":00")));
     }
+
+    #[test]
+    fn history_bucket_grows_with_the_window() {
+        assert_eq!(history_bucket(Duration::hours(6)), Duration::hours(1));
+        assert_eq!(history_bucket(Duration::hours(24)), Duration::hours(1));
+        assert_eq!(history_bucket(Duration::days(30)), Duration::hours(30));
+    }
+
+    #[test]
+    fn index_today_average() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        store
+            .add_measurement(now(), &Measurement::new(20.0, 40.0))
+            .unwrap();
+        store
+            .add_measurement(now() - Duration::hours(1), &Measurement::new(21.25, 45.0))
+            .unwrap();
+        let mut session = TestSession::default();
+        let mut view = TestView::default();
+
+        // act
+        index(&store, &mut view, &mut session, None).unwrap();
+
+        // assert
+        let average: ClimateMeasurement = view.get("today_average").unwrap();
+        assert_eq!(average.temperature, "20.6");
+        assert_eq!(average.humidity, "42.5");
+    }
+
+    #[test]
+    fn index_forecast() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        TestDb::add_forecasts(&store, now() - Duration::hours(2)).unwrap();
+        let mut session = TestSession::default();
+        let mut view = TestView::default();
+
+        // act
+        index(&store, &mut view, &mut session, None).unwrap();
+
+        // assert
+        let forecast: Vec<ForecastSummary> = view.get("forecast").unwrap();
+
+        assert_eq!(
+            forecast.iter().map(|f| f.day.as_str()).collect::<Vec<_>>(),
+            vec!["Today", "Thursday", "Friday"]
+        );
+        assert_eq!(forecast[0].max_temperature, None);
+        assert_eq!(forecast[1].max_temperature, Some("16".to_string()));
+    }
+
+    #[test]
+    fn run_heater_for_duration() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let mut session = TestSession::default();
+
+        // act
+        run_heater(&store, &mut session, Some(300)).unwrap();
+
+        // assert
+        let commands = store.dequeue_commands(DEFAULT_DEVICE).unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(
+            *commands[0].payload(),
+            Payload::Command(Command::RunHeaterFor(300))
+        );
+    }
+
+    #[test]
+    fn run_heater_rejects_long_durations() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let mut session = TestSession::default();
+
+        // act
+        run_heater(&store, &mut session, Some(MAX_HEATER_SECONDS + 1)).unwrap();
+
+        // assert
+        assert!(store.dequeue_commands(DEFAULT_DEVICE).unwrap().is_empty());
+        assert!(session
+            .get::<String>("flash")
+            .unwrap()
+            .unwrap()
+            .starts_with("cannot run the heater for"));
+    }
+
+    #[test]
+    fn set_solid_colour_queues_the_colour() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let mut session = TestSession::default();
+
+        // act
+        set_solid_colour(&store, &mut session, "#ff8000").unwrap();
+
+        // assert
+        let commands = store.dequeue_commands(DEFAULT_DEVICE).unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(
+            *commands[0].payload(),
+            Payload::Command(Command::SetSolidColour(255, 128, 0))
+        );
+    }
+
+    #[test]
+    fn set_solid_colour_accepts_short_hex() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let mut session = TestSession::default();
+
+        // act
+        set_solid_colour(&store, &mut session, "#f80").unwrap();
+
+        // assert
+        let commands = store.dequeue_commands(DEFAULT_DEVICE).unwrap();
+        assert_eq!(
+            *commands[0].payload(),
+            Payload::Command(Command::SetSolidColour(255, 136, 0))
+        );
+    }
+
+    #[test]
+    fn set_solid_colour_rejects_invalid_colours() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let mut session = TestSession::default();
+
+        for colour in &["#ff80", "#gg8000", "#+f8000", "#ff80000", "#ff80é"] {
+            // act
+            set_solid_colour(&store, &mut session, colour).unwrap();
+
+            // assert
+            assert!(store.dequeue_commands(DEFAULT_DEVICE).unwrap().is_empty());
+            assert!(session
+                .get::<String>("flash")
+                .unwrap()
+                .unwrap()
+                .starts_with("cannot set solid colour"));
+        }
+    }
+
+    #[test]
+    fn status_with_recent_event() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        store
+            .add_event(
+                DEFAULT_DEVICE,
+                &Message::raw(
+                    Utc::now() - Duration::seconds(30),
+                    Payload::Event(Event::SingleTap),
+                ),
+            )
+            .unwrap();
+
+        // act
+        let status = status(&store, Duration::minutes(3));
+
+        // assert
+        assert_eq!(status.status, "ok");
+        assert!(!status.alarming);
+        assert!((30..35).contains(&status.last_event_age_seconds.unwrap()));
+    }
+
+    #[test]
+    fn status_with_stale_event() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        store
+            .add_event(
+                DEFAULT_DEVICE,
+                &Message::raw(
+                    Utc::now() - Duration::minutes(5),
+                    Payload::Event(Event::SingleTap),
+                ),
+            )
+            .unwrap();
+
+        // act
+        let status = status(&store, Duration::minutes(3));
+
+        // assert
+        assert_eq!(status.status, "alarming");
+        assert!(status.alarming);
+    }
+
+    #[test]
+    fn status_with_no_events() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+
+        // act
+        let status = status(&store, Duration::minutes(3));
+
+        // assert
+        assert!(status.alarming);
+        assert_eq!(status.last_event_age_seconds, None);
+        assert_eq!(status.uptime_seconds, None);
+    }
+
+    #[test]
+    fn status_reports_uptime_since_the_last_start() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        for hours in &[5, 2] {
+            store
+                .add_event(
+                    DEFAULT_DEVICE,
+                    &Message::raw(
+                        now() - Duration::hours(*hours),
+                        Payload::Event(Event::Started),
+                    ),
+                )
+                .unwrap();
+        }
+        store
+            .add_event(
+                DEFAULT_DEVICE,
+                &Message::raw(
+                    now() - Duration::minutes(1),
+                    Payload::Event(Event::HeaterStarted),
+                ),
+            )
+            .unwrap();
+
+        // act
+        let status = status(&store, Duration::minutes(3));
+
+        // assert
+        assert_eq!(status.uptime_seconds, Some(2 * 60 * 60));
+    }
+
+    #[test]
+    fn commands_go_to_the_selected_device() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let mut session = TestSession::default();
+        select_device(&session, "kitchen").unwrap();
+
+        // act
+        run_heater(&store, &mut session, None).unwrap();
+
+        // assert
+        assert!(store.dequeue_commands(DEFAULT_DEVICE).unwrap().is_empty());
+        assert_eq!(store.dequeue_commands("kitchen").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn index_shows_events_for_the_selected_device() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        store
+            .add_event("kitchen", &Message::new_event(Event::SingleTap))
+            .unwrap();
+        store
+            .add_event(DEFAULT_DEVICE, &Message::new_event(Event::Started))
+            .unwrap();
+        let mut session = TestSession::default();
+        let mut view = TestView::default();
+        select_device(&session, "kitchen").unwrap();
+
+        // act
+        index(&store, &mut view, &mut session, None).unwrap();
+
+        // assert
+        let devices: Vec<String> = view.get("devices").unwrap();
+        let events: Vec<EventSummary> = view.get("events").unwrap();
+        assert_eq!(devices, vec!["default", "kitchen"]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].title, "Single tap");
+    }
+
+    #[test]
+    fn index_shows_the_requested_number_of_events() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        for _ in 0..MAX_INDEX_EVENTS + 10 {
+            store
+                .add_event(DEFAULT_DEVICE, &Message::new_event(Event::SingleTap))
+                .unwrap();
+        }
+        let mut session = TestSession::default();
+
+        // act
+        let mut count = |events| {
+            let mut view = TestView::default();
+            index(&store, &mut view, &mut session, events).unwrap();
+            let events: Vec<EventSummary> = view.get("events").unwrap();
+            events.len()
+        };
+        let counts = (count(None), count(Some(50)), count(Some(1000)));
+
+        // assert
+        assert_eq!(counts, (20, 50, 200));
+    }
+
+    #[test]
+    fn leds_returns_latest_colours_and_brightness() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        store
+            .add_event(
+                DEFAULT_DEVICE,
+                &Message::new_event(Event::LEDColours(vec![(10, 10, 100); 8])),
+            )
+            .unwrap();
+        store
+            .add_event(
+                DEFAULT_DEVICE,
+                &Message::new_event(Event::LEDBrightness(0.5)),
+            )
+            .unwrap();
+
+        // act
+        let state = leds(&store, &TestSession::default()).unwrap().unwrap();
+
+        // assert
+        assert_eq!(state.colours, vec![(10, 10, 100); 8]);
+        assert_eq!(state.brightness, Some(0.5));
+    }
+
+    #[test]
+    fn devices_from_the_latest_discovery() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let device = |name: &str| glow_events::TPLinkDevice {
+            name: name.to_string(),
+        };
+        store
+            .add_event(
+                DEFAULT_DEVICE,
+                &Message::raw(
+                    Utc::now() - Duration::minutes(10),
+                    Payload::Event(Event::Devices(vec![device("Heater")])),
+                ),
+            )
+            .unwrap();
+        store
+            .add_event(
+                DEFAULT_DEVICE,
+                &Message::raw(
+                    Utc::now() - Duration::minutes(5),
+                    Payload::Event(Event::Devices(vec![device("Heater"), device("Lamp")])),
+                ),
+            )
+            .unwrap();
+
+        // act
+        let devices = devices(&store, &TestSession::default()).unwrap();
+
+        // assert
+        assert_eq!(devices, vec![device("Heater"), device("Lamp")]);
+        assert_eq!(
+            serde_json::to_string(&devices).unwrap(),
+            r#"[{"name":"Heater"},{"name":"Lamp"}]"#
+        );
+    }
+
+    #[test]
+    fn devices_is_empty_before_any_discovery() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+
+        // act
+        let devices = devices(&store, &TestSession::default()).unwrap();
+
+        // assert
+        assert!(devices.is_empty());
+    }
+
+    #[test]
+    fn leds_is_empty_before_any_colours() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+
+        // act
+        let state = leds(&store, &TestSession::default()).unwrap();
+
+        // assert
+        assert!(state.is_none());
+    }
+
+    #[test]
+    fn leds_are_for_the_selected_device() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        store
+            .add_event(
+                "kitchen",
+                &Message::new_event(Event::LEDColours(vec![(1, 2, 3); 8])),
+            )
+            .unwrap();
+        let session = TestSession::default();
+
+        // act
+        let default_state = leds(&store, &session).unwrap();
+        select_device(&session, "kitchen").unwrap();
+        let kitchen_state = leds(&store, &session).unwrap();
+
+        // assert
+        assert!(default_state.is_none());
+        assert_eq!(kitchen_state.unwrap().colours, vec![(1, 2, 3); 8]);
+    }
+
+    #[test]
+    fn validate_events_rejects_v1_and_malformed_events() {
+        // arrange
+        let incoming = vec![
+            serde_json::to_value(Message::new_event(Event::SingleTap)).unwrap(),
+            serde_json::to_value(v1::Event::new(v1::Message::Stop)).unwrap(),
+            serde_json::json!({"temperature": 21.5}),
+        ]
+        .into_iter()
+        .map(IncomingEvent::from_json)
+        .collect();
+
+        // act
+        let result = validate_events(incoming);
+
+        // assert
+        assert_eq!(
+            result.unwrap_err(),
+            "event 1 is a v1 event, upgrade the device to send v2 events\n\
+             event 2 is not a valid event: missing field `stamp`"
+        );
+    }
+
+    #[test]
+    fn validate_events_accepts_v2_events() {
+        // arrange
+        let events = vec![
+            Message::new_event(Event::SingleTap),
+            Message::new_command(Command::Stop),
+        ];
+        let incoming = events
+            .iter()
+            .map(|event| IncomingEvent::from_cbor(serde_cbor::value::to_value(event).unwrap()))
+            .collect();
+
+        // act
+        let result = validate_events(incoming);
+
+        // assert
+        assert_eq!(result.unwrap(), events);
+    }
+
+    #[test]
+    fn store_events_counts_only_new_events() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let metrics = Metrics::new();
+        let events = vec![
+            Message::new_event(Event::SingleTap),
+            Message::new_event(Event::Measurement(Measurement::new(20.0, 50.0))),
+        ];
+
+        // act
+        store_events(&store, &metrics, DEFAULT_DEVICE, &events).unwrap();
+        store_events(&store, &metrics, DEFAULT_DEVICE, &events).unwrap();
+
+        // assert
+        assert_eq!(metrics.events_stored.get(), 2);
+        assert_eq!(metrics.measurements_stored.get(), 1);
+    }
 }
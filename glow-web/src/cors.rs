@@ -0,0 +1,120 @@
+//! Cross-origin access to the API
+//!
+//! Browsers on other origins are only let in when they are listed in `CORS_ALLOWED_ORIGINS`.
+use actix_cors::{Cors, CorsFactory};
+use actix_web::middleware::Condition;
+use glow_events::v2::DEVICE_HEADER;
+
+/// Split a comma separated list of origins, ignoring blanks
+pub fn parse_allowed_origins(origins: Option<&str>) -> Vec<String> {
+    origins
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// CORS middleware for the API, doing nothing unless some origins are allowed
+pub fn cors(allowed_origins: &[String]) -> Condition<CorsFactory> {
+    let cors = allowed_origins
+        .iter()
+        .fold(Cors::new(), |cors, origin| cors.allowed_origin(origin))
+        .allowed_methods(["GET", "POST"])
+        .allowed_headers(["Authorization", "Accept", "Content-Type", DEVICE_HEADER])
+        .max_age(3600)
+        .finish();
+    Condition::new(!allowed_origins.is_empty(), cors)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        dev::ServiceResponse,
+        http::{header, Method, StatusCode},
+        test, web, App, HttpResponse,
+    };
+
+    use super::*;
+
+    async fn preflight(allowed_origins: &[String], origin: &str) -> ServiceResponse {
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("/api")
+                    .wrap(cors(allowed_origins))
+                    .route("/events", web::get().to(HttpResponse::Ok)),
+            ),
+        )
+        .await;
+        let req = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/api/events")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .to_request();
+        test::call_service(&mut app, req).await
+    }
+
+    #[test]
+    fn parse_origins() {
+        assert!(parse_allowed_origins(None).is_empty());
+        assert!(parse_allowed_origins(Some(" , ")).is_empty());
+        assert_eq!(
+            parse_allowed_origins(Some("https://a.example, https://b.example")),
+            vec!["https://a.example", "https://b.example"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn preflight_from_allowed_origin() {
+        // arrange
+        let origins = parse_allowed_origins(Some("https://glow.example"));
+
+        // act
+        let resp = preflight(&origins, "https://glow.example").await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://glow.example"
+        );
+        let methods = resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_METHODS)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(methods.contains("POST"));
+    }
+
+    #[actix_rt::test]
+    async fn preflight_from_disallowed_origin() {
+        // arrange
+        let origins = parse_allowed_origins(Some("https://glow.example"));
+
+        // act
+        let resp = preflight(&origins, "https://evil.example").await;
+
+        // assert
+        assert!(resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    #[actix_rt::test]
+    async fn no_cross_origin_access_by_default() {
+        // act
+        let resp = preflight(&[], "https://glow.example").await;
+
+        // assert
+        assert!(resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+}
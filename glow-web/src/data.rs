@@ -9,13 +9,18 @@ use eyre::{eyre, Result};
 use serde::{Deserialize, Serialize};
 
 use glow_events::v2::{Event, Message, Payload};
+use glow_events::Measurement;
 
-use crate::weather::Observation;
-use chrono::{DateTime, Utc};
+use crate::weather::{Observation, WindDirection};
+use chrono::{DateTime, NaiveDate, Utc};
 
 pub struct AppData {
     pub token: String,
     pub password: String,
+    /// How long the device can be quiet before it is considered to be alarming
+    pub device_silence: chrono::Duration,
+    /// Whether routes that wipe stored data are turned on, never in production
+    pub allow_destructive: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -33,17 +38,64 @@ impl From<Observation> for ClimateMeasurement {
     }
 }
 
+impl From<Measurement> for ClimateMeasurement {
+    fn from(measurement: Measurement) -> Self {
+        ClimateMeasurement {
+            temperature: measurement.temperature,
+            humidity: measurement.humidity,
+        }
+    }
+}
+
 impl TryFrom<Message> for ClimateMeasurement {
     type Error = eyre::Error;
 
     fn try_from(message: Message) -> Result<Self, Self::Error> {
-        if let Payload::Event(Event::Measurement(measurement)) = message.payload() {
-            Ok(ClimateMeasurement {
+        match message.payload() {
+            Payload::Event(
+                Event::Measurement(measurement) | Event::LabelledMeasurement(_, measurement),
+            ) => Ok(ClimateMeasurement {
                 temperature: measurement.temperature as f64,
                 humidity: measurement.humidity as f64,
-            })
-        } else {
-            Err(eyre!("not a measurement"))
+            }),
+            _ => Err(eyre!("not a measurement")),
+        }
+    }
+}
+
+/// A stored event with the id it was stored under
+///
+/// Ids only ever go up, so the highest one seen can be used to fetch just the newer events.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub id: i64,
+    #[serde(flatten)]
+    pub message: Message,
+}
+
+/// Indoor temperature and humidity summarised over a UTC day
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct DailyClimate {
+    pub day: NaiveDate,
+    pub min_temperature: f64,
+    pub max_temperature: f64,
+    pub mean_temperature: f64,
+    pub min_humidity: f64,
+    pub max_humidity: f64,
+    pub mean_humidity: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Wind {
+    pub speed: u32,
+    pub direction: WindDirection,
+}
+
+impl From<&Observation> for Wind {
+    fn from(observation: &Observation) -> Self {
+        Wind {
+            speed: observation.wind_speed,
+            direction: observation.wind_direction,
         }
     }
 }
@@ -52,6 +104,9 @@ impl TryFrom<Message> for ClimateMeasurement {
 pub struct ClimateObservation {
     pub indoor: Option<ClimateMeasurement>,
     pub outdoor: Option<ClimateMeasurement>,
+    pub wind: Option<Wind>,
+    /// Apparent outdoor temperature
+    pub feels_like: Option<f64>,
     pub date_time: DateTime<Utc>,
 }
 
@@ -70,6 +125,8 @@ impl ClimateObservation {
         };
         Ok(Self {
             indoor: message.map(ClimateMeasurement::try_from).transpose()?,
+            wind: observation.as_ref().map(Wind::from),
+            feels_like: observation.as_ref().map(Observation::feels_like),
             outdoor: observation.map(ClimateMeasurement::from),
             date_time,
         })
@@ -84,6 +141,8 @@ impl TryFrom<Message> for ClimateObservation {
         Ok(Self {
             indoor: Some(ClimateMeasurement::try_from(message)?),
             outdoor: None,
+            wind: None,
+            feels_like: None,
             date_time,
         })
     }
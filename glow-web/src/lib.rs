@@ -4,28 +4,47 @@
 #[macro_use]
 extern crate rusqlite;
 
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
 use actix::Actor;
 use actix_session::CookieSession;
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use actix_web::{middleware::Logger, web, App, HttpServer, Resource};
 use actix_web_httpauth::middleware::HttpAuthentication;
+use eyre::WrapErr;
 use log::info;
 use tera::{Result as TeraResult, Tera};
 
+use crate::alert::Webhook;
 use crate::authentication::{bearer_validator, CheckLogin};
+use crate::config::Config;
 use crate::data::AppData;
-use crate::monitor::EventsMonitor;
-use crate::store::{SQLiteStorePool, Store, StorePool};
-#[cfg(feature = "weather-monitor")]
+use crate::metrics::Metrics;
+use crate::monitor::{
+    AlarmSnooze, EventsMonitor, TemperatureLimits, DEFAULT_DEVICE_SILENCE_MINUTES,
+};
+use crate::relay::EventRelay;
+use crate::store::{SQLiteStorePool, Store, StorePool, DEFAULT_COMMAND_TTL_MINUTES};
+use crate::timing::RequestTiming;
 use crate::weather::{BBCWeatherService, WeatherMonitor};
 
+mod alert;
+mod api_error;
 mod authentication;
+mod config;
 mod controllers;
+mod cors;
 mod data;
 mod formatting;
+mod metrics;
 mod monitor;
+mod relay;
+mod retry;
 mod routes;
 mod session;
+mod socket;
 mod store;
+mod timing;
+mod tls;
 mod view;
 #[cfg(feature = "weather-monitor")]
 mod weather;
@@ -36,45 +55,73 @@ mod weather;
 ///
 /// Will return `Err` if actix-web exits with an io error.
 pub async fn run_server() -> std::io::Result<()> {
-    let env = EnvironmentData::load();
+    let env = EnvironmentData::load().unwrap_or_else(|err| panic!("Invalid setup: {:?}", err));
+    let bind_address = env.bind_address;
     let tera = templates().expect("Could not load templates");
-    let pool = SQLiteStorePool::from_path(&env.db_path);
+    let tls = env.tls.as_ref().map(|(cert, key)| {
+        tls::load_tls_config(cert, key).unwrap_or_else(|err| panic!("Invalid TLS setup: {:?}", err))
+    });
+    let pool = SQLiteStorePool::from_path(&env.db_path).with_command_ttl(env.command_ttl);
 
     pool.get().map(|store| store.migrate_db()).unwrap();
 
-    EventsMonitor::new(pool.clone()).start();
-    #[cfg(feature = "weather-monitor")]
-    WeatherMonitor::new(pool.clone(), BBCWeatherService::new(&env.weather_location)).start();
+    let metrics = Metrics::new();
+    let snooze = AlarmSnooze::default();
 
-    HttpServer::new(move || {
+    EventsMonitor::new(
+        pool.clone(),
+        metrics.clone(),
+        env.device_silence,
+        Webhook::new(env.alert_webhook.clone()),
+        snooze.clone(),
+        env.temperature_limits,
+    )
+    .start();
+    let weather = WeatherMonitor::new(
+        pool.clone(),
+        BBCWeatherService::new(&env.weather_location),
+        metrics.clone(),
+        env.weather_interval,
+    );
+    weather.clone().start();
+    let relay = EventRelay::new(env.events_webhook.clone(), env.events_webhook_token.clone());
+
+    let server = HttpServer::new(move || {
         let env = env.clone();
         let tera = tera.clone();
+        let events_body_limit = env.events_body_limit;
 
         App::new()
             .wrap(Logger::default())
+            .wrap(RequestTiming)
             .wrap(
                 CookieSession::signed(&env.cookie_key)
                     .name("glow")
                     .http_only(true)
-                    .secure(false)
-                    .max_age(60 * 60 * 24 * 3),
+                    .secure(env.session.secure)
+                    .max_age(env.session.max_age),
             )
             .data(AppData {
                 token: env.app_token,
                 password: std::str::from_utf8(&env.app_password).unwrap().to_string(),
+                device_silence: env.device_silence,
+                allow_destructive: env.allow_destructive,
             })
             .data(pool.clone())
+            .data(metrics.clone())
+            .data(weather.clone())
+            .data(relay.clone())
+            .data(snooze.clone())
             .data(tera)
             .service(
                 web::scope("/api")
                     .wrap(HttpAuthentication::bearer(bearer_validator))
-                    .service(
-                        web::resource("/events")
-                            .route(web::post().to(routes::store_events))
-                            .route(web::get().to(routes::list_events)),
-                    ),
+                    .wrap(cors::cors(&env.cors_allowed_origins))
+                    .configure(|api| api_routes(api, events_body_limit)),
             )
             .service(web::resource("/status").route(web::get().to(routes::status)))
+            .service(web::resource("/healthz").route(web::get().to(routes::healthz)))
+            .service(web::resource("/metrics").route(web::get().to(routes::metrics)))
             .service(
                 web::resource("/login")
                     .route(web::get().to(routes::login))
@@ -85,16 +132,26 @@ pub async fn run_server() -> std::io::Result<()> {
                     .wrap(CheckLogin)
                     .route("", web::get().to(routes::index))
                     .route("/logout", web::get().to(routes::logout))
+                    .route("/device", web::post().to(routes::select_device))
                     .route("/brightness", web::post().to(routes::set_brightness))
+                    .route("/solid-colour", web::post().to(routes::set_solid_colour))
+                    .route("/resume-auto", web::post().to(routes::resume_auto))
                     .route("/list-devices", web::post().to(routes::list_devices))
+                    .route("/self-test", web::post().to(routes::self_test))
                     .route("/stop-device", web::post().to(routes::stop_device))
                     .route("/run-heater", web::post().to(routes::run_heater))
-                    .route("/stop-heater", web::post().to(routes::stop_heater)),
+                    .route("/stop-heater", web::post().to(routes::stop_heater))
+                    .route("/clear-commands", web::post().to(routes::clear_commands))
+                    .route("/refresh-weather", web::post().to(routes::refresh_weather)),
             )
-    })
-    .bind("127.0.0.1:8088")?
-    .run()
-    .await
+    });
+
+    if let Some(tls) = tls {
+        info!("Serving HTTPS on {}", bind_address);
+        server.bind_rustls(bind_address, tls)?.run().await
+    } else {
+        server.bind(bind_address)?.run().await
+    }
 }
 
 #[cfg(feature = "embedded-templates")]
@@ -120,6 +177,31 @@ fn templates() -> TeraResult<Tera> {
     Tera::new(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*"))
 }
 
+/// The routes under `/api`, for the device and other clients with the bearer token
+fn api_routes(api: &mut web::ServiceConfig, events_body_limit: usize) {
+    api.service(api_resource("/events/after/{id}").route(web::get().to(routes::list_events_after)))
+        .service(api_resource("/events/range").route(web::get().to(routes::list_events_between)))
+        .service(
+            api_resource("/events")
+                .app_data(web::PayloadConfig::new(events_body_limit))
+                .route(web::post().to(routes::store_events))
+                .route(web::get().to(routes::list_events))
+                .route(web::delete().to(routes::delete_events)),
+        )
+        .service(api_resource("/leds").route(web::get().to(routes::leds)))
+        .service(api_resource("/devices").route(web::get().to(routes::devices)))
+        .service(api_resource("/histogram").route(web::get().to(routes::histogram)))
+        .service(api_resource("/alarm/snooze").route(web::post().to(routes::snooze_alarm)))
+        .service(web::resource("/ws").route(web::get().to(routes::command_socket)));
+}
+
+/// A resource that reports bad queries and paths as JSON errors
+fn api_resource(path: &str) -> Resource {
+    web::resource(path)
+        .app_data(api_error::query_config())
+        .app_data(api_error::path_config())
+}
+
 #[derive(Clone)]
 struct EnvironmentData {
     db_path: String,
@@ -127,23 +209,315 @@ struct EnvironmentData {
     app_password: Vec<u8>,
     cookie_key: Vec<u8>,
     weather_location: String,
+    weather_interval: Duration,
+    bind_address: SocketAddr,
+    device_silence: chrono::Duration,
+    command_ttl: chrono::Duration,
+    alert_webhook: Option<String>,
+    events_webhook: Option<String>,
+    events_webhook_token: Option<String>,
+    temperature_limits: TemperatureLimits,
+    tls: Option<(PathBuf, PathBuf)>,
+    session: SessionConfig,
+    cors_allowed_origins: Vec<String>,
+    events_body_limit: usize,
+    allow_destructive: bool,
 }
 
 impl EnvironmentData {
-    pub fn load() -> Self {
-        Self {
-            db_path: std::env::var("DB_PATH").expect("DB_PATH is required"),
-            app_token: std::env::var("APP_TOKEN").expect("APP_TOKEN is required"),
-            app_password: base64::decode(
-                &std::env::var("APP_PASSWORD").expect("APP_PASSWORD is required"),
+    /// Load from the environment, filling gaps from the `GLOW_CONFIG` file if there is one
+    pub fn load() -> eyre::Result<Self> {
+        let config = match std::env::var("GLOW_CONFIG") {
+            Ok(path) => Config::from_file(path.as_ref())?,
+            Err(_) => Config::default(),
+        };
+        Self::from_config(config.overlay(|name| std::env::var(name).ok()))
+    }
+
+    fn from_config(config: Config) -> eyre::Result<Self> {
+        let mut missing = vec![];
+        let mut required = |value: Option<String>, name: &'static str| {
+            value.unwrap_or_else(|| {
+                missing.push(name);
+                String::new()
+            })
+        };
+        let db_path = required(config.db_path, "DB_PATH");
+        let app_token = required(config.app_token, "APP_TOKEN");
+        let app_password = required(config.app_password, "APP_PASSWORD");
+        let cookie_secret = required(config.cookie_secret, "COOKIE_SECRET");
+        let weather_location = required(config.bbc_weather_location, "BBC_WEATHER_LOCATION");
+        if !missing.is_empty() {
+            return Err(eyre::eyre!(
+                "missing required settings: {}",
+                missing.join(", ")
+            ));
+        }
+
+        Ok(Self {
+            db_path,
+            app_token,
+            app_password: base64::decode(&app_password)
+                .wrap_err("APP_PASSWORD is not valid base64")?,
+            cookie_key: base64::decode(&cookie_secret)
+                .wrap_err("COOKIE_SECRET is not valid base64")?,
+            weather_location,
+            weather_interval: parse_weather_interval(config.weather_interval_seconds.as_deref())
+                .wrap_err("WEATHER_INTERVAL_SECONDS is invalid")?,
+            bind_address: parse_bind_address(
+                config
+                    .bind_address
+                    .as_deref()
+                    .unwrap_or(DEFAULT_BIND_ADDRESS),
             )
-            .expect("APP_PASSWORD is not valid base64"),
-            cookie_key: base64::decode(
-                &std::env::var("COOKIE_SECRET").expect("COOKIE_SECRET is required"),
+            .wrap_err("BIND_ADDRESS is invalid")?,
+            device_silence: chrono::Duration::minutes(parse_or(
+                config.device_silence_minutes.as_deref(),
+                DEFAULT_DEVICE_SILENCE_MINUTES,
+                "DEVICE_SILENCE_MINUTES",
+            )?),
+            command_ttl: chrono::Duration::minutes(parse_or(
+                config.command_ttl_minutes.as_deref(),
+                DEFAULT_COMMAND_TTL_MINUTES,
+                "COMMAND_TTL_MINUTES",
+            )?),
+            alert_webhook: config.alert_webhook_url,
+            events_webhook: config.events_webhook_url,
+            events_webhook_token: config.events_webhook_token,
+            temperature_limits: TemperatureLimits {
+                min: parse_or(
+                    config.temperature_alert_min.as_deref(),
+                    TemperatureLimits::default().min,
+                    "TEMPERATURE_ALERT_MIN",
+                )?,
+                max: parse_or(
+                    config.temperature_alert_max.as_deref(),
+                    TemperatureLimits::default().max,
+                    "TEMPERATURE_ALERT_MAX",
+                )?,
+            },
+            session: parse_session_config(
+                config.cookie_secure.as_deref(),
+                config.session_max_age_seconds.as_deref(),
             )
-            .expect("COOKIE_SECRET is not valid base64"),
-            weather_location: std::env::var("BBC_WEATHER_LOCATION")
-                .expect("BBC_WEATHER_LOCATION is required"),
+            .wrap_err("COOKIE_SECURE or SESSION_MAX_AGE_SECONDS is invalid")?,
+            tls: match (config.tls_cert, config.tls_key) {
+                (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+                (None, None) => None,
+                _ => return Err(eyre::eyre!("TLS_CERT and TLS_KEY must be set together")),
+            },
+            cors_allowed_origins: cors::parse_allowed_origins(
+                config.cors_allowed_origins.as_deref(),
+            ),
+            events_body_limit: parse_or(
+                config.events_body_limit_bytes.as_deref(),
+                DEFAULT_EVENTS_BODY_LIMIT,
+                "EVENTS_BODY_LIMIT_BYTES",
+            )?,
+            allow_destructive: parse_flag(config.allow_destructive.as_deref())
+                .wrap_err("ALLOW_DESTRUCTIVE is invalid")?,
+        })
+    }
+}
+
+/// Parse an optional number, falling back to a default when it is not set
+fn parse_or<T: std::str::FromStr>(value: Option<&str>, default: T, name: &str) -> eyre::Result<T> {
+    value.map_or(Ok(default), |value| {
+        value
+            .parse()
+            .map_err(|_| eyre::eyre!("{} must be a number, not '{}'", name, value))
+    })
+}
+
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:8088";
+const DEFAULT_SESSION_MAX_AGE_SECONDS: i64 = 60 * 60 * 24 * 3;
+const DEFAULT_WEATHER_INTERVAL_SECONDS: u64 = 60 * 60;
+/// Largest batch of events the device can post, after any gzip is decompressed
+const DEFAULT_EVENTS_BODY_LIMIT: usize = 1024 * 1024;
+/// Checking the weather more often than this would be hammering the provider
+const MIN_WEATHER_INTERVAL_SECONDS: u64 = 60;
+
+/// How the session cookie is set
+#[derive(Clone, Debug, PartialEq)]
+struct SessionConfig {
+    /// Only send the cookie over HTTPS
+    secure: bool,
+    max_age: i64,
+}
+
+fn parse_session_config(
+    secure: Option<&str>,
+    max_age: Option<&str>,
+) -> eyre::Result<SessionConfig> {
+    Ok(SessionConfig {
+        secure: parse_flag(secure)?,
+        max_age: max_age.map_or(Ok(DEFAULT_SESSION_MAX_AGE_SECONDS), |max_age| {
+            max_age
+                .parse()
+                .wrap_err_with(|| format!("'{}' is not a number of seconds", max_age))
+        })?,
+    })
+}
+
+/// Parse an optional true or false setting, off unless it is set
+fn parse_flag(value: Option<&str>) -> eyre::Result<bool> {
+    match value {
+        None | Some("false" | "0") => Ok(false),
+        Some("true" | "1") => Ok(true),
+        Some(other) => Err(eyre::eyre!("'{}' is not true or false", other)),
+    }
+}
+
+fn parse_weather_interval(seconds: Option<&str>) -> eyre::Result<Duration> {
+    let seconds = seconds.map_or(Ok(DEFAULT_WEATHER_INTERVAL_SECONDS), |seconds| {
+        seconds
+            .parse()
+            .wrap_err_with(|| format!("'{}' is not a number of seconds", seconds))
+    })?;
+    if seconds < MIN_WEATHER_INTERVAL_SECONDS {
+        return Err(eyre::eyre!(
+            "{} seconds is less than the minimum of {}",
+            seconds,
+            MIN_WEATHER_INTERVAL_SECONDS
+        ));
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+fn parse_bind_address(address: &str) -> eyre::Result<SocketAddr> {
+    address.parse().wrap_err_with(|| {
+        format!(
+            "'{}' is not a socket address like {}",
+            address, DEFAULT_BIND_ADDRESS
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid_bind_address() {
+        assert_eq!(
+            parse_bind_address("0.0.0.0:8080").unwrap(),
+            "0.0.0.0:8080".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_bind_address(DEFAULT_BIND_ADDRESS).unwrap().port(),
+            8088
+        );
+    }
+
+    #[test]
+    fn parse_invalid_bind_address() {
+        assert!(parse_bind_address("localhost").is_err());
+        assert!(parse_bind_address("127.0.0.1").is_err());
+        assert!(parse_bind_address("127.0.0.1:http").is_err());
+    }
+
+    #[test]
+    fn session_config_defaults() {
+        assert_eq!(
+            parse_session_config(None, None).unwrap(),
+            SessionConfig {
+                secure: false,
+                max_age: 60 * 60 * 24 * 3,
+            }
+        );
+    }
+
+    #[test]
+    fn session_config_overrides() {
+        assert_eq!(
+            parse_session_config(Some("true"), Some("3600")).unwrap(),
+            SessionConfig {
+                secure: true,
+                max_age: 3600,
+            }
+        );
+        assert!(!parse_session_config(Some("0"), None).unwrap().secure);
+    }
+
+    #[test]
+    fn session_config_invalid() {
+        assert!(parse_session_config(Some("yes please"), None).is_err());
+        assert!(parse_session_config(None, Some("three days")).is_err());
+    }
+
+    #[test]
+    fn weather_interval() {
+        assert_eq!(
+            parse_weather_interval(None).unwrap(),
+            Duration::from_secs(DEFAULT_WEATHER_INTERVAL_SECONDS)
+        );
+        assert_eq!(
+            parse_weather_interval(Some("90")).unwrap(),
+            Duration::from_secs(90)
+        );
+        assert!(parse_weather_interval(Some("59")).is_err());
+        assert!(parse_weather_interval(Some("hourly")).is_err());
+    }
+
+    fn required_config() -> Config {
+        Config {
+            db_path: Some("glow.db".to_string()),
+            app_token: Some("token".to_string()),
+            app_password: Some(base64::encode("password")),
+            cookie_secret: Some(base64::encode(&[0; 32])),
+            bbc_weather_location: Some("2643743".to_string()),
+            ..Config::default()
         }
     }
+
+    #[test]
+    fn environment_data_from_config() {
+        // arrange
+        let config = Config {
+            device_silence_minutes: Some("10".to_string()),
+            ..required_config()
+        };
+
+        // act
+        let env = EnvironmentData::from_config(config).unwrap();
+
+        // assert
+        assert_eq!(env.db_path, "glow.db");
+        assert_eq!(env.app_password, b"password");
+        assert_eq!(env.device_silence, chrono::Duration::minutes(10));
+        assert_eq!(env.bind_address.port(), 8088);
+        assert!(env.tls.is_none());
+    }
+
+    #[test]
+    fn environment_data_lists_every_missing_setting() {
+        // arrange
+        let config = Config {
+            db_path: Some("glow.db".to_string()),
+            ..Config::default()
+        };
+
+        // act
+        let err = EnvironmentData::from_config(config).err().unwrap();
+
+        // assert
+        assert_eq!(
+            err.to_string(),
+            "missing required settings: APP_TOKEN, APP_PASSWORD, COOKIE_SECRET, BBC_WEATHER_LOCATION"
+        );
+    }
+
+    #[test]
+    fn environment_data_invalid_settings() {
+        let invalid = |config: Config| EnvironmentData::from_config(config).is_err();
+
+        assert!(invalid(Config {
+            device_silence_minutes: Some("ten".to_string()),
+            ..required_config()
+        }));
+        assert!(invalid(Config {
+            tls_cert: Some("cert.pem".to_string()),
+            ..required_config()
+        }));
+    }
 }
@@ -4,7 +4,7 @@ use glow_web::run_server;
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
+    glow_events::logging::init();
 
     run_server().await
 }
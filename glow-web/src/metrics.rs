@@ -0,0 +1,99 @@
+//! Prometheus metrics
+//!
+//! A single `Metrics` is created at startup and shared between the request handlers and the
+//! background monitors. The metric handles are cheap to clone and all share the same registry.
+use eyre::Result;
+use prometheus::{Encoder, Gauge, IntCounter, IntGauge, Registry, TextEncoder};
+
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub events_stored: IntCounter,
+    pub measurements_stored: IntCounter,
+    pub temperature: Gauge,
+    pub humidity: Gauge,
+    pub power: Gauge,
+    pub device_alarming: IntGauge,
+    pub weather_fetch_successes: IntCounter,
+    pub weather_fetch_failures: IntCounter,
+}
+
+impl Metrics {
+    /// Create and register all metrics
+    ///
+    /// # Panics
+    ///
+    /// Will panic if a metric is invalid or registered twice, which is a programming error.
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("glow".to_string()), None)
+            .expect("could not create metrics registry");
+
+        let metrics = Self {
+            events_stored: IntCounter::new("events_stored_total", "Total events stored")
+                .expect("invalid metric"),
+            measurements_stored: IntCounter::new(
+                "measurements_stored_total",
+                "Total environment measurements stored",
+            )
+            .expect("invalid metric"),
+            temperature: Gauge::new(
+                "temperature_celsius",
+                "Temperature of the last stored measurement",
+            )
+            .expect("invalid metric"),
+            humidity: Gauge::new(
+                "humidity_percent",
+                "Relative humidity of the last stored measurement",
+            )
+            .expect("invalid metric"),
+            power: Gauge::new("power_watts", "Power draw of the last heater power reading")
+                .expect("invalid metric"),
+            device_alarming: IntGauge::new(
+                "device_alarming",
+                "Whether the device has stopped sending events",
+            )
+            .expect("invalid metric"),
+            weather_fetch_successes: IntCounter::new(
+                "weather_fetch_successes_total",
+                "Total successful weather updates",
+            )
+            .expect("invalid metric"),
+            weather_fetch_failures: IntCounter::new(
+                "weather_fetch_failures_total",
+                "Total failed weather updates",
+            )
+            .expect("invalid metric"),
+            registry,
+        };
+
+        metrics.register(Box::new(metrics.events_stored.clone()));
+        metrics.register(Box::new(metrics.measurements_stored.clone()));
+        metrics.register(Box::new(metrics.temperature.clone()));
+        metrics.register(Box::new(metrics.humidity.clone()));
+        metrics.register(Box::new(metrics.power.clone()));
+        metrics.register(Box::new(metrics.device_alarming.clone()));
+        metrics.register(Box::new(metrics.weather_fetch_successes.clone()));
+        metrics.register(Box::new(metrics.weather_fetch_failures.clone()));
+
+        metrics
+    }
+
+    fn register(&self, collector: Box<dyn prometheus::core::Collector>) {
+        self.registry
+            .register(collector)
+            .expect("could not register metric");
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
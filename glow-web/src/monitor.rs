@@ -1,41 +1,210 @@
-use std::time::Duration;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use actix::prelude::*;
-use chrono::offset::Utc;
+use chrono::{offset::Utc, DateTime};
 use log::error;
 
+use crate::alert::{self, Alert, Transition, Webhook};
+use crate::metrics::Metrics;
 use crate::store::{Store, StorePool};
 
+/// How long the device can go without sending an event before alarming, in minutes
+pub const DEFAULT_DEVICE_SILENCE_MINUTES: i64 = 3;
+
+/// How far back inside the limits the temperature must come before an alert is cleared
+///
+/// This stops a temperature hovering around a limit from sending a stream of alerts.
+const TEMPERATURE_HYSTERESIS: f64 = 0.5;
+
+/// The band of indoor temperatures that is safe, in °C
+#[derive(Debug, Clone, Copy)]
+pub struct TemperatureLimits {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Default for TemperatureLimits {
+    fn default() -> Self {
+        // below 5°C risks frozen pipes
+        Self {
+            min: 5.0,
+            max: 30.0,
+        }
+    }
+}
+
+/// When the device-silent alarm is snoozed until, shared by the monitor and the API
+///
+/// Snoozing covers planned downtime; the monitor neither logs nor alerts about a silent device
+/// until the snooze runs out.
+#[derive(Clone, Default)]
+pub struct AlarmSnooze(Arc<Mutex<Option<DateTime<Utc>>>>);
+
+impl AlarmSnooze {
+    pub fn snooze_until(&self, until: DateTime<Utc>) {
+        *self.0.lock().unwrap() = Some(until);
+    }
+
+    pub fn is_snoozed(&self, now: DateTime<Utc>) -> bool {
+        matches!(*self.0.lock().unwrap(), Some(until) if now < until)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TemperatureState {
+    Normal,
+    Low,
+    High,
+}
+
 pub struct EventsMonitor<P: StorePool> {
     pool: P,
+    metrics: Metrics,
+    silence: chrono::Duration,
+    webhook: Webhook,
+    alarming: bool,
+    snooze: AlarmSnooze,
+    temperature_limits: TemperatureLimits,
+    temperature_state: TemperatureState,
     count: u32,
 }
 
 impl<P: StorePool + 'static> EventsMonitor<P> {
-    pub fn new(pool: P) -> EventsMonitor<P> {
-        EventsMonitor { pool, count: 0 }
+    pub fn new(
+        pool: P,
+        metrics: Metrics,
+        silence: chrono::Duration,
+        webhook: Webhook,
+        snooze: AlarmSnooze,
+        temperature_limits: TemperatureLimits,
+    ) -> EventsMonitor<P> {
+        EventsMonitor {
+            pool,
+            metrics,
+            silence,
+            webhook,
+            alarming: false,
+            snooze,
+            temperature_limits,
+            temperature_state: TemperatureState::Normal,
+            count: 0,
+        }
     }
 
-    fn hb(&mut self, _ctx: &mut Context<Self>) {
-        if is_alarming(&self.pool.get().unwrap(), self.count) {
-            error!("device not emitting events");
+    fn hb(&mut self, ctx: &mut Context<Self>) {
+        let store = self.pool.get().unwrap();
+        // during known downtime leave the alarm as it was until the snooze runs out
+        if !self.snooze.is_snoozed(Utc::now()) {
+            self.check_silence(ctx, &store);
+        }
+
+        if let Some(temperature) = store
+            .get_latest_measurement()
+            .and_then(|observation| observation.indoor)
+            .map(|measurement| measurement.temperature)
+        {
+            self.check_temperature(ctx, temperature);
         }
         self.count += 1;
     }
+
+    fn check_silence(&mut self, ctx: &mut Context<Self>, store: &P::Store) {
+        let alarming = is_alarming(store, self.count, self.silence);
+        self.metrics.device_alarming.set(i64::from(alarming));
+        if alarming {
+            error!("device not emitting events");
+        }
+
+        if let Some(status) = alert::transition(self.alarming, alarming) {
+            let message = match status {
+                Transition::Raised => format!(
+                    "device has not sent an event for {} minutes",
+                    self.silence.num_minutes()
+                ),
+                Transition::Cleared => "device is sending events again".to_string(),
+            };
+            let alert = Alert::new("device-silent", status, message);
+            ctx.spawn(actix::fut::wrap_future(self.webhook.clone().send(alert)));
+        }
+        self.alarming = alarming;
+    }
+
+    fn check_temperature(&mut self, ctx: &mut Context<Self>, temperature: f64) {
+        let limits = self.temperature_limits;
+        let state = classify_temp(temperature, limits.min, limits.max, self.temperature_state);
+
+        if state != self.temperature_state {
+            let (status, message) = match state {
+                TemperatureState::Low => (
+                    Transition::Raised,
+                    format!(
+                        "temperature {:.1}°C is below {:.1}°C",
+                        temperature, limits.min
+                    ),
+                ),
+                TemperatureState::High => (
+                    Transition::Raised,
+                    format!(
+                        "temperature {:.1}°C is above {:.1}°C",
+                        temperature, limits.max
+                    ),
+                ),
+                TemperatureState::Normal => (
+                    Transition::Cleared,
+                    format!("temperature {:.1}°C is back to normal", temperature),
+                ),
+            };
+            let alert = Alert::new("temperature", status, message);
+            ctx.spawn(actix::fut::wrap_future(self.webhook.clone().send(alert)));
+        }
+        self.temperature_state = state;
+    }
 }
 
-fn is_alarming(store: &impl Store, count: u32) -> bool {
+/// Classify a temperature against the safe band
+///
+/// Crossing a limit changes the state straight away but getting back to normal needs the
+/// temperature to come `TEMPERATURE_HYSTERESIS` back inside the band.
+pub(crate) fn classify_temp(
+    temperature: f64,
+    min: f64,
+    max: f64,
+    previous: TemperatureState,
+) -> TemperatureState {
+    if temperature < min {
+        TemperatureState::Low
+    } else if temperature > max {
+        TemperatureState::High
+    } else {
+        match previous {
+            TemperatureState::Low if temperature < min + TEMPERATURE_HYSTERESIS => {
+                TemperatureState::Low
+            }
+            TemperatureState::High if temperature > max - TEMPERATURE_HYSTERESIS => {
+                TemperatureState::High
+            }
+            _ => TemperatureState::Normal,
+        }
+    }
+}
+
+fn is_alarming(store: &impl Store, count: u32, silence: chrono::Duration) -> bool {
     match store.get_latest_event() {
         // If we have an event check how recently it was received
-        Some(event) => {
-            let elapsed = Utc::now().signed_duration_since(event.stamp());
-            elapsed > chrono::Duration::minutes(3)
-        }
+        Some(event) => is_stale(Utc::now().signed_duration_since(event.stamp()), silence),
         // If we have no events check that we've been up for a little while
         None => count > 10,
     }
 }
 
+/// Whether the latest event is old enough that the device looks to have stopped
+pub(crate) fn is_stale(age: chrono::Duration, silence: chrono::Duration) -> bool {
+    age > silence
+}
+
 impl<P: StorePool + 'static> Actor for EventsMonitor<P> {
     type Context = Context<Self>;
 
@@ -47,3 +216,97 @@ impl<P: StorePool + 'static> Actor for EventsMonitor<P> {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use glow_events::v2::{Event, Message, Payload};
+
+    use super::*;
+    use crate::store::{
+        test::{now, TestDb},
+        DEFAULT_DEVICE,
+    };
+
+    #[test]
+    fn alarming_uses_configured_silence() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        store
+            .add_event(
+                DEFAULT_DEVICE,
+                &Message::raw(
+                    Utc::now() - Duration::minutes(5),
+                    Payload::Event(Event::SingleTap),
+                ),
+            )
+            .unwrap();
+
+        // assert
+        assert!(is_alarming(&store, 0, Duration::minutes(3)));
+        assert!(!is_alarming(&store, 0, Duration::minutes(10)));
+    }
+
+    #[test]
+    fn not_alarming_without_events_at_startup() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+
+        // assert
+        assert!(!is_alarming(&store, 0, Duration::minutes(3)));
+        assert!(is_alarming(&store, 11, Duration::minutes(3)));
+    }
+
+    #[test]
+    fn snooze_suppresses_alarms_within_the_window() {
+        // arrange
+        let snooze = AlarmSnooze::default();
+        let start = now();
+
+        // act
+        snooze.snooze_until(start + Duration::minutes(30));
+
+        // assert
+        assert!(snooze.is_snoozed(start));
+        assert!(snooze.is_snoozed(start + Duration::minutes(29)));
+        assert!(!snooze.is_snoozed(start + Duration::minutes(30)));
+        assert!(!snooze.is_snoozed(start + Duration::hours(2)));
+    }
+
+    #[test]
+    fn not_snoozed_by_default() {
+        assert!(!AlarmSnooze::default().is_snoozed(now()));
+    }
+
+    #[test]
+    fn classify_temp_transitions() {
+        use TemperatureState::{High, Low, Normal};
+
+        let cases = [
+            // (temperature, previous, expected)
+            (20.0, Normal, Normal),
+            (4.9, Normal, Low),
+            (5.0, Normal, Normal),
+            (5.2, Low, Low),
+            (5.5, Low, Normal),
+            (3.0, Low, Low),
+            (30.1, Normal, High),
+            (29.8, High, High),
+            (29.5, High, Normal),
+            (4.0, High, Low),
+            (31.0, Low, High),
+        ];
+
+        for (temperature, previous, expected) in &cases {
+            assert_eq!(
+                classify_temp(*temperature, 5.0, 30.0, *previous),
+                *expected,
+                "{} from {:?}",
+                temperature,
+                previous
+            );
+        }
+    }
+}
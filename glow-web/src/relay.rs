@@ -0,0 +1,196 @@
+//! Relaying stored events to another webhook
+//!
+//! Every batch the device sends is posted on once it is stored, for home automation and the
+//! like. Relaying is fire-and-forget: a batch is retried a few times and then dropped, and the
+//! device never waits for it.
+use std::{sync::Arc, time::Duration};
+
+use eyre::Result;
+use futures::future::{FutureExt, LocalBoxFuture};
+use glow_events::v2::Message;
+use log::error;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::alert::post_json;
+use crate::retry::with_retries;
+
+const RELAY_ATTEMPTS: u32 = 3;
+const RELAY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The body posted for each batch
+#[derive(Debug, Serialize)]
+struct RelayedBatch<'a> {
+    device: &'a str,
+    events: &'a [Message],
+}
+
+/// Somewhere to post relayed batches
+pub trait Poster: Send + Sync {
+    fn post(&self, body: Value) -> LocalBoxFuture<'static, Result<()>>;
+}
+
+/// Posts to a URL, with a bearer token if one is set
+struct WebhookPoster {
+    url: String,
+    token: Option<String>,
+}
+
+impl Poster for WebhookPoster {
+    fn post(&self, body: Value) -> LocalBoxFuture<'static, Result<()>> {
+        let url = self.url.clone();
+        let token = self.token.clone();
+        async move { post_json(&url, token.as_deref(), &body).await }.boxed_local()
+    }
+}
+
+/// Where stored events are relayed, nothing is relayed if no url is configured
+#[derive(Clone, Default)]
+pub struct EventRelay {
+    poster: Option<Arc<dyn Poster>>,
+    retry_delay: Duration,
+}
+
+impl EventRelay {
+    pub fn new(url: Option<String>, token: Option<String>) -> Self {
+        Self {
+            poster: url.map(|url| Arc::new(WebhookPoster { url, token }) as Arc<dyn Poster>),
+            retry_delay: RELAY_RETRY_DELAY,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_poster(poster: impl Poster + 'static) -> Self {
+        Self {
+            poster: Some(Arc::new(poster)),
+            retry_delay: Duration::from_millis(1),
+        }
+    }
+
+    /// Post a stored batch in the background
+    pub fn forward(&self, device: &str, events: &[Message]) {
+        let poster = match &self.poster {
+            Some(poster) if !events.is_empty() => poster.clone(),
+            _ => return,
+        };
+        let body = match serde_json::to_value(RelayedBatch { device, events }) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("failed to serialize events to relay: {}", err);
+                return;
+            }
+        };
+        let count = events.len();
+        let retry_delay = self.retry_delay;
+        actix_rt::spawn(async move {
+            if let Err(err) =
+                with_retries(RELAY_ATTEMPTS, retry_delay, || poster.post(body.clone())).await
+            {
+                error!("failed to relay {} events: {}", count, err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use eyre::eyre;
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+    use super::*;
+
+    /// Stands in for the webhook, failing a fixed number of times before accepting batches
+    pub struct RecordingPoster {
+        failures: AtomicU32,
+        posted: UnboundedSender<Value>,
+    }
+
+    impl RecordingPoster {
+        pub fn new(failures: u32) -> (Self, UnboundedReceiver<Value>) {
+            let (posted, received) = unbounded();
+            (
+                Self {
+                    failures: AtomicU32::new(failures),
+                    posted,
+                },
+                received,
+            )
+        }
+    }
+
+    impl Poster for RecordingPoster {
+        fn post(&self, body: Value) -> LocalBoxFuture<'static, Result<()>> {
+            self.posted.unbounded_send(body).unwrap();
+            let remaining = self.failures.load(Ordering::SeqCst);
+            let result = if remaining > 0 {
+                self.failures.store(remaining - 1, Ordering::SeqCst);
+                Err(eyre!("failed to connect"))
+            } else {
+                Ok(())
+            };
+            futures::future::ready(result).boxed_local()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use glow_events::v2::Event;
+
+    use super::test::RecordingPoster;
+    use super::*;
+
+    #[actix_rt::test]
+    async fn forwards_the_batch() {
+        // arrange
+        let (poster, mut posted) = RecordingPoster::new(0);
+        let relay = EventRelay::with_poster(poster);
+        let events = vec![
+            Message::new_event(Event::SingleTap),
+            Message::new_event(Event::DoubleTap),
+        ];
+
+        // act
+        relay.forward("kitchen", &events);
+
+        // assert
+        let body = posted.next().await.unwrap();
+        assert_eq!(body["device"], "kitchen");
+        assert_eq!(
+            serde_json::from_value::<Vec<Message>>(body["events"].clone()).unwrap(),
+            events
+        );
+    }
+
+    #[actix_rt::test]
+    async fn retries_failed_posts() {
+        // arrange
+        let (poster, mut posted) = RecordingPoster::new(2);
+        let relay = EventRelay::with_poster(poster);
+
+        // act
+        relay.forward("kitchen", &[Message::new_event(Event::SingleTap)]);
+
+        // assert
+        let attempts = posted.by_ref().take(3).collect::<Vec<_>>().await;
+        assert_eq!(attempts.len(), 3);
+        assert!(attempts.iter().all(|body| body == &attempts[0]));
+    }
+
+    #[actix_rt::test]
+    async fn empty_batches_are_not_relayed() {
+        // arrange
+        let (poster, mut posted) = RecordingPoster::new(0);
+        let relay = EventRelay::with_poster(poster);
+
+        // act
+        relay.forward("kitchen", &[]);
+        drop(relay);
+
+        // assert
+        assert_eq!(posted.next().await, None);
+    }
+}
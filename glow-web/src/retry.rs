@@ -0,0 +1,33 @@
+//! Retrying flaky async operations
+use std::{future::Future, time::Duration};
+
+use eyre::Result;
+use log::error;
+
+/// Retry a fallible async operation with exponential backoff
+///
+/// The operation is tried up to `attempts` times, waiting `delay` after the first failure
+/// and doubling the wait after each subsequent failure. The last error is returned.
+pub async fn with_retries<T, F, R>(attempts: u32, delay: Duration, mut operation: F) -> Result<T>
+where
+    F: FnMut() -> R,
+    R: Future<Output = Result<T>>,
+{
+    let mut delay = delay;
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < attempts => {
+                error!(
+                    "attempt {} of {} failed, retrying in {:?}: {}",
+                    attempt, attempts, delay, err
+                );
+                actix_rt::time::delay_for(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
@@ -1,27 +1,61 @@
-use actix_web::{error, http, web, Error, HttpResponse, Responder};
-use serde_json::json;
+use actix_web::{error, http, web, Error, HttpRequest, HttpResponse, Responder};
+use actix_web_actors::ws;
+use log::error;
 
-use glow_events::v2::Message;
+use glow_events::v2::{Message, CBOR_CONTENT_TYPE, DEVICE_HEADER};
 
 use crate::{
+    api_error::ApiError,
     controllers,
+    metrics::Metrics,
+    monitor::AlarmSnooze,
+    relay::EventRelay,
     session::ActixSession,
-    store,
-    view::data::{Login, SetBrightness},
+    socket::CommandSocket,
+    store::{self, Store, StorePool},
+    view::data::{
+        EventsQuery, EventsRangeQuery, HistogramQuery, IndexQuery, Login, RunHeater, SelectDevice,
+        SetBrightness, SetSolidColour, SnoozeQuery,
+    },
     view::{TeraView, View},
+    weather::BBCWeatherMonitor,
     AppData,
 };
 
-pub async fn status() -> impl Responder {
-    HttpResponse::Ok().json(json!({"status": "ok"}))
+pub async fn status(store: store::SQLiteStore, state: web::Data<AppData>) -> impl Responder {
+    HttpResponse::Ok().json(controllers::status(&store, state.device_silence))
 }
 
+/// Readiness check that the database can be queried, without authentication
+pub async fn healthz(pool: web::Data<store::SQLiteStorePool>) -> HttpResponse {
+    match pool.get().and_then(|store| store.ping()) {
+        Ok(()) => HttpResponse::Ok().body("ok"),
+        Err(err) => {
+            error!("health check failed: {:?}", err);
+            HttpResponse::ServiceUnavailable().body("database unavailable")
+        }
+    }
+}
+
+pub async fn metrics(metrics: web::Data<Metrics>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(map_err(metrics.render())?))
+}
+
+/// The dashboard, showing the latest 20 events unless `?events=` asks for more (up to 200)
 pub async fn index(
     store: store::SQLiteStore,
     mut view: TeraView,
     mut session: ActixSession,
+    query: web::Query<IndexQuery>,
 ) -> Result<HttpResponse, Error> {
-    ok_html(controllers::index(&store, &mut view, &mut session))
+    ok_html(controllers::index(
+        &store,
+        &mut view,
+        &mut session,
+        query.events,
+    ))
 }
 
 // brightness will only ever be between 1 and 100
@@ -40,6 +74,29 @@ pub async fn set_brightness(
     Ok(found("/"))
 }
 
+pub async fn set_solid_colour(
+    form: web::Form<SetSolidColour>,
+    store: store::SQLiteStore,
+    mut session: ActixSession,
+) -> Result<HttpResponse, Error> {
+    map_err(controllers::set_solid_colour(
+        &store,
+        &mut session,
+        &form.colour,
+    ))?;
+
+    Ok(found("/"))
+}
+
+pub async fn resume_auto(
+    store: store::SQLiteStore,
+    mut session: ActixSession,
+) -> Result<HttpResponse, Error> {
+    map_err(controllers::resume_auto(&store, &mut session))?;
+
+    Ok(found("/"))
+}
+
 pub async fn list_devices(
     store: store::SQLiteStore,
     mut session: ActixSession,
@@ -49,11 +106,21 @@ pub async fn list_devices(
     Ok(found("/"))
 }
 
+pub async fn self_test(
+    store: store::SQLiteStore,
+    mut session: ActixSession,
+) -> Result<HttpResponse, Error> {
+    map_err(controllers::self_test(&store, &mut session))?;
+
+    Ok(found("/"))
+}
+
 pub async fn run_heater(
     store: store::SQLiteStore,
     mut session: ActixSession,
+    form: web::Form<RunHeater>,
 ) -> Result<HttpResponse, Error> {
-    map_err(controllers::run_heater(&store, &mut session))?;
+    map_err(controllers::run_heater(&store, &mut session, form.duration))?;
 
     Ok(found("/"))
 }
@@ -76,6 +143,33 @@ pub async fn stop_device(
     Ok(found("/"))
 }
 
+pub async fn clear_commands(
+    store: store::SQLiteStore,
+    mut session: ActixSession,
+) -> Result<HttpResponse, Error> {
+    map_err(controllers::clear_commands(&store, &mut session))?;
+
+    Ok(found("/"))
+}
+
+pub async fn refresh_weather(
+    monitor: web::Data<BBCWeatherMonitor>,
+    mut session: ActixSession,
+) -> Result<HttpResponse, Error> {
+    map_err(controllers::refresh_weather(monitor.get_ref().clone(), &mut session).await)?;
+
+    Ok(found("/"))
+}
+
+pub async fn select_device(
+    session: ActixSession,
+    form: web::Form<SelectDevice>,
+) -> Result<HttpResponse, Error> {
+    map_err(controllers::select_device(&session, &form.device))?;
+
+    Ok(found("/"))
+}
+
 pub async fn login(view: TeraView) -> impl Responder {
     ok_html(view.render("login.html"))
 }
@@ -101,15 +195,186 @@ pub async fn logout(session: ActixSession) -> Result<HttpResponse, Error> {
     Ok(found("/login"))
 }
 
+/// Store events from the device and return any queued commands
+///
+/// Events can be sent as JSON or CBOR, commands are returned as CBOR if the device accepts it.
+/// Bodies sent with `Content-Encoding: gzip` are decompressed by the `Bytes` extractor, which
+/// also enforces the `PayloadConfig` limit set on the resource.
 pub async fn store_events(
+    req: HttpRequest,
     store: store::SQLiteStore,
-    events: web::Json<Vec<Message>>,
-) -> Result<HttpResponse, Error> {
-    Ok(HttpResponse::Ok().json(map_err(controllers::store_events(&store, &events.0))?))
+    metrics: web::Data<Metrics>,
+    relay: web::Data<EventRelay>,
+    body: Result<web::Bytes, Error>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.map_err(unreadable_batch)?;
+    let incoming = if header_is_cbor(&req, http::header::CONTENT_TYPE) {
+        serde_cbor::from_slice::<Vec<_>>(&body)
+            .map_err(not_a_batch)?
+            .into_iter()
+            .map(controllers::IncomingEvent::from_cbor)
+            .collect()
+    } else {
+        serde_json::from_slice::<Vec<_>>(&body)
+            .map_err(not_a_batch)?
+            .into_iter()
+            .map(controllers::IncomingEvent::from_json)
+            .collect()
+    };
+    let events = controllers::validate_events(incoming).map_err(ApiError::bad_request)?;
+    let device = device_id(&req);
+    let commands = controllers::store_events(&store, &metrics, &device, &events)?;
+    relay.forward(&device, &events);
+
+    if header_is_cbor(&req, http::header::ACCEPT) {
+        Ok(HttpResponse::Ok()
+            .content_type(CBOR_CONTENT_TYPE)
+            .body(Message::batch_to_cbor(&commands).map_err(ApiError::internal)?))
+    } else {
+        Ok(HttpResponse::Ok().json(commands))
+    }
+}
+
+fn not_a_batch(err: impl std::fmt::Display) -> ApiError {
+    ApiError::bad_request(format!("expected a list of events: {}", err))
+}
+
+fn unreadable_batch(err: impl std::fmt::Display) -> ApiError {
+    ApiError::bad_request(format!(
+        "could not read the events: {} Send smaller batches or raise EVENTS_BODY_LIMIT_BYTES.",
+        err
+    ))
 }
 
-pub async fn list_events(store: store::SQLiteStore) -> Result<HttpResponse, Error> {
-    Ok(HttpResponse::Ok().json(map_err(controllers::list_events(&store))?))
+fn header_is_cbor(req: &HttpRequest, name: http::header::HeaderName) -> bool {
+    matches!(
+        req.headers().get(name).and_then(|value| value.to_str().ok()),
+        Some(value) if value.contains(CBOR_CONTENT_TYPE)
+    )
+}
+
+/// The device a request came from, devices that do not identify themselves share a default
+fn device_id(req: &HttpRequest) -> String {
+    req.headers()
+        .get(DEVICE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .unwrap_or(store::DEFAULT_DEVICE)
+        .to_string()
+}
+
+/// What the LED strip is showing now, no content until it has reported any colours
+pub async fn leds(
+    store: store::SQLiteStore,
+    session: ActixSession,
+) -> Result<HttpResponse, ApiError> {
+    Ok(match controllers::leds(&store, &session)? {
+        Some(state) => HttpResponse::Ok().json(state),
+        None => HttpResponse::NoContent().finish(),
+    })
+}
+
+/// The TP-Link devices found by the latest discovery
+pub async fn devices(
+    store: store::SQLiteStore,
+    session: ActixSession,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(controllers::devices(&store, &session)?))
+}
+
+/// Count indoor temperatures into buckets, over `?hours=` (default 24) in `?width=` °C wide
+/// buckets (default 1)
+pub async fn histogram(
+    store: store::SQLiteStore,
+    query: web::Query<HistogramQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let hours = query.hours.unwrap_or(controllers::DEFAULT_HISTOGRAM_HOURS);
+    let width = query.width.unwrap_or(controllers::DEFAULT_HISTOGRAM_WIDTH);
+    if hours <= 0 {
+        return Err(ApiError::bad_request("hours must be above zero"));
+    }
+    if !(width.is_finite() && width > 0.0) {
+        return Err(ApiError::bad_request("width must be above zero"));
+    }
+    Ok(HttpResponse::Ok().json(controllers::temperature_histogram(&store, hours, width)?))
+}
+
+/// List the latest events, optionally only those of one type with `?type=led.colours`
+pub async fn list_events(
+    store: store::SQLiteStore,
+    query: web::Query<EventsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(controllers::list_events(
+        &store,
+        query.event_type.as_deref(),
+    )?))
+}
+
+/// Delete every stored event and measurement, only when `ALLOW_DESTRUCTIVE` is set
+///
+/// For wiping staging servers, responds with how many rows were deleted.
+pub async fn delete_events(
+    store: store::SQLiteStore,
+    state: web::Data<AppData>,
+) -> Result<HttpResponse, ApiError> {
+    if !state.allow_destructive {
+        return Err(ApiError::forbidden(
+            "deleting events is turned off, set ALLOW_DESTRUCTIVE=true to allow it",
+        ));
+    }
+    let deleted = store.delete_all_events()?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "deleted": deleted })))
+}
+
+/// List events stored after the one with the id in the path, oldest first
+///
+/// Start from 0 then poll with the highest id seen to only get new events.
+pub async fn list_events_after(
+    store: store::SQLiteStore,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(controllers::list_events_after(&store, id.into_inner())?))
+}
+
+/// List events stamped between `?from=` and `?to=`, both RFC 3339 timestamps
+pub async fn list_events_between(
+    store: store::SQLiteStore,
+    query: web::Query<EventsRangeQuery>,
+) -> Result<HttpResponse, ApiError> {
+    if query.from > query.to {
+        return Err(ApiError::bad_request("from must not be after to"));
+    }
+    Ok(HttpResponse::Ok().json(controllers::list_events_between(
+        &store, query.from, query.to,
+    )?))
+}
+
+/// Snooze the device-silent alarm for `?minutes=` (default 60) during planned downtime
+pub async fn snooze_alarm(
+    snooze: web::Data<AlarmSnooze>,
+    query: web::Query<SnoozeQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let minutes = query.minutes.unwrap_or(controllers::DEFAULT_SNOOZE_MINUTES);
+    if !(0..=controllers::MAX_SNOOZE_MINUTES).contains(&minutes) {
+        return Err(ApiError::bad_request(format!(
+            "minutes must be between 0 and {}",
+            controllers::MAX_SNOOZE_MINUTES
+        )));
+    }
+    Ok(HttpResponse::Ok().json(controllers::snooze_alarm(&snooze, minutes)))
+}
+
+pub async fn command_socket(
+    req: HttpRequest,
+    stream: web::Payload,
+    pool: web::Data<store::SQLiteStorePool>,
+) -> Result<HttpResponse, Error> {
+    ws::start(
+        CommandSocket::new(pool.get_ref().clone(), device_id(&req)),
+        &req,
+        stream,
+    )
 }
 
 pub(crate) fn found<B>(location: &str) -> HttpResponse<B> {
@@ -129,3 +394,531 @@ fn ok_html(body: eyre::Result<String>) -> Result<HttpResponse, Error> {
 fn map_err<T>(r: eyre::Result<T>) -> Result<T, Error> {
     r.map_err(error::ErrorInternalServerError)
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test, web, App,
+    };
+    use futures::StreamExt;
+    use glow_events::v2::{Command, Event, Message, Payload, CBOR_CONTENT_TYPE, DEVICE_HEADER};
+    use glow_events::Measurement;
+
+    use crate::data::StoredMessage;
+    use crate::metrics::Metrics;
+    use crate::monitor::AlarmSnooze;
+    use crate::relay::{test::RecordingPoster, EventRelay};
+    use crate::store::{
+        self,
+        test::{now, TestDb},
+        SQLiteStorePool, Store,
+    };
+    use crate::view::data::{AlarmSnoozed, TemperatureBucket};
+
+    #[actix_rt::test]
+    async fn metrics_are_exposed() {
+        // arrange
+        let metrics = Metrics::new();
+        metrics.events_stored.inc();
+        let mut app = test::init_service(
+            App::new()
+                .data(metrics)
+                .route("/metrics", web::get().to(super::metrics)),
+        )
+        .await;
+
+        // act
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = String::from_utf8(test::read_response(&mut app, req).await.to_vec()).unwrap();
+
+        // assert
+        assert!(body.contains("glow_events_stored_total 1"));
+        assert!(body.contains("# TYPE glow_measurements_stored_total counter"));
+        assert!(body.contains("# TYPE glow_temperature_celsius gauge"));
+        assert!(body.contains("# TYPE glow_humidity_percent gauge"));
+        assert!(body.contains("glow_weather_fetch_successes_total"));
+        assert!(body.contains("glow_weather_fetch_failures_total"));
+    }
+
+    #[actix_rt::test]
+    async fn healthz_checks_the_database() {
+        // arrange
+        let mut app = test::init_service(
+            App::new()
+                .data(SQLiteStorePool::memory())
+                .route("/healthz", web::get().to(super::healthz)),
+        )
+        .await;
+
+        // act
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn command_socket_requires_websocket_upgrade() {
+        // arrange
+        let mut app = test::init_service(
+            App::new()
+                .data(SQLiteStorePool::memory())
+                .route("/ws", web::get().to(super::command_socket)),
+        )
+        .await;
+
+        // act
+        let req = test::TestRequest::get().uri("/ws").to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn store_events_accepts_cbor() {
+        // arrange
+        let db = TestDb::with_now(now);
+        db.store()
+            .unwrap()
+            .queue_command(store::DEFAULT_DEVICE, Command::RunHeater)
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(db.pool().clone())
+                .data(Metrics::new())
+                .data(EventRelay::default())
+                .route("/events", web::post().to(super::store_events)),
+        )
+        .await;
+        let events = vec![Message::new_event(Event::Measurement(Measurement::new(
+            21.5, 40.0,
+        )))];
+
+        // act
+        let req = test::TestRequest::post()
+            .uri("/events")
+            .header(header::CONTENT_TYPE, CBOR_CONTENT_TYPE)
+            .header(header::ACCEPT, CBOR_CONTENT_TYPE)
+            .set_payload(Message::batch_to_cbor(&events).unwrap())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            CBOR_CONTENT_TYPE
+        );
+        let commands = Message::batch_from_cbor(&test::read_body(resp).await).unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(*commands[0].payload(), Payload::Command(Command::RunHeater));
+        assert!(db.store().unwrap().get_latest_measurement().is_some());
+    }
+
+    #[actix_rt::test]
+    async fn store_events_scopes_to_device_header() {
+        // arrange
+        let db = TestDb::with_now(now);
+        {
+            let store = db.store().unwrap();
+            store.queue_command("kitchen", Command::RunHeater).unwrap();
+            store
+                .queue_command(store::DEFAULT_DEVICE, Command::StopHeater)
+                .unwrap();
+        }
+        let mut app = test::init_service(
+            App::new()
+                .data(db.pool().clone())
+                .data(Metrics::new())
+                .data(EventRelay::default())
+                .route("/events", web::post().to(super::store_events)),
+        )
+        .await;
+        let events = vec![Message::new_event(Event::SingleTap)];
+
+        // act
+        let req = test::TestRequest::post()
+            .uri("/events")
+            .header(DEVICE_HEADER, "kitchen")
+            .set_json(&events)
+            .to_request();
+        let commands: Vec<Message> = test::read_response_json(&mut app, req).await;
+
+        // assert
+        let store = db.store().unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(*commands[0].payload(), Payload::Command(Command::RunHeater));
+        assert_eq!(
+            store.get_latest_device_events("kitchen", 10).unwrap().len(),
+            1
+        );
+        assert!(store
+            .get_latest_device_events(store::DEFAULT_DEVICE, 10)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn store_events_relays_the_batch() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let (poster, mut posted) = RecordingPoster::new(0);
+        let mut app = test::init_service(
+            App::new()
+                .data(db.pool().clone())
+                .data(Metrics::new())
+                .data(EventRelay::with_poster(poster))
+                .route("/events", web::post().to(super::store_events)),
+        )
+        .await;
+        let events = vec![Message::new_event(Event::SingleTap)];
+
+        // act
+        let req = test::TestRequest::post()
+            .uri("/events")
+            .header(DEVICE_HEADER, "kitchen")
+            .set_json(&events)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = posted.next().await.unwrap();
+        assert_eq!(body["device"], "kitchen");
+        assert_eq!(
+            serde_json::from_value::<Vec<Message>>(body["events"].clone()).unwrap(),
+            events
+        );
+    }
+
+    #[actix_rt::test]
+    async fn store_events_accepts_gzipped_batches() {
+        // arrange
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let db = TestDb::with_now(now);
+        let mut app = test::init_service(
+            App::new()
+                .data(db.pool().clone())
+                .data(Metrics::new())
+                .data(EventRelay::default())
+                .route("/events", web::post().to(super::store_events)),
+        )
+        .await;
+        let events = vec![
+            Message::new_event(Event::SingleTap),
+            Message::new_event(Event::DoubleTap),
+        ];
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&serde_json::to_vec(&events).unwrap())
+            .unwrap();
+
+        // act
+        let req = test::TestRequest::post()
+            .uri("/events")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .set_payload(encoder.finish().unwrap())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(db.store().unwrap().get_latest_events(10).unwrap().len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn store_events_rejects_oversized_batches() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let mut app = test::init_service(
+            App::new()
+                .data(db.pool().clone())
+                .data(Metrics::new())
+                .data(EventRelay::default())
+                .service(
+                    web::resource("/events")
+                        .app_data(web::PayloadConfig::new(64))
+                        .route(web::post().to(super::store_events)),
+                ),
+        )
+        .await;
+        let events = vec![Message::new_event(Event::SingleTap); 10];
+
+        // act
+        let req = test::TestRequest::post()
+            .uri("/events")
+            .header(header::CONTENT_TYPE, "application/json")
+            .set_payload(serde_json::to_vec(&events).unwrap())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("EVENTS_BODY_LIMIT_BYTES"), "{}", body);
+        assert!(db
+            .store()
+            .unwrap()
+            .get_latest_events(10)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn store_events_rejects_v1_events() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let mut app = test::init_service(
+            App::new()
+                .data(db.pool().clone())
+                .data(Metrics::new())
+                .data(EventRelay::default())
+                .route("/events", web::post().to(super::store_events)),
+        )
+        .await;
+        let events = vec![glow_events::v1::Event::new_measurement(21.5, 40.0)];
+
+        // act
+        let req = test::TestRequest::post()
+            .uri("/events")
+            .set_json(&events)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("event 0 is a v1 event"));
+        assert!(db.store().unwrap().get_latest_event().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn list_events_after_includes_the_ids() {
+        // arrange
+        let db = TestDb::with_now(now);
+        db.store()
+            .unwrap()
+            .add_event(store::DEFAULT_DEVICE, &Message::new_event(Event::SingleTap))
+            .unwrap();
+        let mut app = test::init_service(App::new().data(db.pool().clone()).route(
+            "/events/after/{id}",
+            web::get().to(super::list_events_after),
+        ))
+        .await;
+
+        // act
+        let req = test::TestRequest::get().uri("/events/after/0").to_request();
+        let events: Vec<StoredMessage> = test::read_response_json(&mut app, req).await;
+
+        // assert
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            *events[0].message.payload(),
+            Payload::Event(Event::SingleTap)
+        );
+        let req = test::TestRequest::get()
+            .uri(&format!("/events/after/{}", events[0].id))
+            .to_request();
+        let events: Vec<StoredMessage> = test::read_response_json(&mut app, req).await;
+        assert!(events.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn list_events_between_rejects_inverted_ranges() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let mut app = test::init_service(
+            App::new()
+                .data(db.pool().clone())
+                .route("/events/range", web::get().to(super::list_events_between)),
+        )
+        .await;
+
+        // act
+        let req = test::TestRequest::get()
+            .uri("/events/range?from=2012-12-12T12:00:00Z&to=2012-12-12T11:00:00Z")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn histogram_buckets_temperatures() {
+        // arrange
+        let db = TestDb::with_now(now);
+        {
+            let store = db.store().unwrap();
+            for temperature in &[19.5, 20.5, 21.0] {
+                store
+                    .add_measurement(now(), &Measurement::new(*temperature, 50.0))
+                    .unwrap();
+            }
+        }
+        let mut app = test::init_service(
+            App::new()
+                .data(db.pool().clone())
+                .route("/histogram", web::get().to(super::histogram)),
+        )
+        .await;
+
+        // act
+        let req = test::TestRequest::get()
+            .uri("/histogram?width=2")
+            .to_request();
+        let buckets: Vec<TemperatureBucket> = test::read_response_json(&mut app, req).await;
+
+        // assert
+        assert_eq!(
+            buckets,
+            vec![
+                TemperatureBucket {
+                    from: 18.0,
+                    to: 20.0,
+                    count: 1
+                },
+                TemperatureBucket {
+                    from: 20.0,
+                    to: 22.0,
+                    count: 2
+                },
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn histogram_rejects_empty_buckets() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let mut app = test::init_service(
+            App::new()
+                .data(db.pool().clone())
+                .route("/histogram", web::get().to(super::histogram)),
+        )
+        .await;
+
+        // act
+        let req = test::TestRequest::get()
+            .uri("/histogram?width=0")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "error": "width must be above zero" })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn snooze_alarm_snoozes_the_monitor() {
+        // arrange
+        let snooze = AlarmSnooze::default();
+        let mut app = test::init_service(
+            App::new()
+                .data(snooze.clone())
+                .route("/alarm/snooze", web::post().to(super::snooze_alarm)),
+        )
+        .await;
+
+        // act
+        let req = test::TestRequest::post()
+            .uri("/alarm/snooze?minutes=30")
+            .to_request();
+        let snoozed: AlarmSnoozed = test::read_response_json(&mut app, req).await;
+
+        // assert
+        assert!(snooze.is_snoozed(snoozed.until - chrono::Duration::seconds(1)));
+        assert!(!snooze.is_snoozed(snoozed.until));
+        assert!(snoozed.until > chrono::Utc::now() + chrono::Duration::minutes(29));
+    }
+
+    #[actix_rt::test]
+    async fn snooze_alarm_rejects_negative_minutes() {
+        // arrange
+        let mut app = test::init_service(
+            App::new()
+                .data(AlarmSnooze::default())
+                .route("/alarm/snooze", web::post().to(super::snooze_alarm)),
+        )
+        .await;
+
+        // act
+        let req = test::TestRequest::post()
+            .uri("/alarm/snooze?minutes=-5")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    fn app_data(allow_destructive: bool) -> crate::AppData {
+        crate::AppData {
+            token: "token".to_string(),
+            password: "password".to_string(),
+            device_silence: chrono::Duration::minutes(3),
+            allow_destructive,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn delete_events_is_refused_unless_allowed() {
+        // arrange
+        let db = TestDb::with_now(now);
+        db.store()
+            .unwrap()
+            .add_event(store::DEFAULT_DEVICE, &Message::new_event(Event::SingleTap))
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(db.pool().clone())
+                .data(app_data(false))
+                .route("/events", web::delete().to(super::delete_events)),
+        )
+        .await;
+
+        // act
+        let req = test::TestRequest::delete().uri("/events").to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(db.store().unwrap().get_latest_event().is_some());
+    }
+
+    #[actix_rt::test]
+    async fn delete_events_reports_the_rows_deleted() {
+        // arrange
+        let db = TestDb::with_now(now);
+        db.store()
+            .unwrap()
+            .add_event(store::DEFAULT_DEVICE, &Message::new_event(Event::SingleTap))
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(db.pool().clone())
+                .data(app_data(true))
+                .route("/events", web::delete().to(super::delete_events)),
+        )
+        .await;
+
+        // act
+        let req = test::TestRequest::delete().uri("/events").to_request();
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        // assert
+        assert_eq!(body, serde_json::json!({ "deleted": 1 }));
+        assert!(db.store().unwrap().get_latest_event().is_none());
+    }
+}
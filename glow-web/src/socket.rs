@@ -0,0 +1,96 @@
+//! Push queued commands to the device over a websocket
+//!
+//! The device still posts its events to `/api/events`, but while it holds a socket open
+//! commands are delivered as soon as they are queued rather than on the next poll.
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_web_actors::ws;
+use log::{error, info};
+
+use crate::store::{Store, StorePool};
+
+const COMMAND_INTERVAL: Duration = Duration::from_secs(1);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct CommandSocket<P: StorePool> {
+    pool: P,
+    device: String,
+    heartbeat: Instant,
+}
+
+impl<P: StorePool + 'static> CommandSocket<P> {
+    pub fn new(pool: P, device: String) -> Self {
+        Self {
+            pool,
+            device,
+            heartbeat: Instant::now(),
+        }
+    }
+
+    fn push_commands(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        let commands = self
+            .pool
+            .get()
+            .and_then(|store| store.dequeue_commands(&self.device));
+
+        match commands {
+            Ok(commands) => {
+                for command in commands {
+                    match serde_json::to_string(&command) {
+                        Ok(command) => ctx.text(command),
+                        Err(err) => error!("failed to serialize command: {}", err),
+                    }
+                }
+            }
+            Err(err) => error!("failed to dequeue commands: {}", err),
+        }
+    }
+
+    fn check_heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        if Instant::now().duration_since(self.heartbeat) > CLIENT_TIMEOUT {
+            info!("command socket heartbeat failed, disconnecting");
+            ctx.stop();
+        } else {
+            ctx.ping(b"");
+        }
+    }
+}
+
+impl<P: StorePool + 'static> Actor for CommandSocket<P> {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("device {} connected to command socket", self.device);
+
+        ctx.run_interval(COMMAND_INTERVAL, |act, ctx| act.push_commands(ctx));
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| act.check_heartbeat(ctx));
+    }
+}
+
+impl<P: StorePool + 'static> StreamHandler<Result<ws::Message, ws::ProtocolError>>
+    for CommandSocket<P>
+{
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                self.heartbeat = Instant::now();
+                ctx.pong(&msg);
+            }
+            Ok(ws::Message::Pong(_)) => {
+                self.heartbeat = Instant::now();
+            }
+            Ok(ws::Message::Close(reason)) => {
+                info!("device closed command socket: {:?}", reason);
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => {}
+            Err(err) => {
+                error!("command socket protocol error: {}", err);
+                ctx.stop();
+            }
+        }
+    }
+}
@@ -4,18 +4,19 @@ use std::{
 };
 
 use actix_web::FromRequest;
-use chrono::{DateTime, Duration, DurationRound, Utc};
-use eyre::{Result, WrapErr};
+use chrono::{DateTime, Duration, DurationRound, TimeZone, Utc};
+use eyre::{eyre, Result, WrapErr};
 use fallible_iterator::FallibleIterator;
 use futures::future::{err, ok, Ready};
 use itertools::Itertools;
+use log::info;
 use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::{self, SqliteConnectionManager};
 use rand::Rng;
 use rusqlite::{types::FromSqlError, Row, NO_PARAMS};
 
 use crate::{
-    data::ClimateObservation,
+    data::{ClimateObservation, DailyClimate, StoredMessage},
     weather::{Forecast, Observation},
 };
 use glow_events::{
@@ -23,6 +24,12 @@ use glow_events::{
     Measurement,
 };
 
+/// The device that events and commands belong to when the device does not identify itself
+pub const DEFAULT_DEVICE: &str = "default";
+
+/// How long a queued command waits for the device before it is dropped
+pub const DEFAULT_COMMAND_TTL_MINUTES: i64 = 5;
+
 pub trait StorePool: std::marker::Unpin + Clone {
     type Store: Store;
 
@@ -32,9 +39,43 @@ pub trait StorePool: std::marker::Unpin + Clone {
 pub trait Store {
     fn migrate_db(&self);
 
-    fn add_event(&self, message: &Message) -> Result<()>;
+    /// Check the database can be queried
+    fn ping(&self) -> Result<()>;
 
+    /// Add an event unless the device has already sent it, returning whether it was new
+    fn add_event(&self, device: &str, message: &Message) -> Result<bool>;
+    /// Add events in a single transaction, along with any measurements and power readings
+    /// they carry, returning the events that were new
+    fn add_events_batch<'a>(
+        &self,
+        device: &str,
+        messages: &'a [Message],
+    ) -> Result<Vec<&'a Message>>;
+
+    /// Get the latest events from all devices
     fn get_latest_events(&self, limit: u32) -> Result<Vec<Message>>;
+    /// Get the latest events from one device
+    fn get_latest_device_events(&self, device: &str, limit: u32) -> Result<Vec<Message>>;
+
+    /// Get events from all devices stamped between `from` and `to` inclusive, oldest first
+    fn get_events_between(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<Message>>;
+
+    /// Get events from all devices stored after the one with `id`, oldest first
+    fn get_events_after(&self, id: i64, limit: u32) -> Result<Vec<StoredMessage>>;
+
+    /// Get the latest events with an `Event::event_type` such as `led.colours`
+    fn get_latest_events_of_type(&self, event_type: &str, limit: u32) -> Result<Vec<Message>>;
+
+    /// Get when a device last sent each `Event::event_type`
+    fn get_last_seen(&self, device: &str) -> Result<HashMap<String, DateTime<Utc>>>;
+
+    /// Get the names of all devices that have sent events
+    fn get_devices(&self) -> Result<Vec<String>>;
 
     // the point of this method is to swallow the error
     #[allow(clippy::match_wildcard_for_single_variants)]
@@ -47,44 +88,83 @@ pub trait Store {
 
     fn get_latest_event_like(&self, like: &str) -> Result<Option<Message>>;
 
+    /// Get the latest event from `device` whose payload is like `like`
+    fn get_latest_device_event_like(&self, device: &str, like: &str) -> Result<Option<Message>>;
+
+    /// How long since the device last sent `Started`, `None` if it never has
+    fn get_uptime(&self) -> Option<Duration>;
+
     fn add_measurement(&self, stamp: DateTime<Utc>, measurement: &Measurement) -> Result<()>;
+    fn add_labelled_measurement(
+        &self,
+        stamp: DateTime<Utc>,
+        sensor: &str,
+        measurement: &Measurement,
+    ) -> Result<()>;
     fn get_latest_measurement(&self) -> Option<ClimateObservation>;
-    fn get_measurements_since(&self, stamp: Duration) -> Result<Vec<Message>>;
-
-    fn queue_command(&self, command: Command) -> Result<()>;
-    fn dequeue_commands(&self) -> Result<Vec<Message>>;
+    fn get_latest_labelled_measurements(&self) -> Result<Vec<Message>>;
+    /// Get measurements, averaged into `bucket` wide slots stamped with their start if given
+    fn get_measurements_since(
+        &self,
+        stamp: Duration,
+        bucket: Option<Duration>,
+    ) -> Result<Vec<Message>>;
+    /// Get the mean indoor temperature and humidity since midnight, `None` before any readings
+    fn get_today_average(&self) -> Result<Option<Measurement>>;
+    /// Get the indoor highs, lows and averages for today and each of the previous `days`
+    fn get_daily_climate_since(&self, days: i64) -> Result<Vec<DailyClimate>>;
+    /// Count indoor temperatures into `bucket_width` wide buckets, keyed by their lower bound
+    ///
+    /// Only buckets with measurements in are returned, coldest first.
+    fn get_temperature_histogram(
+        &self,
+        since: Duration,
+        bucket_width: f64,
+    ) -> Result<Vec<(f64, u32)>>;
+
+    fn add_power_reading(&self, stamp: DateTime<Utc>, watts: f32) -> Result<()>;
+    fn get_power_readings_since(&self, stamp: Duration) -> Result<Vec<(DateTime<Utc>, f32)>>;
+
+    fn queue_command(&self, device: &str, command: Command) -> Result<()>;
+    fn dequeue_commands(&self, device: &str) -> Result<Vec<Message>>;
+    /// Delete commands the device has not collected yet, returning how many there were
+    fn clear_pending_commands(&self, device: &str) -> Result<usize>;
+
+    /// Delete every event and the readings taken from them, returning how many rows went
+    fn delete_all_events(&self) -> Result<usize>;
 
     fn add_observation(&self, observation: &Observation) -> Result<()>;
     fn add_forecast(&self, forecast: &Forecast) -> Result<()>;
     fn get_observations_since(&self, stamp: Duration) -> Result<Vec<Observation>>;
-
-    fn get_climate_history_since(&self, stamp: Duration) -> Result<Vec<ClimateObservation>> {
+    fn get_forecasts_since(&self, stamp: Duration) -> Result<Vec<Forecast>>;
+
+    /// Get indoor and outdoor readings since `stamp`, one for each `bucket` wide slot of time
+    fn get_climate_history_since(
+        &self,
+        stamp: Duration,
+        bucket: Duration,
+    ) -> Result<Vec<ClimateObservation>> {
         let mut measurements = self
-            .get_measurements_since(stamp)
+            .get_measurements_since(stamp, Some(bucket))
             .wrap_err("failed getting measurements")?
-            .iter()
-            .group_by(|event| event.stamp().duration_trunc(Duration::hours(1)).unwrap())
             .into_iter()
-            .map(|(hour, group)| {
-                let event = group.last().unwrap();
-                (hour, Message::raw(hour, event.payload().to_owned()))
-            })
+            .map(|event| (event.stamp(), event))
             .collect::<HashMap<DateTime<Utc>, Message>>();
 
         let mut observations = self
             .get_observations_since(stamp)
             .wrap_err("failed getting weather observations")?
             .iter()
-            .group_by(|obs| obs.date_time.duration_trunc(Duration::hours(1)).unwrap())
+            .group_by(|obs| obs.date_time.duration_trunc(bucket).unwrap())
             .into_iter()
-            .map(|(hour, group)| {
+            .map(|(slot, group)| {
                 let mut obs = group.last().unwrap().clone();
-                obs.date_time = hour;
-                (hour, obs)
+                obs.date_time = slot;
+                (slot, obs)
             })
             .collect::<HashMap<DateTime<Utc>, crate::weather::Observation>>();
 
-        let mut hours = measurements
+        let mut slots = measurements
             .keys()
             .cloned()
             .collect::<HashSet<DateTime<Utc>>>()
@@ -97,15 +177,15 @@ pub trait Store {
             .cloned()
             .collect::<Vec<DateTime<Utc>>>();
 
-        hours.sort_unstable();
-        hours.reverse();
+        slots.sort_unstable();
+        slots.reverse();
 
-        let climate = hours
+        let climate = slots
             .iter()
-            .map(|hour| {
+            .map(|slot| {
                 Ok(ClimateObservation::try_from_parts(
-                    measurements.remove(hour),
-                    observations.remove(hour),
+                    measurements.remove(slot),
+                    observations.remove(slot),
                 )?)
             })
             .collect::<Result<Vec<ClimateObservation>>>()?;
@@ -118,6 +198,7 @@ pub trait Store {
 pub struct SQLiteStorePool {
     pool: Pool<SqliteConnectionManager>,
     now: fn() -> DateTime<Utc>,
+    command_ttl: Duration,
 }
 
 impl SQLiteStorePool {
@@ -125,9 +206,15 @@ impl SQLiteStorePool {
         Self {
             pool,
             now: Utc::now,
+            command_ttl: Duration::minutes(DEFAULT_COMMAND_TTL_MINUTES),
         }
     }
 
+    pub fn with_command_ttl(mut self, command_ttl: Duration) -> Self {
+        self.command_ttl = command_ttl;
+        self
+    }
+
     pub fn from_path(path: &str) -> Self {
         Self::new(Pool::new(SqliteConnectionManager::file(path)).unwrap())
     }
@@ -143,7 +230,11 @@ impl SQLiteStorePool {
         pool: Pool<SqliteConnectionManager>,
         now: fn() -> DateTime<Utc>,
     ) -> Self {
-        Self { pool, now }
+        Self {
+            pool,
+            now,
+            command_ttl: Duration::minutes(DEFAULT_COMMAND_TTL_MINUTES),
+        }
     }
 
     pub(crate) fn memory_with_now(now: fn() -> DateTime<Utc>) -> Self {
@@ -155,18 +246,31 @@ impl StorePool for SQLiteStorePool {
     type Store = SQLiteStore;
 
     fn get(&self) -> Result<Self::Store> {
-        Ok(SQLiteStore::new(self.pool.get()?, self.now))
+        Ok(SQLiteStore::new(
+            self.pool.get()?,
+            self.now,
+            self.command_ttl,
+        ))
     }
 }
 
 pub struct SQLiteStore {
     conn: PooledConnection<SqliteConnectionManager>,
     now: fn() -> DateTime<Utc>,
+    command_ttl: Duration,
 }
 
 impl SQLiteStore {
-    fn new(conn: PooledConnection<SqliteConnectionManager>, now: fn() -> DateTime<Utc>) -> Self {
-        Self { conn, now }
+    fn new(
+        conn: PooledConnection<SqliteConnectionManager>,
+        now: fn() -> DateTime<Utc>,
+        command_ttl: Duration,
+    ) -> Self {
+        Self {
+            conn,
+            now,
+            command_ttl,
+        }
     }
 }
 
@@ -192,6 +296,7 @@ impl FromRequest for SQLiteStore {
 
 // TODO: tear this up and throw it away, these tables are bonkers!
 impl Store for SQLiteStore {
+    #[allow(clippy::too_many_lines)]
     fn migrate_db(&self) {
         self.conn
             .execute(
@@ -232,6 +337,34 @@ impl Store for SQLiteStore {
         )
         .expect("Cannot create events.stamp index");
 
+        if !column_exists(&self.conn, "environment_measurements", "sensor") {
+            self.conn
+                .execute(
+                    "ALTER TABLE environment_measurements ADD COLUMN sensor TEXT",
+                    params![],
+                )
+                .expect("Cannot add environment_measurements.sensor column");
+        }
+
+        self.conn
+            .execute(
+                r"
+            CREATE TABLE IF NOT EXISTS power_readings (
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                stamp DATETIME,
+                watts REAL
+            );
+            ",
+                params![],
+            )
+            .expect("Cannot create power_readings table");
+        self.conn
+            .execute(
+                "CREATE INDEX IF NOT EXISTS power_readings_stamp ON power_readings (stamp);",
+                params![],
+            )
+            .expect("Cannot create power_readings.stamp index");
+
         self.conn
             .execute(
                 r#"
@@ -274,19 +407,108 @@ impl Store for SQLiteStore {
                 params![],
             )
             .expect("Cannot create weather table");
-    }
 
-    fn add_event(&self, message: &Message) -> Result<()> {
-        Ok(self
-            .conn
+        // Events and commands from before devices were added all belong to the default device
+        for table in &["events", "commands"] {
+            if !column_exists(&self.conn, table, "device") {
+                self.conn
+                    .execute(
+                        &format!(
+                            "ALTER TABLE {} ADD COLUMN device TEXT NOT NULL DEFAULT '{}'",
+                            table, DEFAULT_DEVICE
+                        ),
+                        NO_PARAMS,
+                    )
+                    .expect("Cannot add device column");
+            }
+        }
+        self.conn
             .execute(
-                "INSERT INTO events (stamp, payload) VALUES (?1, ?2)",
-                params![
-                    message.stamp(),
-                    serde_json::to_string(message.payload()).unwrap()
-                ],
+                "CREATE INDEX IF NOT EXISTS events_device_stamp ON events (device, stamp);",
+                NO_PARAMS,
             )
-            .map(|_| ())?)
+            .expect("Cannot create events.device index");
+
+        if !column_exists(&self.conn, "events", "event_type") {
+            self.conn
+                .execute("ALTER TABLE events ADD COLUMN event_type TEXT", NO_PARAMS)
+                .expect("Cannot add event_type column");
+            backfill_event_types(&self.conn).expect("Cannot backfill event types");
+        }
+        self.conn
+            .execute(
+                "CREATE INDEX IF NOT EXISTS events_event_type_stamp ON events (event_type, stamp);",
+                NO_PARAMS,
+            )
+            .expect("Cannot create events.event_type index");
+
+        // A device retrying a post after a timeout sends the same events again
+        if !index_exists(&self.conn, "events_unique") {
+            self.conn
+                .execute(
+                    r"
+                    DELETE FROM events WHERE rowid NOT IN (
+                        SELECT MIN(rowid) FROM events GROUP BY device, stamp, payload
+                    )
+                    ",
+                    NO_PARAMS,
+                )
+                .expect("Cannot remove duplicate events");
+            self.conn
+                .execute(
+                    "CREATE UNIQUE INDEX events_unique ON events (device, stamp, payload);",
+                    NO_PARAMS,
+                )
+                .expect("Cannot create events unique index");
+        }
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.conn
+            .query_row("SELECT 1", NO_PARAMS, |row| row.get::<_, i64>(0))?;
+        Ok(())
+    }
+
+    fn add_event(&self, device: &str, message: &Message) -> Result<bool> {
+        Ok(self.conn.execute(
+            "INSERT OR IGNORE INTO events (device, event_type, stamp, payload) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                device,
+                event_type(message.payload()),
+                message.stamp(),
+                serde_json::to_string(message.payload()).unwrap()
+            ],
+        )? > 0)
+    }
+
+    fn add_events_batch<'a>(
+        &self,
+        device: &str,
+        messages: &'a [Message],
+    ) -> Result<Vec<&'a Message>> {
+        let transaction = self.conn.unchecked_transaction()?;
+        let mut added = Vec::with_capacity(messages.len());
+        for message in messages {
+            if !self.add_event(device, message)? {
+                // a retried post, the readings were stored the first time
+                continue;
+            }
+            match message.payload() {
+                Payload::Event(Event::Measurement(measurement)) => {
+                    self.add_measurement(message.stamp(), measurement)?;
+                }
+                Payload::Event(Event::LabelledMeasurement(sensor, measurement)) => {
+                    self.add_labelled_measurement(message.stamp(), sensor, measurement)?;
+                }
+                Payload::Event(Event::PowerReading { watts }) => {
+                    self.add_power_reading(message.stamp(), *watts)?;
+                }
+                _ => {}
+            }
+            added.push(message);
+        }
+        transaction.commit()?;
+        Ok(added)
     }
 
     fn get_latest_events(&self, limit: u32) -> Result<Vec<Message>> {
@@ -298,6 +520,85 @@ impl Store for SQLiteStore {
             .collect()?)
     }
 
+    fn get_latest_device_events(&self, device: &str, limit: u32) -> Result<Vec<Message>> {
+        Ok(self
+            .conn
+            .prepare(
+                "SELECT stamp, payload FROM events WHERE device = ? ORDER BY stamp DESC LIMIT ?",
+            )?
+            .query(params![device, limit])?
+            .map(parse_message_row)
+            .collect()?)
+    }
+
+    fn get_events_between(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<Message>> {
+        Ok(self
+            .conn
+            .prepare(
+                "SELECT stamp, payload FROM events WHERE stamp BETWEEN ? AND ? ORDER BY stamp LIMIT ?",
+            )?
+            .query(params![from, to, limit])?
+            .map(parse_message_row)
+            .collect()?)
+    }
+
+    fn get_events_after(&self, id: i64, limit: u32) -> Result<Vec<StoredMessage>> {
+        Ok(self
+            .conn
+            .prepare(
+                "SELECT stamp, payload, rowid FROM events WHERE rowid > ? ORDER BY rowid LIMIT ?",
+            )?
+            .query(params![id, limit])?
+            .map(|row| {
+                Ok(StoredMessage {
+                    id: row.get(2)?,
+                    message: parse_message_row(row)?,
+                })
+            })
+            .collect()?)
+    }
+
+    fn get_latest_events_of_type(&self, event_type: &str, limit: u32) -> Result<Vec<Message>> {
+        Ok(self
+            .conn
+            .prepare(
+                "SELECT stamp, payload FROM events WHERE event_type = ? ORDER BY stamp DESC LIMIT ?",
+            )?
+            .query(params![event_type, limit])?
+            .map(parse_message_row)
+            .collect()?)
+    }
+
+    fn get_last_seen(&self, device: &str) -> Result<HashMap<String, DateTime<Utc>>> {
+        Ok(self
+            .conn
+            .prepare(
+                r"
+                SELECT event_type, MAX(stamp)
+                FROM events
+                WHERE device = ?1 AND event_type IS NOT NULL
+                GROUP BY event_type
+            ",
+            )?
+            .query(params![device])?
+            .map(|row| Ok((row.get(0)?, row.get(1)?)))
+            .collect()?)
+    }
+
+    fn get_devices(&self) -> Result<Vec<String>> {
+        Ok(self
+            .conn
+            .prepare("SELECT DISTINCT device FROM events ORDER BY device")?
+            .query(NO_PARAMS)?
+            .map(|row| row.get(0))
+            .collect()?)
+    }
+
     fn get_latest_event_like(&self, like: &str) -> Result<Option<Message>> {
         let mut events = self.conn
             .prepare(
@@ -313,6 +614,25 @@ impl Store for SQLiteStore {
         }
     }
 
+    fn get_latest_device_event_like(&self, device: &str, like: &str) -> Result<Option<Message>> {
+        let mut events = self
+            .conn
+            .prepare(
+                "SELECT stamp, payload FROM events WHERE device = ? AND payload like ? ORDER BY stamp DESC LIMIT 1",
+            )?
+            .query(params![device, like])?
+            .map(parse_message_row)
+            .collect::<Vec<Message>>()?;
+        Ok(events.pop())
+    }
+
+    fn get_uptime(&self) -> Option<Duration> {
+        match self.get_latest_event_like(r#"%"Started"%"#) {
+            Ok(Some(started)) => Some((self.now)().signed_duration_since(started.stamp())),
+            _ => None,
+        }
+    }
+
     fn add_measurement(&self, stamp: DateTime<Utc>, measurement: &Measurement) -> Result<()> {
         Ok(self.conn.execute(
             "INSERT INTO environment_measurements (stamp, temperature, humidity) VALUES (?1, ?2, ?3)",
@@ -320,11 +640,23 @@ impl Store for SQLiteStore {
         ).map(|_| ())?)
     }
 
+    fn add_labelled_measurement(
+        &self,
+        stamp: DateTime<Utc>,
+        sensor: &str,
+        measurement: &Measurement,
+    ) -> Result<()> {
+        Ok(self.conn.execute(
+            "INSERT INTO environment_measurements (stamp, sensor, temperature, humidity) VALUES (?1, ?2, ?3, ?4)",
+            params![stamp, sensor, measurement.temperature, measurement.humidity,],
+        ).map(|_| ())?)
+    }
+
     // the point of this method is to swallow the error
     #[allow(clippy::match_wildcard_for_single_variants)]
     fn get_latest_measurement(&self) -> Option<ClimateObservation> {
         let result = self.conn.query_row(
-            "SELECT stamp, temperature, humidity FROM environment_measurements ORDER BY stamp DESC LIMIT 1",
+            "SELECT stamp, temperature, humidity FROM environment_measurements WHERE sensor IS NULL ORDER BY stamp DESC LIMIT 1",
             NO_PARAMS,
             parse_measurement_row,
         );
@@ -334,23 +666,205 @@ impl Store for SQLiteStore {
         }
     }
 
-    fn get_measurements_since(&self, since: Duration) -> Result<Vec<Message>> {
+    fn get_latest_labelled_measurements(&self) -> Result<Vec<Message>> {
+        Ok(self
+            .conn
+            .prepare(
+                r"
+                SELECT stamp, temperature, humidity, sensor
+                FROM environment_measurements AS m
+                WHERE sensor IS NOT NULL AND stamp = (
+                    SELECT MAX(stamp) FROM environment_measurements WHERE sensor = m.sensor
+                )
+                ORDER BY sensor
+            ",
+            )?
+            .query(NO_PARAMS)?
+            .map(parse_labelled_measurement_row)
+            .collect::<Vec<Message>>()?)
+    }
+
+    fn get_measurements_since(
+        &self,
+        since: Duration,
+        bucket: Option<Duration>,
+    ) -> Result<Vec<Message>> {
+        let since = (self.now)().checked_sub_signed(since).unwrap();
+        let bucket = match bucket {
+            Some(bucket) => bucket.num_seconds().max(1),
+            None => {
+                return Ok(self.conn.prepare("SELECT stamp, temperature, humidity FROM environment_measurements WHERE sensor IS NULL AND stamp >= ? ORDER BY stamp DESC")?
+                    .query(params![since])?
+                    .map(parse_measurement_row)
+                    .collect::<Vec<Message>>()?)
+            }
+        };
+        // average in SQL so long windows do not pull every row
+        Ok(self
+            .conn
+            .prepare(
+                r"
+                SELECT
+                    CAST(strftime('%s', stamp) AS INTEGER) / ?2 AS slot,
+                    AVG(temperature), AVG(humidity)
+                FROM environment_measurements
+                WHERE sensor IS NULL AND stamp >= ?1
+                GROUP BY slot
+                ORDER BY slot DESC
+            ",
+            )?
+            .query(params![since, bucket])?
+            .map(|row| {
+                let slot: i64 = row.get(0)?;
+                Ok(Message::raw(
+                    Utc.timestamp(slot * bucket, 0),
+                    Payload::Event(Event::Measurement(Measurement::new(
+                        row.get(1)?,
+                        row.get(2)?,
+                    ))),
+                ))
+            })
+            .collect::<Vec<Message>>()?)
+    }
+
+    fn get_temperature_histogram(
+        &self,
+        since: Duration,
+        bucket_width: f64,
+    ) -> Result<Vec<(f64, u32)>> {
+        if !(bucket_width.is_finite() && bucket_width > 0.0) {
+            return Err(eyre!(
+                "bucket width must be above zero, not {}",
+                bucket_width
+            ));
+        }
+        let since = (self.now)().checked_sub_signed(since).unwrap();
+        // CAST truncates towards zero so step below zero down by one to floor
+        Ok(self
+            .conn
+            .prepare(
+                r"
+                SELECT bucket, COUNT(*)
+                FROM (
+                    SELECT
+                        CAST(temperature / ?2 AS INTEGER)
+                            - (temperature / ?2 < CAST(temperature / ?2 AS INTEGER)) AS bucket
+                    FROM environment_measurements
+                    WHERE sensor IS NULL AND stamp >= ?1
+                )
+                GROUP BY bucket
+                ORDER BY bucket
+            ",
+            )?
+            .query(params![since, bucket_width])?
+            .map(|row| {
+                let bucket: i64 = row.get(0)?;
+                let count: u32 = row.get(1)?;
+                #[allow(clippy::cast_precision_loss)]
+                Ok((bucket as f64 * bucket_width, count))
+            })
+            .collect::<Vec<(f64, u32)>>()?)
+    }
+
+    fn get_today_average(&self) -> Result<Option<Measurement>> {
+        let midnight = (self.now)().date().and_hms(0, 0, 0);
+        let (temperature, humidity): (Option<f64>, Option<f64>) = self.conn.query_row(
+            r"
+            SELECT AVG(temperature), AVG(humidity)
+            FROM environment_measurements
+            WHERE sensor IS NULL AND stamp >= ?1 AND stamp < ?2
+            ",
+            params![midnight, midnight + Duration::days(1)],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok(temperature
+            .zip(humidity)
+            .map(|(temperature, humidity)| Measurement::new(temperature, humidity)))
+    }
+
+    fn get_daily_climate_since(&self, days: i64) -> Result<Vec<DailyClimate>> {
+        let since = ((self.now)() - Duration::days(days))
+            .date()
+            .and_hms(0, 0, 0);
+        Ok(self
+            .conn
+            .prepare(
+                r"
+                SELECT
+                    date(stamp) AS day,
+                    MIN(temperature), MAX(temperature), AVG(temperature),
+                    MIN(humidity), MAX(humidity), AVG(humidity)
+                FROM environment_measurements
+                WHERE sensor IS NULL AND stamp >= ?
+                GROUP BY day
+                ORDER BY day DESC
+            ",
+            )?
+            .query(params![since])?
+            .map(|row| {
+                Ok(DailyClimate {
+                    day: row.get(0)?,
+                    min_temperature: row.get(1)?,
+                    max_temperature: row.get(2)?,
+                    mean_temperature: row.get(3)?,
+                    min_humidity: row.get(4)?,
+                    max_humidity: row.get(5)?,
+                    mean_humidity: row.get(6)?,
+                })
+            })
+            .collect::<Vec<DailyClimate>>()?)
+    }
+
+    fn add_power_reading(&self, stamp: DateTime<Utc>, watts: f32) -> Result<()> {
+        Ok(self
+            .conn
+            .execute(
+                "INSERT INTO power_readings (stamp, watts) VALUES (?1, ?2)",
+                params![stamp, f64::from(watts)],
+            )
+            .map(|_| ())?)
+    }
+
+    fn get_power_readings_since(&self, since: Duration) -> Result<Vec<(DateTime<Utc>, f32)>> {
         let now = self.now;
-        Ok(self.conn.prepare("SELECT stamp, temperature, humidity FROM environment_measurements WHERE stamp >= ? ORDER BY stamp DESC")?
+        Ok(self
+            .conn
+            .prepare(
+                "SELECT stamp, watts FROM power_readings WHERE stamp >= ? ORDER BY stamp DESC",
+            )?
             .query(params![now().checked_sub_signed(since).unwrap()])?
-            .map(parse_measurement_row)
-            .collect::<Vec<Message>>()?)
+            .map(|row| {
+                let watts: f64 = row.get(1)?;
+                #[allow(clippy::cast_possible_truncation)]
+                Ok((row.get(0)?, watts as f32))
+            })
+            .collect::<Vec<(DateTime<Utc>, f32)>>()?)
     }
 
-    fn queue_command(&self, command: Command) -> Result<()> {
-        insert_message_to(&"commands", &self.conn, &Message::new_command(command)).map(|_| ())
+    fn queue_command(&self, device: &str, command: Command) -> Result<()> {
+        insert_message_to(
+            "commands",
+            &self.conn,
+            device,
+            &Message::new_command(command),
+        )
+        .map(|_| ())
     }
 
-    fn dequeue_commands(&self) -> Result<Vec<Message>> {
+    fn dequeue_commands(&self, device: &str) -> Result<Vec<Message>> {
+        // commands that waited too long for the device would be a surprise, consume them unsent
+        let expired = self.conn.execute(
+            "UPDATE commands SET group_token = 1 WHERE group_token = 0 AND device = ?1 AND stamp < ?2",
+            params![device, (self.now)() - self.command_ttl],
+        )?;
+        if expired > 0 {
+            info!("dropped {} expired commands for {}", expired, device);
+        }
+
         let token: u32 = rand::thread_rng().gen_range(2, std::u32::MAX);
         self.conn.execute(
-            "UPDATE commands SET group_token = ?1, stamp = ?2 WHERE group_token = 0",
-            params![token, Utc::now()],
+            "UPDATE commands SET group_token = ?1, stamp = ?2 WHERE group_token = 0 AND device = ?3",
+            params![token, Utc::now(), device],
         )?;
         let commands = self
             .conn
@@ -362,7 +876,26 @@ impl Store for SQLiteStore {
             "UPDATE commands SET group_token = 1 WHERE group_token = ?1",
             params![token],
         )?;
-        Ok(commands)
+        Ok(dedupe_commands(commands))
+    }
+
+    fn clear_pending_commands(&self, device: &str) -> Result<usize> {
+        Ok(self.conn.execute(
+            "DELETE FROM commands WHERE group_token = 0 AND device = ?1",
+            params![device],
+        )?)
+    }
+
+    fn delete_all_events(&self) -> Result<usize> {
+        let transaction = self.conn.unchecked_transaction()?;
+        let mut deleted = 0;
+        for table in &["events", "environment_measurements", "power_readings"] {
+            deleted += self
+                .conn
+                .execute(&format!("DELETE FROM {}", table), NO_PARAMS)?;
+        }
+        transaction.commit()?;
+        Ok(deleted)
     }
 
     fn add_observation(&self, observation: &Observation) -> Result<()> {
@@ -410,6 +943,26 @@ impl Store for SQLiteStore {
             .map(parse_observation_row)
             .collect::<Vec<Observation>>()?)
     }
+
+    /// Get forecasts published since `since`, newest first
+    ///
+    /// Each forecast fetch covers three days which share a publication time so, within a
+    /// fetch, the forecasts are kept in day order.
+    fn get_forecasts_since(&self, since: Duration) -> Result<Vec<Forecast>> {
+        let now = self.now;
+        Ok(self
+            .conn
+            .prepare(
+                r"
+                SELECT payload
+                FROM weather
+                WHERE type='forecast' AND date_time >= ? ORDER BY date_time DESC, rowid ASC
+            ",
+            )?
+            .query(params![now().checked_sub_signed(since).unwrap()])?
+            .map(parse_forecast_row)
+            .collect::<Vec<Forecast>>()?)
+    }
 }
 
 fn parse_observation_row(row: &Row<'_>) -> rusqlite::Result<Observation> {
@@ -418,6 +971,12 @@ fn parse_observation_row(row: &Row<'_>) -> rusqlite::Result<Observation> {
         .map_err(|err| -> rusqlite::Error { FromSqlError::Other(Box::new(err)).into() })
 }
 
+fn parse_forecast_row(row: &Row<'_>) -> rusqlite::Result<Forecast> {
+    let data: String = row.get(0)?;
+    serde_json::from_str(&data)
+        .map_err(|err| -> rusqlite::Error { FromSqlError::Other(Box::new(err)).into() })
+}
+
 fn parse_message_row(row: &Row<'_>) -> rusqlite::Result<Message> {
     let payload_str: String = row.get(1)?;
     match serde_json::from_str(&payload_str) {
@@ -436,15 +995,90 @@ fn parse_measurement_row(row: &Row<'_>) -> rusqlite::Result<Message> {
     ))
 }
 
+fn parse_labelled_measurement_row(row: &Row<'_>) -> rusqlite::Result<Message> {
+    Ok(Message::raw(
+        row.get(0)?,
+        Payload::Event(Event::LabelledMeasurement(
+            row.get(3)?,
+            Measurement::new(row.get(1)?, row.get(2)?),
+        )),
+    ))
+}
+
+fn column_exists(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    table: &str,
+    column: &str,
+) -> bool {
+    let query = format!("PRAGMA table_info({})", table);
+    conn.prepare(query.as_str())
+        .and_then(|mut statement| {
+            statement
+                .query(NO_PARAMS)?
+                .map(|row| row.get::<_, String>(1))
+                .any(|name| Ok(name == column))
+        })
+        .unwrap_or(false)
+}
+
+fn index_exists(conn: &PooledConnection<SqliteConnectionManager>, index: &str) -> bool {
+    conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name = ?",
+        params![index],
+        |row| row.get::<_, i64>(0),
+    )
+    .is_ok_and(|count| count > 0)
+}
+
+/// Collapse runs of the same command, such as "Run heater" clicked several times
+fn dedupe_commands(mut commands: Vec<Message>) -> Vec<Message> {
+    commands.dedup_by(|a, b| a.payload() == b.payload());
+    commands
+}
+
+fn event_type(payload: &Payload) -> Option<&'static str> {
+    match payload {
+        Payload::Event(event) => Some(event.event_type()),
+        Payload::Command(_) => None,
+    }
+}
+
+/// Fill in the type of events stored before the `event_type` column existed
+fn backfill_event_types(conn: &PooledConnection<SqliteConnectionManager>) -> Result<()> {
+    let rows = conn
+        .prepare("SELECT rowid, payload FROM events")?
+        .query(NO_PARAMS)?
+        .map(|row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))
+        .collect::<Vec<_>>()?;
+    for (rowid, payload) in rows {
+        if let Some(event_type) = serde_json::from_str::<Payload>(&payload)
+            .ok()
+            .as_ref()
+            .and_then(event_type)
+        {
+            conn.execute(
+                "UPDATE events SET event_type = ?1 WHERE rowid = ?2",
+                params![event_type, rowid],
+            )?;
+        }
+    }
+    Ok(())
+}
+
 fn insert_message_to(
     table: &str,
     conn: &PooledConnection<SqliteConnectionManager>,
+    device: &str,
     message: &Message,
 ) -> Result<usize> {
-    let query = format!("INSERT INTO {} (stamp, payload) VALUES (?1, ?2)", table);
+    let query = format!(
+        "INSERT INTO {} (device, stamp, payload) VALUES (?1, ?2, ?3)",
+        table
+    );
     Ok(conn.execute(
         query.as_str(),
         params![
+            device,
             message.stamp(),
             serde_json::to_string(message.payload()).unwrap()
         ],
@@ -458,7 +1092,7 @@ pub mod test {
     use rand::prelude::*;
 
     use super::{SQLiteStorePool, Store, StorePool};
-    use crate::weather::{Observation, WindDirection};
+    use crate::weather::{Forecast, Observation, WindDirection};
     use glow_events::Measurement;
 
     pub fn now() -> DateTime<Utc> {
@@ -513,6 +1147,26 @@ pub mod test {
             Ok(())
         }
 
+        pub fn add_forecasts(store: &impl Store, date_time: DateTime<Utc>) -> Result<()> {
+            for (day, (min_temperature, max_temperature)) in
+                [(8, None), (9, Some(16)), (10, Some(17))]
+                    .iter()
+                    .enumerate()
+            {
+                store.add_forecast(&Forecast {
+                    max_temperature: *max_temperature,
+                    min_temperature: *min_temperature,
+                    humidity: 80,
+                    wind_speed: 10,
+                    wind_direction: WindDirection::Westerly,
+                    date_time,
+                    point: (12.1, 12.2),
+                    url: format!("https://example.org?day={}", day),
+                })?;
+            }
+            Ok(())
+        }
+
         pub fn add_measurements(
             store: &impl Store,
             num: u32,
@@ -554,11 +1208,13 @@ mod tests {
         let store = db.store().unwrap();
 
         // act
-        store.queue_command(Command::Stop).unwrap();
-        store.queue_command(Command::Stop).unwrap();
+        store.queue_command(DEFAULT_DEVICE, Command::Stop).unwrap();
+        store
+            .queue_command(DEFAULT_DEVICE, Command::RunHeater)
+            .unwrap();
 
-        let commands1 = store.dequeue_commands().unwrap();
-        let commands2 = store.dequeue_commands().unwrap();
+        let commands1 = store.dequeue_commands(DEFAULT_DEVICE).unwrap();
+        let commands2 = store.dequeue_commands(DEFAULT_DEVICE).unwrap();
 
         // assert
         assert_eq!(commands1.len(), 2);
@@ -566,39 +1222,522 @@ mod tests {
     }
 
     #[test]
-    fn test_get_measurements_since() {
+    fn get_today_average_only_averages_today() {
         // arrange
         let db = TestDb::with_now(now);
         let store = db.store().unwrap();
-
-        vec![
-            ("2012-12-12T11:00:00Z", 10.0),
-            ("2012-12-12T11:10:00Z", 11.0),
-            ("2012-12-12T11:20:00Z", 12.0),
-            ("2012-12-12T11:30:00Z", 13.0),
-            ("2012-12-12T11:50:00Z", 14.0),
-            ("2012-12-12T11:55:00Z", 15.0),
-        ]
-        .iter()
-        .for_each(|&(stamp, temp)| {
-            store
-                .add_measurement(
-                    stamp.parse::<DateTime<Utc>>().unwrap(),
-                    &Measurement::new(temp, 10.0),
-                )
-                .unwrap();
-        });
+        let midnight = "2012-12-12T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        store
+            .add_measurement(midnight - Duration::minutes(1), &Measurement::new(0.0, 0.0))
+            .unwrap();
+        store
+            .add_measurement(midnight, &Measurement::new(18.0, 40.0))
+            .unwrap();
+        store
+            .add_measurement(now(), &Measurement::new(21.0, 50.0))
+            .unwrap();
+        store
+            .add_labelled_measurement(now(), "loft", &Measurement::new(5.0, 90.0))
+            .unwrap();
 
         // act
-        let measurements = store.get_measurements_since(Duration::minutes(30)).unwrap();
+        let average = store.get_today_average().unwrap();
 
         // assert
-        assert_eq!(measurements.len(), 2);
-        assert_eq!(
-            measurements,
-            vec![
-                ("2012-12-12T11:55:00Z", 15.0),
-                ("2012-12-12T11:50:00Z", 14.0),
+        assert_eq!(average, Some(Measurement::new(19.5, 45.0)));
+    }
+
+    #[test]
+    fn get_today_average_is_none_without_readings_today() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        store
+            .add_measurement(now() - Duration::days(1), &Measurement::new(20.0, 50.0))
+            .unwrap();
+
+        // act
+        let average = store.get_today_average().unwrap();
+
+        // assert
+        assert_eq!(average, None);
+    }
+
+    #[test]
+    fn get_daily_climate_since_aggregates_each_day() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let yesterday = "2012-12-11T00:30:00Z".parse::<DateTime<Utc>>().unwrap();
+        for hour in 0..24 {
+            store
+                .add_measurement(
+                    yesterday + Duration::hours(hour.into()),
+                    &Measurement::new(10.0 + f64::from(hour), 40.0 + f64::from(hour) / 2.0),
+                )
+                .unwrap();
+        }
+        store
+            .add_measurement(now(), &Measurement::new(20.0, 50.0))
+            .unwrap();
+        store
+            .add_measurement(now() - Duration::days(2), &Measurement::new(0.0, 0.0))
+            .unwrap();
+
+        // act
+        let days = store.get_daily_climate_since(1).unwrap();
+
+        // assert
+        assert_eq!(
+            days,
+            vec![
+                DailyClimate {
+                    day: now().date().naive_utc(),
+                    min_temperature: 20.0,
+                    max_temperature: 20.0,
+                    mean_temperature: 20.0,
+                    min_humidity: 50.0,
+                    max_humidity: 50.0,
+                    mean_humidity: 50.0,
+                },
+                DailyClimate {
+                    day: yesterday.date().naive_utc(),
+                    min_temperature: 10.0,
+                    max_temperature: 33.0,
+                    mean_temperature: 21.5,
+                    min_humidity: 40.0,
+                    max_humidity: 51.5,
+                    mean_humidity: 45.75,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn delete_all_events_empties_events_and_readings() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        store
+            .add_events_batch(
+                DEFAULT_DEVICE,
+                &[
+                    Message::new_event(Event::SingleTap),
+                    Message::new_event(Event::Measurement(Measurement::new(20.0, 50.0))),
+                    Message::new_event(Event::PowerReading { watts: 1500.0 }),
+                ],
+            )
+            .unwrap();
+        store.queue_command(DEFAULT_DEVICE, Command::Stop).unwrap();
+
+        // act
+        let deleted = store.delete_all_events().unwrap();
+
+        // assert
+        assert_eq!(deleted, 5);
+        assert!(store.get_latest_events(10).unwrap().is_empty());
+        assert!(store.get_latest_measurement().is_none());
+        assert!(store
+            .get_power_readings_since(Duration::days(1))
+            .unwrap()
+            .is_empty());
+        assert_eq!(store.dequeue_commands(DEFAULT_DEVICE).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn clear_pending_commands_removes_queued_commands() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        store
+            .queue_command(DEFAULT_DEVICE, Command::RunHeater)
+            .unwrap();
+        store.queue_command(DEFAULT_DEVICE, Command::Stop).unwrap();
+        store.queue_command("kitchen", Command::RunHeater).unwrap();
+
+        // act
+        let cleared = store.clear_pending_commands(DEFAULT_DEVICE).unwrap();
+
+        // assert
+        assert_eq!(cleared, 2);
+        assert!(store.dequeue_commands(DEFAULT_DEVICE).unwrap().is_empty());
+        assert_eq!(store.dequeue_commands("kitchen").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn get_last_seen_has_latest_stamp_per_type() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        for (minutes, event) in [
+            (30, Event::SingleTap),
+            (20, Event::Measurement(Measurement::new(20.0, 50.0))),
+            (10, Event::SingleTap),
+            (5, Event::HeaterStarted),
+        ] {
+            store
+                .add_event(
+                    DEFAULT_DEVICE,
+                    &Message::raw(now() - Duration::minutes(minutes), Payload::Event(event)),
+                )
+                .unwrap();
+        }
+        store
+            .add_event(
+                "kitchen",
+                &Message::raw(now(), Payload::Event(Event::SingleTap)),
+            )
+            .unwrap();
+
+        // act
+        let last_seen = store.get_last_seen(DEFAULT_DEVICE).unwrap();
+
+        // assert
+        assert_eq!(last_seen.len(), 3);
+        assert_eq!(last_seen["tap.single"], now() - Duration::minutes(10));
+        assert_eq!(
+            last_seen["environment.measurement"],
+            now() - Duration::minutes(20)
+        );
+        assert_eq!(
+            last_seen["tplink.heater-started"],
+            now() - Duration::minutes(5)
+        );
+    }
+
+    #[test]
+    fn add_events_batch_stores_everything() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let messages = (0..500)
+            .map(|i| {
+                let stamp = now() - Duration::seconds(i);
+                let event = match i % 5 {
+                    0 | 1 => Event::Measurement(Measurement::new(20.0, 50.0)),
+                    2 => {
+                        Event::LabelledMeasurement("loft".to_string(), Measurement::new(9.0, 80.0))
+                    }
+                    3 => Event::PowerReading { watts: 1500.0 },
+                    _ => Event::SingleTap,
+                };
+                Message::raw(stamp, Payload::Event(event))
+            })
+            .collect::<Vec<_>>();
+
+        // act
+        let added = store.add_events_batch(DEFAULT_DEVICE, &messages).unwrap();
+
+        // assert
+        assert_eq!(added.len(), 500);
+        assert_eq!(store.get_latest_events(1000).unwrap().len(), 500);
+        assert_eq!(
+            store
+                .get_measurements_since(Duration::hours(1), None)
+                .unwrap()
+                .len(),
+            200
+        );
+        assert_eq!(
+            store
+                .get_power_readings_since(Duration::hours(1))
+                .unwrap()
+                .len(),
+            100
+        );
+    }
+
+    #[test]
+    fn add_event_ignores_repeats() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let message = Message::raw(
+            now(),
+            Payload::Event(Event::Measurement(Measurement::new(20.0, 50.0))),
+        );
+
+        // act
+        store.add_event(DEFAULT_DEVICE, &message).unwrap();
+        let repeats = [message.clone(), message.clone()];
+        let added = store.add_events_batch(DEFAULT_DEVICE, &repeats).unwrap();
+        store.add_event("loft", &message).unwrap();
+
+        // assert
+        assert!(added.is_empty());
+        assert_eq!(
+            store.get_latest_device_events(DEFAULT_DEVICE, 10).unwrap(),
+            vec![message.clone()]
+        );
+        assert_eq!(store.get_latest_events(10).unwrap().len(), 2);
+        assert!(store
+            .get_measurements_since(Duration::hours(1), None)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn get_events_between_filters_by_stamp() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        for hours in 0..10 {
+            let message = Message::raw(
+                now() - Duration::hours(hours),
+                Payload::Event(Event::SingleTap),
+            );
+            store.add_event(DEFAULT_DEVICE, &message).unwrap();
+        }
+
+        // act
+        let events = store
+            .get_events_between(now() - Duration::hours(6), now() - Duration::hours(3), 10)
+            .unwrap();
+
+        // assert
+        assert_eq!(
+            events.iter().map(Message::stamp).collect::<Vec<_>>(),
+            vec![
+                now() - Duration::hours(6),
+                now() - Duration::hours(5),
+                now() - Duration::hours(4),
+                now() - Duration::hours(3),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_latest_events_of_type_filters_by_type() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        for event in [
+            Event::Measurement(Measurement::new(20.0, 50.0)),
+            Event::LEDColours(vec![]),
+            Event::SingleTap,
+            Event::LEDColours(vec![]),
+        ] {
+            store
+                .add_event(DEFAULT_DEVICE, &Message::new_event(event))
+                .unwrap();
+        }
+
+        // act
+        let events = store.get_latest_events_of_type("led.colours", 10).unwrap();
+
+        // assert
+        assert_eq!(events.len(), 2);
+        assert!(events
+            .iter()
+            .all(|event| *event.payload() == Payload::Event(Event::LEDColours(vec![]))));
+    }
+
+    #[test]
+    fn migrate_db_backfills_event_types() {
+        // arrange
+        let pool = SQLiteStorePool::memory_with_now(now);
+        let store = pool.get().unwrap();
+        store
+            .conn
+            .execute(
+                "CREATE TABLE events (created_at TIMESTAMP, stamp DATETIME, payload TEXT)",
+                NO_PARAMS,
+            )
+            .unwrap();
+        store
+            .conn
+            .execute(
+                "INSERT INTO events (stamp, payload) VALUES (?1, ?2)",
+                params![
+                    now(),
+                    serde_json::to_string(&Payload::Event(Event::SingleTap)).unwrap()
+                ],
+            )
+            .unwrap();
+
+        // act
+        store.migrate_db();
+
+        // assert
+        assert_eq!(
+            store
+                .get_latest_events_of_type("tap.single", 10)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(store.get_devices().unwrap(), vec![DEFAULT_DEVICE]);
+    }
+
+    #[test]
+    fn get_events_after_returns_only_newer_events() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let events = vec![
+            Message::new_event(Event::SingleTap),
+            Message::new_event(Event::DoubleTap),
+            Message::new_event(Event::TripleTap),
+        ];
+        for event in &events {
+            store.add_event(DEFAULT_DEVICE, event).unwrap();
+        }
+        let all = store.get_events_after(0, 10).unwrap();
+
+        // act
+        let newer = store.get_events_after(all[0].id, 10).unwrap();
+        let limited = store.get_events_after(all[0].id, 1).unwrap();
+        let none = store.get_events_after(all[2].id, 10).unwrap();
+
+        // assert
+        assert_eq!(
+            all.iter().map(|stored| &stored.message).collect::<Vec<_>>(),
+            events.iter().collect::<Vec<_>>()
+        );
+        assert!(all.windows(2).all(|pair| pair[0].id < pair[1].id));
+        assert_eq!(newer, all[1..]);
+        assert_eq!(limited, all[1..2]);
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn events_and_commands_are_scoped_by_device() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+
+        // act
+        store
+            .add_event("kitchen", &Message::new_event(Event::SingleTap))
+            .unwrap();
+        store
+            .add_event(DEFAULT_DEVICE, &Message::new_event(Event::Started))
+            .unwrap();
+        store.queue_command("kitchen", Command::RunHeater).unwrap();
+
+        // assert
+        assert_eq!(store.get_devices().unwrap(), vec!["default", "kitchen"]);
+        assert_eq!(store.get_latest_events(10).unwrap().len(), 2);
+        assert_eq!(
+            store.get_latest_device_events("kitchen", 10).unwrap().len(),
+            1
+        );
+        assert!(store.dequeue_commands(DEFAULT_DEVICE).unwrap().is_empty());
+        assert_eq!(store.dequeue_commands("kitchen").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn dequeue_commands_drops_expired_commands() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.pool().get().unwrap();
+        store
+            .queue_command(DEFAULT_DEVICE, Command::SetBrightness(0.5))
+            .unwrap();
+        store
+            .conn
+            .execute(
+                "UPDATE commands SET stamp = ?1",
+                params![now() - Duration::minutes(10)],
+            )
+            .unwrap();
+        store
+            .queue_command(DEFAULT_DEVICE, Command::RunHeater)
+            .unwrap();
+
+        // act
+        let commands = store.dequeue_commands(DEFAULT_DEVICE).unwrap();
+
+        // assert
+        assert_eq!(commands.len(), 1);
+        assert_eq!(*commands[0].payload(), Payload::Command(Command::RunHeater));
+        assert!(store.dequeue_commands(DEFAULT_DEVICE).unwrap().is_empty());
+    }
+
+    #[test]
+    fn dedupe_commands_collapses_repeated_commands() {
+        // arrange
+        let commands = vec![
+            Command::RunHeater,
+            Command::RunHeater,
+            Command::RunHeater,
+            Command::SetBrightness(0.5),
+            Command::RunHeater,
+            Command::StopHeater,
+            Command::StopHeater,
+        ];
+
+        // act
+        let deduped = dedupe_commands(commands.into_iter().map(Message::new_command).collect());
+
+        // assert
+        let payloads: Vec<&Payload> = deduped.iter().map(Message::payload).collect();
+        assert_eq!(
+            payloads,
+            vec![
+                &Payload::Command(Command::RunHeater),
+                &Payload::Command(Command::SetBrightness(0.5)),
+                &Payload::Command(Command::RunHeater),
+                &Payload::Command(Command::StopHeater),
+            ]
+        );
+    }
+
+    #[test]
+    fn dequeue_commands_collapses_repeated_commands() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        for _ in 0..3 {
+            store
+                .queue_command(DEFAULT_DEVICE, Command::RunHeater)
+                .unwrap();
+        }
+
+        // act
+        let commands = store.dequeue_commands(DEFAULT_DEVICE).unwrap();
+
+        // assert
+        assert_eq!(commands.len(), 1);
+        assert_eq!(*commands[0].payload(), Payload::Command(Command::RunHeater));
+    }
+
+    #[test]
+    fn test_get_measurements_since() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+
+        vec![
+            ("2012-12-12T11:00:00Z", 10.0),
+            ("2012-12-12T11:10:00Z", 11.0),
+            ("2012-12-12T11:20:00Z", 12.0),
+            ("2012-12-12T11:30:00Z", 13.0),
+            ("2012-12-12T11:50:00Z", 14.0),
+            ("2012-12-12T11:55:00Z", 15.0),
+        ]
+        .iter()
+        .for_each(|&(stamp, temp)| {
+            store
+                .add_measurement(
+                    stamp.parse::<DateTime<Utc>>().unwrap(),
+                    &Measurement::new(temp, 10.0),
+                )
+                .unwrap();
+        });
+
+        // act
+        let measurements = store
+            .get_measurements_since(Duration::minutes(30), None)
+            .unwrap();
+
+        // assert
+        assert_eq!(measurements.len(), 2);
+        assert_eq!(
+            measurements,
+            vec![
+                ("2012-12-12T11:55:00Z", 15.0),
+                ("2012-12-12T11:50:00Z", 14.0),
             ]
             .iter()
             .map(|(stamp, temp)| {
@@ -611,6 +1750,59 @@ mod tests {
         );
     }
 
+    #[test]
+    fn labelled_measurements_round_trip() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let stamp = "2012-12-12T11:55:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        store
+            .add_labelled_measurement(stamp, "bathroom", &Measurement::new(21.5, 80.0))
+            .unwrap();
+        store
+            .add_labelled_measurement(
+                stamp - Duration::minutes(5),
+                "bathroom",
+                &Measurement::new(20.5, 70.0),
+            )
+            .unwrap();
+        store
+            .add_labelled_measurement(stamp, "nursery", &Measurement::new(18.5, 50.0))
+            .unwrap();
+        store
+            .add_measurement(stamp, &Measurement::new(19.0, 40.0))
+            .unwrap();
+
+        // act
+        let latest = store.get_latest_labelled_measurements().unwrap();
+        let unlabelled = store
+            .get_measurements_since(Duration::hours(1), None)
+            .unwrap();
+
+        // assert
+        assert_eq!(
+            latest,
+            vec![
+                Message::raw(
+                    stamp,
+                    Payload::Event(Event::LabelledMeasurement(
+                        "bathroom".to_string(),
+                        Measurement::new(21.5, 80.0)
+                    ))
+                ),
+                Message::raw(
+                    stamp,
+                    Payload::Event(Event::LabelledMeasurement(
+                        "nursery".to_string(),
+                        Measurement::new(18.5, 50.0)
+                    ))
+                ),
+            ]
+        );
+        assert_eq!(unlabelled.len(), 1);
+    }
+
     #[test]
     fn get_observations_since() {
         // arrange
@@ -627,6 +1819,156 @@ mod tests {
         assert_eq!(observations.len(), 25);
     }
 
+    #[test]
+    fn power_readings_round_trip() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        store
+            .add_power_reading(now() - Duration::hours(2), 12.5)
+            .unwrap();
+        store
+            .add_power_reading(now() - Duration::minutes(10), 1520.0)
+            .unwrap();
+
+        // act
+        let readings = store.get_power_readings_since(Duration::hours(1)).unwrap();
+
+        // assert
+        assert_eq!(readings, vec![(now() - Duration::minutes(10), 1520.0)]);
+    }
+
+    #[test]
+    fn get_forecasts_since() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        TestDb::add_forecasts(&store, now() - Duration::hours(30)).unwrap();
+        TestDb::add_forecasts(&store, now() - Duration::hours(1)).unwrap();
+
+        // act
+        let forecasts = store.get_forecasts_since(Duration::hours(24)).unwrap();
+
+        // assert
+        assert_eq!(forecasts.len(), 3);
+        assert_eq!(
+            forecasts
+                .iter()
+                .map(|f| (f.min_temperature, f.max_temperature))
+                .collect::<Vec<_>>(),
+            vec![(8, None), (9, Some(16)), (10, Some(17))]
+        );
+        assert_eq!(forecasts[0].date_time, now() - Duration::hours(1));
+    }
+
+    #[test]
+    fn get_measurements_since_averages_buckets() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let until = now();
+        TestDb::add_measurements(&store, 1000, until - Duration::hours(10), until).unwrap();
+        store
+            .add_measurement(
+                "2012-12-12T12:00:10Z".parse().unwrap(),
+                &Measurement::new(20.0, 50.0),
+            )
+            .unwrap();
+
+        // act
+        let hourly = store
+            .get_measurements_since(Duration::hours(10), Some(Duration::hours(1)))
+            .unwrap();
+        let half_hourly = store
+            .get_measurements_since(Duration::hours(10), Some(Duration::minutes(30)))
+            .unwrap();
+
+        // assert
+        assert_eq!(hourly.len(), 11);
+        assert_eq!(half_hourly.len(), 21);
+        assert_eq!(
+            hourly[0].stamp(),
+            now().duration_trunc(Duration::hours(1)).unwrap()
+        );
+        assert!(hourly
+            .windows(2)
+            .all(|pair| pair[0].stamp() - pair[1].stamp() == Duration::hours(1)));
+    }
+
+    #[test]
+    fn get_temperature_histogram_counts_each_bucket() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        let temperatures = [-1.5, -0.5, 0.0, 0.5, 2.0, 2.5, 3.9, 21.0];
+        for (minutes, temperature) in (0..).zip(&temperatures) {
+            store
+                .add_measurement(
+                    now() - Duration::minutes(minutes),
+                    &Measurement::new(*temperature, 50.0),
+                )
+                .unwrap();
+        }
+        // too old to count
+        store
+            .add_measurement(now() - Duration::hours(2), &Measurement::new(10.0, 50.0))
+            .unwrap();
+
+        // act
+        let histogram = store
+            .get_temperature_histogram(Duration::hours(1), 2.0)
+            .unwrap();
+
+        // assert
+        assert_eq!(histogram, vec![(-2.0, 2), (0.0, 2), (2.0, 3), (20.0, 1)]);
+    }
+
+    #[test]
+    fn get_temperature_histogram_needs_a_positive_width() {
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+
+        assert!(store
+            .get_temperature_histogram(Duration::hours(1), 0.0)
+            .is_err());
+        assert!(store
+            .get_temperature_histogram(Duration::hours(1), f64::NAN)
+            .is_err());
+    }
+
+    #[test]
+    fn get_latest_device_event_like_ignores_other_devices() {
+        // arrange
+        let db = TestDb::with_now(now);
+        let store = db.store().unwrap();
+        store
+            .add_event(
+                DEFAULT_DEVICE,
+                &Message::raw(
+                    now() - Duration::minutes(5),
+                    Payload::Event(Event::LEDBrightness(0.5)),
+                ),
+            )
+            .unwrap();
+        store
+            .add_event(
+                "kitchen",
+                &Message::raw(now(), Payload::Event(Event::LEDBrightness(1.0))),
+            )
+            .unwrap();
+
+        // act
+        let latest = store
+            .get_latest_device_event_like(DEFAULT_DEVICE, r#"%"LEDBrightness"%"#)
+            .unwrap();
+
+        // assert
+        assert_eq!(
+            latest.map(|message| message.payload().clone()),
+            Some(Payload::Event(Event::LEDBrightness(0.5)))
+        );
+    }
+
     #[test]
     fn get_climate_since() {
         // arrange
@@ -639,7 +1981,7 @@ mod tests {
 
         // act
         let climate_history = store
-            .get_climate_history_since(Duration::hours(24))
+            .get_climate_history_since(Duration::hours(24), Duration::hours(1))
             .unwrap();
 
         // assert
@@ -664,7 +2006,7 @@ mod tests {
 
         // act
         let climate_history = store
-            .get_climate_history_since(Duration::hours(24))
+            .get_climate_history_since(Duration::hours(24), Duration::hours(1))
             .unwrap();
 
         // assert
@@ -694,7 +2036,7 @@ mod tests {
 
         // act
         let climate_history = store
-            .get_climate_history_since(Duration::hours(24))
+            .get_climate_history_since(Duration::hours(24), Duration::hours(1))
             .unwrap();
 
         // assert
@@ -0,0 +1,114 @@
+//! Request timing
+//!
+//! Logs how long every request took with its path and status, to find the slow routes.
+use std::{
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    Error,
+};
+use futures::future::{ok, FutureExt, LocalBoxFuture, Ready};
+use log::info;
+
+pub struct RequestTiming;
+
+impl<S, B> Transform<S> for RequestTiming
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTimingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestTimingMiddleware { service })
+    }
+}
+
+pub struct RequestTimingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestTimingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let method = req.method().clone();
+        let path = req.path().to_string();
+        self.service
+            .call(req)
+            .map(move |result| {
+                let status = match &result {
+                    Ok(response) => response.status(),
+                    Err(err) => err.as_response_error().status_code(),
+                };
+                info!(
+                    "{} {} {} took {:?}",
+                    method,
+                    path,
+                    status.as_u16(),
+                    start.elapsed()
+                );
+                result
+            })
+            .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn passes_the_response_through() {
+        // arrange
+        let mut app = test::init_service(App::new().wrap(RequestTiming).route(
+            "/slow",
+            web::get().to(|| HttpResponse::Created().body("made it")),
+        ))
+        .await;
+        let req = test::TestRequest::get().uri("/slow").to_request();
+
+        // act
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(test::read_body(resp).await, "made it");
+    }
+
+    #[actix_rt::test]
+    async fn passes_missing_routes_through() {
+        // arrange
+        let mut app = test::init_service(App::new().wrap(RequestTiming)).await;
+        let req = test::TestRequest::get().uri("/missing").to_request();
+
+        // act
+        let resp = test::call_service(&mut app, req).await;
+
+        // assert
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,96 @@
+//! Serve HTTPS directly rather than from behind a reverse proxy
+//!
+//! TLS is turned on by setting both `TLS_CERT` and `TLS_KEY` to PEM files.
+use std::{fs::File, io::BufReader, path::Path};
+
+use eyre::{eyre, Result, WrapErr};
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    NoClientAuth, PrivateKey, ServerConfig,
+};
+
+/// Load a certificate chain and private key, checking they can be used to serve TLS
+pub fn load_tls_config(cert_path: &Path, key_path: &Path) -> Result<ServerConfig> {
+    let chain = certs(&mut open(cert_path)?)
+        .map_err(|()| eyre!("{} is not a valid PEM file", cert_path.display()))?;
+    if chain.is_empty() {
+        return Err(eyre!("no certificates found in {}", cert_path.display()));
+    }
+    let key = load_key(key_path)?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(chain, key)
+        .wrap_err_with(|| format!("cannot use the key in {}", key_path.display()))?;
+    Ok(config)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let invalid = || eyre!("{} is not a valid PEM file", path.display());
+    let mut keys = pkcs8_private_keys(&mut open(path)?).map_err(|()| invalid())?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(path)?).map_err(|()| invalid())?;
+    }
+    keys.pop()
+        .ok_or_else(|| eyre!("no private key found in {}", path.display()))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    Ok(BufReader::new(File::open(path).wrap_err_with(|| {
+        format!("cannot open {}", path.display())
+    })?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::load_tls_config;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/tls")).join(name)
+    }
+
+    fn load_error(cert: &str, key: &str) -> String {
+        match load_tls_config(&fixture(cert), &fixture(key)) {
+            Ok(_) => panic!("{} and {} should not load", cert, key),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn loads_self_signed_certificate() {
+        // act
+        let config = load_tls_config(&fixture("cert.pem"), &fixture("key.pem"));
+
+        // assert
+        assert!(config.is_ok());
+    }
+
+    #[test]
+    fn missing_file_names_the_path() {
+        // act
+        let err = load_error("missing.pem", "key.pem");
+
+        // assert
+        assert!(err.contains("missing.pem"));
+    }
+
+    #[test]
+    fn certificate_file_without_certificates_is_rejected() {
+        // act
+        let err = load_error("key.pem", "key.pem");
+
+        // assert
+        assert!(err.contains("no certificates found"));
+    }
+
+    #[test]
+    fn key_file_without_a_key_is_rejected() {
+        // act
+        let err = load_error("cert.pem", "cert.pem");
+
+        // assert
+        assert!(err.contains("no private key found"));
+    }
+}
@@ -2,16 +2,21 @@
 //!
 //! These data types are fully formatted and ready to be displayed in a UI.
 //! They often have a corollary in the `data` module.
-use std::collections::HashMap;
+use std::{collections::HashMap, convert::TryFrom};
 
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
+use eyre::eyre;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
-use glow_events::v2::{Event, Message, Payload};
+use glow_events::{
+    colour::rgb_to_hex,
+    v2::{Event, Message, Payload},
+};
 
 use crate::data;
 use crate::formatting::format_time_since;
+use crate::weather::Forecast;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ClimateMeasurement {
@@ -32,6 +37,13 @@ impl From<data::ClimateMeasurement> for ClimateMeasurement {
 pub struct ClimateObservation {
     pub indoor: Option<ClimateMeasurement>,
     pub outdoor: Option<ClimateMeasurement>,
+    pub wind_speed: Option<String>,
+    pub wind_direction: Option<String>,
+    pub feels_like: Option<String>,
+    /// Indoor minus outdoor temperature
+    pub temperature_delta: Option<String>,
+    /// Indoor minus outdoor humidity
+    pub humidity_delta: Option<String>,
     pub age: String,
     pub date: String,
     pub time: String,
@@ -42,9 +54,27 @@ impl From<data::ClimateObservation> for ClimateObservation {
         let date = observation.date_time.format("%Y-%m-%d").to_string();
         let time = observation.date_time.format("%H:%M").to_string();
         let age = format_time_since(Utc::now(), observation.date_time);
+        let both = observation
+            .indoor
+            .as_ref()
+            .zip(observation.outdoor.as_ref());
+        let temperature_delta = both
+            .map(|(indoor, outdoor)| format!("{:+.1}", indoor.temperature - outdoor.temperature));
+        let humidity_delta =
+            both.map(|(indoor, outdoor)| format!("{:+.1}", indoor.humidity - outdoor.humidity));
         Self {
             indoor: observation.indoor.map(ClimateMeasurement::from),
             outdoor: observation.outdoor.map(ClimateMeasurement::from),
+            wind_speed: observation.wind.as_ref().map(|wind| wind.speed.to_string()),
+            wind_direction: observation
+                .wind
+                .as_ref()
+                .map(|wind| wind.direction.abbreviation().to_string()),
+            feels_like: observation
+                .feels_like
+                .map(|feels_like| format!("{:.1}", feels_like)),
+            temperature_delta,
+            humidity_delta,
             age,
             date,
             time,
@@ -52,6 +82,76 @@ impl From<data::ClimateObservation> for ClimateObservation {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForecastSummary {
+    pub day: String,
+    pub min_temperature: String,
+    pub max_temperature: Option<String>,
+    pub humidity: String,
+    pub wind_speed: String,
+}
+
+impl ForecastSummary {
+    /// Summarise the forecast for `day` days after it was published
+    pub fn new(day: i64, forecast: &Forecast) -> Self {
+        Self {
+            day: if day == 0 {
+                String::from("Today")
+            } else {
+                (forecast.date_time + Duration::days(day))
+                    .format("%A")
+                    .to_string()
+            },
+            min_temperature: forecast.min_temperature.to_string(),
+            max_temperature: forecast.max_temperature.map(|t| t.to_string()),
+            humidity: forecast.humidity.to_string(),
+            wind_speed: forecast.wind_speed.to_string(),
+        }
+    }
+}
+
+/// One day of indoor highs and lows
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DailyClimateSummary {
+    pub day: String,
+    pub min_temperature: String,
+    pub max_temperature: String,
+    pub mean_humidity: String,
+}
+
+impl From<data::DailyClimate> for DailyClimateSummary {
+    fn from(daily: data::DailyClimate) -> Self {
+        Self {
+            day: daily.day.format("%a %e %b").to_string(),
+            min_temperature: format!("{:.1}", daily.min_temperature),
+            max_temperature: format!("{:.1}", daily.max_temperature),
+            mean_humidity: format!("{:.0}", daily.mean_humidity),
+        }
+    }
+}
+
+/// The latest reading from a labelled sensor
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SensorObservation {
+    pub sensor: String,
+    pub observation: ClimateObservation,
+}
+
+impl TryFrom<Message> for SensorObservation {
+    type Error = eyre::Error;
+
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
+        if let Payload::Event(Event::LabelledMeasurement(sensor, _)) = message.payload() {
+            Ok(Self {
+                sensor: sensor.clone(),
+                observation: data::ClimateObservation::try_from(message)?.into(),
+            })
+        } else {
+            Err(eyre!("not a labelled measurement"))
+        }
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub(crate) struct EventSummary {
     pub icon: String,
@@ -73,18 +173,29 @@ impl From<Message> for EventSummary {
 
 impl From<&Message> for EventSummary {
     fn from(message: &Message) -> Self {
-        let mut summary = EventSummary::default();
-
-        if let Payload::Event(event) = message.payload() {
-            summary.stamp = message.stamp().format("%F %T").to_string();
-            summary.date = message.stamp().format("%Y-%m-%d").to_string();
-            summary.time = message.stamp().format("%H:%M:%S").to_string();
-            summary.title = event.title().to_string();
-            summary.icon = get_event_icon(event).to_string();
-            summary.icon_colour = get_event_icon_colour(event).to_string();
-            summary.detail = format!("{}", event);
-            summary.event_type = event.event_type().to_string();
-            summary.extra = get_event_extra(event);
+        let mut summary = EventSummary {
+            stamp: message.stamp().format("%F %T").to_string(),
+            date: message.stamp().format("%Y-%m-%d").to_string(),
+            time: message.stamp().format("%H:%M:%S").to_string(),
+            ..EventSummary::default()
+        };
+
+        match message.payload() {
+            Payload::Event(event) => {
+                summary.title = event.title().to_string();
+                summary.icon = get_event_icon(event).to_string();
+                summary.icon_colour = get_event_icon_colour(event).to_string();
+                summary.detail = format!("{}", event);
+                summary.event_type = event.event_type().to_string();
+                summary.extra = get_event_extra(event);
+            }
+            Payload::Command(command) => {
+                summary.title = command.title().to_string();
+                summary.icon = "send".to_string();
+                summary.icon_colour = "indigo".to_string();
+                summary.detail = command.to_string();
+                summary.event_type = command.command_type().to_string();
+            }
         }
         summary
     }
@@ -92,21 +203,35 @@ impl From<&Message> for EventSummary {
 
 fn get_event_icon(event: &Event) -> &'static str {
     match event {
-        Event::Measurement(_) | Event::MeasurementFailure => "eco",
-        Event::SingleTap => "touch_app",
-        Event::Devices(_) | Event::HeaterStarted | Event::HeaterStopped => "settings_remote",
+        Event::Measurement(_) | Event::LabelledMeasurement(_, _) | Event::MeasurementFailure => {
+            "eco"
+        }
+        Event::SingleTap | Event::DoubleTap | Event::TripleTap => "touch_app",
+        Event::Devices(_) | Event::HeaterStarted | Event::HeaterStopped | Event::HeaterState(_) => {
+            "settings_remote"
+        }
+        Event::PowerReading { .. } => "power",
         Event::LEDBrightness(_) | Event::LEDColours(_) => "brightness_4",
+        Event::SelfTestResult { .. } => "fact_check",
         Event::Started => "started",
+        Event::CommandAck { .. } => "done",
     }
 }
 
 fn get_event_icon_colour(event: &Event) -> &'static str {
     match event {
-        Event::Measurement(_) | Event::MeasurementFailure => "green",
-        Event::SingleTap => "teal",
-        Event::Devices(_) | Event::HeaterStarted | Event::HeaterStopped => "amber",
+        Event::Measurement(_)
+        | Event::LabelledMeasurement(_, _)
+        | Event::MeasurementFailure
+        | Event::SelfTestResult { passed: true, .. } => "green",
+        Event::SingleTap | Event::DoubleTap | Event::TripleTap => "teal",
+        Event::Devices(_) | Event::HeaterStarted | Event::HeaterStopped | Event::HeaterState(_) => {
+            "amber"
+        }
+        Event::PowerReading { .. } => "orange",
         Event::LEDBrightness(_) | Event::LEDColours(_) => "light-blue",
-        Event::Started => "red",
+        Event::SelfTestResult { passed: false, .. } | Event::Started => "red",
+        Event::CommandAck { .. } => "indigo",
     }
 }
 
@@ -116,7 +241,7 @@ fn get_event_extra(event: &Event) -> HashMap<String, Value> {
         Event::LEDColours(colours) => {
             let colours = colours
                 .iter()
-                .map(|c| format!("#{:02X}{:02X}{:02X}", c.0, c.1, c.2))
+                .map(|c| rgb_to_hex((c.0, c.1, c.2)))
                 .collect::<Vec<String>>();
 
             extra.insert("colours".into(), colours.into());
@@ -134,11 +259,106 @@ fn get_event_extra(event: &Event) -> HashMap<String, Value> {
     extra
 }
 
+/// Whether the device is reporting, for uptime checks
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceStatus {
+    pub status: String,
+    pub last_event_age_seconds: Option<i64>,
+    pub alarming: bool,
+    /// Seconds since the device last started
+    pub uptime_seconds: Option<i64>,
+}
+
+/// How long since a device last sent a type of event
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LastSeen {
+    pub event_type: String,
+    pub age: String,
+}
+
+/// What the LED strip is showing according to the latest events
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LedState {
+    pub colours: Vec<(u8, u8, u8)>,
+    pub brightness: Option<f32>,
+}
+
 #[derive(Deserialize)]
 pub struct SetBrightness {
     pub brightness: u32,
 }
 
+#[derive(Deserialize)]
+pub struct SetSolidColour {
+    /// A `#RRGGBB` colour from a colour picker, or a short `#RGB` one
+    pub colour: String,
+}
+
+#[derive(Deserialize)]
+pub struct IndexQuery {
+    /// How many recent events to show
+    pub events: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    #[serde(rename = "type")]
+    pub event_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EventsRangeQuery {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct HistogramQuery {
+    pub hours: Option<i64>,
+    pub width: Option<f64>,
+}
+
+/// How many indoor temperatures fell in `from..to`
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct TemperatureBucket {
+    pub from: f64,
+    pub to: f64,
+    pub count: u32,
+}
+
+#[derive(Deserialize)]
+pub struct SnoozeQuery {
+    pub minutes: Option<i64>,
+}
+
+/// When the device-silent alarm will sound again
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct AlarmSnoozed {
+    pub until: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct SelectDevice {
+    pub device: String,
+}
+
+#[derive(Deserialize)]
+pub struct RunHeater {
+    /// How long to run the heater for in seconds, an empty field uses the device default
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub duration: Option<u64>,
+}
+
+fn empty_as_none<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)?.as_deref() {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Login {
     pub password: String,
@@ -155,9 +375,88 @@ mod tests {
         Measurement, TPLinkDevice,
     };
 
-    use super::EventSummary;
+    use chrono::Utc;
+
+    use super::{ClimateObservation, EventSummary};
+    use crate::data;
+    use crate::weather::{Observation, WindDirection};
 
     #[test]
+    fn climate_observation_with_wind() {
+        // arrange
+        let observation = Observation {
+            temperature: 12,
+            humidity: 80,
+            wind_speed: 14,
+            wind_direction: WindDirection::SouthWesterly,
+            date_time: Utc::now(),
+            point: (12.1, 12.2),
+            url: "https://example.org".to_string(),
+        };
+        let observation =
+            data::ClimateObservation::try_from_parts(None, Some(observation)).unwrap();
+
+        // act
+        let observation = ClimateObservation::from(observation);
+
+        // assert
+        assert_eq!(observation.wind_speed, Some("14".to_string()));
+        assert_eq!(observation.wind_direction, Some("SW".to_string()));
+    }
+
+    #[test]
+    fn climate_observation_without_observation() {
+        // arrange
+        let message = Message::new_event(Event::Measurement(Measurement::new(20.1, 50.2)));
+        let observation = data::ClimateObservation::try_from_parts(Some(message), None).unwrap();
+
+        // act
+        let observation = ClimateObservation::from(observation);
+
+        // assert
+        assert!(observation.outdoor.is_none());
+        assert_eq!(observation.wind_speed, None);
+        assert_eq!(observation.wind_direction, None);
+    }
+
+    #[test]
+    fn climate_observation_deltas() {
+        // arrange
+        let outdoor = Observation {
+            temperature: 12,
+            humidity: 80,
+            wind_speed: 14,
+            wind_direction: WindDirection::SouthWesterly,
+            date_time: Utc::now(),
+            point: (12.1, 12.2),
+            url: "https://example.org".to_string(),
+        };
+        let indoor = Message::new_event(Event::Measurement(Measurement::new(20.14, 50.2)));
+        let cases = vec![
+            (
+                Some(indoor.clone()),
+                Some(outdoor.clone()),
+                Some("+8.1"),
+                Some("-29.8"),
+            ),
+            (Some(indoor), None, None, None),
+            (None, Some(outdoor), None, None),
+        ];
+
+        for (indoor, outdoor, temperature_delta, humidity_delta) in cases {
+            let observation = data::ClimateObservation::try_from_parts(indoor, outdoor).unwrap();
+
+            // act
+            let observation = ClimateObservation::from(observation);
+
+            // assert
+            assert_eq!(observation.temperature_delta.as_deref(), temperature_delta);
+            assert_eq!(observation.humidity_delta.as_deref(), humidity_delta);
+        }
+    }
+
+    #[test]
+    #[allow(clippy::too_many_lines)]
     fn event_summary() {
         struct EventSummaryTest {
             message: Message,
@@ -193,6 +492,16 @@ mod tests {
                 "green",
                 HashMap::new(),
             ),
+            EventSummaryTest::new(
+                Message::new(Payload::Event(Event::LabelledMeasurement(
+                    "bathroom".to_string(),
+                    Measurement::new(1.1, 2.2),
+                ))),
+                "bathroom temperature: 1.10°C humidity: 2.20%",
+                "eco",
+                "green",
+                HashMap::new(),
+            ),
             EventSummaryTest::new(
                 Message::new(Payload::Event(Event::SingleTap)),
                 "single tap",
@@ -200,6 +509,27 @@ mod tests {
                 "teal",
                 HashMap::new(),
             ),
+            EventSummaryTest::new(
+                Message::new(Payload::Event(Event::PowerReading { watts: 1520.0 })),
+                "power: 1520.0W",
+                "power",
+                "orange",
+                HashMap::new(),
+            ),
+            EventSummaryTest::new(
+                Message::new(Payload::Event(Event::HeaterState(false))),
+                "heater off",
+                "settings_remote",
+                "amber",
+                HashMap::new(),
+            ),
+            EventSummaryTest::new(
+                Message::new(Payload::Event(Event::DoubleTap)),
+                "double tap",
+                "touch_app",
+                "teal",
+                HashMap::new(),
+            ),
             EventSummaryTest::new(
                 Message::new(Payload::Event(Event::Started)),
                 "started",
@@ -207,6 +537,16 @@ mod tests {
                 "red",
                 HashMap::new(),
             ),
+            EventSummaryTest::new(
+                Message::new(Payload::Event(Event::SelfTestResult {
+                    component: "sensor".to_string(),
+                    passed: false,
+                })),
+                "sensor self test failed",
+                "fact_check",
+                "red",
+                HashMap::new(),
+            ),
             EventSummaryTest::new(
                 Message::new(Payload::Event(Event::LEDColours(vec![
                     (123, 123, 123),
@@ -242,9 +582,16 @@ mod tests {
             ),
             EventSummaryTest::new(
                 Message::new(Payload::Command(Command::Stop)),
-                "",
-                "",
-                "",
+                "stop",
+                "send",
+                "indigo",
+                HashMap::new(),
+            ),
+            EventSummaryTest::new(
+                Message::new(Payload::Command(Command::SetBrightness(0.5))),
+                "set brightness: 0.50",
+                "send",
+                "indigo",
                 HashMap::new(),
             ),
         ];
@@ -18,33 +18,61 @@ use log::{error, info};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-use crate::store::{Store, StorePool};
-use futures::join;
+use crate::metrics::Metrics;
+use crate::retry::with_retries;
+use crate::store::{SQLiteStorePool, Store, StorePool};
+use futures::{join, FutureExt};
+
+const WEATHER_ATTEMPTS: u32 = 3;
+const WEATHER_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The monitor as the server runs it, shared with the routes for on-demand refreshes
+pub type BBCWeatherMonitor = WeatherMonitor<SQLiteStorePool, BBCWeatherService<HyperUrlGetter>>;
 
 #[derive(Clone)]
 pub struct WeatherMonitor<P: StorePool, W: WeatherService> {
     pool: P,
     weather: W,
+    metrics: Metrics,
+    interval: Duration,
 }
 
 impl<P: StorePool + 'static, W: WeatherService + 'static> WeatherMonitor<P, W> {
-    pub fn new(pool: P, weather: W) -> Self {
-        Self { pool, weather }
-    }
-
-    async fn update(self) {
-        let (observation, forecast) = join!(self.weather.observation(), self.weather.forecast());
-        self.pool
-            .get()
-            .and_then(|store| {
-                store.add_observation(&observation?)?;
-                forecast?
-                    .iter()
-                    .map(|forecast| store.add_forecast(forecast))
-                    .collect::<Result<Vec<()>>>()?;
-                Ok(())
-            })
-            .unwrap_or_else(|err| error!("{}", err));
+    pub fn new(pool: P, weather: W, metrics: Metrics, interval: Duration) -> Self {
+        Self {
+            pool,
+            weather,
+            metrics,
+            interval,
+        }
+    }
+
+    /// Fetch the latest observation and forecast and store them
+    pub async fn update(self) -> Result<()> {
+        let (observation, forecast) = join!(
+            with_retries(WEATHER_ATTEMPTS, WEATHER_RETRY_DELAY, || self
+                .weather
+                .observation()),
+            with_retries(WEATHER_ATTEMPTS, WEATHER_RETRY_DELAY, || self
+                .weather
+                .forecast()),
+        );
+        let result = self.pool.get().and_then(|store| {
+            store.add_observation(&observation?)?;
+            forecast?
+                .iter()
+                .map(|forecast| store.add_forecast(forecast))
+                .collect::<Result<Vec<()>>>()?;
+            Ok(())
+        });
+        match &result {
+            Ok(()) => self.metrics.weather_fetch_successes.inc(),
+            Err(err) => {
+                self.metrics.weather_fetch_failures.inc();
+                error!("{}", err);
+            }
+        }
+        result
     }
 }
 
@@ -54,10 +82,10 @@ impl<P: StorePool + 'static, W: WeatherService + 'static> Actor for WeatherMonit
     fn started(&mut self, ctx: &mut Context<Self>) {
         info!("Weather service is alive");
 
-        ctx.spawn(actix::fut::wrap_future(self.clone().update()));
+        ctx.spawn(actix::fut::wrap_future(self.clone().update().map(|_| ())));
 
-        ctx.run_interval(Duration::from_secs(60 * 60), move |act, ctx| {
-            let fut = actix::fut::wrap_future(act.clone().update());
+        ctx.run_interval(self.interval, move |act, ctx| {
+            let fut = actix::fut::wrap_future(act.clone().update().map(|_| ()));
             ctx.spawn(fut);
         });
     }
@@ -83,6 +111,30 @@ pub enum WindDirection {
     NorthNorthWesterly,
 }
 
+impl WindDirection {
+    /// The compass point abbreviation, for example "SW"
+    pub fn abbreviation(self) -> &'static str {
+        match self {
+            WindDirection::Northerly => "N",
+            WindDirection::NorthNorthEasterly => "NNE",
+            WindDirection::NorthEasterly => "NE",
+            WindDirection::EastNorthEasterly => "ENE",
+            WindDirection::Easterly => "E",
+            WindDirection::EastSouthEasterly => "ESE",
+            WindDirection::SouthEasterly => "SE",
+            WindDirection::SouthSouthEasterly => "SSE",
+            WindDirection::Southerly => "S",
+            WindDirection::SouthSouthWesterly => "SSW",
+            WindDirection::SouthWesterly => "SW",
+            WindDirection::WestSouthWesterly => "WSW",
+            WindDirection::Westerly => "W",
+            WindDirection::WestNorthWesterly => "WNW",
+            WindDirection::NorthWesterly => "NW",
+            WindDirection::NorthNorthWesterly => "NNW",
+        }
+    }
+}
+
 impl FromStr for WindDirection {
     type Err = Error;
 
@@ -122,6 +174,44 @@ pub struct Observation {
     pub url: String,
 }
 
+impl Observation {
+    /// How warm or cold it feels outside, see `feels_like`
+    pub fn feels_like(&self) -> f64 {
+        feels_like(
+            f64::from(self.temperature),
+            f64::from(self.humidity),
+            f64::from(self.wind_speed),
+        )
+    }
+}
+
+const KPH_PER_MPH: f64 = 1.609_344;
+
+/// Apparent temperature in °C from a temperature in °C, relative humidity and wind speed in mph
+///
+/// Uses the North American wind chill index at 10°C and below and the Rothfusz heat index
+/// regression at 27°C and above. Neither applies in between so the temperature is returned.
+pub fn feels_like(temperature: f64, humidity: f64, wind_speed: f64) -> f64 {
+    let wind_kph = wind_speed * KPH_PER_MPH;
+    if temperature <= 10.0 && wind_kph > 4.8 {
+        let wind = wind_kph.powf(0.16);
+        13.12 + 0.6215 * temperature - 11.37 * wind + 0.3965 * temperature * wind
+    } else if temperature >= 27.0 && humidity >= 40.0 {
+        let t = glow_events::celsius_to_fahrenheit(temperature);
+        let r = humidity;
+        let index = -42.379 + 2.049_015_23 * t + 10.143_331_27 * r
+            - 0.224_755_41 * t * r
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * r * r
+            + 0.001_228_74 * t * t * r
+            + 0.000_852_82 * t * r * r
+            - 0.000_001_99 * t * t * r * r;
+        (index - 32.0) * 5.0 / 9.0
+    } else {
+        temperature
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Forecast {
     pub max_temperature: Option<u32>,
@@ -380,10 +470,18 @@ impl UrlGetter for HyperUrlGetter {
 }
 
 #[cfg(test)]
+// async_trait expansions of the test getters trip these
+#[allow(clippy::used_underscore_binding, clippy::unused_async)]
 mod tests {
     use super::*;
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
     use tokio;
 
+    use crate::store::test::TestDb;
+
     #[derive(Clone, Default, Debug)]
     struct TestUrlGetter {
         response: Vec<u8>,
@@ -402,9 +500,51 @@ mod tests {
         }
     }
 
-    #[tokio::test]
-    async fn get_observation() {
-        let data = r#"<?xml version="1.0" encoding="UTF-8"?>
+    /// Fails a fixed number of times before returning the response
+    #[derive(Clone, Default, Debug)]
+    struct FlakyUrlGetter {
+        failures: Arc<AtomicU32>,
+        response: Vec<u8>,
+    }
+
+    impl FlakyUrlGetter {
+        fn new(failures: u32, response: Vec<u8>) -> Self {
+            Self {
+                failures: Arc::new(AtomicU32::new(failures)),
+                response,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl UrlGetter for FlakyUrlGetter {
+        async fn get(&self, _url: &str) -> Result<Vec<u8>> {
+            let remaining = self.failures.load(Ordering::SeqCst);
+            if remaining > 0 {
+                self.failures.store(remaining - 1, Ordering::SeqCst);
+                Err(eyre!("failed to connect"))
+            } else {
+                Ok(self.response.clone())
+            }
+        }
+    }
+
+    /// Serves the forecast feed for forecast URLs and the observation feed for the rest
+    #[derive(Clone, Default, Debug)]
+    struct FeedUrlGetter;
+
+    #[async_trait]
+    impl UrlGetter for FeedUrlGetter {
+        async fn get(&self, url: &str) -> Result<Vec<u8>> {
+            if url.contains("forecast") {
+                Ok(FORECAST_DATA.as_bytes().to_owned())
+            } else {
+                Ok(OBSERVATION_DATA.as_bytes().to_owned())
+            }
+        }
+    }
+
+    const OBSERVATION_DATA: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
 <rss xmlns:atom="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:georss="http://www.georss.org/georss" version="2.0">
   <channel>
     <title>BBC Weather - Observations for  Land's End Airport, GB</title>
@@ -428,18 +568,8 @@ mod tests {
     </item>
   </channel>
 </rss>"#;
-        let service =
-            BBCWeatherService::with_getter("test", TestUrlGetter::new(data.as_bytes().to_owned()));
 
-        let observation = service.observation().await.unwrap();
-
-        assert_eq!(observation.temperature, 15);
-        assert_eq!(observation.wind_direction, WindDirection::SouthWesterly);
-    }
-
-    #[tokio::test]
-    async fn get_forecast() {
-        let data = r#"<?xml version="1.0" encoding="UTF-8"?>
+    const FORECAST_DATA: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
 <rss xmlns:atom="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:georss="http://www.georss.org/georss" version="2.0">
   <channel>
     <title>BBC Weather - Forecast for  Land's End Airport, GB</title>
@@ -487,8 +617,51 @@ mod tests {
   </channel>
 </rss>"#;
 
-        let service =
-            BBCWeatherService::with_getter("test", TestUrlGetter::new(data.as_bytes().to_owned()));
+    #[tokio::test]
+    async fn retry_succeeds_after_failures() {
+        let service = BBCWeatherService::with_getter(
+            "test",
+            FlakyUrlGetter::new(2, OBSERVATION_DATA.as_bytes().to_owned()),
+        );
+
+        let observation = with_retries(3, Duration::from_millis(1), || service.observation())
+            .await
+            .unwrap();
+
+        assert_eq!(observation.temperature, 15);
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up_after_all_attempts() {
+        let service = BBCWeatherService::with_getter(
+            "test",
+            FlakyUrlGetter::new(3, OBSERVATION_DATA.as_bytes().to_owned()),
+        );
+
+        let observation = with_retries(3, Duration::from_millis(1), || service.observation()).await;
+
+        assert!(observation.is_err());
+    }
+
+    #[tokio::test]
+    async fn get_observation() {
+        let service = BBCWeatherService::with_getter(
+            "test",
+            TestUrlGetter::new(OBSERVATION_DATA.as_bytes().to_owned()),
+        );
+
+        let observation = service.observation().await.unwrap();
+
+        assert_eq!(observation.temperature, 15);
+        assert_eq!(observation.wind_direction, WindDirection::SouthWesterly);
+    }
+
+    #[tokio::test]
+    async fn get_forecast() {
+        let service = BBCWeatherService::with_getter(
+            "test",
+            TestUrlGetter::new(FORECAST_DATA.as_bytes().to_owned()),
+        );
         let forecast = service.forecast().await.unwrap();
 
         assert_eq!(forecast.len(), 3);
@@ -496,4 +669,79 @@ mod tests {
         assert_eq!(forecast[0].min_temperature, 13);
         assert_eq!(forecast[1].max_temperature, Some(16));
     }
+
+    #[tokio::test]
+    async fn update_stores_the_observation() {
+        // arrange
+        let db = TestDb::with_now(|| "2020-07-07T16:00:00Z".parse().unwrap());
+        let monitor = WeatherMonitor::new(
+            db.pool().clone(),
+            BBCWeatherService::with_getter("test", FeedUrlGetter),
+            Metrics::new(),
+            Duration::from_secs(60),
+        );
+
+        // act
+        let result = monitor.update().await;
+
+        // assert
+        assert!(result.is_ok());
+        let observations = db
+            .store()
+            .unwrap()
+            .get_observations_since(chrono::Duration::hours(2))
+            .unwrap();
+        assert_eq!(observations.len(), 1);
+        assert_eq!(observations[0].temperature, 15);
+    }
+
+    #[test]
+    fn wind_direction_abbreviation() {
+        let cases = [
+            ("Northerly", "N"),
+            ("North North Easterly", "NNE"),
+            ("North Easterly", "NE"),
+            ("East North Easterly", "ENE"),
+            ("Easterly", "E"),
+            ("East South Easterly", "ESE"),
+            ("South Easterly", "SE"),
+            ("South South Easterly", "SSE"),
+            ("Southerly", "S"),
+            ("South South Westerly", "SSW"),
+            ("South Westerly", "SW"),
+            ("West South Westerly", "WSW"),
+            ("Westerly", "W"),
+            ("West North Westerly", "WNW"),
+            ("North Westerly", "NW"),
+            ("North North Westerly", "NNW"),
+        ];
+
+        for (name, abbreviation) in &cases {
+            let direction: WindDirection = name.parse().unwrap();
+            assert_eq!(direction.abbreviation(), *abbreviation);
+        }
+    }
+
+    #[test]
+    fn feels_like_wind_chill() {
+        // Environment Canada: 0°C with a 20km/h wind feels like -5°C
+        assert!((feels_like(0.0, 50.0, 20.0 / KPH_PER_MPH) + 5.2).abs() < 0.1);
+        // -10°C with a 20km/h wind feels like -18°C
+        assert!((feels_like(-10.0, 50.0, 20.0 / KPH_PER_MPH) + 17.9).abs() < 0.1);
+    }
+
+    #[test]
+    fn feels_like_heat_index() {
+        // NWS heat index chart: 90°F at 60% humidity feels like 100°F
+        assert!((feels_like(32.2, 60.0, 5.0) - 37.8).abs() < 0.5);
+        // 86°F at 70% humidity feels like 95°F
+        assert!((feels_like(30.0, 70.0, 5.0) - 35.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn feels_like_midrange_is_the_temperature() {
+        assert!((feels_like(18.0, 60.0, 20.0) - 18.0).abs() < f64::EPSILON);
+        // too calm for wind chill
+        assert!((feels_like(5.0, 60.0, 1.0) - 5.0).abs() < f64::EPSILON);
+    }
 }
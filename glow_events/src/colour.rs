@@ -0,0 +1,81 @@
+//! Hex colours, shared by the device and the web view
+use std::fmt;
+
+/// Why a hex colour could not be parsed
+#[derive(Debug, PartialEq, Clone)]
+pub struct HexColourError(pub String);
+
+impl fmt::Display for HexColourError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} is not a #RGB or #RRGGBB colour", self.0)
+    }
+}
+
+impl std::error::Error for HexColourError {}
+
+/// Format red, green and blue as `#RRGGBB`
+pub fn rgb_to_hex((red, green, blue): (u8, u8, u8)) -> String {
+    format!("#{:02X}{:02X}{:02X}", red, green, blue)
+}
+
+/// Parse a `#RRGGBB` or short `#RGB` colour, the `#` is optional
+pub fn rgb_from_hex(hex: &str) -> Result<(u8, u8, u8), HexColourError> {
+    let error = || HexColourError(hex.to_string());
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(error());
+    }
+    let channel = |digits: &str| u8::from_str_radix(digits, 16).map_err(|_| error());
+    match digits.len() {
+        // each digit of a short colour is doubled, so #f80 is #ff8800
+        3 => {
+            let short = |at: usize| channel(&digits[at..=at]).map(|value| value * 0x11);
+            Ok((short(0)?, short(1)?, short(2)?))
+        }
+        6 => Ok((
+            channel(&digits[0..2])?,
+            channel(&digits[2..4])?,
+            channel(&digits[4..6])?,
+        )),
+        _ => Err(error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_hex() {
+        assert_eq!(rgb_to_hex((255, 128, 0)), "#FF8000");
+        assert_eq!(rgb_to_hex((10, 10, 100)), "#0A0A64");
+    }
+
+    #[test]
+    fn from_long_hex() {
+        assert_eq!(rgb_from_hex("#ff8000"), Ok((255, 128, 0)));
+        assert_eq!(rgb_from_hex("0A0a64"), Ok((10, 10, 100)));
+    }
+
+    #[test]
+    fn from_short_hex() {
+        assert_eq!(rgb_from_hex("#f80"), Ok((255, 136, 0)));
+        assert_eq!(rgb_from_hex("0aF"), Ok((0, 170, 255)));
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        for rgb in &[(0, 0, 0), (255, 255, 255), (1, 127, 200)] {
+            assert_eq!(rgb_from_hex(&rgb_to_hex(*rgb)), Ok(*rgb));
+        }
+    }
+
+    #[test]
+    fn from_invalid_hex() {
+        for hex in &[
+            "", "#", "#ff80", "#ff80000", "#gg8000", "#+f8000", "##f80", "#ff80é", "coral",
+        ] {
+            assert_eq!(rgb_from_hex(hex), Err(HexColourError(hex.to_string())));
+        }
+    }
+}
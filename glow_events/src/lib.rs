@@ -1,8 +1,46 @@
+use std::{fmt, ops::RangeInclusive};
+
 use serde::{Deserialize, Serialize};
 
+pub mod colour;
+pub mod logging;
 pub mod v1;
 pub mod v2;
 
+/// Convert a temperature for anything that expects Fahrenheit
+pub fn celsius_to_fahrenheit(celsius: f64) -> f64 {
+    celsius * 9.0 / 5.0 + 32.0
+}
+
+/// Temperatures in °C the AM2320 can report, anything outside is a bad reading
+pub const TEMPERATURE_RANGE: RangeInclusive<f64> = -40.0..=85.0;
+/// Relative humidities in % that make physical sense
+pub const HUMIDITY_RANGE: RangeInclusive<f64> = 0.0..=100.0;
+
+/// Why a measurement is not plausible
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MeasurementError {
+    TemperatureOutOfRange(f64),
+    HumidityOutOfRange(f64),
+}
+
+impl fmt::Display for MeasurementError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MeasurementError::TemperatureOutOfRange(temperature) => write!(
+                f,
+                "temperature {}°C is outside {:?}",
+                temperature, TEMPERATURE_RANGE
+            ),
+            MeasurementError::HumidityOutOfRange(humidity) => {
+                write!(f, "humidity {}% is outside {:?}", humidity, HUMIDITY_RANGE)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MeasurementError {}
+
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub struct Measurement {
     pub temperature: f64,
@@ -17,6 +55,24 @@ impl Measurement {
         }
     }
 
+    /// Create a measurement from a sensor reading, rejecting values that cannot be real
+    ///
+    /// NaN is never in range. Stored and received measurements use `new`, which does not
+    /// check.
+    pub fn new_checked(temperature: f64, humidity: f64) -> Result<Self, MeasurementError> {
+        if !TEMPERATURE_RANGE.contains(&temperature) {
+            return Err(MeasurementError::TemperatureOutOfRange(temperature));
+        }
+        if !HUMIDITY_RANGE.contains(&humidity) {
+            return Err(MeasurementError::HumidityOutOfRange(humidity));
+        }
+        Ok(Self::new(temperature, humidity))
+    }
+
+    pub fn temperature_fahrenheit(&self) -> f64 {
+        celsius_to_fahrenheit(self.temperature)
+    }
+
     pub fn roughly_equal(&self, other: &Measurement) -> bool {
         self.temperature_roughly_equal(other) && (self.humidity - other.humidity).abs() < 0.001
     }
@@ -78,4 +134,53 @@ mod tests {
         // assert
         assert!(!(&previous_data).roughly_equal(&new_data));
     }
+
+    #[test]
+    fn new_checked_accepts_plausible_values() {
+        for (temperature, humidity) in &[(21.5, 45.0), (-40.0, 0.0), (85.0, 100.0)] {
+            assert_eq!(
+                Measurement::new_checked(*temperature, *humidity),
+                Ok(Measurement::new(*temperature, *humidity))
+            );
+        }
+    }
+
+    #[test]
+    fn new_checked_rejects_implausible_values() {
+        assert_eq!(
+            Measurement::new_checked(-500.0, 50.0),
+            Err(MeasurementError::TemperatureOutOfRange(-500.0))
+        );
+        assert_eq!(
+            Measurement::new_checked(85.1, 50.0),
+            Err(MeasurementError::TemperatureOutOfRange(85.1))
+        );
+        assert_eq!(
+            Measurement::new_checked(20.0, 300.0),
+            Err(MeasurementError::HumidityOutOfRange(300.0))
+        );
+        assert_eq!(
+            Measurement::new_checked(20.0, -0.1),
+            Err(MeasurementError::HumidityOutOfRange(-0.1))
+        );
+        assert!(matches!(
+            Measurement::new_checked(f64::NAN, 50.0),
+            Err(MeasurementError::TemperatureOutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn celsius_to_fahrenheit_at_freezing_and_boiling() {
+        assert!((celsius_to_fahrenheit(0.0) - 32.0).abs() < f64::EPSILON);
+        assert!((celsius_to_fahrenheit(100.0) - 212.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn measurement_temperature_fahrenheit() {
+        // arrange
+        let measurement = Measurement::new(20.0, 50.0);
+
+        // assert
+        assert!((measurement.temperature_fahrenheit() - 68.0).abs() < f64::EPSILON);
+    }
 }
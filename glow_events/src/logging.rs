@@ -0,0 +1,63 @@
+//! Logger setup shared by the device and the web app
+//!
+//! Logs are plain text by default. Setting `LOG_FORMAT=json` writes one JSON object per
+//! line instead, which is easier for a log aggregator to ingest.
+use std::{env, io::Write};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::Record;
+use serde_json::json;
+
+pub fn init() {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Ok("json") = env::var("LOG_FORMAT").as_deref() {
+        builder.format(|buf, record| writeln!(buf, "{}", json_line(record, Utc::now())));
+    }
+    builder.init();
+}
+
+fn json_line(record: &Record<'_>, stamp: DateTime<Utc>) -> String {
+    json!({
+        "timestamp": stamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use log::{Level, Record};
+    use serde_json::{json, Value};
+
+    use super::json_line;
+
+    #[test]
+    fn json_line_renders_record() {
+        // arrange
+        let stamp = Utc.ymd(2020, 7, 1).and_hms(12, 30, 0);
+
+        // act
+        let line = json_line(
+            &Record::builder()
+                .args(format_args!("heater on for {}s", 60))
+                .level(Level::Info)
+                .target("glow_device::tplink")
+                .build(),
+            stamp,
+        );
+
+        // assert
+        assert_eq!(
+            serde_json::from_str::<Value>(&line).unwrap(),
+            json!({
+                "timestamp": "2020-07-01T12:30:00.000Z",
+                "level": "INFO",
+                "target": "glow_device::tplink",
+                "message": "heater on for 60s",
+            })
+        );
+    }
+}
@@ -3,7 +3,13 @@ use std::fmt;
 use chrono::{offset::Utc, DateTime};
 use serde::{Deserialize, Serialize};
 
-use crate::{Measurement, TPLinkDevice};
+use crate::{v1, Measurement, TPLinkDevice};
+
+/// Content type for messages encoded with `to_cbor`
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// Header a device uses to identify itself when more than one shares a server
+pub const DEVICE_HEADER: &str = "X-Glow-Device";
 
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
@@ -36,6 +42,41 @@ impl Message {
         &self.payload
     }
 
+    /// Upgrade a message stored in the v1 format, keeping its timestamp
+    ///
+    /// v1 mixed commands and events in one enum, requests like `RunHeater` become commands.
+    pub fn from_v1(event: v1::Event) -> Self {
+        use v1::{EnvironmentEvent, LEDEvent, TPLinkEvent, TapEvent};
+
+        let payload = match event.message() {
+            v1::Message::Environment(EnvironmentEvent::Measurement(measurement)) => {
+                Payload::Event(Event::Measurement(*measurement))
+            }
+            v1::Message::Environment(EnvironmentEvent::Failure) => {
+                Payload::Event(Event::MeasurementFailure)
+            }
+            v1::Message::Tap(TapEvent::SingleTap) => Payload::Event(Event::SingleTap),
+            v1::Message::TPLink(TPLinkEvent::ListDevices) => Payload::Command(Command::ListDevices),
+            v1::Message::TPLink(TPLinkEvent::DeviceList(devices)) => {
+                Payload::Event(Event::Devices(devices.clone()))
+            }
+            v1::Message::TPLink(TPLinkEvent::RunHeater) => Payload::Command(Command::RunHeater),
+            v1::Message::TPLink(TPLinkEvent::HeaterStarted) => Payload::Event(Event::HeaterStarted),
+            v1::Message::TPLink(TPLinkEvent::HeaterStopped) => Payload::Event(Event::HeaterStopped),
+            v1::Message::LED(LEDEvent::Brightness(brightness)) => {
+                Payload::Command(Command::SetBrightness(*brightness))
+            }
+            v1::Message::LED(LEDEvent::Party) => Payload::Command(Command::RunParty),
+            v1::Message::LED(LEDEvent::Update) => Payload::Command(Command::UpdateLEDs),
+            v1::Message::LED(LEDEvent::LEDsUpdated(colours)) => {
+                Payload::Event(Event::LEDColours(colours.clone()))
+            }
+            v1::Message::Stop => Payload::Command(Command::Stop),
+            v1::Message::Started => Payload::Event(Event::Started),
+        };
+        Self::raw(event.stamp(), payload)
+    }
+
     pub fn into_command(self) -> Option<Command> {
         if let Payload::Command(command) = self.payload {
             Some(command)
@@ -51,6 +92,41 @@ impl Message {
             None
         }
     }
+
+    /// A stable JSON shape for tools outside Glow
+    ///
+    /// `{"timestamp": <RFC 3339>, "type": <event or command type>, "payload": <variant data>}`
+    /// where `payload` is `null` for variants without data. Unlike the serde representation
+    /// this does not change shape when the `Message` struct is rearranged.
+    pub fn to_external_json(&self) -> serde_json::Value {
+        let (message_type, payload) = match &self.payload {
+            Payload::Event(event) => (event.event_type(), serde_json::to_value(event)),
+            Payload::Command(command) => (command.command_type(), serde_json::to_value(command)),
+        };
+        serde_json::json!({
+            "timestamp": self.stamp.to_rfc3339(),
+            "type": message_type,
+            "payload": variant_data(payload.unwrap_or(serde_json::Value::Null)),
+        })
+    }
+
+    /// Encode the message as CBOR, a more compact alternative to JSON
+    pub fn to_cbor(&self) -> Result<Vec<u8>, serde_cbor::Error> {
+        serde_cbor::to_vec(self)
+    }
+
+    pub fn from_cbor(data: &[u8]) -> Result<Self, serde_cbor::Error> {
+        serde_cbor::from_slice(data)
+    }
+
+    /// Encode a batch of messages as CBOR
+    pub fn batch_to_cbor(messages: &[Message]) -> Result<Vec<u8>, serde_cbor::Error> {
+        serde_cbor::to_vec(&messages)
+    }
+
+    pub fn batch_from_cbor(data: &[u8]) -> Result<Vec<Self>, serde_cbor::Error> {
+        serde_cbor::from_slice(data)
+    }
 }
 
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
@@ -59,56 +135,197 @@ pub enum Payload {
     Event(Event),
 }
 
+/// The longest `RunHeaterFor` can run the heater for, in seconds
+pub const MAX_HEATER_SECONDS: u64 = 60 * 60;
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub enum Command {
     ListDevices,
     RunHeater,
+    /// Run the heater for a number of seconds rather than the default
+    RunHeaterFor(u64),
     StopHeater,
+    /// Find out whether the heater is on, answered with a `HeaterState`
+    QueryHeaterState,
     SetBrightness(f32),
     UpdateLEDs,
     RunParty,
+    /// Run the party sweeping each colour at its own speed and a number of times rather than
+    /// the default
+    RunPartyWith {
+        speed_ms: u64,
+        cycles: u32,
+    },
+    StartRainbow,
+    StopRainbow,
+    /// Show one colour on every LED, ignoring measurements until `ResumeAuto`
+    SetSolidColour(u8, u8, u8),
+    /// Go back to showing measurements after `SetSolidColour`
+    ResumeAuto,
+    /// Check the sensor, LEDs and connection to `glow-web`, each reports a `SelfTestResult`
+    SelfTest,
     Stop,
 }
 
+impl Command {
+    pub fn title(&self) -> &'static str {
+        match self {
+            Command::ListDevices => "List devices",
+            Command::RunHeater => "Run heater",
+            Command::RunHeaterFor(_) => "Run heater for",
+            Command::StopHeater => "Stop heater",
+            Command::QueryHeaterState => "Query heater state",
+            Command::SetBrightness(_) => "Set brightness",
+            Command::UpdateLEDs => "Update LEDs",
+            Command::RunParty => "Run party",
+            Command::RunPartyWith { .. } => "Run party with",
+            Command::StartRainbow => "Start rainbow",
+            Command::StopRainbow => "Stop rainbow",
+            Command::SetSolidColour(..) => "Set solid colour",
+            Command::ResumeAuto => "Resume auto",
+            Command::SelfTest => "Self test",
+            Command::Stop => "Stop",
+        }
+    }
+
+    pub fn command_type(&self) -> &'static str {
+        match self {
+            Command::ListDevices => "command.list-devices",
+            Command::RunHeater => "command.run-heater",
+            Command::RunHeaterFor(_) => "command.run-heater-for",
+            Command::StopHeater => "command.stop-heater",
+            Command::QueryHeaterState => "command.query-heater-state",
+            Command::SetBrightness(_) => "command.set-brightness",
+            Command::UpdateLEDs => "command.update-leds",
+            Command::RunParty => "command.run-party",
+            Command::RunPartyWith { .. } => "command.run-party-with",
+            Command::StartRainbow => "command.start-rainbow",
+            Command::StopRainbow => "command.stop-rainbow",
+            Command::SetSolidColour(..) => "command.set-solid-colour",
+            Command::ResumeAuto => "command.resume-auto",
+            Command::SelfTest => "command.self-test",
+            Command::Stop => "command.stop",
+        }
+    }
+}
+
+/// Strip the variant name serde wraps enum data in, unit variants have no data
+fn variant_data(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(mut object) if object.len() == 1 => {
+            object.values_mut().next().unwrap().take()
+        }
+        serde_json::Value::String(_) => serde_json::Value::Null,
+        other => other,
+    }
+}
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub enum Event {
     Measurement(Measurement),
+    LabelledMeasurement(String, Measurement),
     MeasurementFailure,
     SingleTap,
+    DoubleTap,
+    TripleTap,
     Devices(Vec<TPLinkDevice>),
     HeaterStarted,
     HeaterStopped,
+    /// Whether the heater plug's relay is on, as reported by the plug
+    HeaterState(bool),
+    PowerReading {
+        watts: f32,
+    },
     LEDBrightness(f32),
     LEDColours(Vec<(u8, u8, u8)>),
+    SelfTestResult {
+        component: String,
+        passed: bool,
+    },
     Started,
+    /// A handler has acted on a command, `command_type` is the command's `command_type()`
+    CommandAck {
+        command_type: String,
+    },
 }
 
 impl Event {
+    /// Acknowledge that `command` has been acted on
+    pub fn ack(command: &Command) -> Self {
+        Event::CommandAck {
+            command_type: command.command_type().to_string(),
+        }
+    }
+
     pub fn title(&self) -> &'static str {
         match self {
             Event::Measurement(_) => "Measurement event",
+            Event::LabelledMeasurement(_, _) => "Labelled measurement event",
             Event::MeasurementFailure => "Measurement failure",
             Event::SingleTap => "Single tap",
+            Event::DoubleTap => "Double tap",
+            Event::TripleTap => "Triple tap",
             Event::Devices(_) => "Device list",
             Event::HeaterStarted => "Heater started",
             Event::HeaterStopped => "Heater stopped",
+            Event::HeaterState(_) => "Heater state",
+            Event::PowerReading { .. } => "Power reading",
             Event::LEDBrightness(_) => "LED brightness",
             Event::LEDColours(_) => "LED colours",
+            Event::SelfTestResult { .. } => "Self test result",
             Event::Started => "Started",
+            Event::CommandAck { .. } => "Command acknowledged",
         }
     }
 
     pub fn event_type(&self) -> &'static str {
         match self {
             Event::Measurement(_) => "environment.measurement",
+            Event::LabelledMeasurement(_, _) => "environment.labelled-measurement",
             Event::MeasurementFailure => "environment.failure",
             Event::SingleTap => "tap.single",
+            Event::DoubleTap => "tap.double",
+            Event::TripleTap => "tap.triple",
             Event::Devices(_) => "tplink.device-list",
             Event::HeaterStarted => "tplink.heater-started",
             Event::HeaterStopped => "tplink.heater-stopped",
+            Event::HeaterState(_) => "tplink.heater-state",
+            Event::PowerReading { .. } => "tplink.power-reading",
             Event::LEDBrightness(_) => "led.brightness",
             Event::LEDColours(_) => "led.colours",
+            Event::SelfTestResult { .. } => "self-test.result",
             Event::Started => "started",
+            Event::CommandAck { .. } => "command.ack",
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::ListDevices => write!(f, "list devices"),
+            Command::RunHeater => write!(f, "run heater"),
+            Command::RunHeaterFor(seconds) => write!(f, "run heater for {}s", seconds),
+            Command::StopHeater => write!(f, "stop heater"),
+            Command::QueryHeaterState => write!(f, "query heater state"),
+            Command::SetBrightness(brightness) => write!(f, "set brightness: {:.2}", brightness),
+            Command::UpdateLEDs => write!(f, "update LEDs"),
+            Command::RunParty => write!(f, "run party"),
+            Command::RunPartyWith { speed_ms, cycles } => {
+                write!(f, "run party {} times at {}ms", cycles, speed_ms)
+            }
+            Command::StartRainbow => write!(f, "start rainbow"),
+            Command::StopRainbow => write!(f, "stop rainbow"),
+            Command::SetSolidColour(red, green, blue) => {
+                write!(
+                    f,
+                    "set solid colour: {}",
+                    crate::colour::rgb_to_hex((*red, *green, *blue))
+                )
+            }
+            Command::ResumeAuto => write!(f, "resume auto"),
+            Command::SelfTest => write!(f, "self test"),
+            Command::Stop => write!(f, "stop"),
         }
     }
 }
@@ -121,14 +338,30 @@ impl fmt::Display for Event {
                 "temperature: {:.2}°C humidity: {:.2}%",
                 measurement.temperature, measurement.humidity
             ),
+            Event::LabelledMeasurement(sensor, measurement) => write!(
+                f,
+                "{} temperature: {:.2}°C humidity: {:.2}%",
+                sensor, measurement.temperature, measurement.humidity
+            ),
             Event::MeasurementFailure => write!(f, "failure"),
             Event::SingleTap => write!(f, "single tap"),
+            Event::DoubleTap => write!(f, "double tap"),
+            Event::TripleTap => write!(f, "triple tap"),
             Event::Devices(_) => write!(f, "device list"),
             Event::HeaterStarted => write!(f, "heater started"),
             Event::HeaterStopped => write!(f, "heater stopped"),
+            Event::HeaterState(on) => write!(f, "heater {}", if *on { "on" } else { "off" }),
+            Event::PowerReading { watts } => write!(f, "power: {:.1}W", watts),
             Event::LEDBrightness(brightness) => write!(f, "brightness: {:.2}", brightness),
             Event::LEDColours(_) => write!(f, "colours updated"),
+            Event::SelfTestResult { component, passed } => write!(
+                f,
+                "{} self test {}",
+                component,
+                if *passed { "passed" } else { "failed" }
+            ),
             Event::Started => write!(f, "started"),
+            Event::CommandAck { command_type } => write!(f, "acknowledged {}", command_type),
         }
     }
 }
@@ -136,6 +369,7 @@ impl fmt::Display for Event {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::TimeZone;
     use std::time::Duration;
 
     #[test]
@@ -160,6 +394,75 @@ mod tests {
         );
     }
 
+    #[test]
+    fn command_titles_and_display() {
+        let cases = [
+            (Command::ListDevices, "List devices", "list devices"),
+            (
+                Command::RunHeaterFor(300),
+                "Run heater for",
+                "run heater for 300s",
+            ),
+            (
+                Command::SetBrightness(0.5),
+                "Set brightness",
+                "set brightness: 0.50",
+            ),
+            (Command::UpdateLEDs, "Update LEDs", "update LEDs"),
+            (Command::Stop, "Stop", "stop"),
+        ];
+
+        for (command, title, display) in cases.iter() {
+            assert_eq!(command.title(), *title);
+            assert_eq!(command.to_string(), *display);
+        }
+    }
+
+    #[test]
+    fn serialize_heater_state() {
+        // arrange
+        let command = Payload::Command(Command::QueryHeaterState);
+        let state = Payload::Event(Event::HeaterState(true));
+
+        // act
+        let command_str = serde_json::to_string(&command).unwrap();
+        let state_str = serde_json::to_string(&state).unwrap();
+
+        // assert
+        assert_eq!(command_str, r#"{"Command":"QueryHeaterState"}"#);
+        assert_eq!(state_str, r#"{"Event":{"HeaterState":true}}"#);
+        assert_eq!(serde_json::from_str::<Payload>(&state_str).unwrap(), state);
+        assert_eq!(
+            serde_json::from_str::<Payload>(&command_str).unwrap(),
+            command
+        );
+    }
+
+    #[test]
+    fn serialize_self_test() {
+        // arrange
+        let command = Payload::Command(Command::SelfTest);
+        let result = Payload::Event(Event::SelfTestResult {
+            component: "leds".to_string(),
+            passed: true,
+        });
+
+        // act
+        let command_str = serde_json::to_string(&command).unwrap();
+        let result_str = serde_json::to_string(&result).unwrap();
+
+        // assert
+        assert_eq!(command_str, r#"{"Command":"SelfTest"}"#);
+        assert_eq!(
+            result_str,
+            r#"{"Event":{"SelfTestResult":{"component":"leds","passed":true}}}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Payload>(&result_str).unwrap(),
+            result
+        );
+    }
+
     #[test]
     fn serialize_a_message() {
         // arrange
@@ -175,6 +478,192 @@ mod tests {
         );
     }
 
+    #[test]
+    fn serialize_a_power_reading() {
+        // arrange
+        let payload = Payload::Event(Event::PowerReading { watts: 1520.5 });
+
+        // act
+        let payload_str = serde_json::to_string(&payload).unwrap();
+
+        // assert
+        assert_eq!(
+            payload_str,
+            r#"{"Event":{"PowerReading":{"watts":1520.5}}}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Payload>(&payload_str).unwrap(),
+            payload
+        );
+    }
+
+    #[test]
+    fn serialize_command_ack() {
+        // arrange
+        let payload = Payload::Event(Event::ack(&Command::SetBrightness(0.5)));
+
+        // act
+        let payload_str = serde_json::to_string(&payload).unwrap();
+
+        // assert
+        assert_eq!(
+            payload_str,
+            r#"{"Event":{"CommandAck":{"command_type":"command.set-brightness"}}}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Payload>(&payload_str).unwrap(),
+            payload
+        );
+    }
+
+    #[test]
+    fn serialize_run_heater_for() {
+        // arrange
+        let payload = Payload::Command(Command::RunHeaterFor(120));
+
+        // act
+        let payload_str = serde_json::to_string(&payload).unwrap();
+
+        // assert
+        assert_eq!(payload_str, r#"{"Command":{"RunHeaterFor":120}}"#);
+        assert_eq!(
+            serde_json::from_str::<Payload>(&payload_str).unwrap(),
+            payload
+        );
+    }
+
+    #[test]
+    fn serialize_run_party_with() {
+        // arrange
+        let payload = Payload::Command(Command::RunPartyWith {
+            speed_ms: 20,
+            cycles: 3,
+        });
+
+        // act
+        let payload_str = serde_json::to_string(&payload).unwrap();
+
+        // assert
+        assert_eq!(
+            payload_str,
+            r#"{"Command":{"RunPartyWith":{"speed_ms":20,"cycles":3}}}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Payload>(&payload_str).unwrap(),
+            payload
+        );
+        assert_eq!(
+            Command::RunPartyWith {
+                speed_ms: 20,
+                cycles: 3
+            }
+            .to_string(),
+            "run party 3 times at 20ms"
+        );
+    }
+
+    #[test]
+    fn serialize_solid_colour_commands() {
+        // arrange
+        let cases = vec![
+            (
+                Payload::Command(Command::SetSolidColour(255, 128, 0)),
+                r#"{"Command":{"SetSolidColour":[255,128,0]}}"#,
+            ),
+            (
+                Payload::Command(Command::ResumeAuto),
+                r#"{"Command":"ResumeAuto"}"#,
+            ),
+        ];
+
+        for (payload, expected) in cases {
+            // act
+            let payload_str = serde_json::to_string(&payload).unwrap();
+
+            // assert
+            assert_eq!(payload_str, expected);
+            assert_eq!(
+                serde_json::from_str::<Payload>(&payload_str).unwrap(),
+                payload
+            );
+        }
+        assert_eq!(
+            Command::SetSolidColour(255, 128, 0).to_string(),
+            "set solid colour: #FF8000"
+        );
+    }
+
+    #[test]
+    fn cbor_and_json_decode_to_the_same_message() {
+        // arrange
+        let message = Message::new_event(Event::LabelledMeasurement(
+            "bathroom".to_string(),
+            Measurement::new(12.3, 43.1),
+        ));
+        let json = serde_json::to_string(&message).unwrap();
+
+        // act
+        let cbor = message.to_cbor().unwrap();
+
+        // assert
+        assert!(cbor.len() < json.len());
+        assert_eq!(Message::from_cbor(&cbor).unwrap(), message);
+        assert_eq!(
+            Message::from_cbor(&cbor).unwrap(),
+            serde_json::from_str::<Message>(&json).unwrap()
+        );
+    }
+
+    #[test]
+    fn cbor_batch_round_trip() {
+        // arrange
+        let messages = vec![
+            Message::new_event(Event::Measurement(Measurement::new(12.3, 43.1))),
+            Message::new_command(Command::SetBrightness(0.2)),
+            Message::new_event(Event::Devices(vec![])),
+        ];
+
+        // act
+        let cbor = Message::batch_to_cbor(&messages).unwrap();
+
+        // assert
+        assert_eq!(Message::batch_from_cbor(&cbor).unwrap(), messages);
+    }
+
+    #[test]
+    fn serialize_a_labelled_measurement() {
+        // arrange
+        let payload = Payload::Event(Event::LabelledMeasurement(
+            "bathroom".to_string(),
+            Measurement::new(12.3, 43.1),
+        ));
+
+        // act
+        let payload_str = serde_json::to_string(&payload).unwrap();
+
+        // assert
+        assert_eq!(
+            payload_str,
+            r#"{"Event":{"LabelledMeasurement":["bathroom",{"temperature":12.3,"humidity":43.1}]}}"#
+        );
+    }
+
+    #[test]
+    fn serialize_deserialize_a_labelled_measurement() {
+        // arrange
+        let message = Message::new_event(Event::LabelledMeasurement(
+            "bathroom".to_string(),
+            Measurement::new(12.12, 13.13),
+        ));
+
+        // act
+        let serialized = serde_json::to_string(&message).unwrap();
+        let deserialized: Message = serde_json::from_str(&serialized).unwrap();
+
+        // assert
+        assert_eq!(message, deserialized);
+    }
+
     #[test]
     fn serialize_a_stop_message() {
         // arrange
@@ -199,4 +688,116 @@ mod tests {
         // assert
         assert_eq!(message, deserialized);
     }
+
+    #[test]
+    fn from_v1_maps_every_message() {
+        // arrange
+        let stamp = Utc.ymd(2020, 1, 2).and_hms(3, 4, 5);
+        let devices = vec![TPLinkDevice {
+            name: "heater".to_string(),
+        }];
+        let cases = vec![
+            (
+                v1::Message::Environment(v1::EnvironmentEvent::Measurement(Measurement::new(
+                    20.5, 45.0,
+                ))),
+                Payload::Event(Event::Measurement(Measurement::new(20.5, 45.0))),
+            ),
+            (
+                v1::Message::Environment(v1::EnvironmentEvent::Failure),
+                Payload::Event(Event::MeasurementFailure),
+            ),
+            (
+                v1::Message::Tap(v1::TapEvent::SingleTap),
+                Payload::Event(Event::SingleTap),
+            ),
+            (
+                v1::Message::TPLink(v1::TPLinkEvent::ListDevices),
+                Payload::Command(Command::ListDevices),
+            ),
+            (
+                v1::Message::TPLink(v1::TPLinkEvent::DeviceList(devices.clone())),
+                Payload::Event(Event::Devices(devices)),
+            ),
+            (
+                v1::Message::TPLink(v1::TPLinkEvent::RunHeater),
+                Payload::Command(Command::RunHeater),
+            ),
+            (
+                v1::Message::TPLink(v1::TPLinkEvent::HeaterStarted),
+                Payload::Event(Event::HeaterStarted),
+            ),
+            (
+                v1::Message::TPLink(v1::TPLinkEvent::HeaterStopped),
+                Payload::Event(Event::HeaterStopped),
+            ),
+            (
+                v1::Message::LED(v1::LEDEvent::Brightness(0.5)),
+                Payload::Command(Command::SetBrightness(0.5)),
+            ),
+            (
+                v1::Message::LED(v1::LEDEvent::Party),
+                Payload::Command(Command::RunParty),
+            ),
+            (
+                v1::Message::LED(v1::LEDEvent::Update),
+                Payload::Command(Command::UpdateLEDs),
+            ),
+            (
+                v1::Message::LED(v1::LEDEvent::LEDsUpdated(vec![(1, 2, 3)])),
+                Payload::Event(Event::LEDColours(vec![(1, 2, 3)])),
+            ),
+            (v1::Message::Stop, Payload::Command(Command::Stop)),
+            (v1::Message::Started, Payload::Event(Event::Started)),
+        ];
+
+        for (v1_message, payload) in cases {
+            // act
+            let message = Message::from_v1(v1::Event::raw(stamp, v1_message.clone()));
+
+            // assert
+            assert_eq!(message, Message::raw(stamp, payload), "{:?}", v1_message);
+        }
+    }
+
+    #[test]
+    fn external_json_for_a_measurement() {
+        // arrange
+        let stamp = Utc.ymd(2020, 1, 2).and_hms(3, 4, 5);
+        let message = Message::raw(
+            stamp,
+            Payload::Event(Event::Measurement(Measurement::new(21.5, 40.0))),
+        );
+
+        // act
+        let json = message.to_external_json();
+
+        // assert
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "timestamp": "2020-01-02T03:04:05+00:00",
+                "type": "environment.measurement",
+                "payload": {"temperature": 21.5, "humidity": 40.0},
+            })
+        );
+    }
+
+    #[test]
+    fn external_json_for_commands() {
+        // arrange
+        let stamp = Utc.ymd(2020, 1, 2).and_hms(3, 4, 5);
+
+        // act
+        let brightness =
+            Message::raw(stamp, Payload::Command(Command::SetBrightness(0.5))).to_external_json();
+        let stop = Message::raw(stamp, Payload::Command(Command::Stop)).to_external_json();
+
+        // assert
+        assert_eq!(brightness["type"], "command.set-brightness");
+        assert_eq!(brightness["payload"], 0.5);
+        assert_eq!(stop["timestamp"], "2020-01-02T03:04:05+00:00");
+        assert_eq!(stop["type"], "command.stop");
+        assert!(stop["payload"].is_null());
+    }
 }
@@ -1,34 +1,354 @@
-use std::env;
+use std::{
+    env,
+    io::{self, BufRead},
+};
 
 use argon2::{self, Config};
+use rand::RngCore;
+
+const USAGE: &str = "usage:
+    hash-password encode (PASSWORD | --stdin) [SALT] [--salt SALT] [--memory KIB] [--time PASSES] [--lanes LANES]
+    hash-password verify (PASSWORD | --stdin) HASH
+
+SALT and HASH are base64 encoded, a random salt is generated when none is given.
+With --stdin the password is read from the first line of standard input, keeping it
+out of the shell history and process list.";
+
+/// Length of a generated salt in bytes
+const SALT_LENGTH: usize = 16;
+
+/// How hard argon2 works, the defaults are those of `argon2::Config::default()`
+#[derive(Debug, PartialEq)]
+struct Params {
+    memory_kib: u32,
+    time_cost: u32,
+    lanes: u32,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        let config = Config::default();
+        Self {
+            memory_kib: config.mem_cost,
+            time_cost: config.time_cost,
+            lanes: config.lanes,
+        }
+    }
+}
+
+/// Where the password comes from
+#[derive(Debug, PartialEq)]
+enum Password {
+    Argument(String),
+    Stdin,
+}
+
+impl Password {
+    fn read(self, stdin: impl BufRead) -> Result<String, String> {
+        match self {
+            Self::Argument(password) => {
+                eprintln!("Entered password: {}", password);
+                Ok(password)
+            }
+            Self::Stdin => read_password(stdin),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Encode {
+        password: Password,
+        salt: Option<Vec<u8>>,
+        params: Params,
+    },
+    Verify {
+        password: Password,
+        hash: String,
+    },
+}
 
 fn main() {
-    let command: String = env::args()
-        .nth(1)
-        .expect("requires a command [encode|verify]");
-    let password: String = env::args().nth(2).expect("requires a password to hash");
-    eprintln!("Entered password: {}", password);
+    let command = parse_args(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, USAGE);
+        std::process::exit(2);
+    });
+
+    let stdin = io::stdin();
+    let result = match command {
+        Command::Encode {
+            password,
+            salt,
+            params,
+        } => password.read(stdin.lock()).and_then(|password| {
+            let salt = salt.unwrap_or_else(|| {
+                let salt = random_salt();
+                eprintln!("Generated salt: {}", base64::encode(&salt));
+                salt
+            });
+            encode(&password, &salt, &params).map(|hash| println!("{}", hash))
+        }),
+        Command::Verify { password, hash } => password
+            .read(stdin.lock())
+            .and_then(|password| verify(&password, &hash))
+            .map(|valid| println!("{}", valid)),
+    };
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let command = args.next().ok_or("requires a command [encode|verify]")?;
+    let password = match args
+        .next()
+        .ok_or("requires a password to hash or --stdin")?
+    {
+        flag if flag == "--stdin" => Password::Stdin,
+        password => Password::Argument(password),
+    };
 
     match command.as_str() {
         "encode" => {
-            let salt: Vec<u8> =
-                base64::decode(&env::args().nth(3).expect("requires a salt for the hash"))
-                    .expect("salt must be base64 encoded");
+            let mut salt = None;
+            let mut params = Params::default();
+            while let Some(arg) = args.next() {
+                let mut value = || args.next().ok_or(format!("{} requires a value", arg));
+                match arg.as_str() {
+                    "--salt" => salt = Some(decode_salt(&value()?)?),
+                    "--memory" => params.memory_kib = parse_number(&arg, &value()?)?,
+                    "--time" => params.time_cost = parse_number(&arg, &value()?)?,
+                    "--lanes" => params.lanes = parse_number(&arg, &value()?)?,
+                    _ if salt.is_none() && !arg.starts_with("--") => {
+                        salt = Some(decode_salt(&arg)?)
+                    }
+                    _ => return Err(format!("unexpected argument '{}'", arg)),
+                }
+            }
+            Ok(Command::Encode {
+                password,
+                salt,
+                params,
+            })
+        }
+        "verify" => Ok(Command::Verify {
+            password,
+            hash: args.next().ok_or("requires a hash to verify")?,
+        }),
+        _ => Err(format!("invalid command '{}'", command)),
+    }
+}
 
-            let config = Config::default();
-            let hash = argon2::hash_encoded(password.as_bytes(), &salt, &config).unwrap();
+fn decode_salt(salt: &str) -> Result<Vec<u8>, String> {
+    base64::decode(salt).map_err(|err| format!("salt must be base64 encoded: {}", err))
+}
 
-            println!("{}", base64::encode(&hash));
-        }
-        "verify" => {
-            let hash_as_vec =
-                base64::decode(&env::args().nth(3).expect("requires a hash to verify"))
-                    .expect("hash must be base64 encoded");
-            let hash = std::str::from_utf8(&hash_as_vec).expect("hash must be valid utf8");
-            println!("{:?}", argon2::verify_encoded(&hash, password.as_bytes()));
+fn parse_number(name: &str, value: &str) -> Result<u32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} must be a number, not '{}'", name, value))
+}
+
+/// Read the password from the first line, without its line ending
+fn read_password(mut input: impl BufRead) -> Result<String, String> {
+    let mut line = String::new();
+    input
+        .read_line(&mut line)
+        .map_err(|err| format!("cannot read the password: {}", err))?;
+    let password = line.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        Err("the password must not be empty".to_string())
+    } else {
+        Ok(password.to_string())
+    }
+}
+
+fn random_salt() -> Vec<u8> {
+    let mut salt = vec![0; SALT_LENGTH];
+    rand::thread_rng().fill_bytes(&mut salt);
+    salt
+}
+
+/// Hash a password, returning the base64 encoded argon2 hash that glow-web expects
+fn encode(password: &str, salt: &[u8], params: &Params) -> Result<String, String> {
+    let config = Config {
+        mem_cost: params.memory_kib,
+        time_cost: params.time_cost,
+        lanes: params.lanes,
+        ..Config::default()
+    };
+    let hash = argon2::hash_encoded(password.as_bytes(), salt, &config)
+        .map_err(|err| format!("cannot hash the password: {}", err))?;
+    Ok(base64::encode(&hash))
+}
+
+/// Check a password against a base64 encoded argon2 hash
+fn verify(password: &str, hash: &str) -> Result<bool, String> {
+    let hash =
+        base64::decode(hash).map_err(|err| format!("hash must be base64 encoded: {}", err))?;
+    let hash = std::str::from_utf8(&hash).map_err(|_| "hash must be valid utf8")?;
+    argon2::verify_encoded(hash, password.as_bytes())
+        .map_err(|err| format!("cannot verify the password: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|arg| (*arg).to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn cheap_params() -> Params {
+        Params {
+            memory_kib: 64,
+            time_cost: 1,
+            lanes: 2,
         }
-        _ => {
-            panic!("invalid command");
+    }
+
+    #[test]
+    fn encode_then_verify() {
+        // arrange
+        let hash = encode("hunter2", b"saltysalt", &cheap_params()).unwrap();
+
+        // act
+        let right = verify("hunter2", &hash).unwrap();
+        let wrong = verify("hunter3", &hash).unwrap();
+
+        // assert
+        assert!(right);
+        assert!(!wrong);
+    }
+
+    #[test]
+    fn encode_uses_the_params() {
+        // act
+        let hash = encode("hunter2", b"saltysalt", &cheap_params()).unwrap();
+
+        // assert
+        let hash = String::from_utf8(base64::decode(&hash).unwrap()).unwrap();
+        assert!(hash.contains("m=64,t=1,p=2"), "{}", hash);
+    }
+
+    #[test]
+    fn encode_rejects_short_salts() {
+        assert!(encode("hunter2", b"salt", &cheap_params()).is_err());
+    }
+
+    #[test]
+    fn verify_rejects_bad_hashes() {
+        assert!(verify("hunter2", "not base64!").is_err());
+        assert!(verify("hunter2", &base64::encode("not a hash")).is_err());
+    }
+
+    #[test]
+    fn random_salts_differ() {
+        assert_ne!(random_salt(), random_salt());
+    }
+
+    #[test]
+    fn parse_encode_with_flags() {
+        // act
+        let command = parse_args(args(&[
+            "encode",
+            "hunter2",
+            "--memory",
+            "64",
+            "--time",
+            "1",
+            "--lanes",
+            "2",
+            "--salt",
+            "c2FsdHlzYWx0",
+        ]))
+        .unwrap();
+
+        // assert
+        assert_eq!(
+            command,
+            Command::Encode {
+                password: Password::Argument("hunter2".to_string()),
+                salt: Some(b"saltysalt".to_vec()),
+                params: cheap_params(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_encode_without_salt() {
+        // act
+        let command = parse_args(args(&["encode", "hunter2"])).unwrap();
+
+        // assert
+        assert_eq!(
+            command,
+            Command::Encode {
+                password: Password::Argument("hunter2".to_string()),
+                salt: None,
+                params: Params::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_encode_with_positional_salt() {
+        // act
+        let command = parse_args(args(&["encode", "hunter2", "c2FsdHlzYWx0"])).unwrap();
+
+        // assert
+        match command {
+            Command::Encode { salt, .. } => assert_eq!(salt, Some(b"saltysalt".to_vec())),
+            Command::Verify { .. } => panic!("expected encode"),
         }
-    };
+    }
+
+    #[test]
+    fn parse_password_from_stdin() {
+        // act
+        let command = parse_args(args(&["verify", "--stdin", "aGFzaA=="])).unwrap();
+
+        // assert
+        assert_eq!(
+            command,
+            Command::Verify {
+                password: Password::Stdin,
+                hash: "aGFzaA==".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn hash_a_password_from_stdin() {
+        // arrange
+        let stdin: &[u8] = b"hunter2\r\nignored\n";
+
+        // act
+        let password = Password::Stdin.read(stdin).unwrap();
+        let hash = encode(&password, b"saltysalt", &cheap_params()).unwrap();
+
+        // assert
+        assert_eq!(password, "hunter2");
+        assert!(verify("hunter2", &hash).unwrap());
+    }
+
+    #[test]
+    fn read_password_rejects_empty_input() {
+        assert!(read_password(&b""[..]).is_err());
+        assert!(read_password(&b"\n"[..]).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_bad_arguments() {
+        assert!(parse_args(args(&["encode", "hunter2", "--time", "fast"])).is_err());
+        assert!(parse_args(args(&["encode", "hunter2", "--lanes"])).is_err());
+        assert!(parse_args(args(&["encode", "hunter2", "--pepper", "1"])).is_err());
+        assert!(parse_args(args(&["verify", "hunter2"])).is_err());
+        assert!(parse_args(args(&["hash", "hunter2"])).is_err());
+    }
 }